@@ -15,7 +15,7 @@ extern crate snow;
 extern crate socket2;
 
 use criterion::{Benchmark, Criterion, Throughput};
-use wireguard::peer::{Peer, Session};
+use wireguard::peer::{DEFAULT_REPLAY_WINDOW_SIZE, Peer, Session};
 use wireguard::noise;
 use wireguard::timestamp::Timestamp;
 use x25519_dalek::{generate_secret, generate_public};
@@ -46,8 +46,8 @@ fn connected_peers() -> (Peer, [u8; 32], Peer, [u8; 32]) {
     let     resp_keys = Keypair::new();
     let mut peer_init = Peer::new(Default::default());
     let mut peer_resp = Peer::new(Default::default());
-    let mut initiator = noise::build_initiator(&init_keys.private, &resp_keys.public, &None).unwrap();
-    let mut responder = noise::build_responder(&resp_keys.private).unwrap();
+    let mut initiator = noise::build_initiator(noise::DEFAULT_PROTOCOL, &init_keys.private, &resp_keys.public, &None).unwrap();
+    let mut responder = noise::build_responder(noise::DEFAULT_PROTOCOL, &resp_keys.private).unwrap();
     let mut buf       = [0u8; 500];
 
     match responder {
@@ -62,8 +62,8 @@ fn connected_peers() -> (Peer, [u8; 32], Peer, [u8; 32]) {
     let len = responder.write_message(&[], &mut buf).unwrap();
     let _   = initiator.read_message(&buf[..len], &mut []).unwrap();
 
-    let mut init_session = Session::new(initiator.into_transport_mode().unwrap(), 1);
-    let mut resp_session = Session::with_their_index(responder.into_transport_mode().unwrap(), 2, init_session.our_index);
+    let mut init_session = Session::new(initiator.into_transport_mode().unwrap(), 1, DEFAULT_REPLAY_WINDOW_SIZE);
+    let mut resp_session = Session::with_their_index(responder.into_transport_mode().unwrap(), 2, init_session.our_index, DEFAULT_REPLAY_WINDOW_SIZE);
     init_session.their_index = resp_session.our_index;
     init_session.birthday = Timestamp::now();
     resp_session.birthday = Timestamp::now();
@@ -83,18 +83,18 @@ fn benchmarks(c: &mut Criterion) {
     c.bench("handshake", Benchmark::new("initialization", |b| {
         let (mut peer, _, _, _) = connected_peers();
         b.iter(move || {
-            peer.initiate_new_session(&[1u8; 32], 1).unwrap()
+            peer.initiate_new_session(&[1u8; 32], noise::DEFAULT_PROTOCOL, 1).unwrap()
         });
     }).throughput(Throughput::Elements(1)));
 
     c.bench("handshake", Benchmark::new("response", |b| {
         let (mut peer_init, init_priv, mut peer_resp, resp_priv) = connected_peers();
-        let (_, init, _) = peer_init.initiate_new_session(&init_priv, 1).expect("initiate");
+        let (_, init, _) = peer_init.initiate_new_session(&init_priv, noise::DEFAULT_PROTOCOL, 1).expect("initiate");
         let init = init.try_into().unwrap();
         let addr = SocketAddr::from(([127, 0, 0, 1], 443)).into();
         b.iter(move || {
             peer_resp.last_handshake_tai64n = None;
-            let handshake = Peer::process_incoming_handshake(&resp_priv, &init).unwrap();
+            let handshake = Peer::process_incoming_handshake(&resp_priv, noise::DEFAULT_PROTOCOL, &init).unwrap();
             peer_resp.complete_incoming_handshake(addr, 2, handshake).expect("second half");
         });
     }).throughput(Throughput::Elements(1)));
@@ -0,0 +1,105 @@
+/* SPDX-License-Identifier: GPL-2.0
+ *
+ * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
+ */
+
+//! The cryptographic building blocks on their own, without the `Peer` bookkeeping around them,
+//! so that a `snow` or `treebitmap` upgrade can be judged by itself. Keep a baseline with
+//! `cargo bench --bench crypto -- --save-baseline <name>` and compare with `--baseline <name>`.
+
+#[macro_use]
+extern crate criterion;
+extern crate rand;
+extern crate snow;
+extern crate treebitmap;
+extern crate wireguard;
+extern crate x25519_dalek;
+
+use criterion::{Benchmark, Criterion, Throughput};
+use rand::{OsRng, Rng};
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use treebitmap::{IpLookupTable, IpLookupTableOps};
+use wireguard::noise;
+use x25519_dalek::{generate_secret, generate_public};
+
+const PAYLOAD_LEN: usize = 1500;
+const TAG_LEN    : usize = 16;
+
+fn keypair(rng: &mut OsRng) -> ([u8; 32], [u8; 32]) {
+    let private = generate_secret(rng);
+    let public  = generate_public(&private).to_bytes();
+    (private, public)
+}
+
+/// Runs both halves of a handshake and returns the (initiator, responder) transport sessions.
+fn handshake(init_priv: &[u8; 32], resp_priv: &[u8; 32], resp_pub: &[u8; 32]) -> (snow::Session, snow::Session) {
+    let mut initiator = noise::build_initiator(noise::DEFAULT_PROTOCOL, init_priv, resp_pub, &None).unwrap();
+    let mut responder = noise::build_responder(noise::DEFAULT_PROTOCOL, resp_priv).unwrap();
+    let mut buf       = [0u8; 256];
+    let mut timestamp = [0u8; 12];
+
+    let len = initiator.write_message(&[0u8; 12], &mut buf).unwrap();
+    let _   = responder.read_message(&buf[..len], &mut timestamp).unwrap();
+    responder.set_psk(2, &[0u8; 32]).unwrap();
+    let len = responder.write_message(&[], &mut buf).unwrap();
+    let _   = initiator.read_message(&buf[..len], &mut []).unwrap();
+
+    (initiator.into_transport_mode().unwrap(), responder.into_transport_mode().unwrap())
+}
+
+fn transport_sessions() -> (snow::Session, snow::Session) {
+    let mut rng               = OsRng::new().unwrap();
+    let (init_priv, _)        = keypair(&mut rng);
+    let (resp_priv, resp_pub) = keypair(&mut rng);
+    handshake(&init_priv, &resp_priv, &resp_pub)
+}
+
+fn benchmarks(c: &mut Criterion) {
+    c.bench("crypto", Benchmark::new("encrypt_1500b", |b| {
+        let (mut sender, _) = transport_sessions();
+        let     payload     = [1u8; PAYLOAD_LEN];
+        let mut out         = [0u8; PAYLOAD_LEN + TAG_LEN];
+        b.iter(|| sender.write_message(&payload, &mut out).unwrap());
+    }).throughput(Throughput::Bytes(PAYLOAD_LEN as u32)));
+
+    c.bench("crypto", Benchmark::new("decrypt_1500b", |b| {
+        let (mut sender, mut receiver) = transport_sessions();
+        let mut sealed = [0u8; PAYLOAD_LEN + TAG_LEN];
+        let mut opened = [0u8; PAYLOAD_LEN];
+        let     len    = sender.write_message(&[1u8; PAYLOAD_LEN], &mut sealed).unwrap();
+        b.iter(|| {
+            receiver.set_receiving_nonce(0).unwrap();
+            receiver.read_message(&sealed[..len], &mut opened).unwrap()
+        });
+    }).throughput(Throughput::Bytes(PAYLOAD_LEN as u32)));
+
+    c.bench("crypto", Benchmark::new("full_handshake", |b| {
+        let mut rng               = OsRng::new().unwrap();
+        let (init_priv, _)        = keypair(&mut rng);
+        let (resp_priv, resp_pub) = keypair(&mut rng);
+        b.iter(|| handshake(&init_priv, &resp_priv, &resp_pub));
+    }).throughput(Throughput::Elements(1)));
+
+    c.bench("crypto", Benchmark::new("ip4_lookup_1000_prefixes", |b| {
+        let mut rng   = OsRng::new().unwrap();
+        let mut table = IpLookupTable::new();
+        for i in 0..1000u32 {
+            let len = rng.gen_range(8, 33);
+            let _   = table.insert(Ipv4Addr::from(rng.gen::<u32>() & (!0u32 << (32 - len))), len, i);
+        }
+        let addrs = (0..1024).map(|_| Ipv4Addr::from(rng.gen::<u32>())).collect::<Vec<_>>();
+        let mut i = 0;
+        b.iter(|| {
+            i = (i + 1) % addrs.len();
+            table.longest_match(addrs[i]).map(|(_, _, value)| *value)
+        });
+    }).throughput(Throughput::Elements(1)));
+}
+
+fn custom_criterion() -> Criterion {
+    Criterion::default().warm_up_time(Duration::new(1, 0)).measurement_time(Duration::new(3, 0))
+}
+
+criterion_group!(name = benches; config = custom_criterion(); targets = benchmarks);
+criterion_main!(benches);
@@ -0,0 +1,104 @@
+/* SPDX-License-Identifier: GPL-2.0
+ *
+ * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
+ */
+
+//! The allowed-IPs routing table at different sizes, next to a plain linear scan over the same
+//! prefixes to show where the tree starts paying for itself.
+
+#[macro_use]
+extern crate criterion;
+extern crate rand;
+extern crate treebitmap;
+
+use criterion::{Criterion, ParameterizedBenchmark};
+use rand::{OsRng, Rng};
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use treebitmap::{IpLookupTable, IpLookupTableOps};
+
+const SIZES: &[usize] = &[10, 100, 1000, 10000];
+
+type Prefix = (Ipv4Addr, u32, u32);
+
+/// `n` random prefixes between /8 and /32, none of them inside 0.0.0.0/8 so that addresses
+/// there are guaranteed misses.
+fn prefixes(n: usize) -> Vec<Prefix> {
+    let mut rng = OsRng::new().unwrap();
+    (0..n as u32).map(|value| {
+        let len  = rng.gen_range(8, 33);
+        let addr = (rng.gen::<u32>() | 0x0100_0000) & (!0u32 << (32 - len));
+        (Ipv4Addr::from(addr), len, value)
+    }).collect()
+}
+
+fn table(prefixes: &[Prefix]) -> IpLookupTable<Ipv4Addr, u32> {
+    let mut table = IpLookupTable::new();
+    for &(addr, len, value) in prefixes {
+        let _ = table.insert(addr, len, value);
+    }
+    table
+}
+
+fn linear_match(prefixes: &[Prefix], addr: Ipv4Addr) -> Option<u32> {
+    let addr = u32::from(addr);
+    prefixes.iter()
+        .filter(|&&(net, len, _)| addr & (!0u32 << (32 - len)) == u32::from(net))
+        .max_by_key(|&&(_, len, _)| len)
+        .map(|&(_, _, value)| value)
+}
+
+fn benchmarks(c: &mut Criterion) {
+    c.bench("routing", ParameterizedBenchmark::new("lookup_hit", |b, &n| {
+        let prefixes = prefixes(n);
+        let table    = table(&prefixes);
+        let mut i    = 0;
+        b.iter(|| {
+            i = (i + 1) % prefixes.len();
+            table.longest_match(prefixes[i].0).map(|(_, _, value)| *value)
+        });
+    }, SIZES.to_vec()).with_function("linear_lookup_hit", |b, &n| {
+        let prefixes = prefixes(n);
+        let mut i    = 0;
+        b.iter(|| {
+            i = (i + 1) % prefixes.len();
+            linear_match(&prefixes, prefixes[i].0)
+        });
+    }));
+
+    c.bench("routing", ParameterizedBenchmark::new("lookup_miss", |b, &n| {
+        let table = table(&prefixes(n));
+        let miss  = Ipv4Addr::new(0, 1, 2, 3);
+        b.iter(|| table.longest_match(miss).map(|(_, _, value)| *value));
+    }, SIZES.to_vec()).with_function("linear_lookup_miss", |b, &n| {
+        let prefixes = prefixes(n);
+        let miss     = Ipv4Addr::new(0, 1, 2, 3);
+        b.iter(|| linear_match(&prefixes, miss));
+    }));
+
+    c.bench("routing", ParameterizedBenchmark::new("insert_batch", |b, &n| {
+        let prefixes = prefixes(n);
+        b.iter(|| table(&prefixes));
+    }, SIZES.to_vec()));
+
+    // What Router does for replace_allowed_ips: take every route out, then put the new ones in.
+    c.bench("routing", ParameterizedBenchmark::new("rebuild_from_scratch", |b, &n| {
+        let prefixes = prefixes(n);
+        b.iter_with_setup(|| table(&prefixes), |mut table| {
+            for &(addr, len, _) in &prefixes {
+                let _ = table.remove(addr, len);
+            }
+            for &(addr, len, value) in &prefixes {
+                let _ = table.insert(addr, len, value);
+            }
+            table
+        });
+    }, SIZES.to_vec()));
+}
+
+fn custom_criterion() -> Criterion {
+    Criterion::default().warm_up_time(Duration::new(1, 0)).measurement_time(Duration::new(3, 0))
+}
+
+criterion_group!(name = benches; config = custom_criterion(); targets = benchmarks);
+criterion_main!(benches);
@@ -0,0 +1,7 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate wireguard;
+
+fuzz_target!(|data: &[u8]| {
+    wireguard::fuzzing::config_message(data);
+});
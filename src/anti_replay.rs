@@ -5,39 +5,60 @@
 
 use failure::Error;
 
-// This is RFC 6479.
+// This is RFC 6479, with the window's size set per interface.
 
-// Power of 2.
-const BITMAP_BITLEN: u64 = 2048;
+/// How far behind the newest nonce others are accepted, unless configured otherwise. 64 is
+/// what the WireGuard paper asks for.
+pub const DEFAULT_WINDOW_SIZE: u32 = 64;
+/// The range the window size may be configured in. It has to be a power of 2 as well.
+pub const MIN_WINDOW_SIZE: u32 = 64;
+pub const MAX_WINDOW_SIZE: u32 = 8192;
 
 const SIZE_OF_INTEGER: u64 = 32;
-const BITMAP_LEN: usize = (BITMAP_BITLEN / SIZE_OF_INTEGER) as usize;
-const BITMAP_INDEX_MASK: u64 = BITMAP_LEN as u64 - 1;
 // REDUNDANT_BIT_SHIFTS = log2(SIZE_OF_INTEGER).
 const REDUNDANT_BIT_SHIFTS: u64 = 5;
 const BITMAP_LOC_MASK: u64 = SIZE_OF_INTEGER - 1;
-/// Size of anti-replay window.
-pub const WINDOW_SIZE: u64 = BITMAP_BITLEN - SIZE_OF_INTEGER;
+
+/// Whether `window` is a window size `AntiReplay::new` takes.
+pub fn is_valid_window_size(window: u32) -> bool {
+    window.is_power_of_two() && window >= MIN_WINDOW_SIZE && window <= MAX_WINDOW_SIZE
+}
 
 pub struct AntiReplay {
-    bitmap: [u32; BITMAP_LEN],
+    bitmap: Vec<u32>,
     last: u64,
+    window: u64,
 }
 
 impl Default for AntiReplay {
     fn default() -> Self {
-        AntiReplay::new()
+        AntiReplay::new(DEFAULT_WINDOW_SIZE)
     }
 }
 
 impl AntiReplay {
-    pub fn new() -> Self {
+    /// A window accepting nonces up to `window` behind the newest one, which has to pass
+    /// `is_valid_window_size`. One 32-bit word of the bitmap is always being recycled, so it
+    /// gets a word on top of the window, rounded up to the power of 2 the indexing needs.
+    pub fn new(window: u32) -> Self {
+        debug_assert!(is_valid_window_size(window), "invalid anti-replay window size {}", window);
+        let words = (u64::from(window) / SIZE_OF_INTEGER + 1).next_power_of_two();
         AntiReplay {
             last: 0,
-            bitmap: [0; BITMAP_LEN],
+            bitmap: vec![0; words as usize],
+            window: u64::from(window),
         }
     }
 
+    /// How far behind the newest nonce one may be and still be accepted.
+    pub fn window_size(&self) -> u64 {
+        self.window
+    }
+
+    fn index_mask(&self) -> u64 {
+        self.bitmap.len() as u64 - 1
+    }
+
     /// Returns true if check is passed, i.e., not a replay or too old.
     ///
     /// Unlike RFC 6479, zero is allowed.
@@ -47,12 +68,12 @@ impl AntiReplay {
             return true;
         }
 
-        if self.last - seq > WINDOW_SIZE {
+        if self.last - seq > self.window_size() {
             return false;
         }
 
         let bit_location = seq & BITMAP_LOC_MASK;
-        let index = (seq >> REDUNDANT_BIT_SHIFTS) & BITMAP_INDEX_MASK;
+        let index = (seq >> REDUNDANT_BIT_SHIFTS) & self.index_mask();
 
         self.bitmap[index as usize] & (1 << bit_location) == 0
     }
@@ -67,11 +88,13 @@ impl AntiReplay {
             let index_cur = self.last >> REDUNDANT_BIT_SHIFTS;
             let diff = index - index_cur;
 
-            if diff >= BITMAP_LEN as u64 {
-                self.bitmap = [0; BITMAP_LEN];
+            if diff >= self.bitmap.len() as u64 {
+                for word in &mut self.bitmap {
+                    *word = 0;
+                }
             } else {
                 for i in 0..diff {
-                    let real_index = (index_cur + i + 1) & BITMAP_INDEX_MASK;
+                    let real_index = (index_cur + i + 1) & self.index_mask();
                     self.bitmap[real_index as usize] = 0;
                 }
             }
@@ -79,7 +102,7 @@ impl AntiReplay {
             self.last = seq;
         }
 
-        let index = index & BITMAP_INDEX_MASK;
+        let index = index & self.index_mask();
         let bit_location = seq & BITMAP_LOC_MASK;
         self.bitmap[index as usize] |= 1 << bit_location;
     }
@@ -97,10 +120,14 @@ impl AntiReplay {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
+    use std::collections::HashSet;
+
+    const WINDOW_SIZE: u64 = DEFAULT_WINDOW_SIZE as u64;
 
     #[test]
     fn anti_replay() {
-        let mut ar = AntiReplay::new();
+        let mut ar = AntiReplay::default();
 
         for i in 0..20000 {
             ar.update(i).unwrap();
@@ -138,9 +165,120 @@ mod tests {
         }
     }
 
+    #[test]
+    fn anti_replay_window_edges() {
+        let mut ar = AntiReplay::default();
+        let last = 3 * WINDOW_SIZE;
+        ar.update(last).unwrap();
+
+        // One below the window is too old, exactly at its edge is still accepted.
+        assert!(ar.update(last - WINDOW_SIZE - 1).is_err());
+        ar.update(last - WINDOW_SIZE).unwrap();
+        assert!(ar.update(last - WINDOW_SIZE).is_err());
+
+        // Duplicates inside the window and of the current maximum.
+        ar.update(last - 1).unwrap();
+        assert!(ar.update(last - 1).is_err());
+        assert!(ar.update(last).is_err());
+
+        // One above the maximum slides the window forward by one.
+        ar.update(last + 1).unwrap();
+        assert!(ar.update(last + 1).is_err());
+        assert!(ar.update(last - WINDOW_SIZE).is_err());
+        ar.update(last - WINDOW_SIZE + 1).unwrap();
+    }
+
+    #[test]
+    fn configured_window_edges() {
+        for &size in &[128u32, 512] {
+            let mut ar = AntiReplay::new(size);
+            let window = ar.window_size();
+            assert_eq!(window, u64::from(size));
+
+            let last = 3 * window;
+            ar.update(last).unwrap();
+            assert!(ar.update(last - window - 1).is_err(), "{}-entry window", size);
+            ar.update(last - window).unwrap();
+            ar.update(last - 1).unwrap();
+            assert!(ar.update(last - 1).is_err());
+
+            // jumping more than a window ahead leaves the old maximum behind
+            ar.update(last + window + 1).unwrap();
+            assert!(ar.update(last).is_err());
+            ar.update(last + 1).unwrap();
+            assert!(ar.update(last + 1).is_err());
+        }
+    }
+
+    #[test]
+    fn valid_window_sizes() {
+        assert!(is_valid_window_size(DEFAULT_WINDOW_SIZE));
+        assert!(is_valid_window_size(64) && is_valid_window_size(8192));
+        assert!(!is_valid_window_size(32) && !is_valid_window_size(16384) && !is_valid_window_size(100) && !is_valid_window_size(0));
+    }
+
+    #[test]
+    fn default_window_is_64() {
+        let mut ar = AntiReplay::default();
+        ar.update(1000).unwrap();
+        ar.update(1000 - 64).unwrap();
+        assert!(ar.update(1000 - 65).is_err());
+    }
+
+    /// Nonces clustered at the bottom and top of the range so that sequences collide, jump
+    /// far ahead and land right at the window's edges.
+    fn nonce() -> BoxedStrategy<u64> {
+        prop_oneof![
+            0..4 * WINDOW_SIZE,
+            (u64::max_value() - 4 * WINDOW_SIZE)..u64::max_value(),
+            any::<u64>(),
+        ].boxed()
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(100_000))]
+
+        #[test]
+        fn anti_replay_matches_model(nonces in prop::collection::vec(nonce(), 1..64)) {
+            let mut ar       = AntiReplay::default();
+            let mut seen     = HashSet::new();
+            let mut accepted = Vec::new();
+            let mut max      = 0;
+
+            for &seq in &nonces {
+                let fresh = !seen.contains(&seq) && (seq > max || max - seq <= WINDOW_SIZE);
+                prop_assert_eq!(ar.update(seq).is_ok(), fresh, "nonce {} with max {}", seq, max);
+                if fresh {
+                    let _ = seen.insert(seq);
+                    accepted.push(seq);
+                    max = max.max(seq);
+                }
+                prop_assert_eq!(ar.last, max);
+            }
+
+            for &seq in &accepted {
+                prop_assert!(ar.update(seq).is_err(), "nonce {} accepted twice", seq);
+            }
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(1_000))]
+
+        #[test]
+        fn anti_replay_accepts_reordered_window(keys in prop::collection::vec(any::<u64>(), 1..WINDOW_SIZE as usize)) {
+            let mut order = (0..keys.len() as u64).collect::<Vec<_>>();
+            order.sort_by_key(|&i| keys[i as usize]);
+
+            let mut ar = AntiReplay::default();
+            prop_assert_eq!(order.iter().filter(|&&seq| ar.update(seq).is_ok()).count(), keys.len());
+            prop_assert!(order.iter().all(|&seq| ar.update(seq).is_err()));
+        }
+    }
+
     #[bench]
     fn bench_anti_replay_sequential(b: &mut ::test::Bencher) {
-        let mut ar = AntiReplay::new();
+        let mut ar = AntiReplay::default();
         let mut seq = 0;
 
         b.iter(|| {
@@ -151,7 +289,7 @@ mod tests {
 
     #[bench]
     fn bench_anti_replay_old(b: &mut ::test::Bencher) {
-        let mut ar = AntiReplay::new();
+        let mut ar = AntiReplay::default();
         ar.update(12345).unwrap();
         ar.update(11234).unwrap();
 
@@ -162,7 +300,7 @@ mod tests {
 
     #[bench]
     fn bench_anti_replay_large_skip(b: &mut ::test::Bencher) {
-        let mut ar = AntiReplay::new();
+        let mut ar = AntiReplay::default();
         let mut seq = 0;
 
         b.iter(|| {
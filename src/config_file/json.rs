@@ -0,0 +1,159 @@
+/* SPDX-License-Identifier: GPL-2.0
+ *
+ * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
+ */
+
+//! The same configuration as a JSON document, built with the `json-config` feature:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "interface": { "private_key": "<base64>", "listen_port": 51820, "mtu": 1420 },
+//!   "peers": [{ "public_key": "<base64>", "endpoint": "1.2.3.4:51820",
+//!               "allowed_ips": ["10.0.0.2/32"], "persistent_keepalive": 25 }]
+//! }
+//! ```
+//!
+//! The fields are the ones the TOML form has, and unset ones are left out rather than `null`.
+
+use error::ParseError;
+use serde_json::{self, Value};
+use super::InterfaceConfig;
+use super::schema::{self, Interface, Peer};
+
+const VERSION: u32 = 1;
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+struct Document {
+    version   : u32,
+    #[serde(default)]
+    interface : Interface,
+    #[serde(default)]
+    peers     : Vec<Peer>,
+}
+
+pub fn from_json(input: &str) -> Result<InterfaceConfig, ParseError> {
+    let document: Document = serde_json::from_str(input)
+        .map_err(|e| ParseError::Syntax { line: e.line(), reason: e.to_string() })?;
+    if document.version != VERSION {
+        return Err(ParseError::Syntax { line: 0, reason: format!("unsupported configuration version {}", document.version) });
+    }
+    schema::to_config(&document.interface, &document.peers).map_err(|reason| ParseError::Syntax { line: 0, reason })
+}
+
+pub fn to_json(config: &InterfaceConfig) -> String {
+    serde_json::to_string_pretty(&document(config)).expect("configuration is always valid JSON")
+}
+
+fn document(config: &InterfaceConfig) -> Document {
+    let (interface, peers) = schema::from_config(config);
+    Document { version: VERSION, interface, peers }
+}
+
+/// A JSON patch (RFC 6902) taking configuration `old` to `new`. Both are read and written back
+/// out first, so formatting and key order don't show up as changes. Peers are compared by
+/// position, so taking one out of the middle replaces the fields of those after it.
+pub fn diff(old: &str, new: &str) -> Result<String, ParseError> {
+    let old = serde_json::to_value(&document(&from_json(old)?)).expect("configuration is always valid JSON");
+    let new = serde_json::to_value(&document(&from_json(new)?)).expect("configuration is always valid JSON");
+    let mut patch = vec![];
+    diff_values("", &old, &new, &mut patch);
+    Ok(Value::Array(patch).to_string())
+}
+
+fn diff_values(path: &str, old: &Value, new: &Value, patch: &mut Vec<Value>) {
+    match (old, new) {
+        (&Value::Object(ref old), &Value::Object(ref new)) => {
+            for (key, old_value) in old {
+                let path = format!("{}/{}", path, escape(key));
+                match new.get(key) {
+                    Some(new_value) => diff_values(&path, old_value, new_value, patch),
+                    None            => patch.push(json!({ "op": "remove", "path": path })),
+                }
+            }
+            for (key, new_value) in new.iter().filter(|&(key, _)| !old.contains_key(key)) {
+                patch.push(json!({ "op": "add", "path": format!("{}/{}", path, escape(key)), "value": new_value }));
+            }
+        },
+        (&Value::Array(ref old), &Value::Array(ref new)) => {
+            for (i, (old_value, new_value)) in old.iter().zip(new).enumerate() {
+                diff_values(&format!("{}/{}", path, i), old_value, new_value, patch);
+            }
+            // removing from the back keeps the indices of the ones still to go valid.
+            for i in (new.len()..old.len()).rev() {
+                patch.push(json!({ "op": "remove", "path": format!("{}/{}", path, i) }));
+            }
+            for new_value in new.iter().skip(old.len()) {
+                patch.push(json!({ "op": "add", "path": format!("{}/-", path), "value": new_value }));
+            }
+        },
+        _ if old != new => patch.push(json!({ "op": "replace", "path": path, "value": new })),
+        _               => {},
+    }
+}
+
+/// `key` as a JSON pointer path segment (RFC 6901).
+fn escape(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = r#"{
+        "version": 1,
+        "interface": { "private_key": "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=", "listen_port": 51820, "mtu": 1420 },
+        "peers": [{ "public_key": "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=", "endpoint": "1.2.3.4:51820",
+                    "allowed_ips": ["10.0.0.2/32"], "persistent_keepalive": 25 }]
+    }"#;
+
+    #[test]
+    fn round_trip() {
+        let config = from_json(EXAMPLE).unwrap();
+        assert_eq!((config.interface.listen_port, config.mtu), (Some(51820), Some(1420)));
+        assert_eq!(config.peers[0].keepalive, Some(25));
+        assert_eq!(config.peers[0].allowed_ips, vec![("10.0.0.2".parse().unwrap(), 32)]);
+
+        let json = to_json(&config);
+        assert!(!json.contains("null"));
+        assert_eq!(serde_json::from_str::<Value>(&to_json(&from_json(&json).unwrap())).unwrap(),
+                   serde_json::from_str::<Value>(&json).unwrap());
+    }
+
+    #[test]
+    fn errors() {
+        let cases = [
+            (r#"{ "version": 2 }"#,                                       0),
+            (r#"{ "version": 1, "peers": [{ "public_key": "AAAA" }] }"#,  0),
+            ("{ \"version\": 1,\n  \"dns\": [] }",                        2),
+            ("{ \"version\": 1,\n  \"peers\": {} }",                      2),
+        ];
+        for &(input, expected_line) in &cases {
+            match from_json(input) {
+                Err(ParseError::Syntax { line, .. }) => assert_eq!(line, expected_line, "{}", input),
+                other                                => panic!("expected syntax error for {:?}, got {:?}", input, other),
+            }
+        }
+    }
+
+    #[test]
+    fn patches() {
+        assert_eq!(diff(EXAMPLE, EXAMPLE).unwrap(), "[]");
+
+        let new = EXAMPLE.replace(r#""listen_port": 51820, "mtu": 1420"#, r#""listen_port": 51821"#)
+                         .replace(r#""persistent_keepalive": 25 }]"#,
+                                  r#""persistent_keepalive": 25 }, { "public_key": "TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=" }]"#);
+        let patch: Value = serde_json::from_str(&diff(EXAMPLE, &new).unwrap()).unwrap();
+        assert_eq!(patch, json!([
+            { "op": "replace", "path": "/interface/listen_port", "value": 51821 },
+            { "op": "remove",  "path": "/interface/mtu" },
+            { "op": "add",     "path": "/peers/-", "value": { "public_key": "TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=", "allowed_ips": [] } },
+        ]));
+
+        let patch: Value = serde_json::from_str(&diff(&new, EXAMPLE).unwrap()).unwrap();
+        assert_eq!(patch[2], json!({ "op": "remove", "path": "/peers/1" }));
+        assert_eq!(escape("a/b~c"), "a~1b~0c");
+    }
+}
@@ -0,0 +1,256 @@
+/* SPDX-License-Identifier: GPL-2.0
+ *
+ * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
+ */
+
+//! Parsing for the INI-like configuration files used by `wg-quick`. The `toml-config` and
+//! `json-config` features add TOML and JSON forms of the same configuration, in `toml` and
+//! `json`.
+//!
+//! Unlike the configuration socket, keys here are base64 rather than hex. Everything after a
+//! `#` on a line is a comment, and key names are matched case-insensitively, as `wg-quick` does.
+
+use std::fs::File;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::Path;
+
+use base64;
+use error::ParseError;
+use secure_mem::SecureBox;
+use types::{InterfaceInfo, PeerInfo, PrivateKey, PublicKey};
+
+#[cfg(feature = "json-config")]
+pub mod json;
+#[cfg(any(feature = "toml-config", feature = "json-config"))]
+mod schema;
+#[cfg(feature = "toml-config")]
+pub mod toml;
+
+#[derive(Debug, Default)]
+pub struct InterfaceConfig {
+    pub interface : InterfaceInfo,
+    pub peers     : Vec<PeerInfo>,
+    pub addresses : Vec<(IpAddr, u32)>,
+    pub dns       : Vec<String>,
+    pub mtu       : Option<u16>,
+    pub table     : Option<String>,
+    pub post_up   : Vec<String>,
+    pub post_down : Vec<String>,
+}
+
+#[derive(PartialEq)]
+enum Section {
+    None, Interface, Peer
+}
+
+pub fn parse(path: &Path) -> Result<InterfaceConfig, ParseError> {
+    let mut contents = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut contents)).map_err(ParseError::Io)?;
+    parse_str(&contents)
+}
+
+pub fn parse_str(contents: &str) -> Result<InterfaceConfig, ParseError> {
+    let mut config  = InterfaceConfig::default();
+    let mut section = Section::None;
+
+    for (i, line) in contents.lines().enumerate() {
+        let line_no = i + 1;
+        let err     = |reason: String| ParseError::Syntax { line: line_no, reason };
+        let line    = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        match line.to_lowercase().as_str() {
+            "[interface]" => { section = Section::Interface; continue; },
+            "[peer]"      => { section = Section::Peer; config.peers.push(PeerInfo::default()); continue; },
+            _             => {}
+        }
+
+        let (key, value) = match line.find('=') {
+            Some(pos) => (line[..pos].trim().to_lowercase(), line[pos+1..].trim()),
+            None      => return Err(err(format!("expected 'key = value', got '{}'", line))),
+        };
+
+        match section {
+            Section::Interface => {
+                let info = &mut config.interface;
+                match key.as_str() {
+                    "privatekey" => {
+                        let private_key = PrivateKey(parse_key(value).map_err(&err)?);
+                        info.pub_key     = Some(private_key.public_key());
+                        info.private_key = Some(SecureBox::new(private_key));
+                    },
+                    "listenport" => info.listen_port = Some(value.parse().map_err(|_| err(format!("invalid port '{}'", value)))?),
+                    "fwmark"     => info.fwmark      = Some(parse_fwmark(value).map_err(&err)?),
+                    "address"    => for addr in split_list(value) { config.addresses.push(parse_cidr(addr).map_err(&err)?); },
+                    "dns"        => config.dns.extend(split_list(value).map(str::to_owned)),
+                    "mtu"        => config.mtu       = Some(value.parse().map_err(|_| err(format!("invalid MTU '{}'", value)))?),
+                    "table"      => config.table     = Some(value.to_owned()),
+                    "postup"     => config.post_up.push(value.to_owned()),
+                    "postdown"   => config.post_down.push(value.to_owned()),
+                    _            => return Err(err(format!("unknown interface key '{}'", key))),
+                }
+            },
+            Section::Peer => {
+                let info = config.peers.last_mut().expect("peer section has a peer");
+                match key.as_str() {
+                    "publickey"           => info.pub_key   = PublicKey(parse_key(value).map_err(&err)?),
+                    "presharedkey"        => info.psk       = Some(parse_key(value).map_err(&err)?),
+                    "endpoint"            => {
+                        info.endpoint      = Some(parse_endpoint(value).map_err(&err)?.into());
+                        info.endpoint_host = value.parse::<SocketAddr>().err().map(|_| value.to_owned());
+                    },
+                    "persistentkeepalive" => info.keepalive = match value {
+                        "off" => None,
+                        _     => Some(value.parse().map_err(|_| err(format!("invalid keepalive '{}'", value)))?),
+                    },
+                    "allowedips"          => for ip in split_list(value) { info.allowed_ips.push(parse_cidr(ip).map_err(&err)?); },
+                    _                     => return Err(err(format!("unknown peer key '{}'", key))),
+                }
+            },
+            Section::None => return Err(err(format!("'{}' outside of a section", key))),
+        }
+    }
+
+    if let Some(peer) = config.peers.iter().find(|peer| peer.pub_key == PublicKey::default()) {
+        return Err(ParseError::Syntax { line: 0, reason: format!("peer {} has no public key", peer) });
+    }
+    Ok(config)
+}
+
+fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|item| !item.is_empty())
+}
+
+fn parse_key(value: &str) -> Result<[u8; 32], String> {
+    let bytes = base64::decode(value).map_err(|_| format!("invalid base64 key '{}'", value))?;
+    if bytes.len() != 32 {
+        return Err(format!("key '{}' isn't 32 bytes", value));
+    }
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&bytes);
+    Ok(key)
+}
+
+fn parse_fwmark(value: &str) -> Result<u32, String> {
+    let parsed = match value {
+        "off"                         => Ok(0),
+        _ if value.starts_with("0x") => u32::from_str_radix(&value[2..], 16),
+        _                             => value.parse(),
+    };
+    parsed.map_err(|_| format!("invalid fwmark '{}'", value))
+}
+
+fn parse_cidr(value: &str) -> Result<(IpAddr, u32), String> {
+    let mut parts = value.splitn(2, '/');
+    let ip : IpAddr = parts.next().unwrap_or("").parse().map_err(|_| format!("invalid address '{}'", value))?;
+    let max_cidr    = if ip.is_ipv4() { 32 } else { 128 };
+    let cidr        = match parts.next() {
+        Some(cidr) => cidr.parse().map_err(|_| format!("invalid prefix length in '{}'", value))?,
+        None       => max_cidr,
+    };
+    if cidr > max_cidr {
+        return Err(format!("prefix length out of range in '{}'", value));
+    }
+    Ok((ip, cidr))
+}
+
+fn parse_endpoint(value: &str) -> Result<SocketAddr, String> {
+    if let Ok(addr) = value.parse() {
+        return Ok(addr);
+    }
+    value.to_socket_addrs().ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| format!("couldn't resolve endpoint '{}'", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // From the wg(8) man page, with the hostname endpoint swapped for an address.
+    const EXAMPLE: &str = "\
+[Interface]
+PrivateKey = yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=
+ListenPort = 51820
+Address = 10.192.122.1/24, fd00::1
+DNS = 10.192.122.53
+MTU = 1420
+Table = off
+PostUp = ip rule add table 200 from 10.192.122.1
+PostDown = ip rule delete table 200 from 10.192.122.1
+
+# peer one
+[Peer]
+PublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=
+Endpoint = 192.95.5.67:1234
+AllowedIPs = 10.192.122.3/32, 10.192.124.1/24
+
+[Peer]
+PublicKey = TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=
+PresharedKey = gN65BkIKy1eCE9pP1wdc8ROUtkHLF2PfAqYdyYBz6EA=
+Endpoint = [2607:5300:60:6b0::c05f:543]:2468 # roaming
+AllowedIPs = 10.192.122.4/32, 192.168.0.0/16
+PersistentKeepalive = 25
+";
+
+    fn cidr(ip: &str, len: u32) -> (IpAddr, u32) {
+        (ip.parse().unwrap(), len)
+    }
+
+    #[test]
+    fn parse_example() {
+        let config = parse_str(EXAMPLE).unwrap();
+
+        let private_key = base64::decode("yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=").unwrap();
+        assert_eq!(&config.interface.private_key.as_ref().unwrap()[..], &private_key[..]);
+        assert!(config.interface.pub_key.is_some());
+        assert_eq!(config.interface.listen_port, Some(51820));
+        assert_eq!(config.addresses, vec![cidr("10.192.122.1", 24), cidr("fd00::1", 128)]);
+        assert_eq!(config.dns, vec!["10.192.122.53".to_owned()]);
+        assert_eq!(config.mtu, Some(1420));
+        assert_eq!(config.table, Some("off".to_owned()));
+        assert_eq!(config.post_up, vec!["ip rule add table 200 from 10.192.122.1".to_owned()]);
+        assert_eq!(config.post_down, vec!["ip rule delete table 200 from 10.192.122.1".to_owned()]);
+
+        assert_eq!(config.peers.len(), 2);
+        let (one, two) = (&config.peers[0], &config.peers[1]);
+        assert_eq!(base64::encode(&one.pub_key), "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=");
+        assert_eq!(*one.endpoint.unwrap(), "192.95.5.67:1234".parse::<SocketAddr>().unwrap());
+        assert_eq!(one.allowed_ips, vec![cidr("10.192.122.3", 32), cidr("10.192.124.1", 24)]);
+        assert_eq!((one.psk, one.keepalive), (None, None));
+        assert_eq!(one.endpoint_host, None);
+
+        assert_eq!(base64::encode(&two.psk.unwrap()), "gN65BkIKy1eCE9pP1wdc8ROUtkHLF2PfAqYdyYBz6EA=");
+        assert_eq!(*two.endpoint.unwrap(), "[2607:5300:60:6b0::c05f:543]:2468".parse::<SocketAddr>().unwrap());
+        assert_eq!(two.allowed_ips, vec![cidr("10.192.122.4", 32), cidr("192.168.0.0", 16)]);
+        assert_eq!(two.keepalive, Some(25));
+    }
+
+    #[test]
+    fn hostname_endpoint() {
+        let config = parse_str("[Peer]\nPublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=\nEndpoint = localhost:51820\n").unwrap();
+        assert_eq!(config.peers[0].endpoint_host, Some("localhost:51820".to_owned()));
+        assert_eq!(config.peers[0].endpoint.map(|e| e.port()), Some(51820));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let cases = [
+            ("PrivateKey = abc",                           1),
+            ("[Interface]\nListenPort = nope",             2),
+            ("[Peer]\nPublicKey = abcd",                   2),
+            ("[Peer]\nAllowedIPs = 10.0.0.0/33",           2),
+            ("[Interface]\nBogus = 1",                     2),
+            ("[Interface]\n\n# comment\nPrivateKey",       4),
+        ];
+        for &(input, expected_line) in &cases {
+            match parse_str(input) {
+                Err(ParseError::Syntax { line, .. }) => assert_eq!(line, expected_line, "{}", input),
+                other                                => panic!("expected syntax error for {:?}, got {:?}", input, other),
+            }
+        }
+    }
+}
@@ -0,0 +1,96 @@
+/* SPDX-License-Identifier: GPL-2.0
+ *
+ * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
+ */
+
+//! The interface and peer sections shared by the TOML and JSON configuration formats, which
+//! only differ in how they wrap them.
+
+use std::net::{IpAddr, SocketAddr};
+
+use base64;
+use secure_mem::SecureBox;
+use super::{InterfaceConfig, parse_cidr, parse_endpoint, parse_key};
+use types::{PeerInfo, PrivateKey, PublicKey};
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+pub struct Interface {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    private_key : Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    listen_port : Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mtu         : Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fwmark      : Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    address     : Vec<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+pub struct Peer {
+    public_key           : String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preshared_key        : Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    endpoint             : Option<String>,
+    #[serde(default)]
+    allowed_ips          : Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    persistent_keepalive : Option<u16>,
+}
+
+/// Checks and decodes the sections, with keys in base64 like in `wg-quick` files.
+pub fn to_config(interface: &Interface, peers: &[Peer]) -> Result<InterfaceConfig, String> {
+    let mut config = InterfaceConfig::default();
+    if let Some(ref private_key) = interface.private_key {
+        let private_key = PrivateKey(parse_key(private_key)?);
+        config.interface.pub_key     = Some(private_key.public_key());
+        config.interface.private_key = Some(SecureBox::new(private_key));
+    }
+    config.interface.listen_port = interface.listen_port;
+    config.interface.fwmark      = interface.fwmark;
+    config.mtu                   = interface.mtu;
+    for addr in &interface.address {
+        config.addresses.push(parse_cidr(addr)?);
+    }
+
+    for peer in peers {
+        let mut info = PeerInfo { pub_key: PublicKey(parse_key(&peer.public_key)?), ..Default::default() };
+        if let Some(ref psk) = peer.preshared_key {
+            info.psk = Some(parse_key(psk)?);
+        }
+        if let Some(ref endpoint) = peer.endpoint {
+            info.endpoint      = Some(parse_endpoint(endpoint)?.into());
+            info.endpoint_host = endpoint.parse::<SocketAddr>().err().map(|_| endpoint.clone());
+        }
+        for ip in &peer.allowed_ips {
+            info.allowed_ips.push(parse_cidr(ip)?);
+        }
+        info.keepalive = peer.persistent_keepalive;
+        config.peers.push(info);
+    }
+    Ok(config)
+}
+
+pub fn from_config(config: &InterfaceConfig) -> (Interface, Vec<Peer>) {
+    let cidr      = |&(ip, len): &(IpAddr, u32)| format!("{}/{}", ip, len);
+    let info      = &config.interface;
+    let interface = Interface {
+        private_key : info.private_key.as_ref().map(|key| base64::encode(&key[..])),
+        listen_port : info.listen_port,
+        mtu         : config.mtu,
+        fwmark      : info.fwmark,
+        address     : config.addresses.iter().map(&cidr).collect(),
+    };
+    let peers = config.peers.iter().map(|peer| Peer {
+        public_key           : base64::encode(&peer.pub_key),
+        preshared_key        : peer.psk.map(|psk| base64::encode(&psk)),
+        endpoint             : peer.endpoint_host.clone().or_else(|| peer.endpoint.map(|endpoint| (*endpoint).to_string())),
+        allowed_ips          : peer.allowed_ips.iter().map(&cidr).collect(),
+        persistent_keepalive : peer.keepalive,
+    }).collect();
+    (interface, peers)
+}
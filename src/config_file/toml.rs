@@ -0,0 +1,131 @@
+/* SPDX-License-Identifier: GPL-2.0
+ *
+ * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
+ */
+
+//! The same configuration as a TOML document, built with the `toml-config` feature:
+//!
+//! ```toml
+//! [interface]
+//! private_key = "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk="
+//! listen_port = 51820
+//! address     = ["10.192.122.1/24"]
+//!
+//! [[peer]]
+//! public_key  = "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg="
+//! endpoint    = "192.95.5.67:1234"
+//! allowed_ips = ["10.192.122.3/32"]
+//! ```
+//!
+//! Besides those, `[interface]` takes `mtu` and `fwmark`, and peers `preshared_key` and
+//! `persistent_keepalive`. Keys are base64, like in `wg-quick` files. Anything else is an
+//! error; the `wg-quick` extras like `DNS` and `PostUp` have no place here.
+
+use error::ParseError;
+use super::InterfaceConfig;
+use super::schema::{self, Interface, Peer};
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Document {
+    #[serde(default)]
+    interface : Interface,
+    #[serde(default)]
+    peer      : Vec<Peer>,
+}
+
+pub fn from_toml(input: &str) -> Result<InterfaceConfig, ParseError> {
+    let document: Document = ::toml::from_str(input).map_err(|e| {
+        let line = e.line_col().map_or(0, |(line, _)| line + 1);
+        ParseError::Syntax { line, reason: e.to_string() }
+    })?;
+    // past the TOML itself there's no telling which line a bad value was on.
+    schema::to_config(&document.interface, &document.peer).map_err(|reason| ParseError::Syntax { line: 0, reason })
+}
+
+pub fn to_toml(config: &InterfaceConfig) -> String {
+    let (interface, peer) = schema::from_config(config);
+    ::toml::to_string(&Document { interface, peer }).expect("configuration is always valid TOML")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64;
+
+    const EXAMPLE: &str = r#"
+[interface]
+private_key = "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk="
+listen_port = 51820
+mtu         = 1420
+fwmark      = 51820
+address     = ["10.192.122.1/24", "fd00::1/128"]
+
+[[peer]]
+public_key  = "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg="
+endpoint    = "192.95.5.67:1234"
+allowed_ips = ["10.192.122.3/32", "10.192.124.1/24"]
+
+[[peer]]
+public_key           = "TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0="
+preshared_key        = "gN65BkIKy1eCE9pP1wdc8ROUtkHLF2PfAqYdyYBz6EA="
+endpoint             = "[2607:5300:60:6b0::c05f:543]:2468"
+allowed_ips          = ["10.192.122.4/32", "192.168.0.0/16"]
+persistent_keepalive = 25
+"#;
+
+    fn assert_same(a: &InterfaceConfig, b: &InterfaceConfig) {
+        assert_eq!(a.interface.private_key.as_ref().map(|key| key[..].to_vec()),
+                   b.interface.private_key.as_ref().map(|key| key[..].to_vec()));
+        assert_eq!(a.interface.pub_key, b.interface.pub_key);
+        assert_eq!((a.interface.listen_port, a.interface.fwmark, a.mtu), (b.interface.listen_port, b.interface.fwmark, b.mtu));
+        assert_eq!(a.addresses, b.addresses);
+        assert_eq!(a.peers.len(), b.peers.len());
+        for (a, b) in a.peers.iter().zip(&b.peers) {
+            assert_eq!((a.pub_key, a.psk, a.keepalive), (b.pub_key, b.psk, b.keepalive));
+            assert_eq!(a.endpoint.map(|e| *e), b.endpoint.map(|e| *e));
+            assert_eq!(a.allowed_ips, b.allowed_ips);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let config = from_toml(EXAMPLE).unwrap();
+        assert_eq!(config.interface.listen_port, Some(51820));
+        assert_eq!(config.mtu, Some(1420));
+        assert_eq!(config.addresses.len(), 2);
+        assert_eq!(config.peers.len(), 2);
+        assert_eq!(base64::encode(&config.peers[1].psk.unwrap()), "gN65BkIKy1eCE9pP1wdc8ROUtkHLF2PfAqYdyYBz6EA=");
+        assert_eq!(config.peers[1].keepalive, Some(25));
+
+        let again = from_toml(&to_toml(&config)).unwrap();
+        assert_same(&config, &again);
+
+        // and it reads the same as the wg-quick form of the same configuration
+        let quick = ::config_file::parse_str("[Interface]\nPrivateKey = yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=\n").unwrap();
+        let toml  = from_toml("[interface]\nprivate_key = \"yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=\"\n").unwrap();
+        assert_same(&quick, &toml);
+    }
+
+    #[test]
+    fn errors() {
+        match from_toml("[interface]\nprivate_key = \"not base64!\"\n") {
+            Err(ParseError::Syntax { line: 0, reason }) => assert!(reason.contains("invalid base64 key"), "{}", reason),
+            other                                       => panic!("expected a key error, got {:?}", other),
+        }
+
+        let cases = [
+            "[[peer]]\npublic_key = \"AAAA\"\n",
+            "[[peer]]\npublic_key = \"xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=\"\nallowed_ips = [\"10.0.0.0/33\"]\n",
+            "[[peer]]\nendpoint = \"192.95.5.67:1234\"\n",
+            "[interface]\nlisten_port = 51820\ndns = \"10.0.0.1\"\n",
+            "[interface]\nlisten_port = \n",
+        ];
+        for input in &cases {
+            match from_toml(input) {
+                Err(ParseError::Syntax { .. }) => {},
+                other                          => panic!("expected syntax error for {:?}, got {:?}", input, other),
+            }
+        }
+    }
+}
@@ -12,23 +12,24 @@ lazy_static! {
     pub static ref REKEY_ATTEMPT_TIME    : Duration = Duration::new(90, 0);
     pub static ref REJECT_AFTER_TIME     : Duration = Duration::new(180, 0);
     pub static ref REKEY_AFTER_TIME      : Duration = Duration::new(120, 0);
-    pub static ref REKEY_AFTER_TIME_RECV : Duration = *REJECT_AFTER_TIME - *KEEPALIVE_TIMEOUT - *REKEY_TIMEOUT;
-    pub static ref WIPE_AFTER_TIME       : Duration = *REJECT_AFTER_TIME * 3;
 
     pub static ref REKEY_TIMEOUT         : Duration = Duration::new(5, 0);
+    pub static ref INITIAL_REKEY_TIMEOUT : Duration = Duration::new(1, 0);
     pub static ref KEEPALIVE_TIMEOUT     : Duration = Duration::new(10, 0);
-    pub static ref STALE_SESSION_TIMEOUT : Duration = *KEEPALIVE_TIMEOUT + *REKEY_TIMEOUT;
+
+    pub static ref RECONNECT_BACKOFF_MIN : Duration = Duration::new(1, 0);
+    pub static ref RECONNECT_BACKOFF_MAX : Duration = Duration::new(90, 0);
 
     pub static ref TIMER_RESOLUTION    : Duration = Duration::from_millis(100);
     pub static ref COOKIE_REFRESH_TIME : Duration = Duration::new(120, 0);
     pub static ref UNDER_LOAD_TIME     : Duration = Duration::new(1, 0);
-
-    pub static ref MAX_HANDSHAKE_ATTEMPTS : u64 = REKEY_ATTEMPT_TIME.as_secs() / REKEY_TIMEOUT.as_secs() - 1;
+    pub static ref INDEX_GC_INTERVAL   : Duration = Duration::new(60, 0);
+    pub static ref ENDPOINT_RESOLVE_INTERVAL : Duration = Duration::new(30, 0);
 }
 
-// transport ratcheting message limits, in seconds
-pub const REKEY_AFTER_MESSAGES  : u64 = u64::MAX - (1 << 16) - 1;
-pub const REJECT_AFTER_MESSAGES : u64 = u64::MAX - (1 << 4) - 1;
+// transport ratcheting message limits, in messages
+pub const REKEY_AFTER_MESSAGES  : u64 = 1 << 60;
+pub const REJECT_AFTER_MESSAGES : u64 = u64::MAX - (1 << 13);
 
 pub const TRANSPORT_HEADER_SIZE : usize = 16;
 pub const AEAD_TAG_SIZE         : usize = 16;
@@ -36,8 +37,36 @@ pub const TRANSPORT_OVERHEAD    : usize = TRANSPORT_HEADER_SIZE + AEAD_TAG_SIZE;
 pub const MAX_SEGMENT_SIZE      : usize = (1 << 16) - 1;
 pub const MAX_CONTENT_SIZE      : usize = MAX_SEGMENT_SIZE - TRANSPORT_OVERHEAD;
 pub const PADDING_MULTIPLE      : usize = 16;
+// a timestamped keepalive: send time, kind and padding, still too short for an IP packet.
+pub const LATENCY_PROBE_SIZE    : usize = PADDING_MULTIPLE;
+pub const LATENCY_PROBE         : u8    = 0;
+pub const LATENCY_ECHO          : u8    = 1;
+
+// inner packet MTU, which already leaves room for the worst-case (IPv6) outer headers.
+pub const DEFAULT_MTU           : u16   = 1420;
+// the smallest datagram every IPv4 host has to take.
+pub const MIN_MTU               : u16   = 576;
 
 pub const MAX_QUEUED_HANDSHAKES : usize = 4096;
+pub const MAX_HANDSHAKES_PER_SECOND : u32 = 25;
 pub const UNDER_LOAD_QUEUE_SIZE : usize = MAX_QUEUED_HANDSHAKES / 8;
-pub const MAX_QUEUED_PACKETS    : usize = 1024;
+pub const MAX_QUEUED_PACKETS    : usize = 64;
 pub const MAX_PEERS_PER_DEVICE  : usize = 1 << 20;
+pub const INDEX_ALLOCATION_TRIES : usize = 100;
+
+// largest configuration request we'll buffer, including its terminating blank line.
+pub const MAX_CONFIG_MESSAGE_SIZE : usize = 1 << 20;
+
+// newest configuration protocol version we speak. 2 extends `get` responses with more stats.
+pub const MAX_CONFIG_PROTOCOL_VERSION : usize = 2;
+
+// configuration changes kept for `get_audit`, oldest dropped first.
+pub const MAX_AUDIT_ENTRIES : usize = 1000;
+
+// sessions kept for `get_sessions`, oldest dropped first.
+pub const MAX_SESSION_HISTORY : usize = 100;
+
+// decrypted packets waiting on the tun device before more are dropped. The peer server's own
+// channel has no such limit, but both warn as they fill past QUEUE_DEPTH_WARNING.
+pub const MAX_QUEUED_TUNNEL_PACKETS : usize = 1024;
+pub const QUEUE_DEPTH_WARNING       : usize = MAX_QUEUED_TUNNEL_PACKETS * 8 / 10;
@@ -6,6 +6,7 @@
 #![allow(unused)]
 
 use consts::COOKIE_REFRESH_TIME;
+use error::DropReason;
 use message::CookieReply;
 use xchacha20poly1305;
 
@@ -13,8 +14,8 @@ use blake2_rfc::blake2s::{blake2s, Blake2sResult};
 use failure::{Error, err_msg};
 use hex;
 use rand::{self, RngCore};
-use subtle::ConstantTimeEq;
 use std::time::Instant;
+use types::constant_time_eq;
 
 pub struct ValidatorMac2 {
     secret: [u8; 16],
@@ -58,7 +59,9 @@ impl Validator {
         debug_assert!(mac.len() == 16);
         let our_mac = blake2s(16, self.mac1_key.as_bytes(), mac_input);
 
-        ensure!(mac.ct_eq(our_mac.as_bytes()).unwrap_u8() == 1, "mac mismatch");
+        if !constant_time_eq(mac, our_mac.as_bytes()) {
+            return Err(DropReason::MacVerificationFailed.into());
+        }
         Ok(())
     }
 
@@ -71,7 +74,7 @@ impl Validator {
         let our_mac2 = mac2.as_bytes();
         let thr_mac2 = &message[message.len()-16..];
 
-        if our_mac2.ct_eq(&thr_mac2).unwrap_u8() != 1 {
+        if !constant_time_eq(our_mac2, thr_mac2) {
             trace!("mac mismatch, ours: {:?}", hex::encode(our_mac2));
             trace!("mac mismatch, thrs: {:?}", hex::encode(thr_mac2));
             bail!("mac mismatch")
@@ -152,6 +155,31 @@ impl Generator {
     }
 }
 
+/// Computes the `mac1` field of a handshake message, keyed by the receiver's static public key,
+/// from scratch rather than through a `Generator`'s cached key, to check it against.
+///
+/// `msg` is everything in the message that precedes the `mac1` field.
+#[cfg(test)]
+fn compute_mac1(msg: &[u8], receiver_pub_key: &[u8]) -> [u8; 16] {
+    let key = blake2s(32, &[], &[b"mac1----", receiver_pub_key].concat());
+    mac(key.as_bytes(), msg)
+}
+
+/// Computes the `mac2` field of a handshake message using a cookie received from its receiver.
+///
+/// `msg` is everything in the message that precedes the `mac2` field, including `mac1`.
+#[cfg(test)]
+fn compute_mac2(msg: &[u8], cookie: &[u8]) -> [u8; 16] {
+    mac(cookie, msg)
+}
+
+#[cfg(test)]
+fn mac(key: &[u8], input: &[u8]) -> [u8; 16] {
+    let mut out = [0u8; 16];
+    out.copy_from_slice(blake2s(16, key, input).as_bytes());
+    out
+}
+
 fn is_secret_valid(secret_time: Option<Instant>) -> bool {
     if let Some(time) = secret_time {
         Instant::now().duration_since(time) <= *COOKIE_REFRESH_TIME
@@ -162,5 +190,82 @@ fn is_secret_valid(secret_time: Option<Instant>) -> bool {
 
 #[test]
 fn sanity() {
+    let pub_key       = [1u8; 32];
+    let source        = [127, 0, 0, 1];
+    let mut validator = Validator::new(&pub_key);
+    let mut generator = Generator::new(&pub_key);
+    let mut packet    = vec![0u8; 148];
+    packet[0] = 1;
+
+    let (mac1, mac2) = generator.build_macs(&packet[..116]);
+    assert!(mac2.is_none());
+    packet[116..132].copy_from_slice(mac1.as_bytes());
+    validator.verify_mac1(&packet[..116], &packet[116..132]).unwrap();
+
+    let reply = validator.generate_reply(7, &packet[116..132], &source).unwrap();
+    assert_eq!(reply.receiver_index(), 7);
+    generator.consume_reply(&reply).unwrap();
+
+    let (mac1, mac2) = generator.build_macs(&packet[..116]);
+    packet[116..132].copy_from_slice(mac1.as_bytes());
+    packet[132..].copy_from_slice(mac2.expect("cookie should be fresh").as_bytes());
+    validator.verify_mac2(&packet, &source).unwrap();
+    assert!(validator.verify_mac2(&packet, &[127, 0, 0, 2]).is_err());
+}
+
+#[test]
+fn mac_vectors() {
+    let mut pub_key = [0u8; 32];
+    let mut msg     = [0u8; 116];
+    for (i, byte) in pub_key.iter_mut().enumerate() { *byte = i as u8; }
+    for (i, byte) in msg[4..].iter_mut().enumerate() { *byte = (i * 7) as u8; }
+    msg[0] = 1;
+
+    let mac1 = compute_mac1(&msg, &pub_key);
+    assert_eq!(hex::encode(mac1), "de53fbae57962fb7f7f2dea79f2e4f59");
+
+    let mac2 = compute_mac2(&[&msg[..], &mac1[..]].concat(), &[0xaa; 16]);
+    assert_eq!(hex::encode(mac2), "58ade1801821c00aaaa602af2a94eadc");
+
+    let (generated, _) = Generator::new(&pub_key).build_macs(&msg);
+    assert_eq!(generated.as_bytes(), &mac1[..]);
+}
+
+// wall-clock timing depends on whatever else the machine is doing, so this only runs when
+// asked for with `cargo test -- --ignored`.
+#[test]
+#[ignore]
+fn mac_comparison_timing() {
+    use std::time::Duration;
+    use test::black_box;
+
+    // Time batches of comparisons against a MAC that differs in its first bit and one that
+    // differs in its last; an early-exit comparison would be measurably faster on the first.
+    fn batch(ours: &[u8; 16], theirs: &[u8; 16]) -> Duration {
+        let start = Instant::now();
+        for _ in 0..2_000 {
+            black_box(constant_time_eq(black_box(ours), black_box(theirs)));
+        }
+        start.elapsed()
+    }
+
+    let ours      = compute_mac1(&[0x42; 116], &[1u8; 32]);
+    let mut early = ours;
+    let mut late  = ours;
+    early[0] ^= 0x01;
+    late[15] ^= 0x80;
+
+    let mut early_times = vec![];
+    let mut late_times  = vec![];
+    for _ in 0..201 {
+        early_times.push(batch(&ours, &early));
+        late_times.push(batch(&ours, &late));
+    }
+    early_times.sort();
+    late_times.sort();
 
+    let early_median = early_times[early_times.len() / 2];
+    let late_median  = late_times[late_times.len() / 2];
+    let (fast, slow) = if early_median < late_median { (early_median, late_median) } else { (late_median, early_median) };
+    assert!(slow < fast * 3 / 2, "comparison time depends on the input: {:?} vs {:?}", early_median, late_median);
 }
@@ -3,8 +3,187 @@
  * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
  */
 
-//use failure::Error;
+use snow::SnowError;
+use std::io;
+use std::net::IpAddr;
 
-//#[derive(Debug, Fail)]
-//enum WireguardError {
-//}
+/// Ways bringing up an `Interface` can fail, as returned by `Interface::start` and
+/// `Interface::start_on`.
+#[derive(Debug, Fail)]
+pub enum InterfaceError {
+    #[fail(display = "couldn't create event loop: {}", _0)]
+    Reactor(#[cause] io::Error),
+
+    #[fail(display = "interface already started")]
+    AlreadyStarted,
+
+    #[fail(display = "couldn't open tunnel device: {}", _0)]
+    Tun(#[cause] io::Error),
+
+    #[fail(display = "couldn't open socket: {}", _0)]
+    Socket(String),
+
+    #[fail(display = "peers are configured but there's no private key")]
+    NoPrivateKey,
+
+    #[fail(display = "couldn't set up noise handshakes: {}", _0)]
+    Noise(#[cause] SnowError),
+
+    #[fail(display = "couldn't start peer server: {}", _0)]
+    PeerServer(String),
+
+    #[fail(display = "invalid configuration: {}", _0)]
+    Config(String),
+
+    #[fail(display = "couldn't apply configuration change: {}", _0)]
+    Update(String),
+}
+
+/// Problems with configuration handed to an `InterfaceBuilder`.
+#[derive(Debug, Fail, PartialEq)]
+pub enum ConfigError {
+    #[fail(display = "private key is all zeroes")]
+    InvalidPrivateKey,
+
+    #[fail(display = "listen port 0 requested, leave it unset for a random port")]
+    InvalidListenPort,
+
+    #[fail(display = "MTU {} is out of range", _0)]
+    InvalidMtu(u16),
+
+    #[fail(display = "allowed IP {}/{} has an out-of-range prefix length", _0, _1)]
+    InvalidAllowedIp(IpAddr, u32),
+
+    #[fail(display = "peer {} is listed more than once", _0)]
+    DuplicatePeer(String),
+
+    #[fail(display = "peer {} has our own public key", _0)]
+    SelfPeer(String),
+
+    #[fail(display = "{} isn't a noise protocol we can build", _0)]
+    InvalidNoiseProtocol(String),
+
+    #[fail(display = "replay window size {} isn't a power of 2 from 64 to 8192", _0)]
+    InvalidReplayWindowSize(u32),
+
+    #[fail(display = "invalid timers: {}", _0)]
+    InvalidTimers(String),
+
+    #[fail(display = "invalid interface name: {}", _0)]
+    InvalidInterfaceName(NameError),
+
+    #[fail(display = "configuration rejected: {}", _0)]
+    Rejected(String),
+}
+
+/// Why a `set` on the configuration socket was refused, for the cases a value failing to
+/// parse doesn't already cover.
+#[derive(Debug, Fail, PartialEq)]
+pub enum SetError {
+    #[fail(display = "unrecognized configuration key {}", _0)]
+    UnknownKey(String),
+
+    #[fail(display = "already at max peers per device")]
+    TooManyPeers,
+
+    #[fail(display = "unsupported protocol version {}", _0)]
+    UnsupportedProtocolVersion(String),
+}
+
+/// Why a string can't be used as an interface name.
+#[derive(Debug, Fail, Clone, PartialEq)]
+pub enum NameError {
+    #[fail(display = "can't be empty")]
+    Empty,
+
+    #[fail(display = "must be at most 15 characters, got {}", _0)]
+    TooLong(usize),
+
+    #[fail(display = "{:?} isn't allowed, only letters, digits, '_' and '-' are", _0)]
+    InvalidChar(char),
+
+    #[fail(display = "can't start with a digit")]
+    StartsWithDigit,
+}
+
+/// Problems reading a wg-quick style configuration file.
+#[derive(Debug, Fail)]
+pub enum ParseError {
+    #[fail(display = "couldn't read configuration file: {}", _0)]
+    Io(#[cause] io::Error),
+
+    #[fail(display = "line {}: {}", line, reason)]
+    Syntax { line: usize, reason: String },
+}
+
+/// Why an incoming or outgoing packet was discarded. Returned as the `failure::Error` from the
+/// packet handlers, where `PeerServer` picks it back out to count it.
+#[derive(Debug, Fail, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DropReason {
+    #[fail(display = "replayed nonce")]
+    ReplayAttack,
+
+    #[fail(display = "session expired")]
+    SessionExpired,
+
+    #[fail(display = "no matching peer")]
+    NoMatchingPeer,
+
+    #[fail(display = "source address outside the peer's allowed IPs")]
+    AllowedIpMismatch,
+
+    #[fail(display = "mac verification failed")]
+    MacVerificationFailed,
+
+    #[fail(display = "packet too large")]
+    OversizedPacket,
+
+    #[fail(display = "malformed packet")]
+    MalformedPacket,
+
+    #[fail(display = "unknown session index")]
+    UnknownSessionIndex,
+
+    #[fail(display = "rate limited")]
+    RateLimited,
+
+    #[fail(display = "crossed handshake initiation")]
+    InitiationConflict,
+
+    #[fail(display = "no free session index")]
+    ResourceExhausted,
+
+    #[fail(display = "too many packets waiting for the tun device")]
+    ChannelFull,
+
+    #[fail(display = "exceeded REJECT-AFTER-MESSAGES")]
+    RejectAfterMessages,
+}
+
+impl DropReason {
+    pub const ALL: [DropReason; 13] = [
+        DropReason::ReplayAttack, DropReason::SessionExpired, DropReason::NoMatchingPeer,
+        DropReason::AllowedIpMismatch, DropReason::MacVerificationFailed, DropReason::OversizedPacket,
+        DropReason::MalformedPacket, DropReason::UnknownSessionIndex, DropReason::RateLimited,
+        DropReason::InitiationConflict, DropReason::ResourceExhausted, DropReason::ChannelFull,
+        DropReason::RejectAfterMessages,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            DropReason::ReplayAttack          => "replay_attack",
+            DropReason::SessionExpired        => "session_expired",
+            DropReason::NoMatchingPeer        => "no_matching_peer",
+            DropReason::AllowedIpMismatch     => "allowed_ip_mismatch",
+            DropReason::MacVerificationFailed => "mac_verification_failed",
+            DropReason::OversizedPacket       => "oversized_packet",
+            DropReason::MalformedPacket       => "malformed_packet",
+            DropReason::UnknownSessionIndex   => "unknown_session_index",
+            DropReason::RateLimited           => "rate_limited",
+            DropReason::InitiationConflict    => "initiation_conflict",
+            DropReason::ResourceExhausted     => "resource_exhausted",
+            DropReason::ChannelFull           => "channel_full",
+            DropReason::RejectAfterMessages   => "reject_after_messages",
+        }
+    }
+}
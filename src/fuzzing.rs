@@ -0,0 +1,71 @@
+/* SPDX-License-Identifier: GPL-2.0
+ *
+ * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
+ */
+
+//! Entry points for the cargo-fuzz targets under `fuzz/`. They take whatever bytes the fuzzer
+//! comes up with, and the only acceptable outcome for any of them is returning.
+
+use bytes::BytesMut;
+use interface::ConfigurationCodec;
+use message::Message;
+use noise;
+use peer::Peer;
+use std::cell::RefCell;
+use std::convert::TryInto;
+use test_peers::{endpoint, handshake, peer_pair};
+use tokio_io::codec::Decoder;
+
+/// A responder with a session to receive transport packets on, and an initiator still waiting
+/// for a handshake response.
+struct Peers {
+    responder      : Peer,
+    responder_priv : [u8; 32],
+    initiator      : Peer,
+}
+
+impl Peers {
+    fn new() -> Peers {
+        let (mut initiator, mut responder) = peer_pair(None);
+        let _ = handshake(&mut initiator, &mut responder, 1, 2);
+        let _ = initiator.peer.initiate_new_session(&initiator.private, noise::DEFAULT_PROTOCOL, 3).unwrap();
+
+        Peers { responder_priv: responder.private, responder: responder.peer, initiator: initiator.peer }
+    }
+}
+
+thread_local! {
+    static PEERS: RefCell<Peers> = RefCell::new(Peers::new());
+}
+
+/// Handshake initiations, responses and cookie replies, as they'd come off the UDP socket.
+pub fn handshake_packet(data: &[u8]) {
+    PEERS.with(|peers| {
+        let mut peers = peers.borrow_mut();
+        match data.to_vec().try_into() {
+            Ok(Message::Initiation(packet)) => {
+                if let Ok(handshake) = Peer::process_incoming_handshake(&peers.responder_priv, noise::DEFAULT_PROTOCOL, &packet) {
+                    let _ = handshake.their_pubkey();
+                }
+            },
+            Ok(Message::Response(packet))    => { let _ = peers.initiator.process_incoming_handshake_response(endpoint(2), &packet); },
+            Ok(Message::CookieReply(packet)) => { let _ = peers.initiator.consume_cookie_reply(&packet); },
+            Ok(Message::Transport(_)) | Err(_) => {},
+        }
+    });
+}
+
+/// Transport packets, handed to a peer that has a session to decrypt them with.
+pub fn transport_packet(data: &[u8]) {
+    PEERS.with(|peers| {
+        if let Ok(Message::Transport(packet)) = data.to_vec().try_into() {
+            let _ = peers.borrow_mut().responder.handle_incoming_transport(endpoint(1), &packet);
+        }
+    });
+}
+
+/// Bytes written to the configuration socket, decoded until the codec wants more or gives up.
+pub fn config_message(data: &[u8]) {
+    let mut buf = BytesMut::from(data);
+    while let Ok(Some(_)) = ConfigurationCodec.decode(&mut buf) {}
+}
@@ -0,0 +1,149 @@
+/* SPDX-License-Identifier: GPL-2.0
+ *
+ * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
+ */
+
+//! ICMP errors we generate ourselves, for inner packets too big to fit through the tunnel.
+
+use byteorder::{BigEndian, ByteOrder};
+
+const IPV4_HEADER_SIZE : usize = 20;
+const IPV6_HEADER_SIZE : usize = 40;
+const ICMP_HEADER_SIZE : usize = 8;
+const IPV6_MIN_MTU     : usize = 1280;
+const REPLY_TTL        : u8    = 64;
+
+/// Builds an ICMP "Fragmentation Needed" (v4) or "Packet Too Big" (v6) error for `packet`, addressed
+/// back to its source. Returns `None` for anything that isn't a well-formed IP packet.
+pub fn packet_too_big(packet: &[u8], mtu: u16) -> Option<Vec<u8>> {
+    match packet.get(0).map(|byte| *byte >> 4) {
+        Some(4) => fragmentation_needed_v4(packet, mtu),
+        Some(6) => packet_too_big_v6(packet, mtu),
+        _       => None,
+    }
+}
+
+fn fragmentation_needed_v4(packet: &[u8], mtu: u16) -> Option<Vec<u8>> {
+    let header_len = ((packet[0] & 0x0f) as usize) * 4;
+    if header_len < IPV4_HEADER_SIZE || packet.len() < header_len {
+        return None;
+    }
+    // the original IP header plus the first 64 bits of its payload, per RFC 792.
+    let quoted    = &packet[..packet.len().min(header_len + 8)];
+    let total_len = IPV4_HEADER_SIZE + ICMP_HEADER_SIZE + quoted.len();
+
+    let mut reply = vec![0u8; total_len];
+    reply[0] = 0x45;
+    BigEndian::write_u16(&mut reply[2..4], total_len as u16);
+    reply[8] = REPLY_TTL;
+    reply[9] = 1; // ICMP
+    reply[12..16].copy_from_slice(&packet[16..20]);
+    reply[16..20].copy_from_slice(&packet[12..16]);
+    let header_checksum = checksum(&reply[..IPV4_HEADER_SIZE], 0);
+    BigEndian::write_u16(&mut reply[10..12], header_checksum);
+
+    {
+        let icmp = &mut reply[IPV4_HEADER_SIZE..];
+        icmp[0] = 3; // destination unreachable
+        icmp[1] = 4; // fragmentation needed and DF set
+        BigEndian::write_u16(&mut icmp[6..8], mtu);
+        icmp[ICMP_HEADER_SIZE..].copy_from_slice(quoted);
+        let icmp_checksum = checksum(icmp, 0);
+        BigEndian::write_u16(&mut icmp[2..4], icmp_checksum);
+    }
+    Some(reply)
+}
+
+fn packet_too_big_v6(packet: &[u8], mtu: u16) -> Option<Vec<u8>> {
+    if packet.len() < IPV6_HEADER_SIZE {
+        return None;
+    }
+    // as much of the original packet as fits without exceeding the minimum IPv6 MTU, per RFC 4443.
+    let quoted      = &packet[..packet.len().min(IPV6_MIN_MTU - IPV6_HEADER_SIZE - ICMP_HEADER_SIZE)];
+    let payload_len = ICMP_HEADER_SIZE + quoted.len();
+
+    let mut reply = vec![0u8; IPV6_HEADER_SIZE + payload_len];
+    reply[0] = 0x60;
+    BigEndian::write_u16(&mut reply[4..6], payload_len as u16);
+    reply[6] = 58; // ICMPv6
+    reply[7] = REPLY_TTL;
+    reply[8..24].copy_from_slice(&packet[24..40]);
+    reply[24..40].copy_from_slice(&packet[8..24]);
+
+    // the checksum covers a pseudo-header of both addresses, the length and the next header.
+    let mut pseudo = sum(&reply[8..40]);
+    pseudo += payload_len as u32 + 58;
+
+    {
+        let icmp = &mut reply[IPV6_HEADER_SIZE..];
+        icmp[0] = 2; // packet too big
+        BigEndian::write_u32(&mut icmp[4..8], u32::from(mtu));
+        icmp[ICMP_HEADER_SIZE..].copy_from_slice(quoted);
+        let icmp_checksum = checksum(icmp, pseudo);
+        BigEndian::write_u16(&mut icmp[2..4], icmp_checksum);
+    }
+    Some(reply)
+}
+
+fn sum(bytes: &[u8]) -> u32 {
+    bytes.chunks(2)
+        .map(|pair| if pair.len() == 2 { u32::from(BigEndian::read_u16(pair)) } else { u32::from(pair[0]) << 8 })
+        .sum()
+}
+
+/// The internet checksum (RFC 1071) of `bytes`, on top of an already-summed `initial`.
+fn checksum(bytes: &[u8], initial: u32) -> u16 {
+    let mut sum = initial + sum(bytes);
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fragmentation_needed() {
+        let mut packet = vec![0u8; 1500];
+        packet[0] = 0x45;
+        BigEndian::write_u16(&mut packet[2..4], 1500);
+        packet[9] = 17;
+        packet[12..16].copy_from_slice(&[10, 0, 0, 2]);
+        packet[16..20].copy_from_slice(&[10, 0, 0, 1]);
+
+        let reply = packet_too_big(&packet, 1420).unwrap();
+        assert_eq!(reply.len(), 20 + 8 + 28);
+        assert_eq!(&reply[12..20], &[10, 0, 0, 1, 10, 0, 0, 2]);
+        assert_eq!((reply[20], reply[21]), (3, 4));
+        assert_eq!(BigEndian::read_u16(&reply[26..28]), 1420);
+        assert_eq!(&reply[28..], &packet[..28]);
+        assert_eq!(checksum(&reply[..20], 0), 0);
+        assert_eq!(checksum(&reply[20..], 0), 0);
+    }
+
+    #[test]
+    fn ipv6_packet_too_big() {
+        let mut packet = vec![0u8; 1500];
+        packet[0] = 0x60;
+        packet[8..24].copy_from_slice(&[0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+        packet[24..40].copy_from_slice(&[0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+
+        let reply = packet_too_big(&packet, 1420).unwrap();
+        assert_eq!(reply.len(), IPV6_MIN_MTU);
+        assert_eq!(&reply[8..24], &packet[24..40]);
+        assert_eq!(&reply[24..40], &packet[8..24]);
+        assert_eq!((reply[40], reply[41]), (2, 0));
+        assert_eq!(BigEndian::read_u32(&reply[44..48]), 1420);
+
+        let pseudo = sum(&reply[8..40]) + (reply.len() - 40) as u32 + 58;
+        assert_eq!(checksum(&reply[40..], pseudo), 0);
+    }
+
+    #[test]
+    fn not_ip() {
+        assert!(packet_too_big(&[0x20; 60], 1420).is_none());
+        assert!(packet_too_big(&[0x45; 10], 1420).is_none());
+    }
+}
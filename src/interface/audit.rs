@@ -0,0 +1,154 @@
+/* SPDX-License-Identifier: GPL-2.0
+ *
+ * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
+ */
+
+//! A bounded record of the configuration changes applied to an interface, and who made them.
+
+use consts::MAX_AUDIT_ENTRIES;
+use interface::State;
+use interface::config::UpdateEvent;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use types::PublicKey;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AuditEvent {
+    PrivateKeySet,
+    ListenPortChanged(u16),
+    PeerAdded([u8; 32]),
+    PeerRemoved([u8; 32]),
+    PresharedKeyChanged([u8; 32]),
+    EndpointChanged([u8; 32], SocketAddr),
+}
+
+impl AuditEvent {
+    /// What applying `event` to `state` will change. Has to be worked out before the update is
+    /// applied, since whether a peer is new depends on what was there before.
+    pub fn for_update(state: &State, event: &UpdateEvent) -> Vec<AuditEvent> {
+        match *event {
+            UpdateEvent::PrivateKey(_)      => vec![AuditEvent::PrivateKeySet],
+            UpdateEvent::ListenPort(port)   => vec![AuditEvent::ListenPortChanged(port)],
+            UpdateEvent::RemovePeer(key)    => vec![AuditEvent::PeerRemoved(key.0)],
+            UpdateEvent::RemoveAllPeers     => state.pubkey_map.keys().map(|key| AuditEvent::PeerRemoved(key.0)).collect(),
+            UpdateEvent::UpdateExistingPeer(ref info, _) if !state.pubkey_map.contains_key(&info.pub_key) => vec![],
+            UpdateEvent::UpdatePeer(ref info, _) | UpdateEvent::UpdateExistingPeer(ref info, _) => {
+                let key        = info.pub_key.0;
+                let mut events = vec![];
+                if !state.pubkey_map.contains_key(&info.pub_key) {
+                    events.push(AuditEvent::PeerAdded(key));
+                }
+                if info.psk.is_some() {
+                    events.push(AuditEvent::PresharedKeyChanged(key));
+                }
+                if let Some(endpoint) = info.endpoint {
+                    events.push(AuditEvent::EndpointChanged(key, *endpoint));
+                }
+                events
+            },
+            _ => vec![],
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let key = |key: &[u8; 32]| PublicKey(*key).to_string();
+        match *self {
+            AuditEvent::PrivateKeySet                   => r#""event":"private_key_set""#.to_owned(),
+            AuditEvent::ListenPortChanged(port)         => format!(r#""event":"listen_port_changed","listen_port":{}"#, port),
+            AuditEvent::PeerAdded(ref peer)             => format!(r#""event":"peer_added","public_key":"{}""#, key(peer)),
+            AuditEvent::PeerRemoved(ref peer)           => format!(r#""event":"peer_removed","public_key":"{}""#, key(peer)),
+            AuditEvent::PresharedKeyChanged(ref peer)   => format!(r#""event":"preshared_key_changed","public_key":"{}""#, key(peer)),
+            AuditEvent::EndpointChanged(ref peer, addr) => format!(r#""event":"endpoint_changed","public_key":"{}","endpoint":"{}""#, key(peer), addr),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AuditEntry {
+    pub timestamp  : SystemTime,
+    pub event      : AuditEvent,
+    /// The process on the other end of the configuration socket, where the OS tells us.
+    pub client_pid : Option<u32>,
+}
+
+impl AuditEntry {
+    fn to_json(&self) -> String {
+        let time = self.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let pid  = self.client_pid.map_or_else(|| "null".to_owned(), |pid| pid.to_string());
+        format!(r#"{{"timestamp_sec":{},"timestamp_nsec":{},{},"client_pid":{}}}"#,
+                time.as_secs(), time.subsec_nanos(), self.event.to_json(), pid)
+    }
+}
+
+/// The most recent `MAX_AUDIT_ENTRIES` changes, oldest first.
+#[derive(Debug, Default)]
+pub struct AuditLog {
+    entries: VecDeque<AuditEntry>,
+}
+
+impl AuditLog {
+    pub fn record(&mut self, event: AuditEvent, client_pid: Option<u32>) {
+        if self.entries.len() >= MAX_AUDIT_ENTRIES {
+            let _ = self.entries.pop_front();
+        }
+        self.entries.push_back(AuditEntry { timestamp: SystemTime::now(), event, client_pid });
+    }
+
+    /// The last `count` entries, oldest first.
+    pub fn last(&self, count: usize) -> impl Iterator<Item = &AuditEntry> {
+        self.entries.iter().skip(self.entries.len().saturating_sub(count))
+    }
+
+    /// The last `count` entries as a JSON array, oldest first.
+    pub fn to_json(&self, count: usize) -> String {
+        let entries = self.last(count).map(AuditEntry::to_json).collect::<Vec<_>>();
+        format!("[{}]", entries.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use types::PeerInfo;
+
+    #[test]
+    fn bounded_log() {
+        let mut log = AuditLog::default();
+        for port in 0..(MAX_AUDIT_ENTRIES + 10) {
+            log.record(AuditEvent::ListenPortChanged(port as u16), None);
+        }
+        assert_eq!(log.entries.len(), MAX_AUDIT_ENTRIES);
+        assert_eq!(log.last(2).map(|entry| entry.event.clone()).collect::<Vec<_>>(),
+                   vec![AuditEvent::ListenPortChanged(MAX_AUDIT_ENTRIES as u16 + 8),
+                        AuditEvent::ListenPortChanged(MAX_AUDIT_ENTRIES as u16 + 9)]);
+        assert_eq!(log.last(MAX_AUDIT_ENTRIES * 2).count(), MAX_AUDIT_ENTRIES);
+    }
+
+    #[test]
+    fn json_entries() {
+        let mut log = AuditLog::default();
+        assert_eq!(log.to_json(10), "[]");
+
+        log.record(AuditEvent::EndpointChanged([0; 32], "192.0.2.1:51820".parse().unwrap()), Some(42));
+        log.record(AuditEvent::PrivateKeySet, None);
+        let json = log.to_json(10);
+        assert!(json.starts_with(r#"[{"timestamp_sec":"#));
+        assert!(json.contains(r#""event":"endpoint_changed","public_key":"AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=","endpoint":"192.0.2.1:51820","client_pid":42}"#));
+        assert!(json.ends_with(r#""event":"private_key_set","client_pid":null}]"#));
+    }
+
+    #[test]
+    fn events_for_updates() {
+        let mut state = State::default();
+        let info      = PeerInfo { pub_key: PublicKey([1; 32]), psk: Some([2; 32]), ..Default::default() };
+        assert_eq!(AuditEvent::for_update(&state, &UpdateEvent::UpdatePeer(info.clone(), false)),
+                   vec![AuditEvent::PeerAdded([1; 32]), AuditEvent::PresharedKeyChanged([1; 32])]);
+
+        let _ = ::interface::config::ConfigurationService::handle_update(&mut state, &UpdateEvent::UpdatePeer(info.clone(), false)).unwrap();
+        assert_eq!(AuditEvent::for_update(&state, &UpdateEvent::UpdatePeer(info, false)),
+                   vec![AuditEvent::PresharedKeyChanged([1; 32])]);
+        assert_eq!(AuditEvent::for_update(&state, &UpdateEvent::RemoveAllPeers), vec![AuditEvent::PeerRemoved([1; 32])]);
+        assert!(AuditEvent::for_update(&state, &UpdateEvent::Fwmark(1)).is_empty());
+    }
+}
@@ -0,0 +1,230 @@
+/* SPDX-License-Identifier: GPL-2.0
+ *
+ * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
+ */
+
+//! Programmatic interface setup, for embedding without going through the configuration socket.
+
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+use anti_replay;
+use consts::{MAX_CONTENT_SIZE, MIN_MTU};
+use error::ConfigError;
+use interface::Interface;
+use interface::config::{ConfigurationService, UpdateEvent};
+use noise;
+use types::{InterfaceName, PeerInfo, PrivateKey, TimerConfig, TimerSetting};
+
+#[derive(Default)]
+pub struct InterfaceBuilder {
+    name        : String,
+    private_key : Option<PrivateKey>,
+    listen_port : Option<u16>,
+    fwmark      : Option<u32>,
+    mtu         : Option<u16>,
+    protocol    : Option<String>,
+    replay_size : Option<u32>,
+    timers      : Option<TimerConfig>,
+    peers       : Vec<PeerInfo>,
+}
+
+impl InterfaceBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.to_owned();
+        self
+    }
+
+    pub fn private_key(mut self, private_key: PrivateKey) -> Self {
+        self.private_key = Some(private_key);
+        self
+    }
+
+    pub fn listen_port(mut self, port: u16) -> Self {
+        self.listen_port = Some(port);
+        self
+    }
+
+    pub fn fwmark(mut self, mark: u32) -> Self {
+        self.fwmark = Some(mark);
+        self
+    }
+
+    /// The largest inner packet sent through the tunnel, `DEFAULT_MTU` if unset. Bigger ones
+    /// are answered with an ICMP error. Has to be at least `MIN_MTU`.
+    pub fn mtu(mut self, mtu: u16) -> Self {
+        self.mtu = Some(mtu);
+        self
+    }
+
+    /// Builds handshakes from a Noise protocol other than WireGuard's own, for testing.
+    pub fn noise_protocol(mut self, protocol: &str) -> Self {
+        self.protocol = Some(protocol.to_owned());
+        self
+    }
+
+    /// How far behind the newest nonce each session accepts packets: a power of two from 64 to 8192.
+    pub fn replay_window_size(mut self, size: u32) -> Self {
+        self.replay_size = Some(size);
+        self
+    }
+
+    /// Replaces the protocol's timers, typically with much shorter ones in tests.
+    pub fn timers(mut self, timers: TimerConfig) -> Self {
+        self.timers = Some(timers);
+        self
+    }
+
+    pub fn add_peer(mut self, info: PeerInfo) -> Self {
+        self.peers.push(info);
+        self
+    }
+
+    /// Every problem with the configuration, rather than just the first.
+    pub fn validate(&self) -> Vec<ConfigError> {
+        let mut errors = vec![];
+
+        if let Err(e) = InterfaceName::new(&self.name) {
+            errors.push(ConfigError::InvalidInterfaceName(e));
+        }
+
+        let pub_key = self.private_key.as_ref().map(PrivateKey::public_key);
+        if self.private_key.as_ref().map_or(false, PrivateKey::is_zero) {
+            errors.push(ConfigError::InvalidPrivateKey);
+        }
+        if self.listen_port == Some(0) {
+            errors.push(ConfigError::InvalidListenPort);
+        }
+        if let Some(mtu) = self.mtu.filter(|&mtu| mtu < MIN_MTU || mtu as usize > MAX_CONTENT_SIZE) {
+            errors.push(ConfigError::InvalidMtu(mtu));
+        }
+        if let Some(ref protocol) = self.protocol {
+            if noise::params(protocol).is_err() {
+                errors.push(ConfigError::InvalidNoiseProtocol(protocol.clone()));
+            }
+        }
+        if let Some(size) = self.replay_size {
+            if !anti_replay::is_valid_window_size(size) {
+                errors.push(ConfigError::InvalidReplayWindowSize(size));
+            }
+        }
+        if let Some(Err(e)) = self.timers.map(|timers| timers.validate()) {
+            errors.push(e);
+        }
+
+        let mut seen = HashSet::new();
+        for peer in &self.peers {
+            if !seen.insert(peer.pub_key) {
+                errors.push(ConfigError::DuplicatePeer(peer.to_string()));
+            }
+            if pub_key == Some(peer.pub_key) {
+                errors.push(ConfigError::SelfPeer(peer.to_string()));
+            }
+            for &(ip, cidr) in &peer.allowed_ips {
+                let max_cidr = match ip { IpAddr::V4(_) => 32, IpAddr::V6(_) => 128 };
+                if cidr > max_cidr {
+                    errors.push(ConfigError::InvalidAllowedIp(ip, cidr));
+                }
+            }
+        }
+        errors
+    }
+
+    pub fn build(self) -> Result<Interface, Vec<ConfigError>> {
+        let errors = self.validate();
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let mut events = vec![];
+        if let Some(private_key) = self.private_key { events.push(UpdateEvent::PrivateKey(private_key)); }
+        if let Some(port)        = self.listen_port { events.push(UpdateEvent::ListenPort(port)); }
+        if let Some(mark)        = self.fwmark      { events.push(UpdateEvent::Fwmark(mark)); }
+        if let Some(protocol)    = self.protocol    { events.push(UpdateEvent::NoiseProtocol(protocol)); }
+        if let Some(size)        = self.replay_size { events.push(UpdateEvent::ReplayWindowSize(size)); }
+        if let Some(timers)      = self.timers {
+            events.push(UpdateEvent::Timers(TimerSetting::ALL.iter().map(|&setting| (setting, timers.get(setting))).collect()));
+        }
+        events.extend(self.peers.into_iter().map(|info| UpdateEvent::UpdatePeer(info, false)));
+
+        let name          = InterfaceName::new(&self.name).map_err(|e| vec![ConfigError::InvalidInterfaceName(e)])?;
+        let mut interface = Interface::new(name);
+        {
+            let mut state = interface.state.write().unwrap();
+            state.interface_info.mtu = self.mtu;
+            for event in &events {
+                match ConfigurationService::handle_update(&mut state, event) {
+                    Ok(Some(msg)) => interface.pending_messages.push(msg),
+                    Ok(None)      => {},
+                    Err(e)        => return Err(vec![ConfigError::Rejected(e.to_string())]),
+                }
+            }
+        }
+        Ok(interface)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error::NameError;
+    use secure_mem::SecureBox;
+    use std::time::Duration;
+    use types::PublicKey;
+
+    fn peer(key: u8, allowed_ip: &str, cidr: u32) -> PeerInfo {
+        PeerInfo { pub_key: PublicKey([key; 32]), allowed_ips: vec![(allowed_ip.parse().unwrap(), cidr)], ..Default::default() }
+    }
+
+    #[test]
+    fn builds_state() {
+        let interface = InterfaceBuilder::new()
+            .name("wg0")
+            .private_key(PrivateKey([0x11u8; 32]))
+            .listen_port(51820)
+            .fwmark(42)
+            .mtu(1280)
+            .replay_window_size(512)
+            .timers(TimerConfig { rekey_after_time: Duration::from_millis(100), ..Default::default() })
+            .add_peer(peer(1, "10.0.0.2", 32))
+            .build().unwrap();
+
+        let state = interface.state.read().unwrap();
+        assert_eq!(state.interface_info.private_key, Some(SecureBox::new(PrivateKey([0x11u8; 32]))));
+        assert_eq!(state.interface_info.listen_port, Some(51820));
+        assert_eq!(state.interface_info.fwmark, Some(42));
+        assert_eq!(state.interface_info.mtu, Some(1280));
+        assert_eq!(state.interface_info.replay_window_size, 512);
+        assert_eq!(state.pubkey_map[&PublicKey([1u8; 32])].lock().unwrap().replay_window_size, 512);
+        assert_eq!(state.pubkey_map[&PublicKey([1u8; 32])].lock().unwrap().timer_config.rekey_after_time, Duration::from_millis(100));
+        assert!(!interface.pending_messages.is_empty());
+    }
+
+    #[test]
+    fn reports_every_error() {
+        let errors = InterfaceBuilder::new()
+            .private_key(PrivateKey::default())
+            .listen_port(0)
+            .mtu(68)
+            .noise_protocol("Noise_IKpsk2_25519_ChaChaPoly_MD5")
+            .replay_window_size(100)
+            .timers(TimerConfig { keepalive_timeout: Duration::new(0, 0), ..Default::default() })
+            .add_peer(peer(1, "10.0.0.0", 33))
+            .add_peer(peer(1, "fd00::", 64))
+            .build().err().unwrap();
+
+        assert_eq!(errors.len(), 9);
+        assert!(errors.contains(&ConfigError::InvalidInterfaceName(NameError::Empty)));
+        assert!(errors.contains(&ConfigError::InvalidPrivateKey));
+        assert!(errors.contains(&ConfigError::InvalidNoiseProtocol("Noise_IKpsk2_25519_ChaChaPoly_MD5".to_owned())));
+        assert!(errors.contains(&ConfigError::InvalidListenPort));
+        assert!(errors.contains(&ConfigError::InvalidMtu(68)));
+        assert!(errors.contains(&ConfigError::InvalidReplayWindowSize(100)));
+        assert!(errors.contains(&ConfigError::InvalidTimers("timers can't be zero".to_owned())));
+        assert!(errors.contains(&ConfigError::InvalidAllowedIp("10.0.0.0".parse().unwrap(), 33)));
+    }
+}
@@ -8,13 +8,16 @@
 // Dev notes:
 // * Configuration service should use channels to report updates it receives over its interface.
 
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket as StdUdpSocket};
 use std::env;
-use std::io::Write;
-use std::{cell::RefCell, iter::Iterator, rc::Rc, mem, str};
+use std::io::{self, Write};
+use std::{iter::Iterator, mem, str, sync::{Arc, Mutex, atomic::Ordering}};
 use std::fs::{File, Permissions, create_dir, remove_file};
 use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream as StdUnixStream;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use base64;
 use bytes::BytesMut;
@@ -22,32 +25,62 @@ use failure::{Error, err_msg};
 use futures::{Async, Future, Poll, Stream, Sink, future, unsync::mpsc};
 use hex::{self, FromHex};
 use tokio_core::reactor::Handle;
-use tokio_io::{AsyncRead, codec::{Encoder, Decoder}};
+use libc;
+use tokio_io::{self, AsyncRead, codec::{Encoder, Decoder}};
 use tokio_uds::UnixListener;
-use x25519_dalek as x25519;
 
-use consts::MAX_PEERS_PER_DEVICE;
-use interface::{SharedState, State};
+use consts::{MAX_AUDIT_ENTRIES, MAX_CONFIG_MESSAGE_SIZE, MAX_CONFIG_PROTOCOL_VERSION, MAX_PEERS_PER_DEVICE};
+use anti_replay;
+use error::{ConfigError, DropReason, SetError};
+use noise;
+use interface::{InterfaceEvent, SharedState, State};
+use interface::audit::AuditEvent;
 use interface::grim_reaper::GrimReaper;
 use interface::peer_server::ChannelMessage;
-use peer::Peer;
-use types::PeerInfo;
+use peer::{DEFAULT_REPLAY_WINDOW_SIZE, Peer};
+use secure_mem::SecureBox;
+use types::{PeerInfo, PrivateKey, PublicKey, TimerConfig, TimerSetting, constant_time_eq, is_valid_endpoint_host};
 
 
 #[derive(Debug)]
 pub enum Command {
     Set(usize, Vec<UpdateEvent>),
-    Get(usize)
+    Get(usize),
+    /// The last N audit log entries, as JSON. Only in protocol version 2.
+    GetAudit(usize, usize),
+    /// The session history, as JSON. Only in protocol version 2.
+    GetSessions(usize),
+    /// A `set` whose items couldn't be parsed, answered with just this errno.
+    Invalid(i32),
 }
 
 #[derive(Debug)]
 #[allow(dead_code)]
 pub enum UpdateEvent {
-    PrivateKey([u8; 32]),
+    PrivateKey(PrivateKey),
     Fwmark(u32),
     ListenPort(u16),
+    RateLimit(u32),
+    UdpRecvBuffer(usize),
+    UdpSendBuffer(usize),
+    PreserveDscp(bool),
+    NoiseProtocol(String),
+    MeasureLatency(bool),
+    MirrorDecrypted(Option<SocketAddr>),
+    MirrorPlaintextOut(Option<SocketAddr>),
+    AutoRoutes(bool),
+    RoutingTable(u32),
+    ReplayWindowSize(u32),
+    /// Every timer a `set` gives, applied together so they're only checked against each other
+    /// once all are in.
+    Timers(Vec<(TimerSetting, Duration)>),
     UpdatePeer(PeerInfo, bool),
-    RemovePeer([u8; 32]),
+    /// An `UpdatePeer` sent with `update_only=true`, which does nothing if the peer isn't there.
+    UpdateExistingPeer(PeerInfo, bool),
+    /// Forgets a peer's endpoints, which `UpdatePeer` can only set. Configuration reloads use it
+    /// when a peer's `Endpoint` is taken out of the file.
+    ClearEndpoint(PublicKey),
+    RemovePeer(PublicKey),
     RemoveAllPeers,
 }
 
@@ -57,43 +90,76 @@ impl UpdateEvent {
         let mut pending_peer        = false;
         let mut remove_pending_peer = false;
         let mut replace_allowed_ips = false;
+        let mut update_only         = false;
         let mut info                = PeerInfo::default();
+        let mut timers              = vec![];
 
         for (key, value) in items {
             match key.as_ref() {
-                "private_key"                   => { events.push(UpdateEvent::PrivateKey(<[u8; 32]>::from_hex(&value)?)); },
+                "protocol_version"              => { if value != "1" { return Err(SetError::UnsupportedProtocolVersion(value).into()); } },
+                "private_key"                   => { events.push(UpdateEvent::PrivateKey(PrivateKey(parse_key(&value)?))); },
                 "listen_port"                   => { events.push(UpdateEvent::ListenPort(value.parse()?)); },
                 "fwmark"                        => { events.push(UpdateEvent::Fwmark(value.parse()?)); },
-                "replace_peers"                 => { events.push(UpdateEvent::RemoveAllPeers); },
-                "preshared_key"                 => { info.psk       = Some(<[u8; 32]>::from_hex(&value)?); },
+                "handshake_rate_limit"          => { events.push(UpdateEvent::RateLimit(value.parse()?)); },
+                "udp_recv_buffer"               => { events.push(UpdateEvent::UdpRecvBuffer(value.parse()?)); },
+                "udp_send_buffer"               => { events.push(UpdateEvent::UdpSendBuffer(value.parse()?)); },
+                "preserve_dscp"                 => { events.push(UpdateEvent::PreserveDscp(value == "true")); },
+                "noise_protocol"                => { events.push(UpdateEvent::NoiseProtocol(value)); },
+                "measure_latency"               => { events.push(UpdateEvent::MeasureLatency(value == "true")); },
+                "mirror_decrypted"              => { events.push(UpdateEvent::MirrorDecrypted(parse_mirror(&value)?)); },
+                "mirror_plaintext_out"          => { events.push(UpdateEvent::MirrorPlaintextOut(parse_mirror(&value)?)); },
+                "auto_routes"                   => { events.push(UpdateEvent::AutoRoutes(value == "true")); },
+                "routing_table"                 => { events.push(UpdateEvent::RoutingTable(value.parse()?)); },
+                "replay_window_size"            => { events.push(UpdateEvent::ReplayWindowSize(parse_replay_window_size(&value)?)); },
+                "replace_peers"                 => { if value == "true" { events.push(UpdateEvent::RemoveAllPeers); } },
+                "preshared_key"                 => { info.psk       = Some(parse_key(&value)?); },
                 "persistent_keepalive_interval" => { info.keepalive = Some(value.parse()?); },
-                "endpoint"                      => { info.endpoint  = Some(value.parse::<SocketAddr>()?.into()); },
-                "replace_allowed_ips"           => { replace_allowed_ips = true; },
-                "remove"                        => { remove_pending_peer = true; },
+                "max_inbound_pps"               => { info.max_inbound_pps = Some(value.parse()?); },
+                "endpoint"                      => match value.parse::<SocketAddr>() {
+                    Ok(addr) => {
+                        // the first endpoint is the one we start with, the rest are fallbacks.
+                        info.endpoint = info.endpoint.or_else(|| Some(addr.into()));
+                        info.endpoints.push(addr);
+                    },
+                    Err(_) if is_valid_endpoint_host(&value) => info.endpoint_host = Some(value),
+                    Err(e)                                   => return Err(e.into()),
+                },
+                "replace_allowed_ips"           => { replace_allowed_ips = value == "true"; },
+                "remove"                        => { remove_pending_peer = value == "true"; },
+                "update_only"                   => { update_only = value == "true"; },
                 "public_key" => {
                     let peer_info = mem::replace(&mut info, PeerInfo::default());
                     match (pending_peer, remove_pending_peer) {
-                        (true, true ) => events.push(UpdateEvent::RemovePeer(peer_info.pub_key)),
-                        (true, false) => events.push(UpdateEvent::UpdatePeer(peer_info, replace_allowed_ips)),
+                        (true, true )                => events.push(UpdateEvent::RemovePeer(peer_info.pub_key)),
+                        (true, false) if update_only => events.push(UpdateEvent::UpdateExistingPeer(peer_info, replace_allowed_ips)),
+                        (true, false)                => events.push(UpdateEvent::UpdatePeer(peer_info, replace_allowed_ips)),
                         _ => {}
                     }
-                    info.pub_key = <[u8; 32]>::from_hex(&value)?;
+                    info.pub_key = PublicKey(parse_key(&value)?);
                     pending_peer = true;
                     remove_pending_peer = false;
                     replace_allowed_ips = false;
+                    update_only = false;
                 },
                 "allowed_ip" => {
                     let (ip, cidr) = value.split_at(value.find('/').ok_or_else(|| err_msg("ip/cidr format error"))?);
                     info.allowed_ips.push((ip.parse()?, (&cidr[1..]).parse()?))
                 },
-                _ => { warn!("unrecognized configuration pair: {}={}", key, value)}
+                _ => match TimerSetting::from_key(&key) {
+                    Some(setting) => timers.push((setting, Duration::from_millis(value.parse()?))),
+                    None          => return Err(SetError::UnknownKey(key.clone()).into()),
+                },
             }
         }
+        if !timers.is_empty() {
+            events.push(UpdateEvent::Timers(timers));
+        }
 
         // "flush" the final peer if there is one
         match (pending_peer, remove_pending_peer) {
-            (true, true ) => events.push(UpdateEvent::RemovePeer(info.pub_key)),
-            (true, false) => events.push(UpdateEvent::UpdatePeer(info, replace_allowed_ips)),
+            (true, true )                => events.push(UpdateEvent::RemovePeer(info.pub_key)),
+            (true, false) if update_only => events.push(UpdateEvent::UpdateExistingPeer(info, replace_allowed_ips)),
+            (true, false)                => events.push(UpdateEvent::UpdatePeer(info, replace_allowed_ips)),
             _ => {}
         }
         trace!("events {:?}", events);
@@ -101,33 +167,87 @@ impl UpdateEvent {
     }
 }
 
+/// A key in a `set`. The protocol sends keys as 64 hex digits, which is all `wg` speaks, but
+/// the base64 form from `wg genkey` and configuration files is taken as well.
+fn parse_key(value: &str) -> Result<[u8; 32], Error> {
+    if value.len() == 64 {
+        return Ok(<[u8; 32]>::from_hex(value)?);
+    }
+    let bytes = base64::decode(value)?;
+    ensure!(bytes.len() == 32, "key must be 32 bytes, got {}", bytes.len());
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&bytes);
+    Ok(key)
+}
+
+fn parse_replay_window_size(value: &str) -> Result<u32, Error> {
+    let size = value.parse()?;
+    if !anti_replay::is_valid_window_size(size) {
+        return Err(ConfigError::InvalidReplayWindowSize(size).into());
+    }
+    Ok(size)
+}
+
+/// A mirror address, where an empty value turns mirroring off.
+fn parse_mirror(value: &str) -> Result<Option<SocketAddr>, Error> {
+    if value.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(value.parse()?))
+    }
+}
+
 pub struct ConfigurationCodec;
 
+fn invalid_data(reason: &'static str) -> Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason).into()
+}
+
 impl Decoder for ConfigurationCodec {
     type Item = Command;
     type Error = Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         // Determine we have a full command ready for parsing.
-        let mut items = Vec::new();
-        let utf8 = String::from_utf8(buf.to_vec()).unwrap();
-        let mut data_iter = utf8.split("\n\n");
-        let blob = data_iter.next().unwrap();
-        if data_iter.next().is_none() {
-            return Ok(None)
+        let end = match buf.windows(2).position(|pair| pair == b"\n\n") {
+            Some(end) => end,
+            None if buf.len() > MAX_CONFIG_MESSAGE_SIZE => return Err(invalid_data("message too large")),
+            None => return Ok(None),
+        };
+        if end + 2 > MAX_CONFIG_MESSAGE_SIZE {
+            return Err(invalid_data("message too large"));
         }
+        let message = buf.split_to(end + 2);
+        let blob    = str::from_utf8(&message[..end]).map_err(|_| invalid_data("message isn't valid UTF-8"))?;
 
         // Parse the key-value pairs into something more usable
+        let mut items = Vec::new();
         for line in blob.split('\n') {
-            let mut entry = line.split('=');
-            items.push((entry.next().unwrap().to_owned(), entry.next().unwrap().to_owned()));
+            let mut entry = line.splitn(2, '=');
+            match (entry.next(), entry.next()) {
+                (Some(key), Some(value)) => items.push((key.to_owned(), value.to_owned())),
+                _                        => return Err(invalid_data("line without a '=' separator")),
+            }
         }
-        buf.split_to(blob.len()+1);
 
         let (ref cmd, ref version) = items.remove(0);
         let command = match cmd.as_str() {
-            "get" => Command::Get(version.parse()?),
-            "set" => Command::Set(version.parse()?, UpdateEvent::from(items)?),
+            "get"       => Command::Get(version.parse()?),
+            "set"       => match UpdateEvent::from(items) {
+                Ok(events) => Command::Set(version.parse()?, events),
+                Err(e)     => {
+                    warn!("rejecting config update: {}", e);
+                    Command::Invalid(errno_for(&e))
+                },
+            },
+            "get_audit" => {
+                let count = match items.iter().find(|&&(ref key, _)| key == "count") {
+                    Some(&(_, ref count)) => count.parse()?,
+                    None                  => MAX_AUDIT_ENTRIES,
+                };
+                Command::GetAudit(version.parse()?, count)
+            },
+            "get_sessions" => Command::GetSessions(version.parse()?),
             _ => bail!("invalid command")
         };
 
@@ -150,6 +270,21 @@ pub struct ConfigurationService {
     interface_name: String,
     config_server: Box<Future<Item = (), Error = ()>>,
     reaper: Box<Future<Item = (), Error = ()>>,
+    _socket: SocketCleanup,
+}
+
+/// Removes the config socket once the service goes away, however that happens, so the next
+/// start doesn't trip over it.
+struct SocketCleanup(PathBuf);
+
+impl Drop for SocketCleanup {
+    fn drop(&mut self) {
+        if let Err(e) = remove_file(&self.0) {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("couldn't remove config socket {}: {}", self.0.display(), e);
+            }
+        }
+    }
 }
 
 impl ConfigurationService {
@@ -162,101 +297,215 @@ impl ConfigurationService {
             f.write_all(b"\n")?;
         }
 
-        let config_path = Self::get_path(interface_name).unwrap();
-        let listener    = UnixListener::bind(config_path.clone(), handle).unwrap();
+        let config_path = Self::get_path(interface_name)?;
+        let listener    = UnixListener::bind(config_path.clone(), handle)?;
+        let socket      = SocketCleanup(config_path.clone());
+        Self::chmod(&config_path, 0o600)?;
 
         // TODO only listen for own socket, verify behavior from `notify` crate
-        let reaper = GrimReaper::spawn(handle, &config_path).unwrap();
+        let reaper = GrimReaper::spawn(handle, &config_path)?;
 
         let config_server = listener.incoming().for_each({
             let handle = handle.clone();
             let state = state.clone();
             move |(stream, _)| {
+                let handle = handle.clone();
+                let client_pid = match peer_credentials(stream.as_raw_fd()) {
+                    Ok((uid, pid)) if is_authorized(uid, unsafe { libc::getuid() }) => pid,
+                    result => {
+                        warn!("rejecting config connection from unauthorized peer ({:?})", result);
+                        handle.spawn(tokio_io::io::write_all(stream, b"errno=1\n\n").then(|_| Ok(()))); // EPERM
+                        return Ok(())
+                    },
+                };
+
                 let (sink, stream) = stream.framed(ConfigurationCodec {}).split();
                 trace!("UnixServer connection.");
 
-                let handle = handle.clone();
                 let responses = stream.and_then({
                     let tx = peer_server_tx.clone();
                     let state = state.clone();
                     move |command| {
-                        let mut state = state.borrow_mut();
-                        match command {
-                            Command::Set(_version, items) => {
-                                for item in &items {
-                                    match Self::handle_update(&mut state, item) {
-                                        Ok(Some(msg)) => { tx.clone().send(msg).wait().unwrap(); },
-                                        Err(_)        => { return future::ok("errno=1\nerrno=1\n\n".into()); },
-                                        _             => {}
-                                    }
-                                }
-                                future::ok("errno=0\nerrno=0\n\n".into())
-                            },
-                            Command::Get(_version) => {
-                                let info = &state.interface_info;
-                                let peers = &state.pubkey_map;
-                                let mut s = String::new();
-                                if let Some(private_key) = info.private_key {
-                                    s.push_str(&format!("private_key={}\n", hex::encode(private_key)));
-                                }
-                                if let Some(port) = info.listen_port {
-                                    s.push_str(&format!("listen_port={}\n", port));
-                                }
-                                for (_, peer) in peers.iter() {
-                                    s.push_str(&peer.borrow().to_config_string());
-                                }
-                                future::ok(format!("{}errno=0\n\n", s))
-                            }
-                        }
+                        future::ok(Self::handle_command(&mut state.write().unwrap(), &tx, command, client_pid))
                     }
                 });
 
                 let fut = sink.send_all(responses)
                     .map(|_| ())
-                    .map_err(|_| ());
+                    .map_err(|e| warn!("config connection error: {}", e));
 
                 handle.spawn(fut);
 
                 Ok(())
             }
-        }).map_err(|_| ());
+        }).map_err(|e| error!("config listener error: {}", e));
 
         Ok(ConfigurationService {
             interface_name: interface_name.to_owned(),
             config_server: Box::new(config_server),
             reaper: Box::new(reaper),
+            _socket: socket,
         })
     }
 
-    fn clear_peer_refs(state: &mut State, peer: &Peer) {
-        for index in peer.get_mapped_indices() {
-            let _ = state.index_map.remove(&index);
+    /// Answers one request, in the protocol version it asked for. Changes are audited as coming
+    /// from `client_pid`.
+    fn handle_command(state: &mut State, tx: &mpsc::UnboundedSender<ChannelMessage>, command: Command, client_pid: Option<u32>) -> String {
+        match command {
+            Command::Set(version, _) | Command::Get(version) if version == 0 || version > MAX_CONFIG_PROTOCOL_VERSION => {
+                warn!("unsupported configuration protocol version {}", version);
+                "errno=93\n\n".into() // EPROTONOSUPPORT
+            },
+            Command::GetAudit(version, _) if version < 2 || version > MAX_CONFIG_PROTOCOL_VERSION => {
+                warn!("get_audit needs configuration protocol version 2, got {}", version);
+                "errno=93\n\n".into()
+            },
+            Command::GetAudit(_, count) => {
+                format!("audit_log={}\nerrno=0\n\n", state.audit_log.to_json(count))
+            },
+            Command::GetSessions(version) if version < 2 || version > MAX_CONFIG_PROTOCOL_VERSION => {
+                warn!("get_sessions needs configuration protocol version 2, got {}", version);
+                "errno=93\n\n".into()
+            },
+            Command::GetSessions(_) => {
+                format!("sessions={}\nerrno=0\n\n", state.session_history.to_json())
+            },
+            Command::Set(_, items) => {
+                for item in &items {
+                    let result = match Self::handle_audited_update(state, item, client_pid) {
+                        Ok(Some(msg)) => state.send_to_peer_server(tx, msg).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "peer server hung up").into()),
+                        Ok(None)      => Ok(()),
+                        Err(e)        => Err(e),
+                    };
+                    if let Err(e) = result {
+                        warn!("failed to apply config update {:?}: {}", item, e);
+                        return format!("errno={}\n\n", errno_for(&e));
+                    }
+                }
+                "errno=0\n\n".into()
+            },
+            Command::Get(version) => {
+                format!("{}errno=0\n\n", Self::get_config_string(state, version))
+            },
+            Command::Invalid(errno) => format!("errno={}\n\n", errno),
+        }
+    }
+
+    /// Builds the body of a `get` response. The interface's public key is deliberately absent:
+    /// a `public_key` line starts a new peer section in the protocol, and clients derive it
+    /// from `private_key` themselves.
+    ///
+    /// Version 2 adds the replay and unknown-peer drop counts even when they're zero, how many
+    /// messages are waiting on the tun device and the peer server, and each peer's session age.
+    fn get_config_string(state: &State, version: usize) -> String {
+        let info = &state.interface_info;
+        let mut s = String::new();
+        if let Some(ref private_key) = info.private_key {
+            s.push_str(&format!("private_key={}\n", hex::encode(private_key)));
+        }
+        if let Some(port) = info.listen_port {
+            s.push_str(&format!("listen_port={}\n", port));
+        }
+        match info.fwmark {
+            Some(fwmark) if fwmark != 0 => s.push_str(&format!("fwmark={}\n", fwmark)),
+            _                           => {}
+        }
+        if let Some((recv, send)) = info.udp_buffer_sizes {
+            s.push_str(&format!("udp_recv_buffer={}\nudp_send_buffer={}\n", recv, send));
+        }
+        if info.preserve_dscp {
+            s.push_str("preserve_dscp=true\n");
+        }
+        if info.measure_latency {
+            s.push_str("measure_latency=true\n");
+        }
+        if let Some(addr) = info.mirror_decrypted {
+            s.push_str(&format!("mirror_decrypted={}\n", addr));
+        }
+        if let Some(addr) = info.mirror_plaintext_out {
+            s.push_str(&format!("mirror_plaintext_out={}\n", addr));
+        }
+        if info.auto_routes {
+            s.push_str("auto_routes=true\n");
+        }
+        if let Some(table) = info.routing_table {
+            s.push_str(&format!("routing_table={}\n", table));
+        }
+        match state.mirror_send_drops.load(Ordering::Relaxed) {
+            0     => {},
+            drops => s.push_str(&format!("mirror_send_drops={}\n", drops)),
+        }
+        if info.replay_window_size != DEFAULT_REPLAY_WINDOW_SIZE {
+            s.push_str(&format!("replay_window_size={}\n", info.replay_window_size));
+        }
+        let default_timers = TimerConfig::default();
+        for &setting in TimerSetting::ALL.iter() {
+            let value = info.timers.get(setting);
+            if value != default_timers.get(setting) {
+                s.push_str(&format!("{}={}\n", setting.key(), value.as_secs() * 1000 + u64::from(value.subsec_millis())));
+            }
+        }
+        if info.noise_protocol != noise::DEFAULT_PROTOCOL {
+            s.push_str(&format!("noise_protocol={}\n", info.noise_protocol));
+        }
+        for reason in DropReason::ALL.iter() {
+            match state.drop_counters.get(reason) {
+                Some(&count) if count > 0 => s.push_str(&format!("drop_reason_{}={}\n", reason.name(), count)),
+                _                         => {},
+            }
+        }
+        if version >= 2 {
+            let count = |reason: DropReason| state.drop_counters.get(&reason).cloned().unwrap_or(0);
+            s.push_str(&format!("drop_reason_replay={}\ndrop_reason_no_peer={}\n",
+                                count(DropReason::ReplayAttack), count(DropReason::NoMatchingPeer)));
+            s.push_str(&format!("utun_queue_depth={}\nconfig_queue_depth={}\n",
+                                state.utun_queue_depth.load(Ordering::Relaxed), state.config_queue_depth.load(Ordering::Relaxed)));
+        }
+        for peer in state.iter_peers() {
+            let peer = peer.lock().unwrap();
+            s.push_str(&peer.to_config_string());
+            if version >= 2 {
+                s.push_str(&peer.to_extended_config_string());
+            }
+        }
+        s
+    }
+
+    /// `handle_update`, recording what it changed in the audit log once it's succeeded.
+    pub fn handle_audited_update(state: &mut State, event: &UpdateEvent, client_pid: Option<u32>) -> Result<Option<ChannelMessage>, Error> {
+        let changes = AuditEvent::for_update(state, event);
+        let message = Self::handle_update(state, event)?;
+        for change in changes {
+            state.audit_log.record(change, client_pid);
         }
-        state.router.remove_allowed_ips(&peer.info.allowed_ips);
+        Ok(message)
     }
 
     pub fn handle_update(state: &mut State, event: &UpdateEvent) -> Result<Option<ChannelMessage>, Error> {
         match *event {
-            UpdateEvent::PrivateKey(private_key) => {
-                if private_key == [0u8; 32] {
+            UpdateEvent::PrivateKey(ref private_key) => {
+                if private_key.is_zero() {
                     state.interface_info.private_key = None;
                     state.interface_info.pub_key     = None;
                     debug!("unset private key");
                     Ok(Some(ChannelMessage::ClearPrivateKey))
                 } else {
-                    let pub_key = x25519::generate_public(&private_key);
-                    state.interface_info.private_key = Some(private_key);
-                    state.interface_info.pub_key     = Some(*pub_key.as_bytes());
-                    debug!("set new private key (pub: {}).", base64::encode(pub_key.as_bytes()));
+                    let pub_key = private_key.public_key();
+                    state.interface_info.private_key = Some(SecureBox::new(private_key.clone()));
+                    state.interface_info.pub_key     = Some(pub_key);
+                    debug!("set new private key (pub: {}).", pub_key);
+                    state.notify(InterfaceEvent::PrivateKeyRotated);
 
-                    if let Some(peer_ref) = state.pubkey_map.remove(&*pub_key.as_bytes()) {
-                        Self::clear_peer_refs(state, &peer_ref.borrow());
+                    if state.remove_peer(&pub_key.0) {
                         debug!("removed self from peers");
                     }
                     Ok(Some(ChannelMessage::NewPrivateKey))
                 }
             },
             UpdateEvent::ListenPort(port) => {
+                if port != 0 && state.interface_info.listen_port != Some(port) {
+                    check_port_free(port)?;
+                }
                 state.interface_info.listen_port = Some(port);
                 debug!("set listen port: {}", port);
                 Ok(Some(ChannelMessage::NewListenPort(port))) // TODO: only notify on listen port *change*
@@ -266,27 +515,128 @@ impl ConfigurationService {
                 debug!("set fwmark: {}", mark);
                 Ok(Some(ChannelMessage::NewFwmark(mark))) // TODO: only notify on fwmark *change*
             },
+            UpdateEvent::RateLimit(limit) => {
+                state.interface_info.handshake_rate_limit = Some(limit);
+                debug!("set handshake rate limit: {}/s", limit);
+                Ok(Some(ChannelMessage::NewRateLimit(limit)))
+            },
+            UpdateEvent::UdpRecvBuffer(size) => {
+                state.interface_info.udp_recv_buffer = Some(size);
+                debug!("set udp receive buffer: {} bytes", size);
+                Ok(Some(ChannelMessage::NewUdpBuffers))
+            },
+            UpdateEvent::UdpSendBuffer(size) => {
+                state.interface_info.udp_send_buffer = Some(size);
+                debug!("set udp send buffer: {} bytes", size);
+                Ok(Some(ChannelMessage::NewUdpBuffers))
+            },
+            UpdateEvent::PreserveDscp(preserve) => {
+                state.interface_info.preserve_dscp = preserve;
+                debug!("set dscp preservation: {}", preserve);
+                Ok(Some(ChannelMessage::NewPreserveDscp(preserve)))
+            },
+            UpdateEvent::NoiseProtocol(ref protocol) => {
+                let _ = noise::params(protocol)?;
+                state.interface_info.noise_protocol = protocol.clone();
+                debug!("set noise protocol: {}", protocol);
+                Ok(None)
+            },
+            UpdateEvent::MeasureLatency(measure) => {
+                state.interface_info.measure_latency = measure;
+                debug!("set keepalive latency measurement: {}", measure);
+                Ok(None)
+            },
+            UpdateEvent::MirrorDecrypted(addr) => {
+                state.interface_info.mirror_decrypted = addr;
+                debug!("set decrypted packet mirror: {:?}", addr);
+                Ok(None)
+            },
+            UpdateEvent::MirrorPlaintextOut(addr) => {
+                state.interface_info.mirror_plaintext_out = addr;
+                debug!("set outbound plaintext mirror: {:?}", addr);
+                Ok(None)
+            },
+            UpdateEvent::AutoRoutes(enabled) => {
+                state.interface_info.auto_routes = enabled;
+                if enabled {
+                    state.reinstall_routes();
+                } else if let Some(ref mut routes) = state.routes {
+                    routes.withdraw_all();
+                }
+                debug!("set automatic routes: {}", enabled);
+                Ok(None)
+            },
+            UpdateEvent::RoutingTable(table) => {
+                state.interface_info.routing_table = Some(table);
+                state.reinstall_routes();
+                debug!("set routing table: {}", table);
+                Ok(None)
+            },
+            UpdateEvent::ReplayWindowSize(size) => {
+                if !anti_replay::is_valid_window_size(size) {
+                    return Err(ConfigError::InvalidReplayWindowSize(size).into());
+                }
+                state.interface_info.replay_window_size = size;
+                // sessions already up keep the window they started with.
+                for peer in state.iter_peers() {
+                    peer.lock().unwrap().replay_window_size = size;
+                }
+                debug!("set replay window size: {}", size);
+                Ok(None)
+            },
+            UpdateEvent::Timers(ref timers) => {
+                let mut config = state.interface_info.timers;
+                for &(setting, value) in timers {
+                    config.set(setting, value);
+                }
+                config.validate()?;
+                state.interface_info.timers = config;
+                // timers already running finish out their old durations.
+                for peer in state.iter_peers() {
+                    peer.lock().unwrap().timer_config = config;
+                }
+                debug!("set timers: {:?}", config);
+                Ok(None)
+            },
             UpdateEvent::UpdatePeer(ref info, replace_allowed_ips) => {
-                let existing_peer = state.pubkey_map.get(&info.pub_key).cloned();
+                let existing_peer = state.get_peer_by_pubkey(&info.pub_key.0);
                 if let Some(peer_ref) = existing_peer {
                     debug!("updating peer: {}", info);
-                    let mut peer = peer_ref.borrow_mut();
+                    let mut peer = peer_ref.lock().unwrap();
                     let mut info = info.clone();
                     if replace_allowed_ips {
-                        state.router.remove_allowed_ips(&peer.info.allowed_ips);
+                        state.router.remove_allowed_ips(&peer.info.allowed_ips, &peer_ref);
                     } else {
-                        info.allowed_ips.extend_from_slice(&peer.info.allowed_ips);
+                        for allowed_ip in &peer.info.allowed_ips {
+                            if !info.allowed_ips.contains(allowed_ip) {
+                                info.allowed_ips.push(*allowed_ip);
+                            }
+                        }
                     }
                     let ret = if info.keepalive.is_some() && peer.info.keepalive != info.keepalive {
                         Some(ChannelMessage::NewPersistentKeepalive(peer_ref.clone()))
                     } else {
                         None
                     };
-                    info.endpoint  = info.endpoint.or(peer.info.endpoint);
-                    info.keepalive = info.keepalive.or(peer.info.keepalive);
-                    info.psk       = info.psk.or(peer.info.psk);
+                    if info.endpoint.is_none() && info.endpoint_host.is_none() {
+                        info.endpoint_host = peer.info.endpoint_host.clone();
+                        info.endpoints     = peer.info.endpoints.clone();
+                    } else {
+                        peer.active_endpoint   = 0;
+                        peer.resolved_endpoint = None;
+                    }
+                    info.endpoint        = info.endpoint.or(peer.info.endpoint);
+                    info.keepalive       = info.keepalive.or(peer.info.keepalive);
+                    info.max_inbound_pps = info.max_inbound_pps.or(peer.info.max_inbound_pps);
+                    info.psk             = match info.psk {
+                        Some(psk) if constant_time_eq(&psk, &[0u8; 32]) => None, // an all-zero key clears the psk
+                        Some(psk)                                       => Some(psk),
+                        None                                            => peer.info.psk,
+                    };
                     state.router.add_allowed_ips(&info.allowed_ips, &peer_ref);
+                    state.install_routes(&info);
                     peer.info = info;
+                    peer.update_inbound_limit();
                     Ok(ret)
                 } else {
                     if let Some(pub_key) = state.interface_info.pub_key {
@@ -296,28 +646,57 @@ impl ConfigurationService {
                         }
                     }
 
-                    if state.pubkey_map.len() >= MAX_PEERS_PER_DEVICE {
-                        bail!("already at max peers per device");
+                    if state.peer_count() >= MAX_PEERS_PER_DEVICE {
+                        return Err(SetError::TooManyPeers.into());
                     }
 
                     debug!("adding new peer: {}", info);
+                    let mut info = info.clone();
+                    if info.psk.map_or(false, |psk| constant_time_eq(&psk, &[0u8; 32])) {
+                        info.psk = None;
+                    }
                     let mut peer = Peer::new(info.clone());
-                    let peer_ref = Rc::new(RefCell::new(peer));
-                    let _ = state.pubkey_map.insert(info.pub_key, peer_ref.clone());
-                    state.router.add_allowed_ips(&info.allowed_ips, &peer_ref);
+                    peer.replay_window_size = state.interface_info.replay_window_size;
+                    peer.timer_config       = state.interface_info.timers;
+                    let peer_ref = Arc::new(Mutex::new(peer));
+                    state.add_peer(peer_ref.clone(), &info);
+                    state.notify(InterfaceEvent::PeerAdded(PeerInfo { psk: None, ..info })); // subscribers don't need the psk
                     Ok(Some(ChannelMessage::NewPeer(peer_ref)))
                 }
             },
+            UpdateEvent::UpdateExistingPeer(ref info, replace_allowed_ips) => {
+                if state.get_peer_by_pubkey(&info.pub_key.0).is_none() {
+                    debug!("ignoring update_only for unknown peer: {}", info);
+                    return Ok(None)
+                }
+                Self::handle_update(state, &UpdateEvent::UpdatePeer(info.clone(), replace_allowed_ips))
+            },
             UpdateEvent::RemoveAllPeers => {
-                state.pubkey_map.clear();
-                state.index_map.clear();
-                state.router.clear();
+                for peer in state.clear_peers() {
+                    let pub_key = peer.lock().unwrap().info.pub_key;
+                    state.notify(InterfaceEvent::PeerRemoved(pub_key));
+                }
+                Ok(None)
+            },
+            UpdateEvent::ClearEndpoint(pub_key) => {
+                if let Some(peer_ref) = state.get_peer_by_pubkey(&pub_key.0) {
+                    let mut peer = peer_ref.lock().unwrap();
+                    debug!("clearing endpoint of peer: {}", peer.info);
+                    peer.info.endpoint      = None;
+                    peer.info.endpoint_host = None;
+                    peer.info.endpoints     = vec![];
+                    peer.active_endpoint    = 0;
+                    peer.resolved_endpoint  = None;
+                }
                 Ok(None)
             },
             UpdateEvent::RemovePeer(pub_key) => {
-                let peer_ref = state.pubkey_map.remove(&pub_key)
-                    .ok_or_else(|| err_msg("trying to remove nonexistent peer"))?;
-                Self::clear_peer_refs(state, &peer_ref.borrow());
+                if state.remove_peer(&pub_key.0) {
+                    debug!("removed peer: {}", pub_key);
+                    state.notify(InterfaceEvent::PeerRemoved(pub_key));
+                } else {
+                    debug!("ignoring removal of nonexistent peer");
+                }
                 Ok(None)
             },
         }
@@ -338,7 +717,10 @@ impl ConfigurationService {
         socket_path.push(interface_name);
         socket_path.set_extension("sock");
         if socket_path.exists() {
-            debug!("Removing existing socket: {}", socket_path.display());
+            // something still accepting on it is another instance, which keeps its socket.
+            ensure!(StdUnixStream::connect(&socket_path).is_err(),
+                    "{} is in use, is {} already running?", socket_path.display(), interface_name);
+            warn!("removing stale socket {}, did a previous instance crash?", socket_path.display());
             remove_file(&socket_path)?;
         }
 
@@ -402,3 +784,601 @@ impl Drop for ConfigurationService {
         }
     }
 }
+
+/// The errno a failed request is answered with: whatever the OS said where it said anything,
+/// `ENOMEM` for a full peer table, `EPROTONOSUPPORT` for a protocol version we don't speak,
+/// `EIO` for other I/O failures and `EINVAL` for the rest, which are values that don't parse.
+fn errno_for(e: &Error) -> i32 {
+    match e.downcast_ref::<SetError>() {
+        Some(&SetError::TooManyPeers)                  => return libc::ENOMEM,
+        Some(&SetError::UnsupportedProtocolVersion(_)) => return libc::EPROTONOSUPPORT,
+        _                                              => {},
+    }
+    match e.downcast_ref::<io::Error>() {
+        Some(e) => match e.raw_os_error() {
+            Some(errno) => errno,
+            None        => match e.kind() {
+                io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput => libc::EINVAL,
+                _                                                        => libc::EIO,
+            },
+        },
+        None => libc::EINVAL,
+    }
+}
+
+/// Fails with `EADDRINUSE` if something else already holds UDP `port`, so a `set` can say so
+/// rather than the peer server failing to rebind after the reply has gone out. The sockets
+/// are only held for the check; any other error is left for the rebind itself to report.
+fn check_port_free(port: u16) -> io::Result<()> {
+    let addrs = [SocketAddr::from((Ipv4Addr::unspecified(), port)), SocketAddr::from((Ipv6Addr::unspecified(), port))];
+    for addr in &addrs {
+        if let Err(e) = StdUdpSocket::bind(addr) {
+            if e.kind() == io::ErrorKind::AddrInUse {
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Only root and the user we're running as may configure the interface.
+fn is_authorized(uid: libc::uid_t, own_uid: libc::uid_t) -> bool {
+    uid == 0 || uid == own_uid
+}
+
+/// The UID and, where the OS says, PID of the process on the other end of the unix socket `fd`.
+#[cfg(target_os = "linux")]
+fn peer_credentials(fd: RawFd) -> io::Result<(libc::uid_t, Option<u32>)> {
+    let mut cred: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_PEERCRED, &mut cred as *mut _ as *mut libc::c_void, &mut len)
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok((cred.uid, Some(cred.pid as u32)))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peer_credentials(fd: RawFd) -> io::Result<(libc::uid_t, Option<u32>)> {
+    let (mut uid, mut gid) = (0, 0);
+    if unsafe { libc::getpeereid(fd, &mut uid, &mut gid) } == -1 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok((uid, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interface::Interface;
+    use peer::PeerConnectionState;
+    use std::net::IpAddr;
+    use x25519_dalek as x25519;
+
+    fn items(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|&(k, v)| (k.to_owned(), v.to_owned())).collect()
+    }
+
+    fn add_peer(state: &mut State, key: u8, allowed_ip: &str) {
+        let mut info = PeerInfo { pub_key: PublicKey([key; 32]), ..Default::default() };
+        info.allowed_ips.push((allowed_ip.parse().unwrap(), 32));
+        ConfigurationService::handle_update(state, &UpdateEvent::UpdatePeer(info, false)).unwrap();
+    }
+
+    fn routed_peer(state: &State, dest: [u8; 4]) -> Option<PublicKey> {
+        state.get_peer_for_ip(IpAddr::from(dest)).map(|peer| peer.lock().unwrap().info.pub_key)
+    }
+
+    #[test]
+    fn replace_allowed_ips() {
+        let mut state = State::default();
+        let mut info  = PeerInfo { pub_key: PublicKey([1u8; 32]), ..Default::default() };
+        info.allowed_ips.push(("10.0.0.1".parse().unwrap(), 32));
+        info.allowed_ips.push(("10.0.0.2".parse().unwrap(), 32));
+        ConfigurationService::handle_update(&mut state, &UpdateEvent::UpdatePeer(info.clone(), false)).unwrap();
+
+        // re-adding the same ips shouldn't duplicate them
+        ConfigurationService::handle_update(&mut state, &UpdateEvent::UpdatePeer(info.clone(), false)).unwrap();
+        assert_eq!(state.pubkey_map[&PublicKey([1u8; 32])].lock().unwrap().info.allowed_ips.len(), 2);
+
+        info.allowed_ips = vec![("10.0.0.3".parse().unwrap(), 32)];
+        ConfigurationService::handle_update(&mut state, &UpdateEvent::UpdatePeer(info, true)).unwrap();
+
+        assert_eq!(routed_peer(&state, [10, 0, 0, 1]), None);
+        assert_eq!(routed_peer(&state, [10, 0, 0, 2]), None);
+        assert_eq!(routed_peer(&state, [10, 0, 0, 3]), Some(PublicKey([1u8; 32])));
+        assert_eq!(state.pubkey_map[&PublicKey([1u8; 32])].lock().unwrap().info.allowed_ips.len(), 1);
+    }
+
+    #[test]
+    fn private_key_derives_public_key() {
+        let mut state   = State::default();
+        let private_key = [0x11u8; 32];
+        let public_key  = x25519::generate_public(&private_key);
+
+        ConfigurationService::handle_update(&mut state, &UpdateEvent::PrivateKey(PrivateKey(private_key))).unwrap();
+        assert_eq!(state.interface_info.pub_key, Some(PublicKey(*public_key.as_bytes())));
+
+        let config = ConfigurationService::get_config_string(&state, 1);
+        assert_eq!(config, format!("private_key={}\n", hex::encode(private_key)));
+
+        ConfigurationService::handle_update(&mut state, &UpdateEvent::PrivateKey(PrivateKey::default())).unwrap();
+        assert_eq!(state.interface_info.pub_key, None);
+        assert!(ConfigurationService::get_config_string(&state, 1).is_empty());
+    }
+
+    #[test]
+    fn listen_port_in_config() {
+        let mut state = State::default();
+        assert!(!ConfigurationService::get_config_string(&state, 1).contains("listen_port="));
+
+        let events = UpdateEvent::from(items(&[("listen_port", "51820")])).unwrap();
+        for event in &events {
+            ConfigurationService::handle_update(&mut state, event).unwrap();
+        }
+        assert_eq!(ConfigurationService::get_config_string(&state, 1), "listen_port=51820\n");
+    }
+
+    #[test]
+    fn udp_buffers_in_config() {
+        let mut state = State::default();
+        for event in &UpdateEvent::from(items(&[("udp_recv_buffer", "4194304"), ("udp_send_buffer", "1048576")])).unwrap() {
+            ConfigurationService::handle_update(&mut state, event).unwrap();
+        }
+        assert_eq!(state.interface_info.udp_recv_buffer, Some(4194304));
+        assert_eq!(state.interface_info.udp_send_buffer, Some(1048576));
+        assert!(ConfigurationService::get_config_string(&state, 1).is_empty());
+
+        // the response reports what the kernel granted, not what was asked for.
+        state.interface_info.udp_buffer_sizes = Some((425984, 2097152));
+        assert_eq!(ConfigurationService::get_config_string(&state, 1), "udp_recv_buffer=425984\nudp_send_buffer=2097152\n");
+    }
+
+    #[test]
+    fn mirrors_in_config() {
+        let mut state = State::default();
+        for event in &UpdateEvent::from(items(&[("mirror_decrypted", "127.0.0.1:9000"), ("mirror_plaintext_out", "[::1]:9001")])).unwrap() {
+            ConfigurationService::handle_update(&mut state, event).unwrap();
+        }
+        let _ = state.mirror_send_drops.fetch_add(4, Ordering::Relaxed);
+        assert_eq!(ConfigurationService::get_config_string(&state, 1),
+                   "mirror_decrypted=127.0.0.1:9000\nmirror_plaintext_out=[::1]:9001\nmirror_send_drops=4\n");
+
+        for event in &UpdateEvent::from(items(&[("mirror_decrypted", "")])).unwrap() {
+            ConfigurationService::handle_update(&mut state, event).unwrap();
+        }
+        assert_eq!(state.interface_info.mirror_decrypted, None);
+        assert_eq!(state.interface_info.mirror_plaintext_out, Some("[::1]:9001".parse().unwrap()));
+        assert!(UpdateEvent::from(items(&[("mirror_decrypted", "monitor:9000")])).is_err());
+    }
+
+    #[test]
+    fn auto_routes_in_config() {
+        let mut state = State::default();
+        for event in &UpdateEvent::from(items(&[("auto_routes", "true"), ("routing_table", "1000")])).unwrap() {
+            ConfigurationService::handle_update(&mut state, event).unwrap();
+        }
+        assert_eq!(ConfigurationService::get_config_string(&state, 1), "auto_routes=true\nrouting_table=1000\n");
+
+        for event in &UpdateEvent::from(items(&[("auto_routes", "false")])).unwrap() {
+            ConfigurationService::handle_update(&mut state, event).unwrap();
+        }
+        assert_eq!(ConfigurationService::get_config_string(&state, 1), "routing_table=1000\n");
+    }
+
+    #[test]
+    fn replay_window_size_in_config() {
+        let mut state = State::default();
+        assert!(UpdateEvent::from(items(&[("replay_window_size", "100")])).is_err());
+        assert_eq!(set_reply(&mut state, b"set=1\nreplay_window_size=16384\n\n"), format!("errno={}\n\n", libc::EINVAL));
+        add_peer(&mut state, 1, "10.0.0.1");
+
+        for event in &UpdateEvent::from(items(&[("replay_window_size", "512")])).unwrap() {
+            ConfigurationService::handle_update(&mut state, event).unwrap();
+        }
+        assert_eq!(state.interface_info.replay_window_size, 512);
+        assert_eq!(state.pubkey_map[&PublicKey([1u8; 32])].lock().unwrap().replay_window_size, 512);
+        assert!(ConfigurationService::get_config_string(&state, 1).contains("replay_window_size=512\n"));
+
+        add_peer(&mut state, 2, "10.0.0.2");
+        assert_eq!(state.pubkey_map[&PublicKey([2u8; 32])].lock().unwrap().replay_window_size, 512);
+    }
+
+    #[test]
+    fn timers_in_config() {
+        let mut state = State::default();
+        add_peer(&mut state, 1, "10.0.0.1");
+
+        // checked together, so a short reject_after_time can come before the rekey_after_time it needs
+        let message = b"set=1\nreject_after_time_ms=400\nrekey_after_time_ms=100\nkeepalive_timeout_ms=100\nrekey_timeout_ms=100\n\n";
+        assert_eq!(set_reply(&mut state, message), "errno=0\n\n");
+        assert_eq!(state.interface_info.timers.reject_after_time, Duration::from_millis(400));
+        assert_eq!(state.interface_info.timers.rekey_attempt_time, TimerConfig::default().rekey_attempt_time);
+        assert_eq!(state.pubkey_map[&PublicKey([1u8; 32])].lock().unwrap().timer_config, state.interface_info.timers);
+        let config = ConfigurationService::get_config_string(&state, 1);
+        assert!(config.contains("rekey_after_time_ms=100\nreject_after_time_ms=400\nrekey_timeout_ms=100\nkeepalive_timeout_ms=100\n"));
+        assert!(!config.contains("rekey_attempt_time_ms"));
+
+        add_peer(&mut state, 2, "10.0.0.2");
+        assert_eq!(state.pubkey_map[&PublicKey([2u8; 32])].lock().unwrap().timer_config, state.interface_info.timers);
+
+        let rejected = format!("errno={}\n\n", libc::EINVAL);
+        assert_eq!(set_reply(&mut state, b"set=1\nrekey_after_time_ms=400\n\n"), rejected);
+        assert_eq!(set_reply(&mut state, b"set=1\nrekey_timeout_ms=0\n\n"), rejected);
+        assert_eq!(set_reply(&mut state, b"set=1\nrekey_timeout_ms=soon\n\n"), format!("errno={}\n\n", libc::EINVAL));
+        assert_eq!(state.interface_info.timers.rekey_after_time, Duration::from_millis(100));
+    }
+
+    #[test]
+    fn peer_added_event() {
+        let interface = Interface::new("wgtest0".parse().unwrap());
+        let events    = interface.events();
+
+        let mut buf = BytesMut::from(format!("set=1\npublic_key={}\nallowed_ip=10.0.0.1/32\n\n", hex::encode(&[1u8; 32])));
+        match ConfigurationCodec.decode(&mut buf).unwrap() {
+            Some(Command::Set(_, updates)) => for update in &updates {
+                ConfigurationService::handle_update(&mut interface.state.write().unwrap(), update).unwrap();
+            },
+            other => panic!("expected a set command, got {:?}", other),
+        }
+        drop(interface);
+
+        // events are delivered as the update is applied, so the stream ends right after them.
+        let events = events.wait().collect::<Result<Vec<_>, _>>().unwrap();
+        match events.as_slice() {
+            &[InterfaceEvent::PeerAdded(ref info)] => {
+                assert_eq!(info.pub_key, PublicKey([1u8; 32]));
+                assert_eq!(info.allowed_ips, vec![("10.0.0.1".parse().unwrap(), 32)]);
+            },
+            other => panic!("expected a single PeerAdded, got {:?}", other),
+        }
+    }
+
+    fn decode(message: &[u8]) -> Result<Option<Command>, Error> {
+        ConfigurationCodec.decode(&mut BytesMut::from(message))
+    }
+
+    fn is_invalid_data(result: Result<Option<Command>, Error>) -> bool {
+        match result {
+            Err(e) => e.downcast_ref::<io::Error>().map_or(false, |e| e.kind() == io::ErrorKind::InvalidData),
+            Ok(_)  => false,
+        }
+    }
+
+    #[test]
+    fn malformed_messages() {
+        assert!(is_invalid_data(decode(b"\n\n")));
+        assert!(is_invalid_data(decode(b"get\n\n")));
+        assert!(is_invalid_data(decode(b"set=1\nprivate_key\n\n")));
+        assert!(is_invalid_data(decode(b"get=1\xff\n\n")));
+        assert!(decode(b"get=1\n").unwrap().is_none());
+    }
+
+    #[test]
+    fn message_size_limit() {
+        let message = |len: usize| {
+            let mut message = b"set=1\npadding=".to_vec();
+            let padding     = len - message.len() - 2;
+            message.extend(vec![b'a'; padding]);
+            message.extend(b"\n\n");
+            message
+        };
+        assert!(decode(&message(MAX_CONFIG_MESSAGE_SIZE)).unwrap().is_some());
+        assert!(is_invalid_data(decode(&message(MAX_CONFIG_MESSAGE_SIZE + 1))));
+        assert!(is_invalid_data(decode(&vec![b'a'; MAX_CONFIG_MESSAGE_SIZE + 1])));
+    }
+
+    #[test]
+    fn pipelined_messages() {
+        let mut buf = BytesMut::from(&b"get=1\n\nget=1\n\n"[..]);
+        for _ in 0..2 {
+            match ConfigurationCodec.decode(&mut buf).unwrap() {
+                Some(Command::Get(1)) => {},
+                other                 => panic!("expected get=1, got {:?}", other),
+            }
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn config_socket_authorization() {
+        let (ours, _theirs) = ::std::os::unix::net::UnixStream::pair().unwrap();
+        let own_uid         = unsafe { libc::getuid() };
+        assert_eq!(peer_credentials(ours.as_raw_fd()).unwrap().0, own_uid);
+        assert!(is_authorized(own_uid, own_uid));
+        assert!(is_authorized(0, own_uid));
+        assert!(!is_authorized(own_uid + 1, own_uid));
+    }
+
+    #[test]
+    fn drop_counters_in_config() {
+        let mut state = State::default();
+        let _ = state.drop_counters.insert(DropReason::ReplayAttack, 3);
+        let _ = state.drop_counters.insert(DropReason::RateLimited, 0);
+        assert_eq!(ConfigurationService::get_config_string(&state, 1), "drop_reason_replay_attack=3\n");
+    }
+
+    #[test]
+    fn config_protocol_versions() {
+        let mut state = State::default();
+        let _ = state.drop_counters.insert(DropReason::NoMatchingPeer, 2);
+        add_peer(&mut state, 1, "10.0.0.1");
+
+        let v1 = ConfigurationService::get_config_string(&state, 1);
+        assert!(v1.starts_with("drop_reason_no_matching_peer=2\npublic_key="));
+        assert!(!v1.contains("drop_reason_replay="));
+
+        let v2 = ConfigurationService::get_config_string(&state, 2);
+        assert!(v2.starts_with("drop_reason_no_matching_peer=2\ndrop_reason_replay=0\ndrop_reason_no_peer=2\nutun_queue_depth=0\nconfig_queue_depth=0\npublic_key="));
+        assert!(v2.ends_with(&v1[v1.find("public_key=").unwrap()..]));
+    }
+
+    #[test]
+    fn unsupported_protocol_version() {
+        let mut state = State::default();
+        let (tx, _rx) = mpsc::unbounded();
+        for command in vec![Command::Get(0), Command::Get(3), Command::Set(3, vec![UpdateEvent::ListenPort(51820)])] {
+            assert_eq!(ConfigurationService::handle_command(&mut state, &tx, command, None), "errno=93\n\n");
+        }
+        assert_eq!(state.interface_info.listen_port, None);
+        assert!(ConfigurationService::handle_command(&mut state, &tx, Command::Get(2), None).ends_with("errno=0\n\n"));
+    }
+
+    #[test]
+    fn listen_port_in_use() {
+        let mut state = State::default();
+        let (tx, _rx) = mpsc::unbounded();
+        let taken     = StdUdpSocket::bind("0.0.0.0:0").unwrap();
+        let port      = taken.local_addr().unwrap().port();
+
+        let reply = ConfigurationService::handle_command(&mut state, &tx, Command::Set(1, vec![UpdateEvent::ListenPort(port)]), None);
+        assert_eq!(reply, format!("errno={}\n\n", libc::EADDRINUSE));
+        assert_eq!(state.interface_info.listen_port, None);
+
+        drop(taken);
+        let reply = ConfigurationService::handle_command(&mut state, &tx, Command::Set(1, vec![UpdateEvent::ListenPort(port)]), None);
+        assert_eq!(reply, "errno=0\n\n");
+        assert_eq!(state.interface_info.listen_port, Some(port));
+    }
+
+    fn set_reply(state: &mut State, message: &[u8]) -> String {
+        let (tx, _rx) = mpsc::unbounded();
+        let command   = decode(message).unwrap().unwrap();
+        ConfigurationService::handle_command(state, &tx, command, None)
+    }
+
+    #[test]
+    fn keys_in_hex_or_base64() {
+        let key = [0x11u8; 32];
+        assert_eq!(parse_key(&hex::encode(key)).unwrap(), key);
+        assert_eq!(parse_key(&base64::encode(&key)).unwrap(), key);
+        assert!(parse_key(&hex::encode([0x11u8; 31])).is_err());
+        assert!(parse_key(&base64::encode(&[0x11u8; 31])).is_err());
+
+        // whichever way a key comes in, `get` hands it back in hex like `wg` expects
+        let mut state = State::default();
+        let message   = format!("set=1\nprivate_key={}\npublic_key={}\n\n", base64::encode(&key), hex::encode([0x22u8; 32]));
+        assert_eq!(set_reply(&mut state, message.as_bytes()), "errno=0\n\n");
+        let config = ConfigurationService::get_config_string(&state, 1);
+        assert!(config.contains(&format!("private_key={}\n", hex::encode(key))));
+        assert!(config.contains(&format!("public_key={}\n", hex::encode([0x22u8; 32]))));
+    }
+
+    #[test]
+    fn set_errnos() {
+        let mut state = State::default();
+        let einval    = format!("errno={}\n\n", libc::EINVAL);
+        assert_eq!(set_reply(&mut state, b"set=1\nprivate_key=not-a-key\n\n"), einval);
+        assert_eq!(set_reply(&mut state, b"set=1\nprivate_key=\n\n"), einval);
+        assert_eq!(set_reply(&mut state, b"set=1\nlisten_port=\n\n"), einval);
+        assert_eq!(set_reply(&mut state, b"set=1\npublic_key=0101\n\n"), einval);
+        assert_eq!(set_reply(&mut state, b"set=1\nno_such_key=1\n\n"), einval);
+        assert!(state.interface_info.private_key.is_none());
+        assert!(state.pubkey_map.is_empty());
+
+        assert_eq!(errno_for(&SetError::TooManyPeers.into()), libc::ENOMEM);
+        assert_eq!(errno_for(&io::Error::new(io::ErrorKind::BrokenPipe, "peer server hung up").into()), libc::EIO);
+        assert_eq!(errno_for(&io::Error::from_raw_os_error(libc::EADDRINUSE).into()), libc::EADDRINUSE);
+    }
+
+    #[test]
+    fn protocol_version_and_update_only() {
+        let mut state = State::default();
+        let peer      = hex::encode([0x22u8; 32]);
+        assert_eq!(set_reply(&mut state, b"set=1\nprotocol_version=1\npreserve_dscp=true\n\n"), "errno=0\n\n");
+        assert_eq!(set_reply(&mut state, b"set=1\nprotocol_version=2\n\n"), format!("errno={}\n\n", libc::EPROTONOSUPPORT));
+
+        let update = format!("set=1\npublic_key={}\nupdate_only=true\npersistent_keepalive_interval=25\n\n", peer);
+        assert_eq!(set_reply(&mut state, update.as_bytes()), "errno=0\n\n");
+        assert!(state.pubkey_map.is_empty());
+
+        let add = format!("set=1\npublic_key={}\n\n", peer);
+        assert_eq!(set_reply(&mut state, add.as_bytes()), "errno=0\n\n");
+        assert_eq!(set_reply(&mut state, update.as_bytes()), "errno=0\n\n");
+        assert_eq!(state.pubkey_map[&PublicKey([0x22u8; 32])].lock().unwrap().info.keepalive, Some(25));
+    }
+
+    #[test]
+    fn audit_log_requests() {
+        let mut state = State::default();
+        let (tx, _rx) = mpsc::unbounded();
+        let updates   = UpdateEvent::from(items(&[("listen_port", "51820"), ("public_key", hex::encode([1u8; 32]).as_str())])).unwrap();
+        assert_eq!(ConfigurationService::handle_command(&mut state, &tx, Command::Set(1, updates), Some(7)), "errno=0\n\n");
+
+        let command = decode(b"get_audit=2\ncount=1\n\n").unwrap().unwrap();
+        let reply   = ConfigurationService::handle_command(&mut state, &tx, command, None);
+        assert!(reply.starts_with("audit_log=[{"));
+        assert!(reply.contains(r#""event":"peer_added""#));
+        assert!(!reply.contains(r#""event":"listen_port_changed""#));
+        assert!(reply.ends_with("\"client_pid\":7}]\nerrno=0\n\n"));
+
+        match decode(b"get_audit=2\n\n").unwrap() {
+            Some(Command::GetAudit(2, MAX_AUDIT_ENTRIES)) => {},
+            other                                          => panic!("expected get_audit=2, got {:?}", other),
+        }
+        assert_eq!(ConfigurationService::handle_command(&mut state, &tx, Command::GetAudit(1, 10), None), "errno=93\n\n");
+    }
+
+    #[test]
+    fn session_history_requests() {
+        let mut state = State::default();
+        let (tx, _rx) = mpsc::unbounded();
+        let command   = decode(b"get_sessions=2\n\n").unwrap().unwrap();
+        assert_eq!(ConfigurationService::handle_command(&mut state, &tx, command, None), "sessions=[]\nerrno=0\n\n");
+        assert_eq!(ConfigurationService::handle_command(&mut state, &tx, Command::GetSessions(1), None), "errno=93\n\n");
+    }
+
+    #[test]
+    fn fwmark_in_config() {
+        let mut state = State::default();
+        for value in &["51820", "0"] {
+            for event in &UpdateEvent::from(items(&[("fwmark", value)])).unwrap() {
+                ConfigurationService::handle_update(&mut state, event).unwrap();
+            }
+            let expected = if *value == "0" { String::new() } else { format!("fwmark={}\n", value) };
+            assert_eq!(ConfigurationService::get_config_string(&state, 1), expected);
+        }
+    }
+
+    #[test]
+    fn peer_config_round_trip() {
+        let mut state = State::default();
+        let key       = hex::encode([1u8; 32]);
+        let events    = UpdateEvent::from(items(&[("public_key", &key),
+                                                  ("endpoint", "[fe80::1]:51820"),
+                                                  ("allowed_ip", "10.0.0.0/24"),
+                                                  ("allowed_ip", "fd00::/64")])).unwrap();
+        for event in &events {
+            ConfigurationService::handle_update(&mut state, event).unwrap();
+        }
+
+        let config = ConfigurationService::get_config_string(&state, 1);
+        let lines  = config.lines().collect::<Vec<_>>();
+        assert!(lines.contains(&&*format!("public_key={}", key)));
+        assert!(lines.contains(&"endpoint=[fe80::1]:51820"));
+        assert!(lines.contains(&"allowed_ip=10.0.0.0/24"));
+        assert!(lines.contains(&"allowed_ip=fd00::/64"));
+    }
+
+    #[test]
+    fn hostname_endpoint() {
+        let mut state = State::default();
+        let key       = hex::encode([1u8; 32]);
+        for event in &UpdateEvent::from(items(&[("public_key", &key), ("endpoint", "vpn.example.com:51820")])).unwrap() {
+            ConfigurationService::handle_update(&mut state, event).unwrap();
+        }
+        for event in &UpdateEvent::from(items(&[("public_key", &key), ("persistent_keepalive_interval", "25")])).unwrap() {
+            ConfigurationService::handle_update(&mut state, event).unwrap();
+        }
+        assert_eq!(state.pubkey_map[&PublicKey([1u8; 32])].lock().unwrap().info.endpoint_host, Some("vpn.example.com:51820".to_owned()));
+
+        // a literal address replaces the hostname.
+        for event in &UpdateEvent::from(items(&[("public_key", &key), ("endpoint", "192.0.2.1:51820")])).unwrap() {
+            ConfigurationService::handle_update(&mut state, event).unwrap();
+        }
+        assert_eq!(state.pubkey_map[&PublicKey([1u8; 32])].lock().unwrap().info.endpoint_host, None);
+
+        for endpoint in &["vpn.example.com", "vpn.example.com:0", "not a host:51820", ""] {
+            assert!(UpdateEvent::from(items(&[("public_key", &key), ("endpoint", endpoint)])).is_err());
+        }
+    }
+
+    #[test]
+    fn multiple_endpoints() {
+        let mut state = State::default();
+        let key       = hex::encode([1u8; 32]);
+        let events    = UpdateEvent::from(items(&[("public_key", &key),
+                                                  ("endpoint", "192.0.2.1:51820"),
+                                                  ("endpoint", "[2001:db8::1]:51820")])).unwrap();
+        for event in &events {
+            ConfigurationService::handle_update(&mut state, event).unwrap();
+        }
+
+        let peer_ref = state.pubkey_map[&PublicKey([1u8; 32])].clone();
+        let mut peer = peer_ref.lock().unwrap();
+        assert_eq!(peer.info.endpoints.len(), 2);
+        assert!(peer.to_config_string().contains("endpoint=192.0.2.1:51820\n"));
+
+        assert!(peer.fail_over_endpoint());
+        assert!(peer.to_config_string().contains("endpoint=[2001:db8::1]:51820\n"));
+    }
+
+    #[test]
+    fn clear_psk() {
+        let mut state = State::default();
+        let key       = hex::encode([1u8; 32]);
+        for psk in &[hex::encode([7u8; 32]), hex::encode([0u8; 32])] {
+            for event in &UpdateEvent::from(items(&[("public_key", &key), ("preshared_key", psk)])).unwrap() {
+                ConfigurationService::handle_update(&mut state, event).unwrap();
+            }
+        }
+        assert_eq!(state.pubkey_map[&PublicKey([1u8; 32])].lock().unwrap().info.psk, None);
+    }
+
+    #[test]
+    fn parse_removals() {
+        let key    = hex::encode([1u8; 32]);
+        let events = UpdateEvent::from(items(&[("replace_peers", "true"),
+                                               ("public_key", &key), ("remove", "true"),
+                                               ("public_key", &key), ("remove", "false")])).unwrap();
+        match events.as_slice() {
+            [UpdateEvent::RemoveAllPeers, UpdateEvent::RemovePeer(removed), UpdateEvent::UpdatePeer(..)] => {
+                assert_eq!(removed, &PublicKey([1u8; 32]));
+            },
+            _ => panic!("unexpected events {:?}", events),
+        }
+
+        assert!(UpdateEvent::from(items(&[("replace_peers", "false")])).unwrap().is_empty());
+    }
+
+    #[test]
+    fn readding_peer_keeps_session() {
+        let mut state = State::default();
+        add_peer(&mut state, 1, "10.0.0.1");
+        let peer_ref = state.pubkey_map[&PublicKey([1u8; 32])].clone();
+        state.map_index(7, peer_ref.clone());
+        state.map_index(8, peer_ref.clone());
+
+        let info = PeerInfo { pub_key: PublicKey([1u8; 32]), keepalive: Some(25), ..Default::default() };
+        ConfigurationService::handle_update(&mut state, &UpdateEvent::UpdatePeer(info.clone(), false)).unwrap();
+        assert_eq!(state.pubkey_map.len(), 1);
+        assert_eq!(state.index_map.len(), 2);
+        assert!(state.index_map.values().all(|peer| Arc::ptr_eq(peer, &peer_ref)));
+        assert!(Arc::ptr_eq(&state.pubkey_map[&PublicKey([1u8; 32])], &peer_ref));
+        assert_eq!(peer_ref.lock().unwrap().info.keepalive, Some(25));
+        assert_eq!(routed_peer(&state, [10, 0, 0, 1]), Some(PublicKey([1u8; 32])));
+
+        // a fresh peer under the same key takes the old one's indices with it
+        state.add_peer(Arc::new(Mutex::new(Peer::new(info.clone()))), &info);
+        assert_eq!(state.pubkey_map.len(), 1);
+        assert!(state.index_map.is_empty());
+        assert_eq!(routed_peer(&state, [10, 0, 0, 1]), None);
+    }
+
+    #[test]
+    fn remove_peers() {
+        let mut state = State::default();
+        add_peer(&mut state, 1, "10.0.0.1");
+        add_peer(&mut state, 2, "10.0.0.1");
+        add_peer(&mut state, 3, "10.0.0.3");
+        assert_eq!(routed_peer(&state, [10, 0, 0, 1]), Some(PublicKey([2u8; 32])));
+        let states = state.pubkey_map[&PublicKey([1u8; 32])].lock().unwrap().subscribe();
+
+        // the route peer 2 took over from peer 1 stays with it
+        ConfigurationService::handle_update(&mut state, &UpdateEvent::RemovePeer(PublicKey([1u8; 32]))).unwrap();
+        assert_eq!(states.collect().wait().unwrap(), vec![PeerConnectionState::Idle, PeerConnectionState::Dead]);
+        assert!(!state.pubkey_map.contains_key(&PublicKey([1u8; 32])));
+        assert_eq!(state.pubkey_map.len(), 2);
+        assert_eq!(routed_peer(&state, [10, 0, 0, 1]), Some(PublicKey([2u8; 32])));
+        assert_eq!(routed_peer(&state, [10, 0, 0, 3]), Some(PublicKey([3u8; 32])));
+
+        // removing an unknown peer is not an error
+        ConfigurationService::handle_update(&mut state, &UpdateEvent::RemovePeer(PublicKey([1u8; 32]))).unwrap();
+
+        ConfigurationService::handle_update(&mut state, &UpdateEvent::RemoveAllPeers).unwrap();
+        assert!(state.pubkey_map.is_empty());
+        assert!(state.index_map.is_empty());
+    }
+}
@@ -0,0 +1,208 @@
+/* SPDX-License-Identifier: GPL-2.0
+ *
+ * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
+ */
+
+//! A bounded record of the sessions an interface has had: who with, for how long, and how
+//! much they carried.
+
+use consts::MAX_SESSION_HISTORY;
+use peer::{HandshakeRole, Peer, SessionCounters};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, atomic::Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use types::PublicKey;
+
+#[derive(Clone, Debug)]
+pub struct SessionRecord {
+    pub established   : SystemTime,
+    /// When the session was dropped, `None` while it's still in use.
+    pub expired       : Option<SystemTime>,
+    pub peer_pubkey   : [u8; 32],
+    pub endpoint      : SocketAddr,
+    pub role          : HandshakeRole,
+    /// Filled in once the session expires, see `bytes` for the counts so far.
+    pub bytes_sent    : u64,
+    pub bytes_recv    : u64,
+    pub session_index : u32,
+    /// The live session's counters, let go of once it expires.
+    counters          : Option<Arc<SessionCounters>>,
+}
+
+impl SessionRecord {
+    /// Bytes `(sent, received)` over the session, so far if it's still in use.
+    pub fn bytes(&self) -> (u64, u64) {
+        match self.counters {
+            Some(ref counters) => (counters.tx_bytes.load(Ordering::Relaxed), counters.rx_bytes.load(Ordering::Relaxed)),
+            None               => (self.bytes_sent, self.bytes_recv),
+        }
+    }
+
+    /// How long the session lasted, or has lasted so far.
+    pub fn lifetime(&self) -> Duration {
+        self.expired.unwrap_or_else(SystemTime::now).duration_since(self.established).unwrap_or_default()
+    }
+
+    fn expire(&mut self, now: SystemTime) {
+        if self.counters.is_some() {
+            let (sent, recv) = self.bytes();
+            self.bytes_sent  = sent;
+            self.bytes_recv  = recv;
+            self.expired     = Some(now);
+            self.counters    = None;
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let time = |time: &SystemTime| time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let (sent, recv) = self.bytes();
+        let established  = time(&self.established);
+        let expired      = match self.expired.as_ref().map(time) {
+            Some(expired) => format!(r#""expired_sec":{},"expired_nsec":{}"#, expired.as_secs(), expired.subsec_nanos()),
+            None          => r#""expired_sec":null,"expired_nsec":null"#.to_owned(),
+        };
+        let role = match self.role {
+            HandshakeRole::Initiator => "initiator",
+            HandshakeRole::Responder => "responder",
+        };
+        format!(r#"{{"public_key":"{}","session_index":{},"endpoint":"{}","role":"{}","established_sec":{},"established_nsec":{},{},"bytes_sent":{},"bytes_recv":{}}}"#,
+                PublicKey(self.peer_pubkey), self.session_index, self.endpoint, role,
+                established.as_secs(), established.subsec_nanos(), expired, sent, recv)
+    }
+}
+
+/// The most recent `MAX_SESSION_HISTORY` sessions, oldest first.
+#[derive(Debug, Default)]
+pub struct SessionHistory {
+    records: VecDeque<SessionRecord>,
+}
+
+impl SessionHistory {
+    /// Starts a record for `peer`'s current session, which has to be `index`.
+    pub fn established(&mut self, peer: &Peer, index: u32) {
+        let session = match peer.sessions.current {
+            Some(ref session) if session.our_index == index => session,
+            _ => return,
+        };
+        let endpoint = match peer.info.endpoint {
+            Some(endpoint) => *endpoint,
+            None           => return,
+        };
+        if self.records.len() >= MAX_SESSION_HISTORY {
+            let _ = self.records.pop_front();
+        }
+        self.records.push_back(SessionRecord {
+            established   : SystemTime::now(),
+            expired       : None,
+            peer_pubkey   : peer.info.pub_key.0,
+            endpoint,
+            role          : session.role,
+            bytes_sent    : 0,
+            bytes_recv    : 0,
+            session_index : index,
+            counters      : Some(session.counters.clone()),
+        });
+    }
+
+    /// Closes the record of session `index`, if there's one still open.
+    pub fn expired(&mut self, index: u32) {
+        self.expired_at(index, SystemTime::now())
+    }
+
+    fn expired_at(&mut self, index: u32, now: SystemTime) {
+        if let Some(record) = self.records.iter_mut().rev().find(|record| record.session_index == index && record.expired.is_none()) {
+            record.expire(now);
+        }
+    }
+
+    pub fn expire_all(&mut self) {
+        let now = SystemTime::now();
+        for record in &mut self.records {
+            record.expire(now);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &SessionRecord> {
+        self.records.iter()
+    }
+
+    /// Every record as a JSON array, oldest first.
+    pub fn to_json(&self) -> String {
+        let records = self.records.iter().map(SessionRecord::to_json).collect::<Vec<_>>();
+        format!("[{}]", records.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(index: u32) -> SessionRecord {
+        SessionRecord {
+            established   : UNIX_EPOCH + Duration::new(100, 5),
+            expired       : None,
+            peer_pubkey   : [0; 32],
+            endpoint      : "192.0.2.1:51820".parse().unwrap(),
+            role          : HandshakeRole::Responder,
+            bytes_sent    : 0,
+            bytes_recv    : 0,
+            session_index : index,
+            counters      : Some(Default::default()),
+        }
+    }
+
+    #[test]
+    fn closing_records() {
+        let mut history = SessionHistory::default();
+        history.records.push_back(record(1));
+        history.records.push_back(record(2));
+        let _ = history.records[0].counters.as_ref().unwrap().tx_bytes.fetch_add(60, Ordering::Relaxed);
+        assert_eq!(history.records[0].bytes(), (60, 0));
+
+        history.expired(1);
+        history.expired(3);
+        assert!(history.records[0].expired.is_some());
+        assert!(history.records[1].expired.is_none());
+        assert_eq!((history.records[0].bytes_sent, history.records[0].bytes_recv), (60, 0));
+
+        // totals are frozen once the session's gone
+        let expired = history.records[0].expired;
+        history.expired(1);
+        history.expire_all();
+        assert_eq!(history.records[0].expired, expired);
+        assert!(history.records[1].expired.is_some());
+    }
+
+    #[test]
+    fn lifetimes() {
+        let mut history = SessionHistory::default();
+        history.records.push_back(record(1));
+        history.records.push_back(record(2));
+        let established = history.records[0].established;
+
+        history.expired_at(1, established + Duration::from_millis(150));
+        history.expired_at(2, established + Duration::from_millis(50));
+        assert_eq!(history.records[0].lifetime(), Duration::from_millis(150));
+        assert_eq!(history.records[1].lifetime(), Duration::from_millis(50));
+
+        // a clock that went backwards doesn't make for a negative lifetime.
+        history.records.push_back(record(3));
+        history.expired_at(3, established - Duration::from_secs(1));
+        assert_eq!(history.records[2].lifetime(), Duration::default());
+    }
+
+    #[test]
+    fn json_records() {
+        let mut history = SessionHistory::default();
+        assert_eq!(history.to_json(), "[]");
+
+        history.records.push_back(record(7));
+        assert_eq!(history.to_json(), concat!(r#"[{"public_key":"AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=","session_index":7,"#,
+                                              r#""endpoint":"192.0.2.1:51820","role":"responder","established_sec":100,"established_nsec":5,"#,
+                                              r#""expired_sec":null,"expired_nsec":null,"bytes_sent":0,"bytes_recv":0}]"#));
+        history.expire_all();
+        assert!(history.to_json().contains(r#""expired_sec":"#));
+        assert!(!history.to_json().contains("null"));
+    }
+}
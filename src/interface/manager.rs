@@ -0,0 +1,88 @@
+/* SPDX-License-Identifier: GPL-2.0
+ *
+ * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
+ */
+
+//! Any number of interfaces running side by side on one reactor, each with state of its own.
+
+use config_file::InterfaceConfig;
+use error::InterfaceError;
+use failure::{Error, err_msg};
+use futures::{Future, Stream, unsync::mpsc};
+use interface::{Interface, ShutdownHandle, reload};
+use std::cell::RefCell;
+use tokio_core::reactor::Core;
+use types::InterfaceName;
+
+/// Runs interfaces as tasks on a reactor it owns, so a gateway managing dozens of them needs
+/// one thread rather than one per interface.
+pub struct InterfaceManager {
+    core       : Core,
+    /// Kept until they've stopped, as dropping an interface's last `ShutdownHandle` stops it.
+    interfaces : RefCell<Vec<Interface>>,
+    stopped_tx : mpsc::UnboundedSender<()>,
+    stopped_rx : mpsc::UnboundedReceiver<()>,
+}
+
+/// An interface spawned by an `InterfaceManager`. It can be stopped from any thread.
+#[derive(Clone)]
+pub struct InterfaceHandle {
+    name     : InterfaceName,
+    shutdown : ShutdownHandle,
+}
+
+impl InterfaceHandle {
+    /// The name the OS gave the tunnel device.
+    pub fn name(&self) -> &InterfaceName {
+        &self.name
+    }
+
+    pub fn shutdown(&self) {
+        self.shutdown.shutdown();
+    }
+}
+
+impl InterfaceManager {
+    pub fn new() -> Result<Self, InterfaceError> {
+        let core                     = Core::new().map_err(InterfaceError::Reactor)?;
+        let (stopped_tx, stopped_rx) = mpsc::unbounded();
+        Ok(InterfaceManager { core, interfaces: RefCell::new(vec![]), stopped_tx, stopped_rx })
+    }
+
+    /// Sets up interface `name` with `config` and spawns it onto the reactor, where it runs
+    /// once `run` is called.
+    pub fn spawn_interface(&self, name: &str, config: InterfaceConfig) -> Result<InterfaceHandle, Error> {
+        let mut interface = Interface::new(name.parse()?);
+        interface.apply_diff(reload::diff(&InterfaceConfig::default(), &config))?;
+
+        let stopped_tx = self.stopped_tx.clone();
+        let fut        = interface.start_on(&self.core.handle())?
+            .then(move |_| stopped_tx.unbounded_send(()).map_err(|_| ()));
+        self.core.handle().spawn(fut);
+
+        let handle = InterfaceHandle { name: interface.name.clone(), shutdown: interface.shutdown_handle() };
+        self.interfaces.borrow_mut().push(interface);
+        Ok(handle)
+    }
+
+    /// Runs the reactor until every interface spawned so far has been shut down.
+    pub fn run(&mut self) -> Result<(), Error> {
+        let running = self.interfaces.borrow().len() as u64;
+        let stopped = self.stopped_rx.by_ref().take(running).for_each(|()| Ok(()));
+        self.core.run(stopped).map_err(|()| err_msg("interface manager channel closed"))?;
+        self.interfaces.borrow_mut().clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_nothing() {
+        let mut manager = InterfaceManager::new().unwrap();
+        assert!(manager.spawn_interface("0bad", InterfaceConfig::default()).is_err());
+        manager.run().unwrap();
+    }
+}
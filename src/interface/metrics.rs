@@ -0,0 +1,156 @@
+/* SPDX-License-Identifier: GPL-2.0
+ *
+ * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
+ */
+
+//! Prometheus exporter for interface and peer statistics, built with the `metrics` feature.
+
+use std::net::SocketAddr;
+use std::time::UNIX_EPOCH;
+
+use failure::Error;
+use futures::{Future, Stream, future::{self, FutureResult}};
+use hyper::{self, Method, StatusCode, header::ContentType};
+use hyper::server::{Http, Request, Response, Service};
+use interface::SharedState;
+use prometheus::{self, CounterVec, Encoder, GaugeVec, Opts, Registry, TextEncoder};
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Handle;
+
+pub struct MetricsServer {
+    addr      : SocketAddr,
+    state     : SharedState,
+    interface : String,
+}
+
+impl MetricsServer {
+    pub fn new(addr: SocketAddr, state: SharedState, interface: &str) -> Self {
+        MetricsServer { addr, state, interface: interface.to_owned() }
+    }
+
+    /// Spawns the HTTP listener onto the reactor behind `handle`; scrapes are served from `/metrics`.
+    pub fn serve(self, handle: &Handle) -> Result<(), Error> {
+        handle.spawn(self.listen(handle)?);
+        Ok(())
+    }
+
+    /// The listener `serve` spawns, for callers that want to stop it again by dropping it.
+    pub(crate) fn listen(self, handle: &Handle) -> Result<impl Future<Item = (), Error = ()>, Error> {
+        let listener = TcpListener::bind(&self.addr, handle)?;
+        let http     = Http::<hyper::Chunk>::new();
+        let service  = MetricsService { state: self.state, interface: self.interface };
+        info!("serving metrics on http://{}/metrics", self.addr);
+
+        let conn_handle = handle.clone();
+        let server = listener.incoming()
+            .for_each(move |(socket, addr)| {
+                http.bind_connection(&conn_handle, socket, addr, service.clone());
+                Ok(())
+            })
+            .map_err(|e| warn!("metrics listener error: {}", e));
+        Ok(server)
+    }
+}
+
+#[derive(Clone)]
+struct MetricsService {
+    state     : SharedState,
+    interface : String,
+}
+
+impl MetricsService {
+    /// Builds a fresh registry for every scrape, as all values are snapshots of the peer state.
+    fn gather(&self) -> Result<Registry, prometheus::Error> {
+        let registry   = Registry::new();
+        let peer_label = &["interface", "peer"];
+
+        let sent       = CounterVec::new(Opts::new("wireguard_sent_bytes_total", "Bytes sent to the peer."), peer_label)?;
+        let received   = CounterVec::new(Opts::new("wireguard_received_bytes_total", "Bytes received from the peer."), peer_label)?;
+        let drops      = CounterVec::new(Opts::new("wireguard_anti_replay_drops_total", "Packets dropped by the replay filter."), peer_label)?;
+        let handshake  = GaugeVec::new(Opts::new("wireguard_latest_handshake_seconds", "UNIX time of the latest handshake."), peer_label)?;
+        let latency    = GaugeVec::new(Opts::new("wireguard_keepalive_latency_seconds", "Round trip of the latest timestamped keepalive and its echo."), peer_label)?;
+        let peers      = GaugeVec::new(Opts::new("wireguard_peers_total", "Number of configured peers."), &["interface"])?;
+
+        let state = self.state.read().unwrap();
+        for peer in state.iter_peers() {
+            let peer   = peer.lock().unwrap();
+            let key    = peer.info.pub_key.to_string();
+            let labels = &[self.interface.as_str(), key.as_str()];
+
+            sent.with_label_values(labels).inc_by(peer.tx_bytes as f64);
+            received.with_label_values(labels).inc_by(peer.rx_bytes as f64);
+            drops.with_label_values(labels).inc_by(peer.anti_replay_drops as f64);
+            let last_handshake = peer.last_handshake_time()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |time| time.as_secs());
+            handshake.with_label_values(labels).set(last_handshake as f64);
+            if let Some(rtt) = peer.last_rtt {
+                latency.with_label_values(labels).set(rtt.as_secs() as f64 + f64::from(rtt.subsec_nanos()) / 1e9);
+            }
+        }
+        peers.with_label_values(&[self.interface.as_str()]).set(state.peer_count() as f64);
+
+        registry.register(Box::new(sent))?;
+        registry.register(Box::new(received))?;
+        registry.register(Box::new(drops))?;
+        registry.register(Box::new(handshake))?;
+        registry.register(Box::new(latency))?;
+        registry.register(Box::new(peers))?;
+        Ok(registry)
+    }
+
+    fn render(&self) -> Result<Vec<u8>, prometheus::Error> {
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.gather()?.gather(), &mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+impl Service for MetricsService {
+    type Request  = Request;
+    type Response = Response;
+    type Error    = hyper::Error;
+    type Future   = FutureResult<Response, hyper::Error>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let response = match (req.method(), req.path()) {
+            (&Method::Get, "/metrics") => match self.render() {
+                Ok(body) => Response::new()
+                    .with_header(ContentType(TextEncoder::new().format_type().parse().unwrap()))
+                    .with_body(body),
+                Err(e)   => {
+                    warn!("failed to render metrics: {}", e);
+                    Response::new().with_status(StatusCode::InternalServerError)
+                },
+            },
+            _ => Response::new().with_status(StatusCode::NotFound),
+        };
+        future::ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interface::State;
+    use peer::Peer;
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::time::Duration;
+    use types::{PeerInfo, PublicKey};
+
+    #[test]
+    fn peer_metrics() {
+        let mut state = State::default();
+        let mut peer  = Peer::new(PeerInfo { pub_key: PublicKey([0xab; 32]), ..Default::default() });
+        peer.tx_bytes = 1234;
+        peer.last_rtt = Some(Duration::from_millis(250));
+        let _ = state.pubkey_map.insert(PublicKey([0xab; 32]), Arc::new(Mutex::new(peer)));
+
+        let service = MetricsService { state: Arc::new(RwLock::new(state)), interface: "wg0".to_owned() };
+        let output  = String::from_utf8(service.render().unwrap()).unwrap();
+        assert!(output.contains("wireguard_sent_bytes_total{interface=\"wg0\",peer=\"Q6urq6urq6urq6urq6urq6urq6urq6urq6urq6urq6s=\"} 1234"));
+        assert!(output.contains("wireguard_peers_total{interface=\"wg0\"} 1"));
+        assert!(output.contains("wireguard_latest_handshake_seconds{interface=\"wg0\",peer=\"Q6urq6urq6urq6urq6urq6urq6urq6urq6urq6urq6s=\"} 0"));
+        assert!(output.contains("wireguard_keepalive_latency_seconds{interface=\"wg0\",peer=\"Q6urq6urq6urq6urq6urq6urq6urq6urq6urq6urq6s=\"} 0.25"));
+    }
+}
@@ -0,0 +1,64 @@
+/* SPDX-License-Identifier: GPL-2.0
+ *
+ * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
+ */
+
+//! Copies of tunnel plaintext for passive monitoring, sent as plain UDP datagrams.
+
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+
+/// Non-blocking sockets for mirroring packets, bound the first time they're needed.
+///
+/// Mirroring is best-effort: anything that can't be sent right away is dropped rather than
+/// holding up the packet it's a copy of.
+#[derive(Default)]
+pub struct Mirror {
+    v4: Option<UdpSocket>,
+    v6: Option<UdpSocket>,
+}
+
+impl Mirror {
+    /// Sends `packet` to `addr`, returning whether it went out.
+    pub fn send(&mut self, packet: &[u8], addr: SocketAddr) -> bool {
+        match self.socket(addr).and_then(|socket| socket.send_to(packet, addr)) {
+            Ok(sent) => sent == packet.len(),
+            Err(e)   => {
+                trace!("dropped mirrored packet to {} ({})", addr, e);
+                false
+            },
+        }
+    }
+
+    fn socket(&mut self, addr: SocketAddr) -> io::Result<&UdpSocket> {
+        let (slot, local) = match addr {
+            SocketAddr::V4(_) => (&mut self.v4, SocketAddr::from((Ipv4Addr::unspecified(), 0))),
+            SocketAddr::V6(_) => (&mut self.v6, SocketAddr::from((Ipv6Addr::unspecified(), 0))),
+        };
+        if slot.is_none() {
+            let socket = UdpSocket::bind(local)?;
+            socket.set_nonblocking(true)?;
+            *slot = Some(socket);
+        }
+        Ok(slot.as_ref().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mirrors_to_address() {
+        let monitor = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut mirror = Mirror::default();
+        assert!(mirror.send(&[0x45, 0, 0, 20], monitor.local_addr().unwrap()));
+        assert!(mirror.send(&[0x45, 0, 0, 21], monitor.local_addr().unwrap()));
+
+        let mut buf = [0u8; 16];
+        let (len, from) = monitor.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &[0x45, 0, 0, 20]);
+        assert_eq!(from.port(), mirror.v4.as_ref().unwrap().local_addr().unwrap().port());
+        assert!(mirror.v6.is_none());
+    }
+}
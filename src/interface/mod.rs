@@ -3,26 +3,67 @@
  * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
  */
 
+mod audit;
+mod builder;
 mod config;
 mod grim_reaper;
+mod history;
+mod manager;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod mirror;
 pub mod peer_server;
+pub mod reload;
+mod resolver;
+mod routes;
+#[cfg(feature = "rest-api")]
+mod rest;
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+mod tun;
 
+pub use self::builder::InterfaceBuilder;
+use self::audit::AuditLog;
+pub use self::history::SessionRecord;
+pub use self::manager::{InterfaceHandle, InterfaceManager};
+use self::history::SessionHistory;
+#[cfg(feature = "metrics")]
+pub use self::metrics::MetricsServer;
+#[cfg(feature = "rest-api")]
+pub use self::rest::RestServer;
 use self::config::ConfigurationService;
-use self::peer_server::PeerServer;
+#[cfg(feature = "fuzzing")]
+pub(crate) use self::config::ConfigurationCodec;
+use self::peer_server::{ChannelMessage, PeerServer};
+use self::reload::ConfigDiff;
+use self::routes::RouteInjector;
+use config_file;
+use consts::{ENDPOINT_RESOLVE_INTERVAL, INDEX_ALLOCATION_TRIES, INDEX_GC_INTERVAL, MAX_QUEUED_TUNNEL_PACKETS, QUEUE_DEPTH_WARNING};
+use error::{DropReason, InterfaceError};
+use noise;
 use router::Router;
 
 use failure::{Error, err_msg};
-use peer::Peer;
+use peer::{HandshakeRole, Peer, PeerConnectionState};
+use rand::{self, Rng};
+use snow::SnowError;
 use std::io;
-use std::rc::{Rc, Weak};
-use std::cell::RefCell;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock, Weak, atomic::{AtomicU64, AtomicUsize, Ordering}};
 use std::collections::HashMap;
-use types::{InterfaceInfo};
+use std::time::{Duration, Instant};
+use types::{InterfaceInfo, InterfaceName, PeerInfo, PublicKey};
+use udp::Endpoint;
+use zeroize::zeroize;
 
 use rips_packets::ipv4::Ipv4Packet;
 
-use futures::{Future, Stream, Sink, unsync};
-use tokio_core::reactor::Core;
+use futures::{Future, Stream, Sink, future::{self, Shared}, sync, unsync};
+use libc;
+use tokio_core::reactor::{Core, Handle};
+use tokio_signal::unix::Signal;
+use tokio_timer::Interval;
+#[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
 use tokio_utun::{UtunStream, UtunCodec};
 
 
@@ -31,23 +72,352 @@ pub fn trace_packet(header: &str, packet: &[u8]) {
     trace!("{} {:?}", header, packet);
 }
 
-pub type SharedPeer = Rc<RefCell<Peer>>;
-pub type WeakSharedPeer = Weak<RefCell<Peer>>;
-pub type SharedState = Rc<RefCell<State>>;
+pub type SharedPeer = Arc<Mutex<Peer>>;
+pub type WeakSharedPeer = Weak<Mutex<Peer>>;
+/// Where both are needed, the state lock is taken before any peer's lock, never the other
+/// way around.
+pub type SharedState = Arc<RwLock<State>>;
 
 #[derive(Default)]
 pub struct State {
-    pubkey_map: HashMap<[u8; 32], SharedPeer>,
+    pubkey_map: HashMap<PublicKey, SharedPeer>,
     index_map: HashMap<u32, SharedPeer>,
     router: Router,
     interface_info: InterfaceInfo,
+    drop_counters: HashMap<DropReason, u64>,
+    /// Mirrored packets that couldn't be sent. Atomic so the packet path can count them
+    /// without a write lock.
+    mirror_send_drops: AtomicU64,
+    /// Decrypted packets handed to the tun device's writer that it hasn't taken yet. Shared so
+    /// the writer can count packets out without the state lock.
+    utun_queue_depth: Arc<AtomicUsize>,
+    /// Configuration changes sent to the peer server that it hasn't picked up yet. Shared like
+    /// `utun_queue_depth`.
+    config_queue_depth: Arc<AtomicUsize>,
+    audit_log: AuditLog,
+    session_history: SessionHistory,
+    event_txs: Vec<sync::mpsc::UnboundedSender<InterfaceEvent>>,
+    /// Whether the interface is up and processing packets.
+    ready: bool,
+    ready_txs: Vec<sync::mpsc::UnboundedSender<bool>>,
+    session_established_hooks: Vec<SessionHook>,
+    session_expired_hooks: Vec<SessionHook>,
+    /// Set once the interface is up, as routes need its index.
+    routes: Option<RouteInjector>,
+}
+
+/// Configuration and session changes, as delivered to `Interface::events` subscribers.
+#[derive(Clone, Debug)]
+pub enum InterfaceEvent {
+    PeerAdded(PeerInfo),
+    PeerRemoved(PublicKey),
+    PeerEndpointChanged { peer: PublicKey, new: SocketAddr },
+    SessionEstablished { peer: PublicKey, index: u32 },
+    SessionExpired { peer: PublicKey },
+    PrivateKeyRotated,
+}
+
+/// A session coming up or going away, as passed to `Interface::on_session_established` and
+/// `Interface::on_session_expired` hooks.
+#[derive(Clone, Debug)]
+pub struct SessionEvent {
+    pub peer_pubkey    : [u8; 32],
+    pub endpoint       : SocketAddr,
+    pub established_at : Instant,
+    pub role           : HandshakeRole,
+}
+
+impl SessionEvent {
+    /// Describes `peer`'s current session, if it has one.
+    fn for_current_session(peer: &Peer) -> Option<SessionEvent> {
+        let session  = peer.sessions.current.as_ref()?;
+        let endpoint = peer.info.endpoint?;
+        Some(SessionEvent {
+            peer_pubkey    : peer.info.pub_key.0,
+            endpoint       : *endpoint,
+            established_at : *peer.timers.handshake_completed,
+            role           : session.role,
+        })
+    }
+}
+
+pub type SessionHook = Arc<Fn(SessionEvent) + Send + Sync>;
+
+impl State {
+    /// Tells subscribers and hooks that `peer` has just moved a new session, `index`, into use.
+    fn session_established(&mut self, peer: &Peer, index: u32) {
+        self.notify(InterfaceEvent::SessionEstablished { peer: peer.info.pub_key, index });
+        self.session_history.established(peer, index);
+        if let Some(event) = SessionEvent::for_current_session(peer) {
+            for hook in &self.session_established_hooks {
+                hook(event.clone());
+            }
+        }
+    }
+
+    /// Tells subscribers and hooks that `peer`'s current session has expired, whether it went
+    /// past REJECT-AFTER-TIME, is about to be wiped or its peer is being removed. Has to be
+    /// called while there's still a session to describe, and reports each one only once.
+    fn session_expired(&mut self, peer: &mut Peer) {
+        let unreported = peer.sessions.current.as_ref().map_or(false, |session| !session.expiry_reported);
+        if !unreported {
+            return;
+        }
+        if let Some(ref mut session) = peer.sessions.current {
+            session.expiry_reported = true;
+        }
+
+        self.notify(InterfaceEvent::SessionExpired { peer: peer.info.pub_key });
+        if let Some(event) = SessionEvent::for_current_session(peer) {
+            for hook in &self.session_expired_hooks {
+                hook(event.clone());
+            }
+        }
+    }
+
+    /// Queues a decrypted packet for the tun device, unless `MAX_QUEUED_TUNNEL_PACKETS` are
+    /// already waiting on it, in which case the packet is dropped as `ChannelFull`.
+    fn send_to_tunnel(&self, tx: &unsync::mpsc::UnboundedSender<Vec<u8>>, packet: Vec<u8>) -> Result<(), Error> {
+        if self.utun_queue_depth.load(Ordering::Relaxed) >= MAX_QUEUED_TUNNEL_PACKETS {
+            return Err(DropReason::ChannelFull.into());
+        }
+        tx.unbounded_send(packet)?;
+        let _ = note_queued(&self.utun_queue_depth, "tun device");
+        Ok(())
+    }
+
+    /// Hands `message` to the peer server, counting it in `config_queue_depth` until the peer
+    /// server picks it up.
+    pub fn send_to_peer_server(&self, tx: &unsync::mpsc::UnboundedSender<ChannelMessage>, message: ChannelMessage)
+        -> Result<(), unsync::mpsc::SendError<ChannelMessage>>
+    {
+        tx.unbounded_send(message)?;
+        let _ = note_queued(&self.config_queue_depth, "peer server");
+        Ok(())
+    }
+
+    /// Hands `event` to every subscriber, forgetting the ones that went away.
+    fn notify(&mut self, event: InterfaceEvent) {
+        self.event_txs.retain(|tx| tx.unbounded_send(event.clone()).is_ok());
+    }
+
+    /// Tells `Interface::ready_watch` subscribers about a change in readiness.
+    fn set_ready(&mut self, ready: bool) {
+        if self.ready == ready {
+            return;
+        }
+        self.ready = ready;
+        self.ready_txs.retain(|tx| tx.unbounded_send(ready).is_ok());
+    }
+
+    /// Routes `info`'s allowed IPs to the interface, if we're managing routes.
+    fn install_routes(&mut self, info: &PeerInfo) {
+        if self.interface_info.auto_routes {
+            if let Some(ref mut routes) = self.routes {
+                routes.install(info.pub_key, &info.allowed_ips, self.interface_info.routing_table);
+            }
+        }
+    }
+
+    /// Brings every peer's routes in line with its allowed IPs and the routing table.
+    fn reinstall_routes(&mut self) {
+        let peers = self.pubkey_map.values()
+            .map(|peer| peer.lock().unwrap().info.clone())
+            .collect::<Vec<_>>();
+        for info in &peers {
+            self.install_routes(info);
+        }
+    }
+
+    /// Tells subscribers if `peer` has moved away from the `previous` endpoint.
+    fn note_endpoint(&mut self, peer: &Peer, previous: Option<Endpoint>) {
+        if let Some(endpoint) = peer.info.endpoint {
+            if previous.map_or(true, |previous| *previous != *endpoint) {
+                self.notify(InterfaceEvent::PeerEndpointChanged { peer: peer.info.pub_key, new: *endpoint });
+            }
+        }
+    }
+
+    pub fn get_peer_by_pubkey(&self, key: &[u8; 32]) -> Option<SharedPeer> {
+        self.pubkey_map.get(&key[..]).cloned()
+    }
+
+    pub fn get_peer_by_index(&self, index: u32) -> Option<SharedPeer> {
+        self.index_map.get(&index).cloned()
+    }
+
+    /// Looks up the peer whose allowed IPs cover `addr` most specifically.
+    pub fn get_peer_for_ip(&self, addr: IpAddr) -> Option<SharedPeer> {
+        self.router.get_peer_from_ip(addr)
+    }
+
+    /// Registers `peer` under `info`'s public key and routes its allowed IPs to it.
+    /// Adds `peer`, first forgetting any peer already there under the same public key so its
+    /// session indices and routes don't outlive it. Updating a peer in place goes through
+    /// `UpdateEvent::UpdatePeer` instead.
+    pub fn add_peer(&mut self, peer: SharedPeer, info: &PeerInfo) {
+        if self.remove_peer(&info.pub_key.0) {
+            debug!("replaced existing peer {}", info.pub_key);
+        }
+        self.router.add_allowed_ips(&info.allowed_ips, &peer);
+        self.install_routes(info);
+        let _ = self.pubkey_map.insert(info.pub_key, peer);
+    }
+
+    /// Forgets the peer with public key `pubkey` along with its session indices and routes.
+    /// Returns whether there was such a peer.
+    pub fn remove_peer(&mut self, pubkey: &[u8; 32]) -> bool {
+        let peer_ref = match self.pubkey_map.remove(&pubkey[..]) {
+            Some(peer_ref) => peer_ref,
+            None           => return false,
+        };
+        let mut peer = peer_ref.lock().unwrap();
+        self.session_expired(&mut peer);
+        peer.mark_removed();
+        for index in peer.get_mapped_indices() {
+            let _ = self.index_map.remove(&index);
+            self.session_history.expired(index);
+        }
+        self.router.remove_allowed_ips(&peer.info.allowed_ips, &peer_ref);
+        if let Some(ref mut routes) = self.routes {
+            routes.withdraw(&peer.info.pub_key);
+        }
+        true
+    }
+
+    /// Forgets every peer at once, handing them back to the caller.
+    fn clear_peers(&mut self) -> Vec<SharedPeer> {
+        let peers = self.pubkey_map.drain().map(|(_, peer)| peer).collect::<Vec<_>>();
+        for peer in &peers {
+            let mut peer = peer.lock().unwrap();
+            self.session_expired(&mut peer);
+            peer.mark_removed();
+        }
+        self.index_map.clear();
+        self.session_history.expire_all();
+        self.router.clear();
+        if let Some(ref mut routes) = self.routes {
+            routes.withdraw_all();
+        }
+        peers
+    }
+
+    /// Drops every peer and overwrites the key material we hold before letting go of it.
+    fn wipe(&mut self) {
+        for peer in self.clear_peers() {
+            let mut peer = peer.lock().unwrap();
+            let _ = peer.expire();
+            if let Some(ref mut psk) = peer.info.psk {
+                zeroize(psk);
+            }
+        }
+        self.interface_info.private_key = None;
+    }
+
+    pub fn iter_peers<'a>(&'a self) -> impl Iterator<Item = SharedPeer> + 'a {
+        self.pubkey_map.values().cloned()
+    }
+
+    fn peer_count(&self) -> usize {
+        self.pubkey_map.len()
+    }
+
+    fn map_index(&mut self, index: u32, peer: SharedPeer) {
+        let _ = self.index_map.insert(index, peer);
+    }
+
+    fn unmap_index(&mut self, index: u32) {
+        let _ = self.index_map.remove(&index);
+        self.session_history.expired(index);
+    }
+
+    /// Makes sure `packet` comes from an address inside `peer`'s allowed IPs.
+    fn validate_source(&self, packet: &[u8], peer: &SharedPeer) -> Result<(), Error> {
+        self.router.validate_source(packet, peer)
+    }
+
+    /// Picks a random session index that isn't in `index_map` yet.
+    fn allocate_index(&self) -> Result<u32, Error> {
+        let mut rng = rand::thread_rng();
+        for _ in 0..INDEX_ALLOCATION_TRIES {
+            let tentative: u32 = rng.gen();
+            if !self.index_map.contains_key(&tentative) {
+                return Ok(tentative);
+            }
+        }
+        Err(DropReason::ResourceExhausted.into())
+    }
+
+    /// Drops `index_map` entries nothing refers to anymore, either because their peer is gone
+    /// from `pubkey_map` or because none of its sessions use the index. Returns how many went.
+    fn remove_orphaned_indices(&mut self) -> usize {
+        let pubkey_map = &self.pubkey_map;
+        let before     = self.index_map.len();
+        self.index_map.retain(|index, peer_ref| {
+            let peer = peer_ref.lock().unwrap();
+            peer.get_mapped_indices().contains(index)
+                && pubkey_map.get(&peer.info.pub_key).map_or(false, |known| Arc::ptr_eq(known, peer_ref))
+        });
+        before - self.index_map.len()
+    }
+}
+
+/// Counts one more item into a queue `depth` deep, warning as it fills past
+/// `QUEUE_DEPTH_WARNING`. Returns whether it warned, which it does once per crossing.
+fn note_queued(depth: &AtomicUsize, queue: &str) -> bool {
+    let depth = depth.fetch_add(1, Ordering::Relaxed) + 1;
+    if depth == QUEUE_DEPTH_WARNING + 1 {
+        warn!("{} queue is more than {} deep, packets may start being dropped", queue, QUEUE_DEPTH_WARNING);
+        return true;
+    }
+    false
+}
+
+/// Counts an item out of a queue, once whoever reads it has taken it.
+fn note_dequeued(depth: &AtomicUsize) {
+    let _ = depth.fetch_sub(1, Ordering::Relaxed);
 }
 
 pub struct Interface {
-    name: String,
+    name: InterfaceName,
     state: SharedState,
+    shutdown_tx: sync::mpsc::UnboundedSender<()>,
+    shutdown_rx: Option<sync::mpsc::UnboundedReceiver<()>>,
+    pending_messages: Vec<ChannelMessage>,
+    config_file: Option<PathBuf>,
+    metrics_addr: Option<SocketAddr>,
+    rest_addr: Option<SocketAddr>,
+    resolve_interval: Duration,
+}
+
+/// Stops a running `Interface` from any thread.
+#[derive(Clone)]
+pub struct ShutdownHandle(sync::mpsc::UnboundedSender<()>);
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        let _ = self.0.unbounded_send(());
+    }
+}
+
+/// Spawns `task` onto `handle`, dropping it once `stop` fires or its sender goes away.
+fn spawn_until<F>(handle: &Handle, task: F, stop: &Shared<sync::oneshot::Receiver<()>>)
+    where F: Future<Item = (), Error = ()> + 'static
+{
+    let stop = stop.clone().then(|_| Ok(()));
+    handle.spawn(task.select(stop).then(|_| Ok(())));
+}
+
+/// Sorts an error from setting up a handshake into snow's own failures and the protocol name
+/// being one we won't build.
+fn noise_error(e: Error) -> InterfaceError {
+    match e.downcast::<SnowError>() {
+        Ok(e)  => InterfaceError::Noise(e),
+        Err(e) => InterfaceError::Config(e.to_string()),
+    }
 }
 
+#[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
 struct VecUtunCodec;
 pub enum UtunPacket {
     Inet4(Vec<u8>),
@@ -62,18 +432,28 @@ impl UtunPacket {
         }
     }
 
+    /// The DSCP bits of the TOS (v4) or traffic class (v6) byte, left in place with ECN cleared.
+    pub fn dscp(&self) -> u8 {
+        match *self {
+            UtunPacket::Inet4(ref packet) if packet.len() >= 2 => packet[1] & 0xfc,
+            UtunPacket::Inet6(ref packet) if packet.len() >= 2 => ((packet[0] << 4) | (packet[1] >> 4)) & 0xfc,
+            _                                                  => 0,
+        }
+    }
+
     pub fn from(raw_packet: Vec<u8>) -> Result<UtunPacket, Error> {
-        match raw_packet[0] >> 4 {
-            4 => Ok(UtunPacket::Inet4(raw_packet)),
-            6 => Ok(UtunPacket::Inet6(raw_packet)),
-            _ => bail!("unrecognized IP version")
+        match raw_packet.get(0).map(|byte| *byte >> 4) {
+            Some(4) => Ok(UtunPacket::Inet4(raw_packet)),
+            Some(6) => Ok(UtunPacket::Inet6(raw_packet)),
+            _       => bail!("unrecognized IP version")
         }
     }
 }
 
+#[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
 impl UtunCodec for VecUtunCodec {
     type In = UtunPacket;
-    type Out = Vec<u8>;
+    type Out = UtunPacket;
 
     fn decode(&mut self, buf: &[u8]) -> io::Result<Self::In> {
         trace!("utun packet type {}", buf[3]);
@@ -84,31 +464,244 @@ impl UtunCodec for VecUtunCodec {
         }
     }
 
-    fn encode(&mut self, mut msg: Self::Out, buf: &mut Vec<u8>) {
-        buf.append(&mut msg);
+    /// Prepends the 4-byte address family header utun expects, as the kernel drops packets with the wrong one.
+    fn encode(&mut self, msg: Self::Out, buf: &mut Vec<u8>) {
+        let family = match msg {
+            UtunPacket::Inet4(_) => libc::AF_INET,
+            UtunPacket::Inet6(_) => libc::AF_INET6,
+        };
+        buf.extend_from_slice(&[0, 0, 0, family as u8]);
+        buf.extend_from_slice(msg.payload());
     }
 }
 
 impl Interface {
-    pub fn new(name: &str) -> Self {
+    pub fn new(name: InterfaceName) -> Self {
         let state = State::default();
+        let (shutdown_tx, shutdown_rx) = sync::mpsc::unbounded();
         Interface {
-            name: name.to_owned(),
-            state: Rc::new(RefCell::new(state)),
+            name,
+            state: Arc::new(RwLock::new(state)),
+            shutdown_tx,
+            shutdown_rx: Some(shutdown_rx),
+            pending_messages: vec![],
+            config_file: None,
+            metrics_addr: None,
+            rest_addr: None,
+            resolve_interval: *ENDPOINT_RESOLVE_INTERVAL,
         }
     }
 
-    pub fn start(&mut self) -> Result<(), Error> {
-        let mut core = Core::new()?;
+    /// Loads `path` (in wg-quick format) when the interface starts, and again on every SIGHUP.
+    pub fn watch_config_file(&mut self, path: &Path) {
+        self.config_file = Some(path.to_owned());
+    }
+
+    /// Serves Prometheus metrics on `addr` while the interface runs. Needs the `metrics` feature.
+    pub fn serve_metrics(&mut self, addr: SocketAddr) {
+        self.metrics_addr = Some(addr);
+    }
+
+    /// Serves the JSON management API on `addr` while the interface runs. Needs the `rest-api`
+    /// feature. There's no authentication, so keep it on a loopback or otherwise trusted address.
+    pub fn serve_rest_api(&mut self, addr: SocketAddr) {
+        self.rest_addr = Some(addr);
+    }
+
+    /// How often endpoints given as hostnames are looked up again. Defaults to 30 seconds.
+    pub fn resolve_endpoints_every(&mut self, interval: Duration) {
+        self.resolve_interval = interval;
+    }
+
+    /// Subscribes to connection state changes for the peer with public key `pubkey`, if it's configured.
+    pub fn peer_state(&self, pubkey: &[u8; 32]) -> Option<sync::mpsc::UnboundedReceiver<PeerConnectionState>> {
+        let state = self.state.read().unwrap();
+        state.get_peer_by_pubkey(pubkey).map(|peer| peer.lock().unwrap().subscribe())
+    }
+
+    /// Subscribes to configuration and session changes for as long as the interface lives.
+    pub fn events(&self) -> sync::mpsc::UnboundedReceiver<InterfaceEvent> {
+        let (tx, rx) = sync::mpsc::unbounded();
+        self.state.write().unwrap().event_txs.push(tx);
+        rx
+    }
+
+    /// Returns a stream of readiness changes, starting with the current one. The interface is
+    /// ready once its tunnel and sockets are set up and it's processing packets, and stops
+    /// being ready when it shuts down.
+    pub fn ready_watch(&self) -> sync::mpsc::UnboundedReceiver<bool> {
+        let mut state = self.state.write().unwrap();
+        let (tx, rx)  = sync::mpsc::unbounded();
+        let _ = tx.unbounded_send(state.ready);
+        state.ready_txs.push(tx);
+        rx
+    }
+
+    /// Calls `hook` whenever a session with any peer is established. Hooks run on the packet
+    /// path with the interface state locked, so they should be quick and mustn't call back
+    /// into the interface.
+    pub fn on_session_established(&self, hook: impl Fn(SessionEvent) + Send + Sync + 'static) {
+        self.state.write().unwrap().session_established_hooks.push(Arc::new(hook));
+    }
+
+    /// Calls `hook` whenever a session with any peer expires: once it goes past
+    /// REJECT-AFTER-TIME, when it's wiped for going stale, or when its peer is removed. Each
+    /// session is only reported once, and hooks are under the same constraints as
+    /// `on_session_established`.
+    pub fn on_session_expired(&self, hook: impl Fn(SessionEvent) + Send + Sync + 'static) {
+        self.state.write().unwrap().session_expired_hooks.push(Arc::new(hook));
+    }
+
+    /// The most recent sessions with any peer, oldest first.
+    pub fn session_history(&self) -> Vec<SessionRecord> {
+        self.state.read().unwrap().session_history.iter().cloned().collect()
+    }
+
+    /// Applies `diff` (from `reload::diff`) to the configuration before the interface starts,
+    /// touching only what changed, so kept peers keep their sessions. The state is up to date
+    /// when this returns; the peer server catches up as it starts.
+    pub fn apply_diff(&mut self, diff: ConfigDiff) -> Result<(), InterfaceError> {
+        let messages = reload::apply_diff(&mut self.state.write().unwrap(), &diff)
+            .map_err(|e| InterfaceError::Update(e.to_string()))?;
+        self.pending_messages.extend(messages);
+        Ok(())
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(self.shutdown_tx.clone())
+    }
+
+    /// Fails if there are peers to handshake with but no private key, or if the key and
+    /// protocol won't make a noise handshake, before anything is opened for them.
+    fn check_keys(&self) -> Result<(), InterfaceError> {
+        let state = self.state.read().unwrap();
+        let info  = &state.interface_info;
+        match info.private_key {
+            Some(ref key)                       => noise::build_responder(&info.noise_protocol, key.as_ref()).map(|_| ()).map_err(noise_error),
+            None if state.pubkey_map.is_empty() => Ok(()),
+            None                                => Err(InterfaceError::NoPrivateKey),
+        }
+    }
+
+    /// Runs the interface on a reactor of its own until it's shut down.
+    pub fn start(&mut self) -> Result<(), InterfaceError> {
+        let mut core = Core::new().map_err(InterfaceError::Reactor)?;
+        let fut      = self.start_on(&core.handle())?;
+        let _        = core.run(fut);
+
+        info!("reactor finished.");
+        Ok(())
+    }
+
+    /// Sets the interface up on the reactor behind `handle`, which may be running others too.
+    /// The returned future runs it until it's shut down, and then closes the tunnel and sockets
+    /// and wipes the interface's keys.
+    pub fn start_on(&mut self, handle: &Handle) -> Result<Box<Future<Item = (), Error = ()>>, InterfaceError> {
+        let shutdown_rx = self.shutdown_rx.take().ok_or(InterfaceError::AlreadyStarted)?;
+
+        // background tasks are stopped when the interface is, as the reactor may outlive it.
+        let (stop_tx, stop_rx) = sync::oneshot::channel::<()>();
+        let stop               = stop_rx.shared();
 
         let (utun_tx, utun_rx) = unsync::mpsc::unbounded::<Vec<u8>>();
 
-        let peer_server    = PeerServer::new(core.handle(), self.state.clone(), utun_tx.clone())?;
-        let utun_stream    = UtunStream::connect(&self.name, &core.handle())?;
-        let interface_name = utun_stream.name()?;
+        let peer_server    = PeerServer::new(handle.clone(), self.state.clone(), utun_tx.clone())
+            .map_err(|e| InterfaceError::PeerServer(e.to_string()))?;
+        if let Some(ref path) = self.config_file {
+            let config   = config_file::parse(path).map_err(|e| InterfaceError::Config(e.to_string()))?;
+            let messages = reload::apply(&mut self.state.write().unwrap(), &config)
+                .map_err(|e| InterfaceError::Config(e.to_string()))?;
+            self.pending_messages.extend(messages);
+
+            let state  = self.state.clone();
+            let path   = path.clone();
+            let tx     = peer_server.tx();
+            let sighup = Signal::new(libc::SIGHUP, handle).flatten_stream()
+                .for_each(move |_| {
+                    info!("SIGHUP received, reloading {}", path.display());
+                    let result = config_file::parse(&path).map_err(Error::from)
+                        .and_then(|config| reload::apply(&mut state.write().unwrap(), &config));
+                    let state = state.read().unwrap();
+                    match result {
+                        Ok(messages) => for message in messages { let _ = state.send_to_peer_server(&tx, message); },
+                        Err(e)       => warn!("failed to reload configuration: {}", e),
+                    }
+                    Ok(())
+                })
+                .map_err(|e| warn!("SIGHUP handler error: {}", e));
+            spawn_until(handle, sighup, &stop);
+        }
+        self.check_keys()?;
+
+        let gc_state = self.state.clone();
+        let index_gc = Interval::new(Instant::now() + *INDEX_GC_INTERVAL, *INDEX_GC_INTERVAL)
+            .map_err(|e| warn!("index gc timer error: {}", e))
+            .for_each(move |_| {
+                let removed = gc_state.write().unwrap().remove_orphaned_indices();
+                if removed > 0 {
+                    info!("removed {} orphaned session indices.", removed);
+                }
+                Ok(())
+            });
+        spawn_until(handle, index_gc, &stop);
+        spawn_until(handle, resolver::task(self.state.clone(), self.resolve_interval), &stop);
+
+        {
+            let state = self.state.read().unwrap();
+            for message in self.pending_messages.drain(..) {
+                state.send_to_peer_server(&peer_server.tx(), message)
+                    .map_err(|_| InterfaceError::PeerServer("hung up".to_owned()))?;
+            }
+        }
+        #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+        let utun_stream    = tun::TunStream::connect(&self.name, handle).map_err(InterfaceError::Tun)?;
+        #[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
+        let utun_stream    = UtunStream::connect(&self.name, handle).map_err(InterfaceError::Tun)?;
+        let interface_name = utun_stream.name().map_err(InterfaceError::Tun)?;
+        #[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
         let utun_stream    = utun_stream.framed(VecUtunCodec{});
-        let config_server  = ConfigurationService::new(&interface_name, &self.state, peer_server.tx(), &core.handle())?.map_err(|_|());
-        self.name = interface_name;
+        let config_server  = ConfigurationService::new(&interface_name, &self.state, peer_server.tx(), handle)
+            .map_err(|e| InterfaceError::Socket(e.to_string()))?
+            .map_err(|_|());
+        self.name = InterfaceName::new(&interface_name).map_err(|e| InterfaceError::Config(e.to_string()))?;
+        match RouteInjector::for_interface(&interface_name) {
+            Ok(routes) => {
+                let mut state = self.state.write().unwrap();
+                state.routes = Some(routes);
+                state.reinstall_routes();
+            },
+            Err(e)     => warn!("can't manage routes for {}: {}", interface_name, e),
+        }
+
+        #[cfg(feature = "metrics")]
+        {
+            if let Some(addr) = self.metrics_addr {
+                let metrics = MetricsServer::new(addr, self.state.clone(), &self.name).listen(handle)
+                    .map_err(|e| InterfaceError::Socket(e.to_string()))?;
+                spawn_until(handle, metrics, &stop);
+            }
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            if self.metrics_addr.is_some() {
+                warn!("built without the metrics feature, not serving metrics.");
+            }
+        }
+
+        #[cfg(feature = "rest-api")]
+        {
+            if let Some(addr) = self.rest_addr {
+                let rest = RestServer::new(addr, self.state.clone(), peer_server.tx()).listen(handle)
+                    .map_err(|e| InterfaceError::Socket(e.to_string()))?;
+                spawn_until(handle, rest, &stop);
+            }
+        }
+        #[cfg(not(feature = "rest-api"))]
+        {
+            if self.rest_addr.is_some() {
+                warn!("built without the rest-api feature, not serving the management API.");
+            }
+        }
 
         let (utun_writer, utun_reader) = utun_stream.split();
 
@@ -117,6 +710,17 @@ impl Interface {
             .send_all(utun_reader.map_err(|e| -> Error { e.into() }))
             .map_err(|e| { warn!("utun read error: {:?}", e); () });
 
+        let utun_rx = {
+            let depth = self.state.read().unwrap().utun_queue_depth.clone();
+            utun_rx.map(move |packet| { note_dequeued(&depth); packet })
+        };
+
+        #[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
+        let utun_rx = utun_rx.filter_map(|packet| match UtunPacket::from(packet) {
+            Ok(packet) => Some(packet),
+            Err(e)     => { debug!("dropping packet to utun: {}", e); None },
+        });
+
         let utun_write_fut = utun_writer
             .sink_map_err(|e| -> Error { e.into() })
             .send_all(utun_rx.map_err(|()| -> Error { err_msg("utun rx failure") }))
@@ -124,12 +728,343 @@ impl Interface {
 
         let utun_futs = utun_write_fut.join(utun_read_fut);
 
-        let fut = peer_server
+        let shutdown = shutdown_rx.into_future()
+            .map(|_| info!("shutdown requested."))
+            .map_err(|_| ());
+
+        let running = peer_server
             .map_err(|e| error!("peer_server error: {:?}", e))
-            .join(config_server.join(utun_futs));
-        let _ = core.run(fut);
+            .join(config_server.join(utun_futs))
+            .map(|_| ())
+            .select(shutdown);
 
-        info!("reactor finished.");
-        Ok(())
+        // everything's set up, so the interface is ready as soon as the reactor picks it up.
+        let state = self.state.clone();
+        let ready = self.state.clone();
+        let name  = self.name.clone();
+        let fut   = future::lazy(move || { ready.write().unwrap().set_ready(true); Ok(()) })
+            .and_then(move |()| running)
+            .then(move |result| -> Result<(), ()> {
+                // dropping the interface's futures closes the tunnel, the UDP sockets and the config socket.
+                drop(result);
+                drop(stop_tx);
+                let mut state = state.write().unwrap();
+                state.set_ready(false);
+                state.wipe();
+                info!("{} stopped.", name);
+                Ok(())
+            });
+        Ok(Box::new(fut))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config_file::InterfaceConfig;
+    use interface::config::UpdateEvent;
+    use message::{Initiation, Response};
+    use noise;
+    use std::convert::TryInto;
+    use test_peers::{endpoint, handshake, peer_pair};
+    use types::{PeerInfo, PrivateKey};
+
+    #[test]
+    fn orphaned_indices_collected() {
+        let mut state = State::default();
+        for i in 0..1000u32 {
+            let mut key = [0u8; 32];
+            key[0] = (i >> 8) as u8;
+            key[1] = i as u8;
+            let info = PeerInfo { pub_key: PublicKey(key), ..Default::default() };
+            ConfigurationService::handle_update(&mut state, &UpdateEvent::UpdatePeer(info, false)).unwrap();
+
+            // an index left behind by a session the peer no longer has.
+            let peer = state.pubkey_map[&PublicKey(key)].clone();
+            let _ = state.index_map.insert(i, peer);
+            if i % 2 == 0 {
+                ConfigurationService::handle_update(&mut state, &UpdateEvent::RemovePeer(PublicKey(key))).unwrap();
+            }
+        }
+        assert_eq!(state.pubkey_map.len(), 500);
+        assert_eq!(state.index_map.len(), 1000);
+
+        assert_eq!(state.remove_orphaned_indices(), 1000);
+        assert!(state.index_map.is_empty());
+        assert_eq!(state.remove_orphaned_indices(), 0);
+    }
+
+    #[test]
+    fn peer_accessors() {
+        let mut state = State::default();
+        let mut info  = PeerInfo { pub_key: PublicKey([1u8; 32]), ..Default::default() };
+        info.allowed_ips.push(("10.0.0.0".parse().unwrap(), 24));
+        let peer = Arc::new(Mutex::new(Peer::new(info.clone())));
+        state.add_peer(peer.clone(), &info);
+        state.map_index(7, peer.clone());
+
+        assert!(state.get_peer_by_pubkey(&[1u8; 32]).map_or(false, |found| Arc::ptr_eq(&found, &peer)));
+        assert!(state.get_peer_by_index(7).map_or(false, |found| Arc::ptr_eq(&found, &peer)));
+        assert!(state.get_peer_for_ip("10.0.0.9".parse().unwrap()).is_some());
+        assert!(state.get_peer_for_ip("10.0.1.9".parse().unwrap()).is_none());
+        assert_eq!(state.iter_peers().count(), 1);
+
+        assert!(state.remove_peer(&[1u8; 32]));
+        assert!(!state.remove_peer(&[1u8; 32]));
+        assert!(state.get_peer_for_ip("10.0.0.9".parse().unwrap()).is_none());
+        assert_eq!(state.iter_peers().count(), 0);
+    }
+
+    #[test]
+    fn allocate_index_around_taken_ones() {
+        let mut state = State::default();
+        let peer      = Arc::new(Mutex::new(Peer::new(PeerInfo::default())));
+        for index in 0..(1u32 << 16) {
+            let _ = state.index_map.insert(index, peer.clone());
+        }
+
+        for _ in 0..1000 {
+            let index = state.allocate_index().unwrap();
+            assert!(!state.index_map.contains_key(&index));
+        }
+    }
+
+    #[test]
+    fn session_hooks() {
+        let interface = Interface::new("wgtest0".parse().unwrap());
+        let seen      = Arc::new(Mutex::new(vec![]));
+        let (established, expired) = (seen.clone(), seen.clone());
+        interface.on_session_established(move |event| established.lock().unwrap().push(("established", event)));
+        interface.on_session_expired(move |event| expired.lock().unwrap().push(("expired", event)));
+
+        let (mut init, mut resp) = peer_pair(None);
+        let response             = handshake(&mut init, &mut resp, 1, 2);
+        init.process_incoming_handshake_response(endpoint(2), &response).unwrap();
+        let info                 = init.info.clone();
+        let init                 = Arc::new(Mutex::new(init.peer));
+
+        {
+            let mut state = interface.state.write().unwrap();
+            state.add_peer(init.clone(), &info);
+            state.session_established(&init.lock().unwrap(), 1);
+            assert!(state.remove_peer(&info.pub_key.0));
+            state.session_expired(&mut init.lock().unwrap()); // already reported on removal
+            let _ = init.lock().unwrap().expire();
+            state.session_expired(&mut init.lock().unwrap()); // nothing left to describe
+        }
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.iter().map(|&(kind, _)| kind).collect::<Vec<_>>(), vec!["established", "expired"]);
+        for &(_, ref event) in seen.iter() {
+            assert_eq!(event.peer_pubkey, resp.public);
+            assert_eq!(event.endpoint, *endpoint(2));
+            assert_eq!(event.role, HandshakeRole::Initiator);
+        }
+    }
+
+    #[test]
+    fn session_history() {
+        let interface              = Interface::new("wgtest0".parse().unwrap());
+        let (init_priv, resp_priv) = (PrivateKey([0x11; 32]), PrivateKey([0x22; 32]));
+        let endpoint: SocketAddr   = "127.0.0.1:51820".parse().unwrap();
+        let mut init = Peer::new(PeerInfo { pub_key: resp_priv.public_key(), endpoint: Some(endpoint.into()), ..Default::default() });
+        let mut resp = Peer::new(PeerInfo { pub_key: init_priv.public_key(), ..Default::default() });
+
+        let mut handshake = |init: &mut Peer, index| {
+            let (_, packet, _)        = init.initiate_new_session(&init_priv[..], noise::DEFAULT_PROTOCOL, index).unwrap();
+            let packet   : Initiation = packet.try_into().unwrap();
+            let handshake             = Peer::process_incoming_handshake(&resp_priv[..], noise::DEFAULT_PROTOCOL, &packet).unwrap();
+            let (response, _)         = resp.complete_incoming_handshake(endpoint.into(), index + 1, handshake).unwrap();
+            let response : Response   = response.try_into().unwrap();
+            init.process_incoming_handshake_response(endpoint.into(), &response).unwrap()
+        };
+
+        assert_eq!(handshake(&mut init, 1), None);
+        interface.state.write().unwrap().session_established(&init, 1);
+        init.handle_outgoing_transport(&[0x45, 0, 0, 20]).unwrap();
+
+        // the first session sticks around as `past` until the peer's sessions are wiped
+        assert_eq!(handshake(&mut init, 3), None);
+        interface.state.write().unwrap().session_established(&init, 3);
+        {
+            let history = interface.session_history();
+            assert_eq!(history.iter().map(|record| record.session_index).collect::<Vec<_>>(), vec![1, 3]);
+            assert!(history.iter().all(|record| record.expired.is_none()));
+            assert_eq!(history[0].bytes(), (4, 0));
+        }
+
+        {
+            let mut state = interface.state.write().unwrap();
+            state.session_expired(&mut init);
+            for index in init.expire() {
+                state.unmap_index(index);
+            }
+        }
+
+        let history = interface.session_history();
+        assert_eq!(history.len(), 2);
+        for record in &history {
+            assert_eq!(record.peer_pubkey, resp_priv.public_key().0);
+            assert_eq!(record.endpoint, endpoint);
+            assert_eq!(record.role, HandshakeRole::Initiator);
+            assert!(record.expired.is_some());
+        }
+        assert!(history[0].established <= history[1].established);
+        assert_eq!((history[0].bytes_sent, history[0].bytes_recv), (4, 0));
+        assert_eq!((history[1].bytes_sent, history[1].bytes_recv), (0, 0));
+    }
+
+    #[test]
+    fn apply_diff_keeps_sessions() {
+        let mut interface = Interface::new("wgtest0".parse().unwrap());
+        let (init_priv, resp_priv, other_priv) = (PrivateKey([0x11; 32]), PrivateKey([0x22; 32]), PrivateKey([0x33; 32]));
+        let endpoint: SocketAddr = "127.0.0.1:51820".parse().unwrap();
+        let peer = |key: &PrivateKey| PeerInfo { pub_key: key.public_key(), endpoint: Some(endpoint.into()), ..Default::default() };
+
+        let old = InterfaceConfig { peers: vec![peer(&resp_priv), peer(&other_priv)], ..Default::default() };
+        interface.apply_diff(reload::diff(&InterfaceConfig::default(), &old)).unwrap();
+
+        let kept = interface.state.read().unwrap().get_peer_by_pubkey(&resp_priv.public_key().0).unwrap();
+        {
+            let mut init = kept.lock().unwrap();
+            let mut resp = Peer::new(PeerInfo { pub_key: init_priv.public_key(), ..Default::default() });
+
+            let (_, packet, _)        = init.initiate_new_session(&init_priv[..], noise::DEFAULT_PROTOCOL, 1).unwrap();
+            let packet   : Initiation = packet.try_into().unwrap();
+            let handshake             = Peer::process_incoming_handshake(&resp_priv[..], noise::DEFAULT_PROTOCOL, &packet).unwrap();
+            let (response, _)         = resp.complete_incoming_handshake(endpoint.into(), 2, handshake).unwrap();
+            let response : Response   = response.try_into().unwrap();
+            init.process_incoming_handshake_response(endpoint.into(), &response).unwrap();
+            assert!(init.ready_for_transport());
+        }
+
+        let mut new = InterfaceConfig { peers: vec![peer(&resp_priv), peer(&PrivateKey([0x44; 32]))], ..Default::default() };
+        new.peers[0].endpoint = Some("127.0.0.2:51820".parse::<SocketAddr>().unwrap().into());
+        let diff = reload::diff(&old, &new);
+        assert_eq!((diff.peers_to_add.len(), diff.peers_to_remove.len(), diff.peers_to_update.len()), (1, 1, 1));
+        interface.apply_diff(diff).unwrap();
+
+        let state = interface.state.read().unwrap();
+        assert_eq!(state.pubkey_map.len(), 2);
+        assert!(state.get_peer_by_pubkey(&other_priv.public_key().0).is_none());
+        assert!(Arc::ptr_eq(&state.get_peer_by_pubkey(&resp_priv.public_key().0).unwrap(), &kept));
+
+        let kept = kept.lock().unwrap();
+        assert!(kept.ready_for_transport());
+        assert_eq!(*kept.info.endpoint.unwrap(), "127.0.0.2:51820".parse::<SocketAddr>().unwrap());
+    }
+
+    #[test]
+    #[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
+    fn utun_family_header() {
+        let mut codec = VecUtunCodec{};
+        let mut v6    = vec![0x60, 0, 0, 0];
+        v6.resize(40, 0);
+
+        let mut buf = vec![];
+        codec.encode(UtunPacket::from(v6.clone()).unwrap(), &mut buf);
+        assert_eq!(&buf[..4], &[0, 0, 0, libc::AF_INET6 as u8]);
+        assert_eq!(&buf[4..], &v6[..]);
+
+        let mut buf = vec![];
+        codec.encode(UtunPacket::from(vec![0x45, 0, 0, 20]).unwrap(), &mut buf);
+        assert_eq!(&buf[..], &[0, 0, 0, 2, 0x45, 0, 0, 20]);
+    }
+
+    #[test]
+    fn packet_version() {
+        assert!(UtunPacket::from(vec![0x20, 0, 0, 0]).is_err());
+        assert!(UtunPacket::from(vec![]).is_err());
+    }
+
+    #[test]
+    fn packet_dscp() {
+        // EF (46) with ECN set, which shouldn't carry over.
+        assert_eq!(UtunPacket::from(vec![0x45, 0xb9, 0, 0]).unwrap().dscp(), 0xb8);
+        assert_eq!(UtunPacket::from(vec![0x6b, 0x90, 0, 0]).unwrap().dscp(), 0xb8);
+        assert_eq!(UtunPacket::from(vec![0x60, 0x00, 0, 0]).unwrap().dscp(), 0);
+    }
+
+    #[test]
+    fn tunnel_queue_limit() {
+        let state     = State::default();
+        let (tx, _rx) = unsync::mpsc::unbounded();
+        let results   = (0..1100).map(|_| state.send_to_tunnel(&tx, vec![0x45; 20])).collect::<Vec<_>>();
+        assert!(results[..MAX_QUEUED_TUNNEL_PACKETS].iter().all(Result::is_ok));
+        for result in &results[MAX_QUEUED_TUNNEL_PACKETS..] {
+            assert_eq!(result.as_ref().unwrap_err().downcast_ref::<DropReason>(), Some(&DropReason::ChannelFull));
+        }
+        assert_eq!(state.utun_queue_depth.load(Ordering::Relaxed), MAX_QUEUED_TUNNEL_PACKETS);
+
+        // one warning as the queue fills past 80%, and none after
+        let depth    = AtomicUsize::new(0);
+        let warnings = (0..MAX_QUEUED_TUNNEL_PACKETS).filter(|_| note_queued(&depth, "test")).count();
+        assert_eq!(warnings, 1);
+        note_dequeued(&depth);
+        assert_eq!(depth.load(Ordering::Relaxed), MAX_QUEUED_TUNNEL_PACKETS - 1);
+    }
+
+    #[test]
+    fn readiness_changes() {
+        let interface = Interface::new("wgtest0".parse().unwrap());
+        let ready     = interface.ready_watch();
+        {
+            let mut state = interface.state.write().unwrap();
+            state.set_ready(true);
+            state.set_ready(true);
+            state.set_ready(false);
+        }
+        drop(interface);
+        assert_eq!(ready.wait().collect::<Result<Vec<_>, _>>(), Ok(vec![false, true, false]));
+    }
+
+    #[test]
+    fn start_twice() {
+        let mut interface = Interface::new("wgtest0".parse().unwrap());
+        let _             = interface.shutdown_rx.take();
+        match interface.start() {
+            Err(InterfaceError::AlreadyStarted) => {},
+            result                              => panic!("expected AlreadyStarted, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn start_without_private_key() {
+        let mut interface = Interface::new("wgtest0".parse().unwrap());
+        let peer          = PeerInfo { pub_key: PrivateKey([0x22; 32]).public_key(), ..Default::default() };
+        interface.apply_diff(reload::diff(&InterfaceConfig::default(), &InterfaceConfig { peers: vec![peer], ..Default::default() })).unwrap();
+        match interface.start() {
+            Err(InterfaceError::NoPrivateKey) => {},
+            result                            => panic!("expected NoPrivateKey, got {:?}", result),
+        }
+
+        // with nothing to handshake with, there's no need for a key yet.
+        assert!(Interface::new("wgtest0".parse().unwrap()).check_keys().is_ok());
+    }
+
+    #[test]
+    fn start_with_missing_config_file() {
+        let mut interface = Interface::new("wgtest0".parse().unwrap());
+        interface.watch_config_file(Path::new("/nonexistent/wgtest0.conf"));
+        match interface.start() {
+            Err(InterfaceError::Config(_)) => {},
+            result                         => panic!("expected Config, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn noise_errors() {
+        let mut responder = noise::build_responder(noise::DEFAULT_PROTOCOL, &[0x11; 32]).unwrap();
+        let garbage: Error = responder.read_message(&[0u8; 16], &mut [0u8; 256]).unwrap_err().into();
+        match noise_error(garbage) {
+            InterfaceError::Noise(_) => {},
+            e                        => panic!("expected Noise, got {:?}", e),
+        }
+
+        let protocol = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+        match noise::build_responder(protocol, &[0x11; 32]).map_err(noise_error) {
+            Err(InterfaceError::Config(_)) => {},
+            result                         => panic!("expected Config, got {:?}", result.map(|_| ())),
+        }
     }
 }
@@ -3,28 +3,30 @@
  * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
  */
 
-use consts::{REKEY_TIMEOUT, KEEPALIVE_TIMEOUT, STALE_SESSION_TIMEOUT,
-             MAX_CONTENT_SIZE, WIPE_AFTER_TIME, MAX_HANDSHAKE_ATTEMPTS,
-             UNDER_LOAD_QUEUE_SIZE, UNDER_LOAD_TIME};
+use consts::{MAX_CONTENT_SIZE, DEFAULT_MTU,
+             UNDER_LOAD_QUEUE_SIZE, UNDER_LOAD_TIME, MAX_HANDSHAKES_PER_SECOND};
 use cookie;
-use interface::{SharedPeer, SharedState, State, UtunPacket};
+use error::DropReason;
+use icmp;
+use ip_packet::IpPacket;
+use interface::{SharedPeer, SharedState, State, UtunPacket, note_dequeued};
+use interface::mirror::Mirror;
 use message::{Message, Initiation, Response, CookieReply, Transport};
 use peer::{Peer, SessionType, SessionTransition};
-use ratelimiter::RateLimiter;
+use ratelimiter::{RateLimiter, HandshakeCounter};
 use timestamp::Timestamp;
 use timer::{Timer, TimerMessage};
 
 use byteorder::{ByteOrder, LittleEndian};
 use failure::{Error, err_msg};
 use futures::{Async, Future, Stream, Poll, unsync::mpsc, task};
-use rand::{self, Rng, ThreadRng};
 use udp::{Endpoint, UdpSocket, PeerServerMessage, UdpChannel};
 use tokio_core::reactor::Handle;
 
 use std::collections::VecDeque;
 use std::convert::TryInto;
-use std::net::IpAddr;
-use std::rc::Rc;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
 use std::time::Instant;
 
 pub enum ChannelMessage {
@@ -32,6 +34,9 @@ pub enum ChannelMessage {
     NewPrivateKey,
     NewListenPort(u16),
     NewFwmark(u32),
+    NewRateLimit(u32),
+    NewUdpBuffers,
+    NewPreserveDscp(bool),
     NewPersistentKeepalive(SharedPeer),
     NewPeer(SharedPeer),
 }
@@ -62,14 +67,19 @@ pub struct PeerServer {
     tunnel_tx        : mpsc::UnboundedSender<Vec<u8>>,
     cookie           : cookie::Validator,
     rate_limiter     : RateLimiter,
+    handshake_counter: HandshakeCounter,
     under_load_until : Instant,
-    rng              : ThreadRng,
+    preserve_dscp    : bool,
+    mirror           : Mirror,
+    /// The state's `config_queue_depth`, to count messages out of `channel` without its lock.
+    config_depth     : Arc<AtomicUsize>,
 }
 
 impl PeerServer {
     pub fn new(handle: Handle, shared_state: SharedState, tunnel_tx: mpsc::UnboundedSender<Vec<u8>>) -> Result<Self, Error> {
+        let config_depth = shared_state.read().unwrap().config_queue_depth.clone();
         Ok(PeerServer {
-            shared_state, tunnel_tx,
+            shared_state, tunnel_tx, config_depth,
             handle           : handle.clone(),
             timer            : Timer::new(handle.clone()),
             udp              : None,
@@ -79,30 +89,40 @@ impl PeerServer {
             handshakes       : VecDeque::new(),
             cookie           : cookie::Validator::new(&[0u8; 32]),
             rate_limiter     : RateLimiter::new(&handle)?,
+            handshake_counter: HandshakeCounter::new(MAX_HANDSHAKES_PER_SECOND),
             under_load_until : Instant::now(),
-            rng              : rand::thread_rng()
+            preserve_dscp    : false,
+            mirror           : Mirror::default(),
         })
     }
 
     pub fn rebind(&mut self) -> Result<(), Error> {
-        let interface = &self.shared_state.borrow().interface_info;
+        let mut state = self.shared_state.write().unwrap();
 
-        if interface.private_key.is_none() {
+        if state.interface_info.private_key.is_none() {
             self.udp  = None;
             self.port = None;
             return Ok(());
         }
 
-        let port   = interface.listen_port.unwrap_or(0);
-        let fwmark = interface.fwmark.unwrap_or(0);
+        let port   = state.interface_info.listen_port.unwrap_or(0);
+        let fwmark = state.interface_info.fwmark.unwrap_or(0);
 
         if self.port.is_some() && self.port.unwrap() == port {
             debug!("skipping rebind, since we're already listening on the correct port.");
             return Ok(())
         }
 
-        let socket = UdpSocket::bind(port, self.handle.clone())?;
-        info!("listening on {:?}", socket.local_addrs()?);
+        let socket = match UdpSocket::bind(port, self.handle.clone()) {
+            Ok(socket) => socket,
+            Err(e)     => {
+                // keep reporting the port we're still listening on
+                state.interface_info.listen_port = self.port;
+                bail!("failed to bind to port {}: {}", port, e);
+            },
+        };
+        let local_addr = socket.local_addrs()?;
+        info!("listening on {:?}", local_addr);
 
         let udp: UdpChannel = socket.framed().into();
 
@@ -110,9 +130,27 @@ impl PeerServer {
             udp.set_mark(fwmark)?;
         }
 
+        // report the port we actually ended up on, in case we were given a random one
+        state.interface_info.listen_port = Some(local_addr.0.port());
+
         // TODO: clear out peer sticky endpoint sources
         self.udp  = Some(udp);
-        self.port = Some(port);
+        self.port = Some(local_addr.0.port());
+        self.apply_udp_buffers(&mut state)
+    }
+
+    fn apply_udp_buffers(&self, state: &mut State) -> Result<(), Error> {
+        let udp = match self.udp {
+            Some(ref udp) => udp,
+            None          => return Ok(()),
+        };
+
+        let requested = (state.interface_info.udp_recv_buffer, state.interface_info.udp_send_buffer);
+        let actual    = udp.set_buffer_sizes(requested.0, requested.1)?;
+        if requested.0.map_or(false, |size| actual.0 < size) || requested.1.map_or(false, |size| actual.1 < size) {
+            warn!("udp buffers capped by the OS: asked for {:?}, got {:?}", requested, actual);
+        }
+        state.interface_info.udp_buffer_sizes = Some(actual);
         Ok(())
     }
 
@@ -130,16 +168,44 @@ impl PeerServer {
         Ok(())
     }
 
+    /// Encrypts `packet` for `peer` and sends it, marked with the inner packet's DSCP bits if asked to.
+    fn send_transport(&self, peer: &mut Peer, packet: &UtunPacket) -> Result<(), Error> {
+        let tos     = if self.preserve_dscp { packet.dscp() } else { 0 };
+        let message = peer.handle_outgoing_transport(packet.payload())?;
+        self.udp.as_ref().ok_or_else(|| err_msg("no udp socket"))?
+            .send_with_tos(message, tos);
+        Ok(())
+    }
+
+    /// Whether keepalives carry their send time. Has to be read before taking a peer's lock,
+    /// as the state lock always comes first.
+    fn measure_latency(&self) -> bool {
+        self.shared_state.read().unwrap().interface_info.measure_latency
+    }
+
+    fn send_keepalive(&self, peer: &mut Peer, measure_latency: bool) -> Result<(), Error> {
+        self.send_to_peer(peer.handle_outgoing_keepalive(measure_latency)?)
+    }
+
     fn send_to_tunnel(&self, packet: Vec<u8>) -> Result<(), Error> {
-        self.tunnel_tx.unbounded_send(packet).map_err(|e| e.into())
+        self.shared_state.read().unwrap().send_to_tunnel(&self.tunnel_tx, packet)
     }
 
-    fn unused_index(&mut self, state: &mut State) -> u32 {
-        loop {
-            let tentative: u32 = self.rng.gen();
-            if !state.index_map.contains_key(&tentative) {
-                return tentative;
-            }
+    /// Sends a copy of `packet` to a monitoring address, counting it if it couldn't go out.
+    fn mirror(&mut self, packet: &[u8], addr: SocketAddr) {
+        if !self.mirror.send(packet, addr) {
+            let _ = self.shared_state.read().unwrap().mirror_send_drops.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts packets dropped for a known `DropReason`, and warns about any other failure.
+    fn note_drop(&self, context: &str, e: &Error) {
+        match e.downcast_ref::<DropReason>() {
+            Some(reason) => {
+                debug!("packet dropped ({})", reason);
+                *self.shared_state.write().unwrap().drop_counters.entry(*reason).or_insert(0) += 1;
+            },
+            None => warn!("{}: {:?}", context, e),
         }
     }
 
@@ -154,9 +220,9 @@ impl PeerServer {
     }
 
     fn handle_ingress_packet(&mut self, addr: Endpoint, packet: Vec<u8>) -> Result<(), Error> {
-        trace!("got a UDP packet from {:?} of length {}, packet type {}", &addr, packet.len(), packet[0]);
+        trace!("got a UDP packet from {:?} of length {}, packet type {:?}", &addr, packet.len(), packet.first());
 
-        let message = packet.try_into()?;
+        let message: Message = packet.try_into().map_err(|_| DropReason::MalformedPacket)?;
         if let Message::Transport(packet) = message {
             self.handle_ingress_transport(addr, &packet)?;
         } else {
@@ -166,6 +232,12 @@ impl PeerServer {
     }
 
     fn queue_ingress_handshake(&mut self, addr: Endpoint, message: Message) {
+        if let Message::Initiation(_) = message {
+            if self.handshake_counter.record() {
+                self.under_load_until = Instant::now() + *UNDER_LOAD_TIME;
+            }
+        }
+
         // TODO: max queue size management
         self.handshakes.push_back((addr, message));
         task::current().notify();
@@ -187,7 +259,7 @@ impl PeerServer {
 
     fn handle_ingress_handshake_init(&mut self, addr: Endpoint, packet: &Initiation) -> Result<(), Error> {
         let shared_state      = self.shared_state.clone();
-        let mut state         = shared_state.borrow_mut();
+        let mut state         = shared_state.write().unwrap();
         let (mac_in, mac_out) = packet.split_at(116);
         self.cookie.verify_mac1(&mac_in[..], &mac_out[..16])?;
 
@@ -203,25 +275,37 @@ impl PeerServer {
             }
 
             if !self.rate_limiter.allow(&addr.ip()) {
-                bail!("rejected by rate limiter.");
+                return Err(DropReason::RateLimited.into());
             }
         }
 
         debug!("got handshake initiation request (0x01)");
 
         let handshake = Peer::process_incoming_handshake(
-            &state.interface_info.private_key.ok_or_else(|| err_msg("no private key!"))?,
+            state.interface_info.private_key.as_ref().ok_or_else(|| err_msg("no private key!"))?.as_ref(),
+            &state.interface_info.noise_protocol,
             packet)?;
 
-        let peer_ref = state.pubkey_map.get(handshake.their_pubkey())
-            .ok_or_else(|| err_msg("unknown peer pubkey"))?.clone();
+        let peer_ref = state.get_peer_by_pubkey(handshake.their_pubkey().try_into().map_err(|_| DropReason::NoMatchingPeer)?)
+            .ok_or(DropReason::NoMatchingPeer)?;
 
-        let index = self.unused_index(&mut state);
-        let (response, dead_index) = peer_ref.borrow_mut().complete_incoming_handshake(addr, index, handshake)?;
+        let our_pub_key = state.interface_info.pub_key.ok_or_else(|| err_msg("no public key!"))?;
+        let index       = state.allocate_index()?;
+        let (response, dead_index) = {
+            let mut peer = peer_ref.lock().unwrap();
+            if let Some(abandoned) = peer.resolve_initiation_conflict(&our_pub_key, &handshake)? {
+                debug!("initiation crossed with {}'s, responding instead (abandoned {})", peer.info, abandoned);
+                state.unmap_index(abandoned);
+            }
+            let previous = peer.info.endpoint;
+            let result   = peer.complete_incoming_handshake(addr, index, handshake)?;
+            state.note_endpoint(&peer, previous);
+            result
+        };
         if let Some(index) = dead_index {
-            let _ = state.index_map.remove(&index);
+            state.unmap_index(index);
         }
-        let _ = state.index_map.insert(index, peer_ref.clone());
+        state.map_index(index, peer_ref.clone());
 
         self.send_to_peer((addr, response))?;
         info!("sent handshake response (index {}).", index);
@@ -245,27 +329,29 @@ impl PeerServer {
             }
 
             if !self.rate_limiter.allow(&addr.ip()) {
-                bail!("rejected by rate limiter.");
+                return Err(DropReason::RateLimited.into());
             }
         }
         debug!("got handshake response (0x02)");
 
-        let mut state = self.shared_state.borrow_mut();
+        let mut state = self.shared_state.write().unwrap();
         let our_index = LittleEndian::read_u32(&packet[8..]);
-        let peer_ref  = state.index_map.get(&our_index)
-            .ok_or_else(|| format_err!("unknown our_index ({})", our_index))?
-            .clone();
-        let mut peer = peer_ref.borrow_mut();
+        let peer_ref  = state.get_peer_by_index(our_index).ok_or(DropReason::UnknownSessionIndex)?;
+        let mut peer   = peer_ref.lock().unwrap();
+        let previous   = peer.info.endpoint;
         let dead_index = peer.process_incoming_handshake_response(addr, packet)?;
         if let Some(index) = dead_index {
-            let _ = state.index_map.remove(&index);
+            state.unmap_index(index);
         }
+        state.note_endpoint(&peer, previous);
+        state.session_established(&peer, our_index);
 
         if peer.ready_for_transport() {
-            if !peer.outgoing_queue.is_empty() {
-                debug!("sending {} queued egress packets", peer.outgoing_queue.len());
-                while let Some(packet) = peer.outgoing_queue.pop_front() {
-                    self.send_to_peer(peer.handle_outgoing_transport(packet.payload())?)?;
+            let queued = peer.take_queued_egress();
+            if !queued.is_empty() {
+                debug!("sending {} queued egress packets", queued.len());
+                for packet in queued {
+                    self.send_transport(&mut peer, &packet)?;
                 }
             } else {
                 self.send_to_peer(peer.handle_outgoing_transport(&[])?)?;
@@ -275,42 +361,50 @@ impl PeerServer {
         }
         info!("handshake response received, current session now {}", our_index);
 
-        self.timer.send_after(*WIPE_AFTER_TIME, TimerMessage::Wipe(Rc::downgrade(&peer_ref)));
+        self.timer.send_after(peer.timer_config.reject_after_time, TimerMessage::Reject(Arc::downgrade(&peer_ref), our_index));
+        self.timer.send_after(peer.timer_config.wipe_after_time(), TimerMessage::Wipe(Arc::downgrade(&peer_ref)));
         Ok(())
     }
 
     fn handle_ingress_cookie_reply(&mut self, _addr: Endpoint, packet: &CookieReply) -> Result<(), Error> {
-        let     state    = self.shared_state.borrow_mut();
-        let     peer_ref = state.index_map.get(&packet.receiver_index()).ok_or_else(|| err_msg("unknown our_index"))?.clone();
-        let mut peer     = peer_ref.borrow_mut();
+        let     state    = self.shared_state.write().unwrap();
+        let     peer_ref = state.get_peer_by_index(packet.receiver_index()).ok_or(DropReason::UnknownSessionIndex)?;
+        let mut peer     = peer_ref.lock().unwrap();
 
         peer.consume_cookie_reply(packet)
     }
 
     fn handle_ingress_transport(&mut self, addr: Endpoint, packet: &Transport) -> Result<(), Error> {
-        let peer_ref = self.shared_state.borrow().index_map.get(&packet.our_index())
-            .ok_or_else(|| err_msg("unknown our_index"))?.clone();
+        let peer_ref = self.shared_state.read().unwrap().get_peer_by_index(packet.our_index())
+            .ok_or(DropReason::UnknownSessionIndex)?;
 
         let (raw_packet, needs_handshake) = {
-            let mut peer = peer_ref.borrow_mut();
-            let mut state = self.shared_state.borrow_mut();
+            let mut state = self.shared_state.write().unwrap();
+            let mut peer  = peer_ref.lock().unwrap();
+            let previous  = peer.info.endpoint;
             let (raw_packet, transition) = peer.handle_incoming_transport(addr, packet)?;
+            state.note_endpoint(&peer, previous);
 
             if let SessionTransition::Transition(possible_dead_index) = transition {
                 if let Some(index) = possible_dead_index {
-                    let _ = state.index_map.remove(&index);
+                    state.unmap_index(index);
                 }
+                state.session_established(&peer, packet.our_index());
 
-                let outgoing: Vec<UtunPacket> = peer.outgoing_queue.drain(..).collect();
-
-                for packet in outgoing {
-                    match peer.handle_outgoing_transport(packet.payload()) {
-                        Ok(message) => self.send_to_peer(message)?,
-                        Err(e) => warn!("failed to encrypt packet: {}", e)
+                for packet in peer.take_queued_egress() {
+                    if let Err(e) = self.send_transport(&mut peer, &packet) {
+                        warn!("failed to encrypt packet: {}", e);
                     }
                 }
 
-                self.timer.send_after(*WIPE_AFTER_TIME, TimerMessage::Wipe(Rc::downgrade(&peer_ref)));
+                self.timer.send_after(peer.timer_config.reject_after_time, TimerMessage::Reject(Arc::downgrade(&peer_ref), packet.our_index()));
+                self.timer.send_after(peer.timer_config.wipe_after_time(), TimerMessage::Wipe(Arc::downgrade(&peer_ref)));
+            }
+
+            let echo = peer.handle_outgoing_latency_echo()
+                .and_then(|echo| echo.map_or(Ok(()), |echo| self.send_to_peer(echo)));
+            if let Err(e) = echo {
+                warn!("failed to echo latency probe: {}", e);
             }
             (raw_packet, peer.needs_new_handshake(false))
         };
@@ -325,41 +419,85 @@ impl PeerServer {
             return Ok(()) // short-circuit on keep-alives
         }
 
-        self.shared_state.borrow_mut().router.validate_source(&raw_packet, &peer_ref)?;
+        let mirror = {
+            let state = self.shared_state.read().unwrap();
+            state.validate_source(&raw_packet, &peer_ref)?;
+            state.interface_info.mirror_decrypted
+        };
+        if let Some(addr) = mirror {
+            self.mirror(&raw_packet, addr);
+        }
         trace!("received transport packet");
         self.send_to_tunnel(raw_packet)?;
         Ok(())
     }
 
     fn handle_egress_packet(&mut self, packet: UtunPacket) -> Result<(), Error> {
-        ensure!(!packet.payload().is_empty() && packet.payload().len() <= MAX_CONTENT_SIZE, "egress packet outside of size bounds");
+        if packet.payload().is_empty() {
+            return Err(DropReason::MalformedPacket.into());
+        }
+        if packet.payload().len() > MAX_CONTENT_SIZE {
+            return Err(DropReason::OversizedPacket.into());
+        }
+
+        let (mtu, mirror) = {
+            let state = self.shared_state.read().unwrap();
+            (state.interface_info.mtu.unwrap_or(DEFAULT_MTU), state.interface_info.mirror_plaintext_out)
+        };
+        if packet.payload().len() > mtu as usize {
+            if let Some(reply) = icmp::packet_too_big(packet.payload(), mtu) {
+                self.send_to_tunnel(reply)?;
+            }
+            debug!("egress packet larger than the tunnel MTU ({} > {})", packet.payload().len(), mtu);
+            return Err(DropReason::OversizedPacket.into());
+        }
 
-        let peer_ref = self.shared_state.borrow_mut().router.route_to_peer(packet.payload())
-            .ok_or_else(|| err_msg("no route to peer"))?;
+        let destination = IpPacket::new(packet.payload()).map(|packet| packet.destination());
+        let peer_ref    = destination.and_then(|addr| self.shared_state.read().unwrap().get_peer_for_ip(addr))
+            .ok_or(DropReason::NoMatchingPeer)?;
 
-        let needs_handshake = {
-            let mut peer = peer_ref.borrow_mut();
-            let needs_handshake = peer.needs_new_handshake(true);
+        if let Some(addr) = mirror {
+            self.mirror(packet.payload(), addr);
+        }
+
+        {
+            let mut peer = peer_ref.lock().unwrap();
             peer.queue_egress(packet);
 
             if peer.ready_for_transport() {
-                if peer.outgoing_queue.len() > 1 {
-                    debug!("sending {} queued egress packets", peer.outgoing_queue.len());
+                let queued = peer.take_queued_egress();
+                if queued.len() > 1 {
+                    debug!("sending {} queued egress packets", queued.len());
                 }
 
-                while let Some(packet) = peer.outgoing_queue.pop_front() {
-                    self.send_to_peer(peer.handle_outgoing_transport(packet.payload())?)?;
+                for packet in queued {
+                    self.send_transport(&mut peer, &packet)?;
                 }
             }
+        }
 
-            needs_handshake
-        };
+        let _ = self.trigger_handshake_if_needed(&peer_ref)?;
+        Ok(())
+    }
 
-        if needs_handshake {
-            debug!("sending handshake init on send because peer says it needs it");
-            self.send_handshake_init(&peer_ref)?;
+    /// Starts a handshake on behalf of an outbound packet if `peer_ref` has no session to send
+    /// it with, or its session is due a rekey. The packet waits in the peer's queue until the
+    /// handshake completes. Returns whether an initiation went out; while one is still waiting
+    /// for its response, no other is sent.
+    fn trigger_handshake_if_needed(&mut self, peer_ref: &SharedPeer) -> Result<bool, Error> {
+        {
+            let peer = peer_ref.lock().unwrap();
+            if !peer.needs_new_handshake(true) {
+                return Ok(false);
+            }
+            if !peer.initiation_due() {
+                trace!("handshake with {} already under way", peer.info);
+                return Ok(false);
+            }
+            debug!("sending handshake init on send to {} ({:?})", peer.info, peer.session_state());
         }
-        Ok(())
+        let _ = self.send_handshake_init(peer_ref)?;
+        Ok(true)
     }
 
     fn send_cookie_reply(&mut self, addr: Endpoint, mac1: &[u8], index: u32) -> Result<(), Error> {
@@ -373,27 +511,32 @@ impl PeerServer {
 
     fn send_handshake_init(&mut self, peer_ref: &SharedPeer) -> Result<u32, Error> {
         let     shared_state = self.shared_state.clone();
-        let mut state        = shared_state.borrow_mut();
-        let mut peer         = peer_ref.borrow_mut();
+        let mut state        = shared_state.write().unwrap();
+        let mut peer         = peer_ref.lock().unwrap();
 
-        if peer.timers.handshake_initialized.elapsed() < *REKEY_TIMEOUT {
-            bail!("skipping handshake init because of REKEY_TIMEOUT");
+        if !peer.initiation_due() {
+            bail!("skipping handshake init because of retry timeout");
         }
 
-        let private_key = &state.interface_info.private_key.ok_or_else(|| err_msg("no private key!"))?;
-        let new_index   = self.unused_index(&mut state);
+        let private_key = state.interface_info.private_key.clone().ok_or_else(|| err_msg("no private key!"))?;
+        let new_index   = state.allocate_index()?;
+        let protocol    = state.interface_info.noise_protocol.clone();
 
-        let (endpoint, init_packet, dead_index) = peer.initiate_new_session(private_key, new_index)?;
-        let _ = state.index_map.insert(new_index, peer_ref.clone());
+        let (endpoint, init_packet, dead_index) = peer.initiate_new_session(private_key.as_ref(), &protocol, new_index)?;
+        state.map_index(new_index, peer_ref.clone());
 
         if let Some(index) = dead_index {
             trace!("removing abandoned 'next' session ({}) from index map", index);
-            let _ = state.index_map.remove(&index);
+            state.unmap_index(index);
         }
 
         self.send_to_peer((endpoint, init_packet))?;
-        peer.timers.handshake_initialized = Timestamp::now();
-        self.timer.send_after(*REKEY_TIMEOUT, TimerMessage::Rekey(Rc::downgrade(&peer_ref), new_index));
+        if peer.timers.handshake_attempts == 0 {
+            peer.timers.rekey_attempt_started = Timestamp::now();
+        }
+        let retry_timeout = peer.timer_config.handshake_retry_timeout(peer.timers.handshake_attempts);
+        let handle        = self.timer.send_after(retry_timeout, TimerMessage::Rekey(Arc::downgrade(&peer_ref), new_index));
+        peer.timers.rekey_timer = Some(handle);
         Ok(new_index)
     }
 
@@ -405,16 +548,36 @@ impl PeerServer {
                     .ok_or_else(|| err_msg("peer no longer there"))?;
                 {
                     // TODO: clear sticky source endpoint if retrying, in case that is the problem
-                    let mut peer = upgraded_peer_ref.borrow_mut();
+                    let shared_state  = self.shared_state.clone();
+                    let mut state     = shared_state.write().unwrap();
+                    let mut peer      = upgraded_peer_ref.lock().unwrap();
+                    let retry_timeout = peer.timer_config.handshake_retry_timeout(peer.timers.handshake_attempts);
+                    let stale_timeout = peer.timer_config.stale_session_timeout();
 
                     match peer.find_session(our_index) {
                         Some((_, SessionType::Next)) => {
-                            if peer.timers.handshake_initialized.elapsed() < *REKEY_TIMEOUT {
-                                let wait = *REKEY_TIMEOUT - peer.timers.handshake_initialized.elapsed();
-                                self.timer.send_after(wait, Rekey(peer_ref.clone(), our_index));
+                            if peer.timers.handshake_initialized.elapsed() < retry_timeout {
+                                let wait = retry_timeout - peer.timers.handshake_initialized.elapsed();
+                                let handle = self.timer.send_after(wait, Rekey(peer_ref.clone(), our_index));
+                                peer.timers.rekey_timer = Some(handle);
                                 bail!("too soon since last init sent, waiting {:?} ({})", wait, our_index);
-                            } else if peer.timers.handshake_attempts >= *MAX_HANDSHAKE_ATTEMPTS {
-                                bail!("REKEY_ATTEMPT_TIME exceeded, giving up.");
+                            } else if peer.rekey_attempt_expired() && peer.fail_over_endpoint() {
+                                info!("handshake with {} unanswered, trying its next endpoint ({:?}).",
+                                      peer.info, peer.info.endpoint.map(|endpoint| *endpoint));
+                                peer.timers.handshake_attempts    = 0;
+                                peer.timers.rekey_attempt_started = Timestamp::now();
+                            } else if peer.rekey_attempt_expired() {
+                                info!("handshake with {} unanswered after {} attempts, giving up and dropping {} queued packets.",
+                                      peer.info, peer.timers.handshake_attempts, peer.outgoing_queue.len());
+                                peer.outgoing_queue.clear();
+                                peer.timers.rekey_attempt_started = Timestamp::unset();
+                                if let Some(session) = peer.sessions.next.take() {
+                                    state.unmap_index(session.our_index);
+                                }
+                                peer.mark_dead();
+                                let delay = peer.schedule_reconnect();
+                                self.timer.send_after(delay, Reconnect(peer_ref.clone()));
+                                bail!("rekey_attempt_time exceeded, giving up for {:?}.", delay);
                             }
                             peer.timers.handshake_attempts += 1;
                             debug!("sending hanshake init (rekey attempt #{})", peer.timers.handshake_attempts);
@@ -423,10 +586,10 @@ impl PeerServer {
                             let since_last_send = peer.timers.data_sent.elapsed();
                             let since_last_auth_recv = peer.timers.authenticated_received.elapsed();
                             if since_last_send > since_last_auth_recv {
-                                self.timer.send_after(*STALE_SESSION_TIMEOUT, Rekey(peer_ref.clone(), our_index));
-                                bail!("stale rekey tick (waiting ~{}s, inactive)", STALE_SESSION_TIMEOUT.as_secs());
-                            } else if since_last_auth_recv <= *STALE_SESSION_TIMEOUT {
-                                let wait = *STALE_SESSION_TIMEOUT - since_last_auth_recv;
+                                self.timer.send_after(stale_timeout, Rekey(peer_ref.clone(), our_index));
+                                bail!("stale rekey tick (waiting ~{}s, inactive)", stale_timeout.as_secs());
+                            } else if since_last_auth_recv <= stale_timeout {
+                                let wait = stale_timeout - since_last_auth_recv;
                                 self.timer.send_after(wait, Rekey(peer_ref.clone(), our_index));
                                 bail!("stale rekey tick (waiting ~{}s, not enough time passed yet)", wait.as_secs());
                             }
@@ -440,26 +603,38 @@ impl PeerServer {
             },
             PassiveKeepAlive(peer_ref) => {
                 let mut upgraded_peer_ref = peer_ref.upgrade().ok_or_else(|| err_msg("peer no longer there"))?;
-                let mut peer = upgraded_peer_ref.borrow_mut();
+                let (unresponsive, keepalive_timeout) = {
+                    let peer = upgraded_peer_ref.lock().unwrap();
+                    (peer.is_unresponsive(), peer.timer_config.keepalive_timeout)
+                };
+                if unresponsive {
+                    self.timer.send_after(keepalive_timeout, PassiveKeepAlive(peer_ref.clone()));
+                    debug!("nothing heard back within keepalive_timeout, sending handshake init instead of keepalive");
+                    self.send_handshake_init(&upgraded_peer_ref)?;
+                    return Ok(());
+                }
+
+                let measure_latency = self.measure_latency();
+                let mut peer        = upgraded_peer_ref.lock().unwrap();
                 {
                     if peer.sessions.current.is_none() {
-                        self.timer.send_after(*KEEPALIVE_TIMEOUT, PassiveKeepAlive(peer_ref.clone()));
+                        self.timer.send_after(keepalive_timeout, PassiveKeepAlive(peer_ref.clone()));
                         bail!("passive keepalive skip: no active session. waiting until there is one.");
                     } else if peer.info.keepalive.is_some() {
-                        self.timer.send_after(*KEEPALIVE_TIMEOUT, PassiveKeepAlive(peer_ref.clone()));
+                        self.timer.send_after(keepalive_timeout, PassiveKeepAlive(peer_ref.clone()));
                         bail!("passive keepalive skip: persistent keepalive set.");
                     }
 
                     let since_last_recv = peer.timers.data_received.elapsed();
                     let since_last_send = peer.timers.data_sent.elapsed();
                     if peer.timers.keepalive_sent {
-                        self.timer.send_after(*KEEPALIVE_TIMEOUT, PassiveKeepAlive(peer_ref.clone()));
-                        bail!("passive keepalive already sent (waiting {}s to see if session survives)", KEEPALIVE_TIMEOUT.as_secs());
+                        self.timer.send_after(keepalive_timeout, PassiveKeepAlive(peer_ref.clone()));
+                        bail!("passive keepalive already sent (waiting {}s to see if session survives)", keepalive_timeout.as_secs());
                     } else if since_last_send < since_last_recv {
-                        self.timer.send_after(*KEEPALIVE_TIMEOUT, PassiveKeepAlive(peer_ref.clone()));
+                        self.timer.send_after(keepalive_timeout, PassiveKeepAlive(peer_ref.clone()));
                         bail!("passive keepalive tick (last data was send not recv)")
-                    } else if since_last_recv < *KEEPALIVE_TIMEOUT {
-                        let wait = *KEEPALIVE_TIMEOUT - since_last_recv;
+                    } else if since_last_recv < keepalive_timeout {
+                        let wait = keepalive_timeout - since_last_recv;
                         self.timer.send_after(wait, PassiveKeepAlive(peer_ref.clone()));
                         bail!("passive keepalive tick (waiting ~{}s due to last recv time)", wait.as_secs());
                     } else {
@@ -467,14 +642,15 @@ impl PeerServer {
                     }
                 }
 
-                self.send_to_peer(peer.handle_outgoing_transport(&[])?)?;
+                self.send_keepalive(&mut peer, measure_latency)?;
                 debug!("sent passive keepalive packet");
 
-                self.timer.send_after(*KEEPALIVE_TIMEOUT, PassiveKeepAlive(peer_ref.clone()));
+                self.timer.send_after(keepalive_timeout, PassiveKeepAlive(peer_ref.clone()));
             },
             PersistentKeepAlive(peer_ref) => {
                 let mut upgraded_peer_ref = peer_ref.upgrade().ok_or_else(|| err_msg("peer no longer there"))?;
-                let mut peer = upgraded_peer_ref.borrow_mut();
+                let measure_latency       = self.measure_latency();
+                let mut peer              = upgraded_peer_ref.lock().unwrap();
 
                 if let Some(persistent_keepalive) = peer.info.persistent_keepalive() {
                     let since_last_auth_any = peer.timers.authenticated_traversed.elapsed();
@@ -485,22 +661,47 @@ impl PeerServer {
                         bail!("persistent keepalive tick (waiting ~{}s due to last authenticated packet time)", wait.as_secs());
                     }
 
-                    self.send_to_peer(peer.handle_outgoing_transport(&[])?)?;
+                    // reschedule before sending, so a missing session doesn't end the keepalive chain
                     let handle = self.timer.send_after(persistent_keepalive, PersistentKeepAlive(peer_ref.clone()));
                     peer.timers.persistent_timer = Some(handle);
+
+                    ensure!(peer.ready_for_transport(), "persistent keepalive skip: no active session.");
+                    self.send_keepalive(&mut peer, measure_latency)?;
                     debug!("sent persistent keepalive packet");
                 } else {
                     bail!("no persistent keepalive set for peer (likely unset between the time the timer was started and now).");
                 }
             },
+            Reconnect(peer_ref) => {
+                let upgraded_peer_ref = peer_ref.upgrade().ok_or_else(|| err_msg("peer no longer there"))?;
+                {
+                    let mut peer = upgraded_peer_ref.lock().unwrap();
+                    ensure!(peer.reconnect_due(), "reconnect skip: heard from {} in the meantime", peer.info);
+                    peer.timers.next_reconnect     = None;
+                    peer.timers.handshake_attempts = 0;
+                    debug!("retrying handshake with {} after backoff", peer.info);
+                }
+
+                self.send_handshake_init(&upgraded_peer_ref)?;
+            },
+            Reject(peer_ref, our_index) => {
+                let upgraded_peer_ref = peer_ref.upgrade().ok_or_else(|| err_msg("peer no longer there"))?;
+                let mut state = self.shared_state.write().unwrap();
+                let mut peer  = upgraded_peer_ref.lock().unwrap();
+                let current   = peer.sessions.current.as_ref().map_or(false, |session| session.our_index == our_index);
+                ensure!(current, "reject skip: session {} is no longer current for {}", our_index, peer.info);
+                debug!("session {} with {} is past REJECT-AFTER-TIME", our_index, peer.info);
+                state.session_expired(&mut peer);
+            },
             Wipe(peer_ref) => {
                 let mut upgraded_peer_ref = peer_ref.upgrade().ok_or_else(|| err_msg("peer no longer there"))?;
-                let mut peer = upgraded_peer_ref.borrow_mut();
-                let mut state = self.shared_state.borrow_mut();
-                if peer.timers.handshake_completed.elapsed() >= *WIPE_AFTER_TIME {
+                let mut state = self.shared_state.write().unwrap();
+                let mut peer  = upgraded_peer_ref.lock().unwrap();
+                if peer.timers.handshake_completed.elapsed() >= peer.timer_config.wipe_after_time() {
                     info!("wiping all old sessions due to staleness timeout for peer {}", peer.info);
-                    for index in peer.sessions.wipe() {
-                        let _ = state.index_map.remove(&index);
+                    state.session_expired(&mut peer);
+                    for index in peer.expire() {
+                        state.unmap_index(index);
                     }
                 } else {
                     debug!("skipping wipe timer for since activity has happened since triggered. ({})", peer.info);
@@ -514,9 +715,9 @@ impl PeerServer {
         use self::ChannelMessage::*;
         match event {
             NewPrivateKey => {
-                let pub_key = self.shared_state.borrow().interface_info.pub_key;
+                let pub_key = self.shared_state.read().unwrap().interface_info.pub_key;
                 if let Some(ref pub_key) = pub_key {
-                    self.cookie = cookie::Validator::new(pub_key);
+                    self.cookie = cookie::Validator::new(pub_key.as_ref());
                     if self.udp.is_none() {
                         self.rebind().unwrap();
                     }
@@ -526,24 +727,25 @@ impl PeerServer {
                 }
             },
             NewPeer(peer_ref) => {
-                let mut peer = peer_ref.borrow_mut();
-                self.timer.send_after(*KEEPALIVE_TIMEOUT, TimerMessage::PassiveKeepAlive(Rc::downgrade(&peer_ref)));
+                let mut peer = peer_ref.lock().unwrap();
+                self.timer.send_after(peer.timer_config.keepalive_timeout, TimerMessage::PassiveKeepAlive(Arc::downgrade(&peer_ref)));
                 if let Some(keepalive) = peer.info.persistent_keepalive() {
-                    let handle = self.timer.send_after(keepalive, TimerMessage::PersistentKeepAlive(Rc::downgrade(&peer_ref)));
+                    let handle = self.timer.send_after(keepalive, TimerMessage::PersistentKeepAlive(Arc::downgrade(&peer_ref)));
                     peer.timers.persistent_timer = Some(handle);
                 }
             },
             NewPersistentKeepalive(peer_ref) => {
-                let mut peer = peer_ref.borrow_mut();
+                let measure_latency = self.measure_latency();
+                let mut peer        = peer_ref.lock().unwrap();
                 if let Some(ref mut handle) = peer.timers.persistent_timer {
                     handle.cancel();
                     debug!("sent cancel signal to old persistent_timer.");
                 }
 
                 if let Some(keepalive) = peer.info.persistent_keepalive() {
-                    let handle = self.timer.send_after(keepalive, TimerMessage::PersistentKeepAlive(Rc::downgrade(&peer_ref)));
+                    let handle = self.timer.send_after(keepalive, TimerMessage::PersistentKeepAlive(Arc::downgrade(&peer_ref)));
                     peer.timers.persistent_timer = Some(handle);
-                    self.send_to_peer(peer.handle_outgoing_transport(&[])?)?;
+                    self.send_keepalive(&mut peer, measure_latency)?;
                     debug!("set new keepalive timer and immediately sent new keepalive packet.");
                 }
             }
@@ -553,6 +755,12 @@ impl PeerServer {
                     udp.set_mark(mark)?;
                 }
             }
+            NewRateLimit(limit) => self.handshake_counter.threshold = limit,
+            NewPreserveDscp(preserve) => self.preserve_dscp = preserve,
+            NewUdpBuffers => {
+                let state = self.shared_state.clone();
+                self.apply_udp_buffers(&mut state.write().unwrap())?;
+            },
             _ => {}
         }
         Ok(())
@@ -570,7 +778,8 @@ impl Future for PeerServer {
             // Handle config events
             match self.channel.rx.poll() {
                 Ok(Async::Ready(Some(event))) => {
-                    let _ = self.handle_incoming_event(event);
+                    note_dequeued(&self.config_depth);
+                    let _ = self.handle_incoming_event(event).map_err(|e| warn!("failed to apply config change: {}", e));
                 },
                 Ok(Async::NotReady)    => { break; },
                 Ok(Async::Ready(None)) => bail!("config stream ended unexpectedly"),
@@ -595,7 +804,7 @@ impl Future for PeerServer {
             // Handle UDP packets from the outside world
                 match self.udp.as_mut().unwrap().ingress.poll() {
                     Ok(Async::Ready(Some((addr, packet)))) => {
-                        let _ = self.handle_ingress_packet(addr, packet).map_err(|e| warn!("UDP ERR: {:?}", e));
+                        let _ = self.handle_ingress_packet(addr, packet).map_err(|e| self.note_drop("UDP ERR", &e));
                     },
                     Ok(Async::NotReady)    => { break; },
                     Ok(Async::Ready(None)) => bail!("incoming udp stream ended unexpectedly"),
@@ -608,7 +817,7 @@ impl Future for PeerServer {
             // Handle packets coming from the local tunnel
             match self.outgoing.rx.poll() {
                 Ok(Async::Ready(Some(packet))) => {
-                    let _ = self.handle_egress_packet(packet).map_err(|e| warn!("UDP ERR: {:?}", e));
+                    let _ = self.handle_egress_packet(packet).map_err(|e| self.note_drop("UDP ERR", &e));
                 },
                 Ok(Async::NotReady)    => { break; },
                 Ok(Async::Ready(None)) => bail!("outgoing udp stream ended unexpectedly"),
@@ -617,9 +826,37 @@ impl Future for PeerServer {
         }
 
         if let Some((addr, message)) = self.handshakes.pop_front() {
-            let _ = self.handle_ingress_handshake(addr, &message).map_err(|e| warn!("handshake err: {:?}", e));
+            let _ = self.handle_ingress_handshake(addr, &message).map_err(|e| self.note_drop("handshake err", &e));
         }
 
         Ok(Async::NotReady)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Mutex, RwLock};
+    use tokio_core::reactor::Core;
+    use types::PeerInfo;
+
+    #[test]
+    fn persistent_keepalive_outlives_missing_session() {
+        let mut core   = Core::new().unwrap();
+        let state      = Arc::new(RwLock::new(State::default()));
+        let (tx, _rx)  = mpsc::unbounded();
+        let mut server = PeerServer::new(core.handle(), state, tx).unwrap();
+        let peer       = Arc::new(Mutex::new(Peer::new(PeerInfo { keepalive: Some(1), ..Default::default() })));
+        assert!(!peer.lock().unwrap().ready_for_transport());
+
+        let err = server.handle_timer(TimerMessage::PersistentKeepAlive(Arc::downgrade(&peer))).unwrap_err();
+        assert!(err.to_string().contains("no active session"));
+        assert!(peer.lock().unwrap().timers.persistent_timer.is_some());
+
+        // the next tick still comes round, so keepalives pick up again once there's a session.
+        match core.run((&mut server.timer).into_future()) {
+            Ok((Some(TimerMessage::PersistentKeepAlive(peer_ref)), _)) => assert!(Arc::ptr_eq(&peer_ref.upgrade().unwrap(), &peer)),
+            _ => panic!("expected the persistent keepalive to be rescheduled"),
+        }
+    }
+}
@@ -0,0 +1,300 @@
+/* SPDX-License-Identifier: GPL-2.0
+ *
+ * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
+ */
+
+//! Bringing a running interface in line with a (re-read) configuration file.
+
+use config_file::InterfaceConfig;
+use failure::Error;
+use interface::State;
+use interface::config::{ConfigurationService, UpdateEvent};
+use interface::peer_server::ChannelMessage;
+use types::{InterfaceInfo, PeerInfo, PrivateKey, PublicKey, constant_time_eq};
+
+/// The interface settings that differ, holding their new values. Settings the new
+/// configuration leaves out are left as they are.
+#[derive(Debug, Default)]
+pub struct InterfaceInfoDiff {
+    pub private_key : Option<PrivateKey>,
+    pub listen_port : Option<u16>,
+    pub fwmark      : Option<u32>,
+}
+
+impl InterfaceInfoDiff {
+    pub fn between(old: &InterfaceInfo, new: &InterfaceInfo) -> Option<InterfaceInfoDiff> {
+        let mut diff = InterfaceInfoDiff::default();
+        if new.private_key.is_some() && old.private_key != new.private_key {
+            diff.private_key = Some(new.private_key.as_ref().map_or_else(PrivateKey::default, |key| PrivateKey::clone(key)));
+        }
+        if new.listen_port.is_some() && old.listen_port != new.listen_port {
+            diff.listen_port = new.listen_port;
+        }
+        if old.fwmark.unwrap_or(0) != new.fwmark.unwrap_or(0) {
+            diff.fwmark = Some(new.fwmark.unwrap_or(0));
+        }
+
+        if diff.private_key.is_none() && diff.listen_port.is_none() && diff.fwmark.is_none() {
+            None
+        } else {
+            Some(diff)
+        }
+    }
+}
+
+/// Which of a kept peer's settings differ.
+#[derive(Debug, Default, PartialEq)]
+pub struct PeerInfoDiff {
+    pub endpoint    : bool,
+    pub psk         : bool,
+    pub allowed_ips : bool,
+    pub keepalive   : bool,
+}
+
+impl PeerInfoDiff {
+    pub fn between(old: &PeerInfo, new: &PeerInfo) -> PeerInfoDiff {
+        PeerInfoDiff {
+            endpoint    : old.endpoint_host != new.endpoint_host
+                || (new.endpoint_host.is_none() && old.endpoint.map(|e| *e) != new.endpoint.map(|e| *e)),
+            psk         : !psk_eq(old.psk, new.psk),
+            allowed_ips : old.allowed_ips != new.allowed_ips,
+            keepalive   : old.keepalive.unwrap_or(0) != new.keepalive.unwrap_or(0),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == PeerInfoDiff::default()
+    }
+
+    /// The updates carrying just the changed fields of `info`, so nothing else about the peer,
+    /// its session included, is touched.
+    fn events(&self, info: &PeerInfo) -> Vec<UpdateEvent> {
+        let mut update = PeerInfo { pub_key: info.pub_key, ..Default::default() };
+        if self.endpoint {
+            update.endpoint      = info.endpoint;
+            update.endpoint_host = info.endpoint_host.clone();
+            update.endpoints     = info.endpoints.clone();
+        }
+        if self.psk {
+            update.psk = Some(info.psk.unwrap_or([0u8; 32])); // an all-zero key clears the psk
+        }
+        if self.allowed_ips {
+            update.allowed_ips = info.allowed_ips.clone();
+        }
+        if self.keepalive {
+            update.keepalive = Some(info.keepalive.unwrap_or(0)); // zero turns it off
+        }
+        let mut events = vec![UpdateEvent::UpdatePeer(update, self.allowed_ips)];
+        if self.endpoint && info.endpoint.is_none() && info.endpoint_host.is_none() {
+            events.push(UpdateEvent::ClearEndpoint(info.pub_key));
+        }
+        events
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ConfigDiff {
+    pub interface_changes : Option<InterfaceInfoDiff>,
+    pub peers_to_add      : Vec<PeerInfo>,
+    pub peers_to_remove   : Vec<[u8; 32]>,
+    pub peers_to_update   : Vec<(PeerInfo, PeerInfoDiff)>,
+}
+
+impl ConfigDiff {
+    /// What it takes to bring the running `state` in line with `config`.
+    pub fn between(state: &State, config: &InterfaceConfig) -> ConfigDiff {
+        let peers = state.iter_peers().map(|peer| peer.lock().unwrap().info.clone()).collect::<Vec<_>>();
+        diff_from(&state.interface_info, &peers, config)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.interface_changes.is_none() && self.peers_to_add.is_empty()
+            && self.peers_to_remove.is_empty() && self.peers_to_update.is_empty()
+    }
+
+    /// The updates making the changes: interface settings first, then peer removals, updates
+    /// and additions, in that order.
+    pub fn events(&self) -> Vec<UpdateEvent> {
+        let mut events = vec![];
+        if let Some(ref changes) = self.interface_changes {
+            if let Some(ref private_key) = changes.private_key {
+                events.push(UpdateEvent::PrivateKey(private_key.clone()));
+            }
+            if let Some(port) = changes.listen_port {
+                events.push(UpdateEvent::ListenPort(port));
+            }
+            if let Some(mark) = changes.fwmark {
+                events.push(UpdateEvent::Fwmark(mark));
+            }
+        }
+        events.extend(self.peers_to_remove.iter().map(|key| UpdateEvent::RemovePeer(PublicKey(*key))));
+        events.extend(self.peers_to_update.iter().flat_map(|&(ref info, ref changes)| changes.events(info)));
+        events.extend(self.peers_to_add.iter().map(|info| UpdateEvent::UpdatePeer(info.clone(), false)));
+        events
+    }
+}
+
+/// What changes between configurations `old` and `new`. Peers are matched up by public key.
+pub fn diff(old: &InterfaceConfig, new: &InterfaceConfig) -> ConfigDiff {
+    diff_from(&old.interface, &old.peers, new)
+}
+
+fn diff_from(old_interface: &InterfaceInfo, old_peers: &[PeerInfo], new: &InterfaceConfig) -> ConfigDiff {
+    let mut diff = ConfigDiff { interface_changes: InterfaceInfoDiff::between(old_interface, &new.interface), ..Default::default() };
+
+    for old in old_peers {
+        if !new.peers.iter().any(|peer| peer.pub_key == old.pub_key) {
+            diff.peers_to_remove.push(old.pub_key.0);
+        }
+    }
+
+    for info in &new.peers {
+        match old_peers.iter().find(|old| old.pub_key == info.pub_key) {
+            Some(old) => {
+                let changes = PeerInfoDiff::between(old, info);
+                if !changes.is_empty() {
+                    diff.peers_to_update.push((info.clone(), changes));
+                }
+            },
+            None => diff.peers_to_add.push(info.clone()),
+        }
+    }
+    diff
+}
+
+fn psk_eq(a: Option<[u8; 32]>, b: Option<[u8; 32]>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => constant_time_eq(&a, &b),
+        (a, b)             => a.is_none() && b.is_none(),
+    }
+}
+
+/// Applies `diff` to `state`, returning what the peer server needs to hear about. Peers that
+/// are kept keep their sessions.
+pub fn apply_diff(state: &mut State, diff: &ConfigDiff) -> Result<Vec<ChannelMessage>, Error> {
+    info!("applying configuration: {} peers added, {} removed, {} updated.",
+          diff.peers_to_add.len(), diff.peers_to_remove.len(), diff.peers_to_update.len());
+
+    let mut messages = vec![];
+    for event in &diff.events() {
+        if let Some(message) = ConfigurationService::handle_audited_update(state, event, None)? {
+            messages.push(message);
+        }
+    }
+    Ok(messages)
+}
+
+/// Applies `config` to `state`, as on a reload of the configuration file.
+pub fn apply(state: &mut State, config: &InterfaceConfig) -> Result<Vec<ChannelMessage>, Error> {
+    let diff = ConfigDiff::between(state, config);
+    state.interface_info.mtu = config.mtu;
+    apply_diff(state, &diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config_file::parse_str;
+    use std::sync::Arc;
+
+    const KEY_ONE : &str = "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=";
+    const KEY_TWO : &str = "TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=";
+
+    fn config(peers: &[(&str, &str)]) -> InterfaceConfig {
+        let mut s = String::new();
+        for &(key, allowed_ip) in peers {
+            s.push_str(&format!("[Peer]\nPublicKey = {}\nAllowedIPs = {}\n", key, allowed_ip));
+        }
+        parse_str(&s).unwrap()
+    }
+
+    #[test]
+    fn reload_diff() {
+        let mut state = State::default();
+        apply(&mut state, &config(&[(KEY_ONE, "10.0.0.1/32")])).unwrap();
+        let one_key = config(&[(KEY_ONE, "10.0.0.1/32")]).peers[0].pub_key;
+        let one     = state.pubkey_map[&one_key].clone();
+
+        let unchanged = ConfigDiff::between(&state, &config(&[(KEY_ONE, "10.0.0.1/32")]));
+        assert!(unchanged.is_empty());
+
+        let new_config = config(&[(KEY_ONE, "10.0.0.5/32"), (KEY_TWO, "10.0.0.2/32")]);
+        let diff       = ConfigDiff::between(&state, &new_config);
+        assert_eq!((diff.peers_to_add.len(), diff.peers_to_remove.len(), diff.peers_to_update.len()), (1, 0, 1));
+        assert_eq!(diff.peers_to_update[0].1, PeerInfoDiff { allowed_ips: true, ..Default::default() });
+
+        apply(&mut state, &new_config).unwrap();
+        assert_eq!(state.pubkey_map.len(), 2);
+        assert!(Arc::ptr_eq(&state.pubkey_map[&one_key], &one));
+        assert_eq!(one.lock().unwrap().info.allowed_ips, new_config.peers[0].allowed_ips);
+
+        let two_key = new_config.peers[1].pub_key;
+        apply(&mut state, &config(&[(KEY_TWO, "10.0.0.2/32")])).unwrap();
+        assert_eq!(state.pubkey_map.keys().collect::<Vec<_>>(), vec![&two_key]);
+    }
+
+    #[test]
+    fn config_diff() {
+        let old = config(&[(KEY_ONE, "10.0.0.1/32"), (KEY_TWO, "10.0.0.2/32")]);
+        assert!(diff(&old, &old).is_empty());
+
+        let mut new = config(&[(KEY_TWO, "10.0.0.2/32")]);
+        new.peers[0].keepalive    = Some(25);
+        new.interface.listen_port = Some(51820);
+        let changes = diff(&old, &new);
+        assert_eq!(changes.peers_to_remove, vec![old.peers[0].pub_key.0]);
+        assert!(changes.peers_to_add.is_empty());
+        assert_eq!(changes.peers_to_update.len(), 1);
+        assert_eq!(changes.peers_to_update[0].1, PeerInfoDiff { keepalive: true, ..Default::default() });
+        assert_eq!(changes.interface_changes.as_ref().and_then(|changes| changes.listen_port), Some(51820));
+        assert!(changes.interface_changes.as_ref().unwrap().private_key.is_none());
+
+        // only the changed fields go out, so the update can't disturb anything else
+        match changes.events().as_slice() {
+            [UpdateEvent::ListenPort(51820), UpdateEvent::RemovePeer(_), UpdateEvent::UpdatePeer(ref info, false)] => {
+                assert_eq!((info.keepalive, info.psk, info.endpoint.is_none()), (Some(25), None, true));
+                assert!(info.allowed_ips.is_empty());
+            },
+            other => panic!("unexpected events {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reload_keeps_private_key() {
+        let mut state = State::default();
+        ConfigurationService::handle_update(&mut state, &UpdateEvent::PrivateKey(PrivateKey([0x11; 32]))).unwrap();
+
+        // a file without a PrivateKey leaves the running one alone, rather than wiping it
+        assert!(ConfigDiff::between(&state, &config(&[(KEY_ONE, "10.0.0.1/32")])).is_empty());
+        apply(&mut state, &config(&[(KEY_ONE, "10.0.0.1/32")])).unwrap();
+        assert_eq!(state.interface_info.private_key.as_ref().map(|key| key.0), Some([0x11; 32]));
+    }
+
+    #[test]
+    fn reload_removes_and_changes_fields() {
+        let mut state = State::default();
+        let peer      = |fields: &str| parse_str(&format!("[Peer]\nPublicKey = {}\n{}", KEY_ONE, fields)).unwrap();
+        apply(&mut state, &peer(&format!("PresharedKey = {}\nEndpoint = 192.0.2.1:51820\nPersistentKeepalive = 25\n\
+                                          AllowedIPs = 10.0.0.1/32, 10.0.1.0/24\n", KEY_TWO))).unwrap();
+        let key = peer("").peers[0].pub_key;
+
+        let moved = peer("Endpoint = 192.0.2.2:51820\nPersistentKeepalive = 10\nAllowedIPs = 10.0.0.1/32\n");
+        apply(&mut state, &moved).unwrap();
+        assert!(ConfigDiff::between(&state, &moved).is_empty());
+        {
+            let peer = state.pubkey_map[&key].lock().unwrap();
+            assert_eq!(peer.info.endpoint.map(|endpoint| *endpoint), Some("192.0.2.2:51820".parse().unwrap()));
+            assert_eq!(peer.info.keepalive, Some(10));
+            assert_eq!(peer.info.allowed_ips, moved.peers[0].allowed_ips);
+            assert!(peer.info.psk.is_none());
+        }
+
+        // taking fields out of the file takes them off the peer
+        let bare = peer("AllowedIPs = 10.0.0.1/32\n");
+        apply(&mut state, &bare).unwrap();
+        assert!(ConfigDiff::between(&state, &bare).is_empty());
+        let peer = state.pubkey_map[&key].lock().unwrap();
+        assert!(peer.info.endpoint.is_none());
+        assert_eq!(peer.info.persistent_keepalive(), None);
+    }
+}
@@ -0,0 +1,122 @@
+/* SPDX-License-Identifier: GPL-2.0
+ *
+ * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
+ */
+
+//! Periodic re-resolution of peer endpoints that were configured by hostname, so that peers
+//! behind dynamic DNS stay reachable when their address changes.
+
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use futures::{Future, Stream, future::{self, Either}};
+use futures_cpupool::CpuPool;
+use interface::{SharedState, State};
+use tokio_timer::Interval;
+use types::PublicKey;
+
+type Resolution = (PublicKey, String, Result<SocketAddr, String>);
+
+/// Resolves every hostname endpoint right away and then once per `interval`. Lookups block, so
+/// they run on a thread of their own and only the results come back to the reactor. Runs until
+/// it's dropped.
+pub fn task(state: SharedState, interval: Duration) -> impl Future<Item = (), Error = ()> {
+    let pool = CpuPool::new(1);
+    Interval::new(Instant::now(), interval)
+        .map_err(|e| warn!("endpoint resolver timer error: {}", e))
+        .for_each(move |_| {
+            let hosts = endpoint_hosts(&state.read().unwrap());
+            if hosts.is_empty() {
+                return Either::A(future::ok(()));
+            }
+
+            let state = state.clone();
+            Either::B(pool.spawn_fn(move || Ok::<_, ()>(resolve(hosts)))
+                .map(move |resolved| apply(&mut state.write().unwrap(), resolved)))
+        })
+}
+
+fn endpoint_hosts(state: &State) -> Vec<(PublicKey, String)> {
+    state.iter_peers()
+        .filter_map(|peer| {
+            let peer = peer.lock().unwrap();
+            peer.info.endpoint_host.clone().map(|host| (peer.info.pub_key, host))
+        })
+        .collect()
+}
+
+fn resolve(hosts: Vec<(PublicKey, String)>) -> Vec<Resolution> {
+    hosts.into_iter().map(|(pub_key, host)| {
+        let addr = host.to_socket_addrs()
+            .map_err(|e| e.to_string())
+            .and_then(|mut addrs| addrs.next().ok_or_else(|| "no addresses".to_owned()));
+        (pub_key, host, addr)
+    }).collect()
+}
+
+/// Points peers at their freshly resolved addresses, where those changed since the last
+/// lookup. An endpoint the peer has roamed to in the meantime is kept otherwise, as is the
+/// last good address when a lookup fails.
+fn apply(state: &mut State, resolved: Vec<Resolution>) {
+    for (pub_key, host, addr) in resolved {
+        let peer_ref = match state.get_peer_by_pubkey(&pub_key.0) {
+            Some(peer_ref) => peer_ref,
+            None           => continue,
+        };
+        let mut peer = peer_ref.lock().unwrap();
+        if peer.info.endpoint_host.as_ref() != Some(&host) {
+            continue; // reconfigured while we were resolving
+        }
+
+        match addr {
+            Ok(addr) => {
+                if peer.resolved_endpoint == Some(addr) {
+                    continue;
+                }
+                info!("{} now resolves to {} (was {:?}) for {}", host, addr, peer.resolved_endpoint, peer.info);
+                let previous = peer.info.endpoint;
+                peer.resolved_endpoint = Some(addr);
+                peer.info.endpoint     = Some(addr.into());
+                state.note_endpoint(&peer, previous);
+            },
+            Err(e) => warn!("couldn't resolve {} for {}, keeping {:?}: {}", host, peer.info, peer.info.endpoint.map(|e| *e), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use peer::Peer;
+    use std::sync::{Arc, Mutex};
+    use types::PeerInfo;
+
+    #[test]
+    fn resolved_endpoints() {
+        let mut state = State::default();
+        let pub_key   = PublicKey([1u8; 32]);
+        let info      = PeerInfo { pub_key, endpoint_host: Some("vpn.example.com:51820".to_owned()), ..Default::default() };
+        let _ = state.pubkey_map.insert(pub_key, Arc::new(Mutex::new(Peer::new(info))));
+        assert_eq!(endpoint_hosts(&state), vec![(pub_key, "vpn.example.com:51820".to_owned())]);
+
+        let host  = "vpn.example.com:51820".to_owned();
+        let first = "192.0.2.1:51820".parse::<SocketAddr>().unwrap();
+        apply(&mut state, vec![(pub_key, host.clone(), Ok(first))]);
+        apply(&mut state, vec![(pub_key, host.clone(), Err("lookup failed".to_owned()))]);
+        assert_eq!(state.pubkey_map[&pub_key].lock().unwrap().info.endpoint.map(|e| *e), Some(first));
+
+        // the same address again leaves an endpoint the peer roamed to alone.
+        let roamed = "198.51.100.7:4000".parse::<SocketAddr>().unwrap();
+        state.pubkey_map[&pub_key].lock().unwrap().info.endpoint = Some(roamed.into());
+        apply(&mut state, vec![(pub_key, host.clone(), Ok(first))]);
+        assert_eq!(state.pubkey_map[&pub_key].lock().unwrap().info.endpoint.map(|e| *e), Some(roamed));
+
+        let second = "[2001:db8::1]:51820".parse::<SocketAddr>().unwrap();
+        apply(&mut state, vec![(pub_key, host, Ok(second))]);
+        let config = state.pubkey_map[&pub_key].lock().unwrap().to_config_string();
+        assert!(config.contains("endpoint=[2001:db8::1]:51820\n"));
+
+        let resolved = resolve(vec![(pub_key, "127.0.0.1:51820".to_owned())]);
+        assert_eq!(resolved[0].2, Ok("127.0.0.1:51820".parse().unwrap()));
+    }
+}
@@ -0,0 +1,389 @@
+/* SPDX-License-Identifier: GPL-2.0
+ *
+ * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
+ */
+
+//! A JSON management API over HTTP, built with the `rest-api` feature. It's an alternative to the
+//! configuration socket for embedders, and applies changes through the same update path.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::UNIX_EPOCH;
+
+use base64;
+use consts::MAX_CONFIG_MESSAGE_SIZE;
+use failure::{Error, err_msg};
+use futures::{Future, Stream, future, unsync::mpsc};
+use hyper::{self, Method, StatusCode, header::ContentType};
+use hyper::server::{Http, Request, Response, Service};
+use interface::SharedState;
+use interface::config::{ConfigurationService, UpdateEvent};
+use interface::peer_server::ChannelMessage;
+use peer::Peer;
+use serde::Serialize;
+use serde_json;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Handle;
+use types::{PeerInfo, PublicKey, is_valid_endpoint_host};
+
+const PEER_PATH: &str = "/v1/peers/";
+
+pub struct RestServer {
+    addr  : SocketAddr,
+    state : SharedState,
+    tx    : mpsc::UnboundedSender<ChannelMessage>,
+}
+
+impl RestServer {
+    pub fn new(addr: SocketAddr, state: SharedState, tx: mpsc::UnboundedSender<ChannelMessage>) -> Self {
+        RestServer { addr, state, tx }
+    }
+
+    /// Spawns the HTTP listener onto the reactor behind `handle`.
+    pub fn serve(self, handle: &Handle) -> Result<(), Error> {
+        handle.spawn(self.listen(handle)?);
+        Ok(())
+    }
+
+    /// The listener `serve` spawns, for callers that want to stop it again by dropping it.
+    pub(crate) fn listen(self, handle: &Handle) -> Result<impl Future<Item = (), Error = ()>, Error> {
+        let listener = TcpListener::bind(&self.addr, handle)?;
+        let http     = Http::<hyper::Chunk>::new();
+        let service  = RestService { state: self.state, tx: self.tx };
+        info!("serving the management API on http://{}/v1/", self.addr);
+
+        let conn_handle = handle.clone();
+        let server = listener.incoming()
+            .for_each(move |(socket, addr)| {
+                http.bind_connection(&conn_handle, socket, addr, service.clone());
+                Ok(())
+            })
+            .map_err(|e| warn!("management API listener error: {}", e));
+        Ok(server)
+    }
+}
+
+#[derive(Serialize)]
+struct InterfaceSnapshot {
+    public_key  : Option<String>,
+    listen_port : Option<u16>,
+    fwmark      : Option<u32>,
+}
+
+#[derive(Serialize)]
+struct PeerSnapshot {
+    public_key                    : String,
+    endpoint                      : Option<String>,
+    allowed_ips                   : Vec<String>,
+    persistent_keepalive_interval : Option<u16>,
+    tx_bytes                      : u64,
+    rx_bytes                      : u64,
+    last_handshake_time_sec       : Option<u64>,
+}
+
+impl<'a> From<&'a Peer> for PeerSnapshot {
+    fn from(peer: &Peer) -> Self {
+        PeerSnapshot {
+            public_key                    : peer.info.pub_key.to_string(),
+            endpoint                      : peer.info.endpoint.map(|endpoint| (*endpoint).to_string()),
+            allowed_ips                   : peer.info.allowed_ips.iter().map(|&(ip, cidr)| format!("{}/{}", ip, cidr)).collect(),
+            persistent_keepalive_interval : peer.info.keepalive,
+            tx_bytes                      : peer.tx_bytes,
+            rx_bytes                      : peer.rx_bytes,
+            last_handshake_time_sec       : peer.last_handshake_time()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|time| time.as_secs()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Stats {
+    peers        : usize,
+    tx_bytes     : u64,
+    rx_bytes     : u64,
+    drop_reasons : BTreeMap<&'static str, u64>,
+}
+
+/// The body of a `PUT /v1/peers/{key}`. Keys are base64, like everywhere else in the API.
+#[derive(Deserialize)]
+struct PeerUpdate {
+    preshared_key                 : Option<String>,
+    endpoint                      : Option<String>,
+    #[serde(default)]
+    allowed_ips                   : Vec<String>,
+    persistent_keepalive_interval : Option<u16>,
+    #[serde(default)]
+    replace_allowed_ips           : bool,
+}
+
+impl PeerUpdate {
+    fn into_info(self, pub_key: PublicKey) -> Result<PeerInfo, Error> {
+        let mut info = PeerInfo { pub_key, keepalive: self.persistent_keepalive_interval, ..Default::default() };
+        if let Some(psk) = self.preshared_key {
+            let bytes = base64::decode(&psk)?;
+            ensure!(bytes.len() == 32, "preshared key must be 32 bytes, got {}", bytes.len());
+            let mut key = [0u8; 32];
+            key.copy_from_slice(&bytes);
+            info.psk = Some(key);
+        }
+        match self.endpoint.as_ref().map(|endpoint| endpoint.parse::<SocketAddr>()) {
+            Some(Ok(addr)) => {
+                info.endpoint = Some(addr.into());
+                info.endpoints.push(addr);
+            },
+            Some(Err(e))   => {
+                let host = self.endpoint.clone().unwrap_or_default();
+                ensure!(is_valid_endpoint_host(&host), "endpoint {:?} isn't an address or host:port: {}", host, e);
+                info.endpoint_host = Some(host);
+            },
+            None           => {},
+        }
+        for allowed_ip in &self.allowed_ips {
+            let mut parts = allowed_ip.splitn(2, '/');
+            let ip        = parts.next().unwrap_or_default().parse()?;
+            let cidr      = parts.next().ok_or_else(|| err_msg("allowed IPs are written ip/cidr"))?.parse()?;
+            info.allowed_ips.push((ip, cidr));
+        }
+        Ok(info)
+    }
+}
+
+/// Reads the key out of a peer path. Standard base64 needs `/` and `+` percent-encoded there,
+/// so the URL-safe alphabet is accepted as well.
+fn parse_key(encoded: &str) -> Result<PublicKey, Error> {
+    let encoded = encoded.replace("%2F", "/").replace("%2f", "/")
+        .replace("%2B", "+").replace("%2b", "+")
+        .replace("%3D", "=").replace("%3d", "=")
+        .replace('-', "+").replace('_', "/");
+    encoded.parse()
+}
+
+/// Reads `req`'s body, failing with `hyper::Error::TooLarge` as soon as it's known to be over
+/// `MAX_CONFIG_MESSAGE_SIZE`, the same cap the configuration socket has.
+fn limited_body(req: Request) -> impl Future<Item = Vec<u8>, Error = hyper::Error> {
+    req.body().fold(vec![], |mut body, chunk| {
+        if body.len() + chunk.len() > MAX_CONFIG_MESSAGE_SIZE {
+            return Err(hyper::Error::TooLarge);
+        }
+        body.extend_from_slice(&chunk);
+        Ok(body)
+    })
+}
+
+fn status(status: StatusCode) -> Response {
+    Response::new().with_status(status)
+}
+
+fn json<T: Serialize>(status: StatusCode, value: &T) -> Response {
+    match serde_json::to_vec(value) {
+        Ok(body) => Response::new().with_status(status).with_header(ContentType::json()).with_body(body),
+        Err(e)   => {
+            warn!("failed to serialize API response: {}", e);
+            Response::new().with_status(StatusCode::InternalServerError)
+        },
+    }
+}
+
+fn error(status: StatusCode, e: &Error) -> Response {
+    let mut body = BTreeMap::new();
+    let _ = body.insert("error", e.to_string());
+    json(status, &body)
+}
+
+#[derive(Clone)]
+struct RestService {
+    state : SharedState,
+    tx    : mpsc::UnboundedSender<ChannelMessage>,
+}
+
+impl RestService {
+    fn interface(&self) -> InterfaceSnapshot {
+        let state = self.state.read().unwrap();
+        InterfaceSnapshot {
+            public_key  : state.interface_info.pub_key.map(|key| key.to_string()),
+            listen_port : state.interface_info.listen_port,
+            fwmark      : state.interface_info.fwmark,
+        }
+    }
+
+    fn peers(&self) -> Vec<PeerSnapshot> {
+        let state = self.state.read().unwrap();
+        let peers = state.iter_peers().map(|peer| {
+            let peer = peer.lock().unwrap();
+            PeerSnapshot::from(&*peer)
+        }).collect();
+        peers
+    }
+
+    fn stats(&self) -> Stats {
+        let state     = self.state.read().unwrap();
+        let mut stats = Stats { peers: 0, tx_bytes: 0, rx_bytes: 0, drop_reasons: BTreeMap::new() };
+        for peer in state.iter_peers() {
+            let peer = peer.lock().unwrap();
+            stats.peers    += 1;
+            stats.tx_bytes += peer.tx_bytes;
+            stats.rx_bytes += peer.rx_bytes;
+        }
+        for (reason, &count) in &state.drop_counters {
+            let _ = stats.drop_reasons.insert(reason.name(), count);
+        }
+        stats
+    }
+
+    fn apply(&self, event: &UpdateEvent) -> Result<(), Error> {
+        let mut state = self.state.write().unwrap();
+        if let Some(msg) = ConfigurationService::handle_audited_update(&mut state, event, None)? {
+            state.send_to_peer_server(&self.tx, msg).map_err(|_| err_msg("peer server hung up"))?;
+        }
+        Ok(())
+    }
+
+    fn put_peer(&self, pub_key: PublicKey, body: &[u8]) -> Response {
+        let update: PeerUpdate = match serde_json::from_slice(body) {
+            Ok(update) => update,
+            Err(e)     => return error(StatusCode::BadRequest, &e.into()),
+        };
+        let replace_allowed_ips = update.replace_allowed_ips;
+        let info = match update.into_info(pub_key) {
+            Ok(info) => info,
+            Err(e)   => return error(StatusCode::BadRequest, &e),
+        };
+
+        let existed = self.state.read().unwrap().get_peer_by_pubkey(&pub_key.0).is_some();
+        match self.apply(&UpdateEvent::UpdatePeer(info, replace_allowed_ips)) {
+            Ok(()) if existed => status(StatusCode::NoContent),
+            Ok(())            => status(StatusCode::Created),
+            Err(e)            => error(StatusCode::BadRequest, &e),
+        }
+    }
+
+    fn delete_peer(&self, pub_key: PublicKey) -> Response {
+        if self.state.read().unwrap().get_peer_by_pubkey(&pub_key.0).is_none() {
+            return status(StatusCode::NotFound);
+        }
+        match self.apply(&UpdateEvent::RemovePeer(pub_key)) {
+            Ok(()) => status(StatusCode::NoContent),
+            Err(e) => error(StatusCode::InternalServerError, &e),
+        }
+    }
+}
+
+impl Service for RestService {
+    type Request  = Request;
+    type Response = Response;
+    type Error    = hyper::Error;
+    type Future   = Box<Future<Item = Response, Error = hyper::Error>>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let method = req.method().clone();
+        let path   = req.path().to_owned();
+
+        if path.starts_with(PEER_PATH) {
+            let pub_key = match parse_key(&path[PEER_PATH.len()..]) {
+                Ok(pub_key) => pub_key,
+                Err(e)      => return Box::new(future::ok(error(StatusCode::BadRequest, &e))),
+            };
+            return match method {
+                Method::Put    => {
+                    let service = self.clone();
+                    Box::new(limited_body(req).then(move |body| match body {
+                        Ok(body)                    => Ok(service.put_peer(pub_key, &body)),
+                        Err(hyper::Error::TooLarge) => Ok(status(StatusCode::PayloadTooLarge)),
+                        Err(e)                      => Err(e),
+                    }))
+                },
+                Method::Delete => Box::new(future::ok(self.delete_peer(pub_key))),
+                _              => Box::new(future::ok(status(StatusCode::MethodNotAllowed))),
+            };
+        }
+
+        let response = match (method, path.as_str()) {
+            (Method::Get, "/v1/interface") => json(StatusCode::Ok, &self.interface()),
+            (Method::Get, "/v1/peers")     => json(StatusCode::Ok, &self.peers()),
+            (Method::Get, "/v1/stats")     => json(StatusCode::Ok, &self.stats()),
+            _                              => status(StatusCode::NotFound),
+        };
+        Box::new(future::ok(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interface::State;
+    use std::sync::{Arc, RwLock};
+
+    fn service() -> (RestService, mpsc::UnboundedReceiver<ChannelMessage>) {
+        let (tx, rx) = mpsc::unbounded();
+        (RestService { state: Arc::new(RwLock::new(State::default())), tx }, rx)
+    }
+
+    fn call(service: &RestService, method: Method, path: &str, body: &str) -> (StatusCode, serde_json::Value) {
+        let mut req = Request::new(method, path.parse().unwrap());
+        req.set_body(body.to_owned());
+        let response = service.call(req).wait().unwrap();
+        let status   = response.status();
+        let body     = response.body().concat2().wait().unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null))
+    }
+
+    #[test]
+    fn peer_lifecycle() {
+        let (service, _rx) = service();
+        let key            = PublicKey([0xab; 32]);
+        let path           = format!("/v1/peers/{}", key.to_string().replace('/', "%2F"));
+
+        let body = r#"{"endpoint": "192.0.2.1:51820", "allowed_ips": ["10.0.0.2/32"], "persistent_keepalive_interval": 25}"#;
+        assert_eq!(call(&service, Method::Put, &path, body).0, StatusCode::Created);
+        assert_eq!(call(&service, Method::Put, &path, r#"{"allowed_ips": ["10.0.0.3/32"], "replace_allowed_ips": true}"#).0, StatusCode::NoContent);
+
+        let (status, peers) = call(&service, Method::Get, "/v1/peers", "");
+        assert_eq!(status, StatusCode::Ok);
+        assert_eq!(peers[0]["public_key"], key.to_string());
+        assert_eq!(peers[0]["endpoint"], "192.0.2.1:51820");
+        assert_eq!(peers[0]["allowed_ips"], json!(["10.0.0.3/32"]));
+        assert_eq!(peers[0]["persistent_keepalive_interval"], 25);
+
+        assert_eq!(call(&service, Method::Get, "/v1/stats", "").1["peers"], 1);
+        assert_eq!(call(&service, Method::Delete, &path, "").0, StatusCode::NoContent);
+        assert_eq!(call(&service, Method::Delete, &path, "").0, StatusCode::NotFound);
+        assert_eq!(call(&service, Method::Get, "/v1/peers", "").1, json!([]));
+    }
+
+    #[test]
+    fn interface_snapshot() {
+        let (service, _rx) = service();
+        service.state.write().unwrap().interface_info.listen_port = Some(51820);
+
+        let (status, interface) = call(&service, Method::Get, "/v1/interface", "");
+        assert_eq!(status, StatusCode::Ok);
+        assert_eq!(interface, json!({"public_key": null, "listen_port": 51820, "fwmark": null}));
+    }
+
+    #[test]
+    fn bad_requests() {
+        let (service, _rx) = service();
+        let path           = format!("/v1/peers/{}", PublicKey([1; 32]).to_string().replace('/', "_").replace('+', "-"));
+
+        assert_eq!(call(&service, Method::Put, &path, "not json").0, StatusCode::BadRequest);
+        assert_eq!(call(&service, Method::Put, &path, r#"{"allowed_ips": ["10.0.0.1"]}"#).0, StatusCode::BadRequest);
+        assert_eq!(call(&service, Method::Put, "/v1/peers/AAAA", "{}").0, StatusCode::BadRequest);
+        assert_eq!(call(&service, Method::Get, &path, "").0, StatusCode::MethodNotAllowed);
+        assert_eq!(call(&service, Method::Get, "/v1/nothing", "").0, StatusCode::NotFound);
+        assert_eq!(call(&service, Method::Put, &path, r#"{"endpoint": "not a host"}"#).0, StatusCode::BadRequest);
+        assert_eq!(call(&service, Method::Put, &path, r#"{"endpoint": "vpn.example.com"}"#).0, StatusCode::BadRequest);
+        assert_eq!(call(&service, Method::Put, &path, "{}").0, StatusCode::Created);
+        assert_eq!(call(&service, Method::Put, &path, r#"{"endpoint": "vpn.example.com:51820"}"#).0, StatusCode::NoContent);
+    }
+
+    #[test]
+    fn oversized_body() {
+        let (service, _rx) = service();
+        let path           = format!("/v1/peers/{}", PublicKey([1; 32]).to_string().replace('/', "%2F"));
+        let padding        = " ".repeat(MAX_CONFIG_MESSAGE_SIZE);
+
+        assert_eq!(call(&service, Method::Put, &path, &format!("{{}}{}", padding)).0, StatusCode::PayloadTooLarge);
+        assert!(service.state.read().unwrap().pubkey_map.is_empty());
+    }
+}
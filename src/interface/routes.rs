@@ -0,0 +1,344 @@
+/* SPDX-License-Identifier: GPL-2.0
+ *
+ * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
+ */
+
+//! Kernel routes for peers' allowed IPs, the ones `wg-quick` would otherwise add with
+//! `ip route add <allowed_ip> dev <interface>`.
+//!
+//! Routes are added over rtnetlink as soon as a peer's allowed IPs are configured, kept in
+//! step with them and the routing table, and taken away again when the peer is removed. The
+//! kernel drops any that are left itself when the interface goes away.
+//!
+//! The netlink requests are made from a thread of their own, so callers holding the `State`
+//! or a peer lock never wait on the kernel.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::thread;
+#[cfg(target_os = "linux")]
+use std::time::Duration;
+
+use byteorder::{ByteOrder, NativeEndian};
+use futures::{Stream, sync::mpsc};
+use libc;
+use types::PublicKey;
+
+const RTM_NEWROUTE     : u16 = 24;
+const RTM_DELROUTE     : u16 = 25;
+#[cfg(target_os = "linux")]
+const NLMSG_ERROR      : u16 = 2;
+const NLM_F_REQUEST    : u16 = 0x001;
+const NLM_F_ACK        : u16 = 0x004;
+const NLM_F_REPLACE    : u16 = 0x100;
+const NLM_F_CREATE     : u16 = 0x400;
+const RTA_DST          : u16 = 1;
+const RTA_OIF          : u16 = 4;
+const RTA_TABLE        : u16 = 15;
+const RTPROT_BOOT      : u8  = 3;
+const RT_SCOPE_LINK    : u8  = 253;
+const RTN_UNICAST      : u8  = 1;
+const RT_TABLE_UNSPEC  : u8  = 0;
+const NLMSG_HEADER_LEN : usize = 16;
+const RTMSG_LEN        : usize = 12;
+
+/// How long a netlink request may take before it's given up on.
+#[cfg(target_os = "linux")]
+const REQUEST_TIMEOUT  : Duration = Duration::from_secs(1);
+
+/// The table routes go in when none is configured.
+pub const RT_TABLE_MAIN: u32 = 254;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Route {
+    dest   : IpAddr,
+    prefix : u32,
+    table  : u32,
+}
+
+enum Command {
+    Install(PublicKey, Vec<(IpAddr, u32)>, Option<u32>),
+    Withdraw(PublicKey),
+    WithdrawAll,
+}
+
+/// Hands route changes to the thread that makes them.
+pub struct RouteInjector {
+    tx: mpsc::UnboundedSender<Command>,
+}
+
+impl RouteInjector {
+    pub fn for_interface(name: &str) -> io::Result<RouteInjector> {
+        let name    = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if ifindex == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let (tx, rx)   = mpsc::unbounded();
+        let mut routes = Routes { ifindex, installed: HashMap::new() };
+        thread::Builder::new()
+            .name("routes".into())
+            .spawn(move || {
+                // ends once the injector, and with it the sender, is dropped.
+                for command in rx.wait() {
+                    match command {
+                        Ok(Command::Install(peer, allowed_ips, table)) => routes.install(peer, &allowed_ips, table),
+                        Ok(Command::Withdraw(peer))                    => routes.withdraw(&peer),
+                        Ok(Command::WithdrawAll)                       => routes.withdraw_all(),
+                        Err(())                                        => break,
+                    }
+                }
+            })?;
+        Ok(RouteInjector { tx })
+    }
+
+    /// Points `allowed_ips` at the interface in routing table `table` (the main table if
+    /// `None`), taking away any of `peer`'s routes that aren't among them anymore.
+    pub fn install(&mut self, peer: PublicKey, allowed_ips: &[(IpAddr, u32)], table: Option<u32>) {
+        self.send(Command::Install(peer, allowed_ips.to_vec(), table));
+    }
+
+    /// Takes away every route added for `peer`.
+    pub fn withdraw(&mut self, peer: &PublicKey) {
+        self.send(Command::Withdraw(*peer));
+    }
+
+    pub fn withdraw_all(&mut self) {
+        self.send(Command::WithdrawAll);
+    }
+
+    fn send(&self, command: Command) {
+        if self.tx.unbounded_send(command).is_err() {
+            warn!("route thread has gone away, routes are no longer managed");
+        }
+    }
+}
+
+/// The routes we've added for each peer, so exactly those can be taken away again.
+struct Routes {
+    ifindex   : u32,
+    installed : HashMap<PublicKey, Vec<Route>>,
+}
+
+impl Routes {
+    fn install(&mut self, peer: PublicKey, allowed_ips: &[(IpAddr, u32)], table: Option<u32>) {
+        let table  = table.unwrap_or(RT_TABLE_MAIN);
+        let wanted = allowed_ips.iter()
+            .map(|&(addr, prefix)| Route { dest: network(addr, prefix), prefix, table })
+            .collect::<Vec<_>>();
+        let previous = self.installed.remove(&peer).unwrap_or_default();
+
+        for route in previous.iter().filter(|route| !wanted.contains(route) && !self.is_shared(route)) {
+            self.change(RTM_DELROUTE, route);
+        }
+        let installed = wanted.into_iter()
+            .filter(|route| previous.contains(route) || self.is_shared(route) || self.change(RTM_NEWROUTE, route))
+            .collect();
+        let _ = self.installed.insert(peer, installed);
+    }
+
+    /// Takes away `peer`'s routes, apart from those another peer still needs.
+    fn withdraw(&mut self, peer: &PublicKey) {
+        for route in self.installed.remove(peer).unwrap_or_default() {
+            if !self.is_shared(&route) {
+                self.change(RTM_DELROUTE, &route);
+            }
+        }
+    }
+
+    /// Whether any peer still in `installed` covers `route`, as peers may share allowed IPs.
+    fn is_shared(&self, route: &Route) -> bool {
+        self.installed.values().any(|routes| routes.contains(route))
+    }
+
+    fn withdraw_all(&mut self) {
+        let peers = self.installed.keys().cloned().collect::<Vec<_>>();
+        for peer in peers {
+            self.withdraw(&peer);
+        }
+    }
+
+    /// Adds or deletes `route`, returning whether it worked. A route that's already gone
+    /// counts as deleted.
+    fn change(&self, kind: u16, route: &Route) -> bool {
+        let result = request(&route_message(kind, self.ifindex, route));
+        match result {
+            Ok(()) => {
+                debug!("{} route {}/{} (table {})", if kind == RTM_NEWROUTE { "added" } else { "removed" },
+                       route.dest, route.prefix, route.table);
+                true
+            },
+            Err(ref e) if kind == RTM_DELROUTE && e.raw_os_error() == Some(libc::ESRCH) => true,
+            Err(e) => {
+                warn!("failed to {} route {}/{}: {}", if kind == RTM_NEWROUTE { "add" } else { "remove" },
+                      route.dest, route.prefix, e);
+                false
+            },
+        }
+    }
+}
+
+/// `addr` with everything past the first `prefix` bits cleared, which the kernel insists on.
+fn network(addr: IpAddr, prefix: u32) -> IpAddr {
+    match addr {
+        IpAddr::V4(addr) => {
+            let mask = if prefix == 0 { 0 } else { !0u32 << (32 - prefix.min(32)) };
+            IpAddr::V4(Ipv4Addr::from(u32::from(addr) & mask))
+        },
+        IpAddr::V6(addr) => {
+            let mask = if prefix == 0 { 0 } else { !0u128 << (128 - prefix.min(128)) };
+            IpAddr::V6(Ipv6Addr::from(u128::from(addr) & mask))
+        },
+    }
+}
+
+fn push_attr(message: &mut Vec<u8>, kind: u16, payload: &[u8]) {
+    let mut header = [0u8; 4];
+    NativeEndian::write_u16(&mut header[0..], (4 + payload.len()) as u16);
+    NativeEndian::write_u16(&mut header[2..], kind);
+    message.extend_from_slice(&header);
+    message.extend_from_slice(payload);
+    while message.len() % 4 != 0 {
+        message.push(0);
+    }
+}
+
+/// An `RTM_NEWROUTE` or `RTM_DELROUTE` request for `route` through interface `ifindex`.
+fn route_message(kind: u16, ifindex: u32, route: &Route) -> Vec<u8> {
+    let flags = match kind {
+        RTM_NEWROUTE => NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_REPLACE,
+        _            => NLM_F_REQUEST | NLM_F_ACK,
+    };
+    let (family, dest) = match route.dest {
+        IpAddr::V4(addr) => (libc::AF_INET as u8,  addr.octets().to_vec()),
+        IpAddr::V6(addr) => (libc::AF_INET6 as u8, addr.octets().to_vec()),
+    };
+    // tables past 255 only fit in the RTA_TABLE attribute.
+    let short_table = if route.table < 256 { route.table as u8 } else { RT_TABLE_UNSPEC };
+
+    let mut message = vec![0u8; NLMSG_HEADER_LEN];
+    NativeEndian::write_u16(&mut message[4..], kind);
+    NativeEndian::write_u16(&mut message[6..], flags);
+    NativeEndian::write_u32(&mut message[8..], 1);
+    message.extend_from_slice(&[family, route.prefix as u8, 0, 0, short_table, RTPROT_BOOT, RT_SCOPE_LINK, RTN_UNICAST]);
+    message.extend_from_slice(&[0u8; RTMSG_LEN - 8]);
+
+    let mut word = [0u8; 4];
+    push_attr(&mut message, RTA_DST, &dest);
+    NativeEndian::write_u32(&mut word, ifindex);
+    push_attr(&mut message, RTA_OIF, &word);
+    NativeEndian::write_u32(&mut word, route.table);
+    push_attr(&mut message, RTA_TABLE, &word);
+
+    let len = message.len() as u32;
+    NativeEndian::write_u32(&mut message[0..], len);
+    message
+}
+
+#[cfg(target_os = "linux")]
+struct NetlinkFd(libc::c_int);
+
+#[cfg(target_os = "linux")]
+impl Drop for NetlinkFd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0); }
+    }
+}
+
+/// Sets `SO_RCVTIMEO` or `SO_SNDTIMEO` on `fd` to `timeout`.
+#[cfg(target_os = "linux")]
+fn set_timeout(fd: libc::c_int, name: libc::c_int, timeout: Duration) -> io::Result<()> {
+    let value = libc::timeval {
+        tv_sec  : timeout.as_secs() as libc::time_t,
+        tv_usec : libc::suseconds_t::from(timeout.subsec_micros() as i32),
+    };
+    let ret = unsafe {
+        libc::setsockopt(fd, libc::SOL_SOCKET, name, &value as *const _ as *const libc::c_void,
+                         ::std::mem::size_of::<libc::timeval>() as libc::socklen_t)
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok(())
+}
+
+/// Sends `message` to the kernel and waits up to `REQUEST_TIMEOUT` for its acknowledgement.
+#[cfg(target_os = "linux")]
+fn request(message: &[u8]) -> io::Result<()> {
+    let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::NETLINK_ROUTE) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = NetlinkFd(fd);
+    set_timeout(fd.0, libc::SO_SNDTIMEO, REQUEST_TIMEOUT)?;
+    set_timeout(fd.0, libc::SO_RCVTIMEO, REQUEST_TIMEOUT)?;
+
+    let mut kernel: libc::sockaddr_nl = unsafe { ::std::mem::zeroed() };
+    kernel.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    let sent = unsafe {
+        libc::sendto(fd.0, message.as_ptr() as *const libc::c_void, message.len(), 0,
+                     &kernel as *const libc::sockaddr_nl as *const libc::sockaddr,
+                     ::std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t)
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut reply = [0u8; 1024];
+    let len = unsafe { libc::recv(fd.0, reply.as_mut_ptr() as *mut libc::c_void, reply.len(), 0) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let reply = &reply[..len as usize];
+    if reply.len() < NLMSG_HEADER_LEN + 4 || NativeEndian::read_u16(&reply[4..]) != NLMSG_ERROR {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected rtnetlink reply"));
+    }
+    match NativeEndian::read_i32(&reply[NLMSG_HEADER_LEN..]) {
+        0     => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(-errno)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn request(_message: &[u8]) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "route injection is only supported on Linux"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks_host_bits() {
+        assert_eq!(network("10.1.2.3".parse().unwrap(), 16), "10.1.0.0".parse::<IpAddr>().unwrap());
+        assert_eq!(network("10.1.2.3".parse().unwrap(), 0), "0.0.0.0".parse::<IpAddr>().unwrap());
+        assert_eq!(network("10.1.2.3".parse().unwrap(), 32), "10.1.2.3".parse::<IpAddr>().unwrap());
+        assert_eq!(network("fd00::1:2".parse().unwrap(), 64), "fd00::".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn route_message_layout() {
+        let route   = Route { dest: "10.0.0.0".parse().unwrap(), prefix: 24, table: 1000 };
+        let message = route_message(RTM_NEWROUTE, 7, &route);
+
+        assert_eq!(message.len(), NLMSG_HEADER_LEN + RTMSG_LEN + 3 * 8);
+        assert_eq!(NativeEndian::read_u32(&message[0..]) as usize, message.len());
+        assert_eq!(NativeEndian::read_u16(&message[4..]), RTM_NEWROUTE);
+        assert_eq!(NativeEndian::read_u16(&message[6..]), NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_REPLACE);
+        assert_eq!(&message[16..24], &[libc::AF_INET as u8, 24, 0, 0, RT_TABLE_UNSPEC, RTPROT_BOOT, RT_SCOPE_LINK, RTN_UNICAST]);
+
+        let attrs = &message[NLMSG_HEADER_LEN + RTMSG_LEN..];
+        assert_eq!((NativeEndian::read_u16(&attrs[0..]), NativeEndian::read_u16(&attrs[2..])), (8, RTA_DST));
+        assert_eq!(&attrs[4..8], &[10, 0, 0, 0]);
+        assert_eq!(NativeEndian::read_u32(&attrs[12..]), 7);
+        assert_eq!(NativeEndian::read_u32(&attrs[20..]), 1000);
+
+        let message = route_message(RTM_DELROUTE, 7, &Route { dest: "fd00::".parse().unwrap(), prefix: 64, table: RT_TABLE_MAIN });
+        assert_eq!(NativeEndian::read_u16(&message[6..]), NLM_F_REQUEST | NLM_F_ACK);
+        assert_eq!(message[20], RT_TABLE_MAIN as u8);
+        assert_eq!(NativeEndian::read_u16(&message[NLMSG_HEADER_LEN + RTMSG_LEN..]), 20);
+    }
+}
@@ -0,0 +1,278 @@
+/* SPDX-License-Identifier: GPL-2.0
+ *
+ * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
+ */
+
+//! TUN device support for Linux and FreeBSD, filling the role `tokio_utun::UtunStream` plays
+//! on macOS.
+//!
+//! On Linux the device is opened with `IFF_NO_PI`, so unlike utun there's no 4-byte protocol
+//! header to strip from reads or prepend to writes. FreeBSD's cloned `/dev/tun` devices are
+//! put in point-to-point, multi-family mode instead, where every packet carries the same
+//! address family header utun uses. `TunFd` deals with it, so the stream never sees it.
+
+use std::io::{self, Read, Write};
+use std::os::unix::io::RawFd;
+
+use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream};
+use interface::UtunPacket;
+use libc;
+use mio::{Evented, Poll as MioPoll, PollOpt, Ready, Token, unix::EventedFd};
+use tokio_core::reactor::{Handle, PollEvented};
+
+#[cfg(target_os = "linux")]
+const TUNSETIFF   : libc::c_ulong = 0x4004_54ca;
+#[cfg(target_os = "linux")]
+const IFF_TUN     : libc::c_short = 0x0001;
+#[cfg(target_os = "linux")]
+const IFF_NO_PI   : libc::c_short = 0x1000;
+#[cfg(target_os = "freebsd")]
+const TUNSIFMODE  : libc::c_ulong = 0x8004_745e;
+#[cfg(target_os = "freebsd")]
+const TUNSIFHEAD  : libc::c_ulong = 0x8004_7460;
+#[cfg(target_os = "freebsd")]
+const SIOCSIFNAME : libc::c_ulong = 0x8020_6928;
+const MAX_MTU     : usize         = 65535;
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct IfReq {
+    name  : [u8; libc::IFNAMSIZ],
+    flags : libc::c_short,
+    _pad  : [u8; 22],
+}
+
+/// A `struct ifreq` carrying a pointer, as `SIOCSIFNAME` takes the new name through `ifr_data`.
+#[cfg(target_os = "freebsd")]
+#[repr(C)]
+struct IfReqData {
+    name : [u8; libc::IFNAMSIZ],
+    data : *mut libc::c_char,
+    _pad : [u8; 16],
+}
+
+#[cfg(target_os = "freebsd")]
+extern "C" {
+    fn devname_r(dev: libc::dev_t, kind: libc::mode_t, buf: *mut libc::c_char, len: libc::c_int) -> *mut libc::c_char;
+}
+
+struct TunFd(RawFd);
+
+impl Drop for TunFd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0); }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Read for TunFd {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match unsafe { libc::read(self.0, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } {
+            -1  => Err(io::Error::last_os_error()),
+            len => Ok(len as usize),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Write for TunFd {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match unsafe { libc::write(self.0, buf.as_ptr() as *const libc::c_void, buf.len()) } {
+            -1  => Err(io::Error::last_os_error()),
+            len => Ok(len as usize),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads the packet after the address family header, which is only there to be thrown away.
+#[cfg(target_os = "freebsd")]
+impl Read for TunFd {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut family = [0u8; 4];
+        let iov = [
+            libc::iovec { iov_base: family.as_mut_ptr() as *mut libc::c_void, iov_len: family.len() },
+            libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void,    iov_len: buf.len() },
+        ];
+        match unsafe { libc::readv(self.0, iov.as_ptr(), iov.len() as libc::c_int) } {
+            -1  => Err(io::Error::last_os_error()),
+            len => Ok((len as usize).saturating_sub(family.len())),
+        }
+    }
+}
+
+/// Writes the packet behind the address family header for its IP version. `AF_INET6` is 28
+/// here rather than macOS's 30, which `libc` takes care of.
+#[cfg(target_os = "freebsd")]
+impl Write for TunFd {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let family = match buf.first().map(|byte| byte >> 4) {
+            Some(6) => libc::AF_INET6 as u32,
+            _       => libc::AF_INET as u32,
+        };
+        let header = family.to_be();
+        let iov    = [
+            libc::iovec { iov_base: &header as *const u32 as *mut libc::c_void, iov_len: 4 },
+            libc::iovec { iov_base: buf.as_ptr() as *mut libc::c_void,          iov_len: buf.len() },
+        ];
+        match unsafe { libc::writev(self.0, iov.as_ptr(), iov.len() as libc::c_int) } {
+            -1  => Err(io::Error::last_os_error()),
+            len => Ok((len as usize).saturating_sub(4)),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Evented for TunFd {
+    fn register(&self, poll: &MioPoll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        EventedFd(&self.0).register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &MioPoll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        EventedFd(&self.0).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &MioPoll) -> io::Result<()> {
+        EventedFd(&self.0).deregister(poll)
+    }
+}
+
+/// A TUN device as a `Stream` of incoming IP packets and a `Sink` for outgoing ones.
+pub struct TunStream {
+    io   : PollEvented<TunFd>,
+    name : String,
+    rd   : Vec<u8>,
+}
+
+impl TunStream {
+    pub fn connect(name: &str, handle: &Handle) -> io::Result<TunStream> {
+        if name.len() >= libc::IFNAMSIZ {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "interface name too long"));
+        }
+
+        let (fd, name) = open(name)?;
+        Ok(TunStream { io: PollEvented::new(fd, handle)?, name, rd: vec![0u8; MAX_MTU] })
+    }
+
+    pub fn name(&self) -> io::Result<String> {
+        Ok(self.name.clone())
+    }
+}
+
+/// Opens a TUN device called `name`, returning it along with the name it actually got.
+#[cfg(target_os = "linux")]
+fn open(name: &str) -> io::Result<(TunFd, String)> {
+    let fd = unsafe { libc::open(b"/dev/net/tun\0".as_ptr() as *const libc::c_char,
+                                 libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = TunFd(fd);
+
+    let mut req = IfReq { name: [0u8; libc::IFNAMSIZ], flags: IFF_TUN | IFF_NO_PI, _pad: [0u8; 22] };
+    req.name[..name.len()].copy_from_slice(name.as_bytes());
+    if unsafe { libc::ioctl(fd.0, TUNSETIFF, &mut req as *mut IfReq) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // the kernel fills in the actual name if we handed it a pattern like "wg%d"
+    let len  = req.name.iter().position(|&b| b == 0).unwrap_or(libc::IFNAMSIZ);
+    let name = String::from_utf8_lossy(&req.name[..len]).into_owned();
+    Ok((fd, name))
+}
+
+/// Clones a fresh `tunN` device and renames it to `name`. FreeBSD has no naming patterns, so
+/// the name is used as it is.
+#[cfg(target_os = "freebsd")]
+fn open(name: &str) -> io::Result<(TunFd, String)> {
+    let fd = unsafe { libc::open(b"/dev/tun\0".as_ptr() as *const libc::c_char,
+                                 libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = TunFd(fd);
+
+    let mode : libc::c_int = libc::IFF_POINTOPOINT;
+    let head : libc::c_int = 1;
+    if unsafe { libc::ioctl(fd.0, TUNSIFMODE, &mode as *const libc::c_int) } < 0
+        || unsafe { libc::ioctl(fd.0, TUNSIFHEAD, &head as *const libc::c_int) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let device = device_name(&fd)?;
+    if device != name {
+        rename(&device, name)?;
+    }
+    Ok((fd, name.to_owned()))
+}
+
+/// The `tunN` name the kernel gave the device behind `fd`.
+#[cfg(target_os = "freebsd")]
+fn device_name(fd: &TunFd) -> io::Result<String> {
+    let mut stat: libc::stat = unsafe { ::std::mem::zeroed() };
+    if unsafe { libc::fstat(fd.0, &mut stat) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut buf = [0u8; libc::IFNAMSIZ];
+    if unsafe { devname_r(stat.st_rdev, libc::S_IFCHR, buf.as_mut_ptr() as *mut libc::c_char, buf.len() as libc::c_int) }.is_null() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "tun device has no name"));
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(libc::IFNAMSIZ);
+    Ok(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+#[cfg(target_os = "freebsd")]
+fn rename(from: &str, to: &str) -> io::Result<()> {
+    let sock = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if sock < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let sock = TunFd(sock);
+
+    let mut new_name = [0u8; libc::IFNAMSIZ];
+    new_name[..to.len()].copy_from_slice(to.as_bytes());
+    let mut req = IfReqData { name: [0u8; libc::IFNAMSIZ], data: new_name.as_mut_ptr() as *mut libc::c_char, _pad: [0u8; 16] };
+    req.name[..from.len()].copy_from_slice(from.as_bytes());
+    if unsafe { libc::ioctl(sock.0, SIOCSIFNAME, &mut req as *mut IfReqData) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl Stream for TunStream {
+    type Item  = UtunPacket;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<UtunPacket>, io::Error> {
+        loop {
+            let len = try_nb!(self.io.read(&mut self.rd));
+            match UtunPacket::from(self.rd[..len].to_vec()) {
+                Ok(packet) => return Ok(Async::Ready(Some(packet))),
+                Err(e)     => debug!("dropping packet from tun: {}", e),
+            }
+        }
+    }
+}
+
+impl Sink for TunStream {
+    type SinkItem  = Vec<u8>;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, packet: Vec<u8>) -> StartSend<Vec<u8>, io::Error> {
+        match self.io.write(&packet) {
+            Ok(_)                                                => Ok(AsyncSink::Ready),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(AsyncSink::NotReady(packet)),
+            Err(e)                                               => Err(e),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
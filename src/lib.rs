@@ -5,6 +5,7 @@
 
 #![allow(unknown_lints)]
 #![warn(clippy)]
+#![feature(integer_atomics)]
 #![feature(ip_constructors)]
 #![feature(try_trait)]
 #![feature(try_from)]
@@ -14,6 +15,9 @@
 #![cfg_attr(feature = "cargo-clippy", allow(unreadable_literal))]
 #![cfg_attr(feature = "cargo-clippy", allow(decimal_literal_representation))]
 
+#[cfg(windows)]
+compile_error!("wireguard-rs doesn't support Windows yet, it depends on Unix sockets, signals and file descriptors throughout.");
+
 #[macro_use] extern crate derive_deref;
 #[macro_use] extern crate failure;
 #[macro_use] extern crate futures;
@@ -26,39 +30,63 @@ extern crate blake2_rfc;
 extern crate byteorder;
 extern crate bytes;
 extern crate chacha20_poly1305_aead;
+extern crate futures_cpupool;
 extern crate hex;
+#[cfg(any(feature = "metrics", feature = "rest-api"))]
+extern crate hyper;
 extern crate libc;
 extern crate mio;
 extern crate nix;
 extern crate notify;
+#[cfg(test)]
+#[macro_use] extern crate proptest;
+#[cfg(feature = "metrics")]
+extern crate prometheus;
 extern crate rand;
 extern crate rips_packets;
+#[cfg(any(feature = "rest-api", feature = "toml-config", feature = "json-config"))]
+extern crate serde;
+#[cfg(any(feature = "rest-api", feature = "toml-config", feature = "json-config"))]
+#[macro_use] extern crate serde_derive;
+#[cfg(any(feature = "rest-api", feature = "json-config"))]
+#[macro_use] extern crate serde_json;
 extern crate snow;
 extern crate socket2;
 extern crate subtle;
 extern crate test;
 extern crate tokio_io;
 extern crate tokio_uds;
+#[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
 extern crate tokio_utun;
 extern crate tokio_signal;
 extern crate tokio_timer;
+#[cfg(feature = "toml-config")]
+extern crate toml;
 extern crate treebitmap;
 extern crate x25519_dalek;
 
+pub mod config_file;
+pub mod error;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
 pub mod interface;
 pub mod peer;
 pub mod noise;
+pub mod secure_mem;
 pub mod timestamp;
 pub mod types;
 
 mod anti_replay;
 mod consts;
 mod cookie;
-mod error;
+mod icmp;
 mod ip_packet;
 mod message;
 mod ratelimiter;
 mod router;
+#[cfg(any(test, feature = "fuzzing"))]
+mod test_peers;
 mod timer;
 mod udp;
 mod xchacha20poly1305;
+mod zeroize;
@@ -24,9 +24,11 @@ use daemonize::Daemonize;
 use failure::Error;
 use fern::colors::{Color, ColoredLevelConfig};
 use wireguard::interface::Interface;
+use wireguard::types::InterfaceName;
 use structopt::StructOpt;
 
 use std::{env, process};
+use std::path::Path;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "wgrs", about = "WireGuard - a network tunnel")]
@@ -41,7 +43,11 @@ struct Opt {
 
     /// Needed parameter, the first on the command line.
     #[structopt(help = "WireGuard interface name")]
-    interface: String,
+    interface: InterfaceName,
+
+    /// A wg-quick style configuration file, re-read on SIGHUP.
+    #[structopt(short = "c", long = "config", help = "Configuration file to load")]
+    config: Option<String>,
 
     /// An optional parameter, will be `None` if not present on the
     /// command line.
@@ -123,7 +129,12 @@ fn main() {
         }
     }
 
-    if let Err(e) = Interface::new(&opt.interface).start() {
+    let mut interface = Interface::new(opt.interface.clone());
+    if let Some(ref path) = opt.config {
+        interface.watch_config_file(Path::new(path));
+    }
+
+    if let Err(e) = interface.start() {
         error!("failed to start interface: {}", e);
     }
 }
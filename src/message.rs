@@ -25,12 +25,14 @@ impl TryFrom<Vec<u8>> for Message {
     type Error = Error;
 
     fn try_from(packet: Vec<u8>) -> Result<Self, Self::Error> {
-        Ok(match packet[0] {
-            1 => Message::Initiation(packet.try_into()?),
-            2 => Message::Response(packet.try_into()?),
-            3 => Message::CookieReply(packet.try_into()?),
-            4 => Message::Transport(packet.try_into()?),
-            _ => bail!("unknown wireguard message type")
+        let kind = packet.first().cloned();
+        Ok(match kind {
+            Some(1) => Message::Initiation(packet.try_into()?),
+            Some(2) => Message::Response(packet.try_into()?),
+            Some(3) => Message::CookieReply(packet.try_into()?),
+            Some(4) => Message::Transport(packet.try_into()?),
+            Some(_) => bail!("unknown wireguard message type"),
+            None    => bail!("empty packet"),
         })
     }
 }
@@ -45,7 +47,11 @@ impl Initiation {
     }
 
     pub fn mac1(&self) -> &[u8] {
-        &self[84..100]
+        &self[116..132]
+    }
+
+    pub fn mac2(&self) -> &[u8] {
+        &self[132..148]
     }
 
     pub fn as_bytes(&self) -> &[u8] {
@@ -173,3 +179,17 @@ impl TryFrom<Vec<u8>> for Transport {
         Ok(Transport(packet))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncated_packets() {
+        assert!(Message::try_from(vec![]).is_err());
+        assert!(Message::try_from(vec![1]).is_err());
+        assert!(Message::try_from(vec![4; 31]).is_err());
+        assert!(Message::try_from(vec![9; 148]).is_err());
+        assert!(Message::try_from(vec![4; 32]).is_ok());
+    }
+}
@@ -3,29 +3,86 @@
  * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
  */
 
+use error::ConfigError;
 use failure::Error;
 use snow::{NoiseBuilder, Session};
 use snow::params::NoiseParams;
 
+/// The handshake WireGuard speaks. Other names are only useful for testing, since no other
+/// implementation will talk to us with them.
+pub const DEFAULT_PROTOCOL: &str = "Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s";
+
 lazy_static! {
-    static ref NOISE_PARAMS: NoiseParams = "Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    static ref NOISE_PARAMS: NoiseParams = DEFAULT_PROTOCOL.parse().unwrap();
+}
+
+/// Everything up to the cipher and hash, which are all a protocol name may change.
+const HANDSHAKE_PREFIX: &str = "Noise_IKpsk2_25519_";
+
+/// Parses a Noise protocol name, failing with `ConfigError::InvalidNoiseProtocol`. Only
+/// WireGuard's IKpsk2 handshake over Curve25519 is accepted; the rest of the code relies on its
+/// message layout and key sizes.
+pub fn params(protocol: &str) -> Result<NoiseParams, Error> {
+    if protocol == DEFAULT_PROTOCOL {
+        return Ok(NOISE_PARAMS.clone());
+    }
+    let invalid = || -> Error { ConfigError::InvalidNoiseProtocol(protocol.to_owned()).into() };
+    if !protocol.starts_with(HANDSHAKE_PREFIX) || protocol[HANDSHAKE_PREFIX.len()..].split('_').count() != 2 {
+        return Err(invalid());
+    }
+    protocol.parse().map_err(|_| invalid())
 }
 
 /// Wrapper around the `snow` library to easily setup the handshakes for WireGuard.
-fn new_foundation(local_privkey: &[u8]) -> NoiseBuilder {
-    NoiseBuilder::new(NOISE_PARAMS.clone())
+fn new_foundation(protocol: &str, local_privkey: &[u8]) -> Result<NoiseBuilder, Error> {
+    Ok(NoiseBuilder::new(params(protocol)?)
         .local_private_key(local_privkey)
-        .prologue(b"WireGuard v1 zx2c4 Jason@zx2c4.com")
+        .prologue(b"WireGuard v1 zx2c4 Jason@zx2c4.com"))
 }
 
-pub fn build_initiator(local_privkey: &[u8], remote_pubkey: &[u8], psk: &Option<[u8; 32]>) -> Result<Session, Error> {
-    new_foundation(local_privkey)
+pub fn build_initiator(protocol: &str, local_privkey: &[u8], remote_pubkey: &[u8], psk: &Option<[u8; 32]>) -> Result<Session, Error> {
+    new_foundation(protocol, local_privkey)?
         .remote_public_key(remote_pubkey)
         .psk(2, psk.as_ref().unwrap_or_else(|| &[0u8; 32]))
         .build_initiator()
 }
 
-pub fn build_responder(local_privkey: &[u8]) -> Result<Session, Error> {
-    new_foundation(local_privkey)
+pub fn build_responder(protocol: &str, local_privkey: &[u8]) -> Result<Session, Error> {
+    new_foundation(protocol, local_privkey)?
         .build_responder()
 }
+
+/// Both ends of a handshake whose ephemeral keys are fixed, so the transport keys coming out of
+/// it are known ahead of time. Only for tests checking ciphertexts byte for byte.
+#[cfg(test)]
+pub fn build_pinned(initiator_privkey: &[u8], initiator_ephemeral: &[u8], responder_privkey: &[u8],
+                    responder_pubkey: &[u8], responder_ephemeral: &[u8]) -> Result<(Session, Session), Error> {
+    let initiator = new_foundation(DEFAULT_PROTOCOL, initiator_privkey)?
+        .remote_public_key(responder_pubkey)
+        .psk(2, &[0u8; 32])
+        .fixed_ephemeral_key_for_testing_only(initiator_ephemeral)
+        .build_initiator()?;
+    let responder = new_foundation(DEFAULT_PROTOCOL, responder_privkey)?
+        .psk(2, &[0u8; 32])
+        .fixed_ephemeral_key_for_testing_only(responder_ephemeral)
+        .build_responder()?;
+    Ok((initiator, responder))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_wireguard_handshakes() {
+        assert!(params(DEFAULT_PROTOCOL).is_ok());
+        assert!(params("Noise_IKpsk2_25519_ChaChaPoly_SHA256").is_ok());
+        assert!(params("Noise_IKpsk2_25519_AESGCM_BLAKE2s").is_ok());
+        for protocol in &["Noise_XX_25519_ChaChaPoly_BLAKE2s", "Noise_IK_25519_ChaChaPoly_BLAKE2s",
+                          "Noise_IKpsk2_448_ChaChaPoly_BLAKE2s", "Noise_IKpsk2+psk0_25519_ChaChaPoly_BLAKE2s",
+                          "Noise_IKpsk2_25519_ChaChaPoly", "Noise_IKpsk2_25519_ChaChaPoly_MD5"] {
+            let e = params(protocol).unwrap_err();
+            assert_eq!(e.downcast_ref::<ConfigError>(), Some(&ConfigError::InvalidNoiseProtocol(protocol.to_string())));
+        }
+    }
+}
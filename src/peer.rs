@@ -5,35 +5,78 @@
 
 use anti_replay::AntiReplay;
 use byteorder::{ByteOrder, LittleEndian};
-use consts::{TRANSPORT_OVERHEAD, TRANSPORT_HEADER_SIZE, REKEY_AFTER_MESSAGES, REKEY_AFTER_TIME,
-             REKEY_AFTER_TIME_RECV, REJECT_AFTER_TIME, REJECT_AFTER_MESSAGES, PADDING_MULTIPLE,
-             MAX_QUEUED_PACKETS, MAX_HANDSHAKE_ATTEMPTS};
+use consts::{TRANSPORT_OVERHEAD, TRANSPORT_HEADER_SIZE, REKEY_AFTER_MESSAGES,
+             REJECT_AFTER_MESSAGES, PADDING_MULTIPLE,
+             MAX_QUEUED_PACKETS, LATENCY_PROBE_SIZE, LATENCY_PROBE, LATENCY_ECHO, RECONNECT_BACKOFF_MIN, RECONNECT_BACKOFF_MAX};
 use cookie;
+use error::DropReason;
 use failure::{Error, err_msg};
+use futures::sync::mpsc;
 use interface::UtunPacket;
 use ip_packet::IpPacket;
 use noise;
+use rand::{self, Rng};
+use ratelimiter::TokenBucket;
 use message::{Initiation, Response, CookieReply, Transport};
 use std::{self, mem};
 use std::collections::VecDeque;
 use std::fmt::{self, Debug, Display, Formatter};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::net::SocketAddr;
+use std::sync::{Arc, atomic::{AtomicU64, Ordering}};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use hex;
 use timer::TimerHandle;
 use timestamp::{Tai64n, Timestamp};
 use snow;
-use types::PeerInfo;
+use types::{PeerInfo, PublicKey, TimerConfig};
 use udp::Endpoint;
+use zeroize::zeroize;
+
+/// How far back a session's anti-replay window reaches unless the interface says otherwise.
+pub use anti_replay::DEFAULT_WINDOW_SIZE as DEFAULT_REPLAY_WINDOW_SIZE;
 
 pub struct Peer {
-    pub info                  : PeerInfo,
-    pub sessions              : Sessions,
-    pub timers                : Timers,
-    pub tx_bytes              : u64,
-    pub rx_bytes              : u64,
-    pub last_handshake_tai64n : Option<Tai64n>,
-    pub outgoing_queue        : VecDeque<UtunPacket>,
-    pub cookie                : cookie::Generator,
+    pub info                       : PeerInfo,
+    pub sessions                   : Sessions,
+    pub timers                     : Timers,
+    pub tx_bytes                   : u64,
+    pub rx_bytes                   : u64,
+    pub tx_packets                 : u64,
+    pub rx_packets                 : u64,
+    pub anti_replay_drops          : u64,
+    /// Set while `info.max_inbound_pps` limits the transport packets we'll take from the peer.
+    pub inbound_limiter            : Option<TokenBucket>,
+    pub inbound_rate_limited_drops : u64,
+    /// Round trip of our latest timestamped keepalive, from sending it to its echo coming back.
+    pub last_rtt                   : Option<Duration>,
+    pub last_handshake_tai64n      : Option<Tai64n>,
+    pub outgoing_queue             : VecDeque<(UtunPacket, Instant)>,
+    pub cookie                     : cookie::Generator,
+    pub connection_state           : PeerConnectionState,
+    pub active_endpoint            : usize,
+    /// What `info.endpoint_host` last resolved to. Only a change in that moves the endpoint,
+    /// so re-resolving doesn't undo the peer roaming.
+    pub resolved_endpoint          : Option<SocketAddr>,
+    /// The anti-replay window of sessions set up from here on, per the interface's
+    /// `replay_window_size`.
+    pub replay_window_size         : u32,
+    /// The interface's `timers`, copied here so they can be read under the peer's lock alone.
+    pub timer_config               : TimerConfig,
+    /// Messages a session may carry each way, `REJECT_AFTER_MESSAGES` outside of tests.
+    pub reject_after_messages      : u64,
+    failed_endpoints               : usize,
+    state_watchers                 : Vec<mpsc::UnboundedSender<PeerConnectionState>>,
+    /// Send time of a timestamped keepalive from the peer that we've yet to echo back.
+    latency_echo                   : Option<u64>,
+}
+
+impl Drop for Peer {
+    fn drop(&mut self) {
+        self.mark_dead();
+        if let Some(ref mut psk) = self.info.psk {
+            zeroize(psk);
+        }
+    }
 }
 
 impl PartialEq for Peer {
@@ -47,6 +90,27 @@ pub enum SessionType {
     Past, Current, Next
 }
 
+/// Which side of the handshake we were on when a session was set up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HandshakeRole {
+    Initiator, Responder
+}
+
+/// Where a peer is in its handshake/transport lifecycle, as implied by its session slots.
+#[derive(Debug, PartialEq)]
+pub enum SessionState {
+    Idle, Initiating, Responding, Transport, Dead
+}
+
+/// Connectivity as seen by anyone embedding the interface, published to `Peer::subscribe` receivers.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PeerConnectionState {
+    Idle,
+    HandshakePending { since: Instant },
+    Connected { since: Instant, endpoint: SocketAddr },
+    Dead,
+}
+
 #[derive(Debug, PartialEq)]
 pub enum SessionTransition {
     NoTransition, Transition(Option<u32>)
@@ -55,53 +119,85 @@ pub enum SessionTransition {
 #[derive(Default)]
 pub struct Timers {
     pub data_sent               : Timestamp,
+    /// When data first went out since we last heard from the peer, unset once we do.
+    pub data_sent_unanswered    : Timestamp,
     pub data_received           : Timestamp,
     pub authenticated_received  : Timestamp,
     pub authenticated_traversed : Timestamp,
     pub egress_queued           : Timestamp,
     pub handshake_completed     : Timestamp,
     pub handshake_initialized   : Timestamp,
+    pub rekey_attempt_started   : Timestamp,
     pub persistent_timer        : Option<TimerHandle>,
+    pub rekey_timer             : Option<TimerHandle>,
     pub handshake_attempts      : u64,
-    pub keepalive_sent          : bool
+    pub keepalive_sent          : bool,
+    pub reconnect_backoff       : Duration,
+    pub next_reconnect          : Option<Instant>,
+}
+
+/// Bytes a session has carried, shared with the interface's session history so the totals
+/// outlive the session itself.
+#[derive(Debug, Default)]
+pub struct SessionCounters {
+    pub tx_bytes : AtomicU64,
+    pub rx_bytes : AtomicU64,
 }
 
 pub struct Session {
-    pub noise       : snow::Session,
-    pub our_index   : u32,
-    pub their_index : u32,
-    pub anti_replay : AntiReplay,
-    pub birthday    : Timestamp,
+    pub noise           : snow::Session,
+    pub our_index       : u32,
+    pub their_index     : u32,
+    pub anti_replay     : AntiReplay,
+    pub birthday        : Timestamp,
+    pub role            : HandshakeRole,
+    pub counters        : Arc<SessionCounters>,
+    /// Whether the interface has already told its hooks this session expired.
+    pub expiry_reported : bool,
 }
 
 impl Session {
-    pub fn new(noise: snow::Session, our_index: u32) -> Session {
+    pub fn new(noise: snow::Session, our_index: u32, replay_window_size: u32) -> Session {
         Session {
             noise,
             our_index,
-            their_index : 0,
-            anti_replay : AntiReplay::default(),
-            birthday    : Timestamp ::default(),
+            their_index     : 0,
+            anti_replay     : AntiReplay::new(replay_window_size),
+            birthday        : Timestamp ::default(),
+            role            : HandshakeRole::Initiator,
+            counters        : Default::default(),
+            expiry_reported : false,
         }
     }
 
-    pub fn with_their_index(noise: snow::Session, our_index: u32, their_index: u32) -> Session {
+    pub fn with_their_index(noise: snow::Session, our_index: u32, their_index: u32, replay_window_size: u32) -> Session {
         Session {
             noise,
             our_index,
             their_index,
-            anti_replay : AntiReplay::default(),
-            birthday    : Timestamp ::default(),
+            anti_replay     : AntiReplay::new(replay_window_size),
+            birthday        : Timestamp ::default(),
+            role            : HandshakeRole::Responder,
+            counters        : Default::default(),
+            expiry_reported : false,
         }
     }
 
+    /// Sessions may neither send nor receive once they're older than `reject_after_time`.
+    pub fn is_expired(&self, reject_after_time: Duration) -> bool {
+        self.birthday.elapsed() >= reject_after_time
+    }
+
     pub fn into_transport_mode(self) -> Result<Session, Error> {
         Ok(Session {
-            noise       : self.noise.into_transport_mode()?,
-            our_index   : self.our_index,
-            their_index : self.their_index,
-            anti_replay : self.anti_replay,
-            birthday    : self.birthday,
+            noise           : self.noise.into_transport_mode()?,
+            our_index       : self.our_index,
+            their_index     : self.their_index,
+            anti_replay     : self.anti_replay,
+            birthday        : self.birthday,
+            role            : self.role,
+            counters        : self.counters,
+            expiry_reported : self.expiry_reported,
         })
     }
 }
@@ -118,6 +214,10 @@ impl IncompleteIncomingHandshake {
     }
 }
 
+/// A peer's keypairs. `current` carries traffic, `next` is a handshake in progress (or, for a
+/// responder, one waiting for the initiator's first transport packet) and `past` is the
+/// previous `current`, kept around for packets still in flight. Every slot's index stays in
+/// the index map while it's filled, so traffic keeps flowing on `current` during a rekey.
 #[derive(Default)]
 pub struct Sessions {
     pub past    : Option<Session>,
@@ -151,16 +251,124 @@ impl Debug for Peer {
 
 impl Peer {
     pub fn new(info: PeerInfo) -> Peer {
-        let cookie = cookie::Generator::new(&info.pub_key);
-        Peer {
+        let cookie   = cookie::Generator::new(info.pub_key.as_ref());
+        let mut peer = Peer {
             info,
             cookie,
-            sessions              : Default::default(),
-            timers                : Default::default(),
-            tx_bytes              : Default::default(),
-            rx_bytes              : Default::default(),
-            last_handshake_tai64n : Default::default(),
-            outgoing_queue        : Default::default(),
+            sessions                   : Default::default(),
+            timers                     : Timers { reconnect_backoff: *RECONNECT_BACKOFF_MIN, ..Default::default() },
+            tx_bytes                   : Default::default(),
+            rx_bytes                   : Default::default(),
+            tx_packets                 : Default::default(),
+            rx_packets                 : Default::default(),
+            anti_replay_drops          : Default::default(),
+            inbound_limiter            : None,
+            inbound_rate_limited_drops : 0,
+            last_rtt                   : None,
+            last_handshake_tai64n      : Default::default(),
+            outgoing_queue             : Default::default(),
+            connection_state           : PeerConnectionState::Idle,
+            active_endpoint            : 0,
+            resolved_endpoint          : None,
+            replay_window_size         : DEFAULT_REPLAY_WINDOW_SIZE,
+            timer_config               : TimerConfig::default(),
+            reject_after_messages      : REJECT_AFTER_MESSAGES,
+            failed_endpoints           : 0,
+            state_watchers             : vec![],
+            latency_echo               : None,
+        };
+        peer.update_inbound_limit();
+        peer
+    }
+
+    /// Brings the inbound limiter in line with `info.max_inbound_pps`. The bucket carries on as
+    /// it was if the rate hasn't changed.
+    pub fn update_inbound_limit(&mut self) {
+        match self.info.max_inbound_pps {
+            Some(rate) if rate > 0 => {
+                if self.inbound_limiter.as_ref().map_or(true, |limiter| limiter.rate() as u32 != rate) {
+                    self.inbound_limiter = Some(TokenBucket::new(rate));
+                }
+            },
+            _ => self.inbound_limiter = None,
+        }
+    }
+
+    /// Returns a stream of connection state changes, starting with the current state. The stream
+    /// ends when the peer is removed.
+    pub fn subscribe(&mut self) -> mpsc::UnboundedReceiver<PeerConnectionState> {
+        let (tx, rx) = mpsc::unbounded();
+        let _ = tx.unbounded_send(self.connection_state.clone());
+        self.state_watchers.push(tx);
+        rx
+    }
+
+    fn set_connection_state(&mut self, state: PeerConnectionState) {
+        if self.connection_state == state {
+            return;
+        }
+        self.connection_state = state;
+        let state = &self.connection_state;
+        self.state_watchers.retain(|tx| tx.unbounded_send(state.clone()).is_ok());
+    }
+
+    fn mark_connected(&mut self, endpoint: SocketAddr) {
+        let state = PeerConnectionState::Connected { since: Instant::now(), endpoint };
+        self.set_connection_state(state);
+    }
+
+    /// Records that we've given up on reaching this peer until something new prompts a handshake.
+    pub fn mark_dead(&mut self) {
+        self.set_connection_state(PeerConnectionState::Dead);
+    }
+
+    /// Marks the peer dead for good as it's removed from the interface, ending every stream
+    /// `subscribe` handed out.
+    pub fn mark_removed(&mut self) {
+        self.mark_dead();
+        self.state_watchers.clear();
+    }
+
+    /// Picks when to try reaching this peer again after giving up on it. The wait doubles with
+    /// each failure up to RECONNECT_BACKOFF_MAX, give or take 20% so that peers which went away
+    /// together don't all come back at once. Returns how long to wait.
+    pub fn schedule_reconnect(&mut self) -> Duration {
+        let backoff = self.timers.reconnect_backoff;
+        let delay   = backoff * rand::thread_rng().gen_range(80, 121) / 100;
+        self.timers.next_reconnect    = Some(Instant::now() + delay);
+        self.timers.reconnect_backoff = (backoff * 2).min(*RECONNECT_BACKOFF_MAX);
+        delay
+    }
+
+    pub fn reconnect_due(&self) -> bool {
+        self.timers.next_reconnect.map_or(false, |at| at <= Instant::now())
+    }
+
+    /// Hearing from the peer again means the next failure starts backing off from scratch, and
+    /// with every endpoint worth trying again.
+    fn reset_reconnect_backoff(&mut self) {
+        self.timers.reconnect_backoff = *RECONNECT_BACKOFF_MIN;
+        self.timers.next_reconnect    = None;
+        self.failed_endpoints         = 0;
+    }
+
+    /// Switches to the next of the peer's configured endpoints after a handshake went
+    /// unanswered. Returns false, having wrapped around to where it started, once every
+    /// endpoint has failed since we last heard from the peer.
+    pub fn fail_over_endpoint(&mut self) -> bool {
+        let count = self.info.endpoints.len();
+        if count < 2 {
+            return false;
+        }
+
+        self.active_endpoint   = (self.active_endpoint + 1) % count;
+        self.info.endpoint     = Some(self.info.endpoints[self.active_endpoint].into());
+        self.failed_endpoints += 1;
+        if self.failed_endpoints < count {
+            true
+        } else {
+            self.failed_endpoints = 0;
+            false
         }
     }
 
@@ -175,34 +383,50 @@ impl Peer {
         }
     }
 
+    /// Holds `packet` until there's a session to send it with, making room by dropping the
+    /// oldest queued packet if need be.
     pub fn queue_egress(&mut self, packet: UtunPacket) {
-        if self.outgoing_queue.len() < MAX_QUEUED_PACKETS {
-            self.outgoing_queue.push_back(packet);
-            self.timers.handshake_attempts = 0;
-        } else {
-            debug!("dropping pending egress packet because the queue is full");
+        self.drop_stale_egress();
+        if self.outgoing_queue.len() >= MAX_QUEUED_PACKETS {
+            debug!("egress queue full, dropping the oldest pending packet");
+            let _ = self.outgoing_queue.pop_front();
+        }
+        self.outgoing_queue.push_back((packet, Instant::now()));
+        self.timers.handshake_attempts = 0;
+    }
+
+    /// Empties the egress queue, leaving out packets that waited longer than `rekey_attempt_time`.
+    pub fn take_queued_egress(&mut self) -> Vec<UtunPacket> {
+        self.drop_stale_egress();
+        self.outgoing_queue.drain(..).map(|(packet, _)| packet).collect()
+    }
+
+    fn drop_stale_egress(&mut self) {
+        let rekey_attempt_time = self.timer_config.rekey_attempt_time;
+        while self.outgoing_queue.front().map_or(false, |&(_, queued)| queued.elapsed() >= rekey_attempt_time) {
+            let _ = self.outgoing_queue.pop_front();
         }
     }
 
     pub fn needs_new_handshake(&self, sending: bool) -> bool {
         if self.sessions.next.is_some() {
             trace!("needs new handshake: {} attempts", self.timers.handshake_attempts);
-            return self.timers.handshake_attempts >= *MAX_HANDSHAKE_ATTEMPTS;
+            return self.rekey_attempt_expired();
         }
         if self.sessions.current.is_none() {
             debug!("needs new handshake: no current session");
             return true;
         }
-        if sending && self.timers.handshake_completed.elapsed() > *REKEY_AFTER_TIME {
+        if sending && self.timers.handshake_completed.elapsed() > self.timer_config.rekey_after_time {
             debug!("needs new handshake: sending after REKEY_AFTER_TIME");
             return true;
         }
-        if !sending && self.timers.handshake_completed.elapsed() > *REKEY_AFTER_TIME_RECV {
+        if !sending && self.timers.handshake_completed.elapsed() > self.timer_config.rekey_after_time_recv() {
             debug!("needs new handshake: receiving after RECV_REKEY_AFTER_TIME");
             return true;
         }
         if let Some(ref session) = self.sessions.current {
-            if session.noise.sending_nonce().unwrap() >= REKEY_AFTER_MESSAGES {
+            if session.noise.sending_nonce().map(|nonce| nonce >= REKEY_AFTER_MESSAGES).unwrap_or(true) {
                 debug!("needs new handshake: nonce >= REKEY_AFTER_MESSAGES");
                 return true;
             }
@@ -210,15 +434,57 @@ impl Peer {
         false
     }
 
+    /// Whether the last initiation has had its time to be answered, so another may be sent.
+    /// Keeps a burst of packets waiting on one handshake from setting off an initiation each.
+    pub fn initiation_due(&self) -> bool {
+        let last_retry_timeout = self.timer_config.handshake_retry_timeout(self.timers.handshake_attempts.saturating_sub(1));
+        self.timers.handshake_initialized.elapsed() >= last_retry_timeout
+    }
+
+    /// Whether we've been trying (and failing) to complete a handshake for longer than `rekey_attempt_time`.
+    pub fn rekey_attempt_expired(&self) -> bool {
+        self.timers.rekey_attempt_started.is_set() && self.timers.rekey_attempt_started.elapsed() >= self.timer_config.rekey_attempt_time
+    }
+
+    /// Whether the peer has gone quiet on us: we sent it data and nothing authenticated came
+    /// back within `keepalive_timeout + rekey_timeout`, WireGuard's new-handshake timer. Keepalives
+    /// don't count, as nothing answers them on an idle tunnel.
+    pub fn is_unresponsive(&self) -> bool {
+        let unanswered = &self.timers.data_sent_unanswered;
+
+        unanswered.is_set()
+            && self.sessions.current.is_some()
+            && unanswered.elapsed() > self.timer_config.keepalive_timeout + self.timer_config.rekey_timeout
+            && self.timers.handshake_completed.elapsed() > self.timer_config.rekey_timeout
+    }
+
     pub fn ready_for_transport(&self) -> bool {
         if let Some(ref current) = self.sessions.current {
-            current.birthday.elapsed() < *REJECT_AFTER_TIME && 
-                current.noise.sending_nonce().unwrap() < REJECT_AFTER_MESSAGES
+            !current.is_expired(self.timer_config.reject_after_time) && current.noise.sending_nonce().map(|nonce| nonce < self.reject_after_messages).unwrap_or(false)
         } else {
             false
         }
     }
 
+    pub fn session_state(&self) -> SessionState {
+        if self.ready_for_transport() {
+            return SessionState::Transport;
+        }
+        match (&self.sessions.next, &self.sessions.current) {
+            (&Some(ref next), _) if next.noise.is_handshake_finished() => SessionState::Responding,
+            (&Some(_), _)                                                => SessionState::Initiating,
+            (&None, &Some(_))                                            => SessionState::Dead,
+            (&None, &None)                                               => SessionState::Idle,
+        }
+    }
+
+    /// Drop every session, returning the indices that should be removed from the index map.
+    pub fn expire(&mut self) -> Vec<u32> {
+        self.timers.handshake_attempts = 0;
+        self.set_connection_state(PeerConnectionState::Idle);
+        self.sessions.wipe()
+    }
+
     pub fn get_mapped_indices(&self) -> Vec<u32> {
         let mut indices = Vec::with_capacity(3);
         if let Some(ref session) = self.sessions.past    { indices.push(session.our_index); }
@@ -227,9 +493,9 @@ impl Peer {
         indices
     }
 
-    pub fn initiate_new_session(&mut self, private_key: &[u8], index: u32) -> Result<(Endpoint, Vec<u8>, Option<u32>), Error> {
-        let     noise    = noise::build_initiator(private_key, &self.info.pub_key, &self.info.psk)?;
-        let mut session  = Session::new(noise, index);
+    pub fn initiate_new_session(&mut self, private_key: &[u8], protocol: &str, index: u32) -> Result<(Endpoint, Vec<u8>, Option<u32>), Error> {
+        let     noise    = noise::build_initiator(protocol, private_key, self.info.pub_key.as_ref(), &self.info.psk)?;
+        let mut session  = Session::new(noise, index, self.replay_window_size);
         let     endpoint = self.info.endpoint.ok_or_else(|| err_msg("no known peer endpoint"))?;
         let mut packet   = vec![0; 148];
 
@@ -250,17 +516,61 @@ impl Peer {
         } else {
             None
         };
+        self.timers.handshake_initialized = Timestamp::now();
+
+        let pending = match self.connection_state {
+            PeerConnectionState::HandshakePending { .. } => true,
+            _                                            => false,
+        };
+        if !pending && !self.ready_for_transport() {
+            self.set_connection_state(PeerConnectionState::HandshakePending { since: Instant::now() });
+        }
 
         Ok((endpoint, packet, dead_index))
     }
 
-    pub fn process_incoming_handshake(private_key: &[u8], packet: &Initiation) -> Result<IncompleteIncomingHandshake, Error> {
+    /// Settles an initiation from this peer that crossed our own outstanding one, once it's
+    /// authenticated and its timestamp accepted, so a replayed one can't cancel ours. If ours went
+    /// out less than `rekey_timeout` ago, the side with the higher public key abandons its attempt
+    /// and responds instead, and the other drops the incoming initiation and waits for the
+    /// response to its own. An older one of ours has likely been lost, so it's abandoned either way.
+    ///
+    /// Returns the index of our abandoned session, if we gave one up.
+    pub fn resolve_initiation_conflict(&mut self, our_pub_key: &PublicKey, incoming: &IncompleteIncomingHandshake) -> Result<Option<u32>, Error> {
+        self.check_initiation_timestamp(incoming.timestamp)?;
+        match self.sessions.next {
+            Some(ref next) if !next.noise.is_handshake_finished() => {},
+            _                                                      => return Ok(None),
+        }
+
+        let crossed = self.timers.handshake_initialized.elapsed() < self.timer_config.rekey_timeout;
+        if crossed && our_pub_key.0 < self.info.pub_key.0 {
+            return Err(DropReason::InitiationConflict.into());
+        }
+
+        if let Some(mut handle) = self.timers.rekey_timer.take() {
+            handle.cancel();
+        }
+        Ok(self.sessions.next.take().map(|session| session.our_index))
+    }
+
+    /// Refuses an initiation whose timestamp isn't later than the last one accepted, as it may be a replay.
+    fn check_initiation_timestamp(&self, timestamp: Tai64n) -> Result<(), Error> {
+        if self.last_handshake_tai64n.map_or(false, |last_tai64n| timestamp <= last_tai64n) {
+            debug!("rejecting initiation from {} with a timestamp no later than the last one", self.info);
+            return Err(DropReason::ReplayAttack.into());
+        }
+        Ok(())
+    }
+
+    pub fn process_incoming_handshake(private_key: &[u8], protocol: &str, packet: &Initiation) -> Result<IncompleteIncomingHandshake, Error> {
         let mut timestamp = [0u8; 12];
-        let mut noise     = noise::build_responder(private_key)?;
+        let mut noise     = noise::build_responder(protocol, private_key)?;
 
-        let len = noise.read_message(packet.noise_bytes(), &mut timestamp)?;
+        let len = noise.read_message(packet.noise_bytes(), &mut timestamp)
+            .map_err(|_| err_msg("couldn't decrypt handshake initiation, wrong key or noise protocol?"))?;
         ensure!(len == 12, "incorrect handshake payload length");
-        let timestamp = timestamp.into();
+        let timestamp = Tai64n::from_bytes(&timestamp);
 
         Ok(IncompleteIncomingHandshake { their_index: packet.sender_index(), timestamp, noise })
     }
@@ -271,14 +581,11 @@ impl Peer {
     /// Returns: the response packet (type 0x02), and an optional dead session index that was removed.
     pub fn complete_incoming_handshake(&mut self, addr: Endpoint, index: u32, incomplete: IncompleteIncomingHandshake) -> Result<(Vec<u8>, Option<u32>), Error> {
         let IncompleteIncomingHandshake { timestamp, their_index, mut noise } = incomplete;
-
-        if let Some(ref last_tai64n) = self.last_handshake_tai64n {
-            ensure!(&timestamp > last_tai64n, "handshake timestamp earlier than last handshake's timestamp");
-        }
+        self.check_initiation_timestamp(timestamp)?;
 
         noise.set_psk(2, &self.info.psk.unwrap_or_else(|| [0u8; 32]))?;
 
-        let mut next_session  = Session::with_their_index(noise, index, their_index);
+        let mut next_session  = Session::with_their_index(noise, index, their_index, self.replay_window_size);
         next_session.birthday = Timestamp::now();
 
         let response_packet = self.get_response_packet(&mut next_session)?;
@@ -293,7 +600,9 @@ impl Peer {
         self.info.endpoint                  = Some(addr);
         self.last_handshake_tai64n          = Some(timestamp);
         self.timers.authenticated_received  = Timestamp::now();
+        self.timers.data_sent_unanswered    = Timestamp::unset();
         self.timers.authenticated_traversed = Timestamp::now();
+        self.reset_reconnect_backoff();
 
         Ok((response_packet, dead_index))
     }
@@ -319,7 +628,8 @@ impl Peer {
 
     pub fn process_incoming_handshake_response(&mut self, addr: Endpoint, packet: &Response) -> Result<Option<u32>, Error> {
         let mut session = mem::replace(&mut self.sessions.next, None).ok_or_else(|| err_msg("no next session"))?;
-        let     _       = session.noise.read_message(packet.noise_bytes(), &mut [])?;
+        let     _       = session.noise.read_message(packet.noise_bytes(), &mut [])
+            .map_err(|_| err_msg("couldn't decrypt handshake response, wrong key or noise protocol?"))?;
 
         session             = session.into_transport_mode()?;
         session.their_index = packet.sender_index();
@@ -327,11 +637,15 @@ impl Peer {
 
         self.info.endpoint                  = Some(addr);
         self.timers.authenticated_received  = Timestamp::now();
+        self.timers.data_sent_unanswered    = Timestamp::unset();
         self.timers.authenticated_traversed = Timestamp::now();
         self.timers.handshake_completed     = Timestamp::now();
+        self.timers.rekey_attempt_started   = Timestamp::unset();
+        self.reset_reconnect_backoff();
 
         let current = mem::replace(&mut self.sessions.current, Some(session));
         let dead    = mem::replace(&mut self.sessions.past,    current);
+        self.mark_connected(*addr);
 
         Ok(dead.map(|session| session.our_index))
     }
@@ -339,26 +653,58 @@ impl Peer {
     pub fn handle_incoming_transport(&mut self, addr: Endpoint, packet: &Transport)
         -> Result<(Vec<u8>, SessionTransition), Error> {
 
+        if let Some(ref mut limiter) = self.inbound_limiter {
+            if !limiter.take() {
+                self.inbound_rate_limited_drops += 1;
+                return Err(DropReason::RateLimited.into());
+            }
+        }
+
         let mut raw_packet = vec![0u8; packet.len()];
+        let mut probe      = None;
         let     nonce      = packet.nonce();
 
+        let reject_after_time     = self.timer_config.reject_after_time;
+        let reject_after_messages = self.reject_after_messages;
+        let fresh_nonce = {
+            let (session, _) = self.find_session(packet.our_index()).ok_or(DropReason::UnknownSessionIndex)?;
+            ensure!(session.noise.is_handshake_finished(), "session is not ready for transport packets");
+            if nonce >= reject_after_messages {
+                return Err(DropReason::RejectAfterMessages.into());
+            }
+            if session.is_expired(reject_after_time) {
+                return Err(DropReason::SessionExpired.into());
+            }
+
+            session.anti_replay.update(nonce).is_ok()
+        };
+
+        if !fresh_nonce {
+            self.anti_replay_drops += 1;
+            return Err(DropReason::ReplayAttack.into());
+        }
+
         let session_type = {
             let (session, session_type) = self.find_session(packet.our_index()).ok_or_else(|| err_msg("no session with index"))?;
-            ensure!(session.noise.is_handshake_finished(),              "session is not ready for transport packets");
-            ensure!(nonce                      < REJECT_AFTER_MESSAGES, "exceeded REJECT-AFTER-MESSAGES");
-            ensure!(session.birthday.elapsed() < *REJECT_AFTER_TIME,    "exceeded REJECT-AFTER-TIME");
-
-            session.anti_replay.update(nonce)?;
             session.noise.set_receiving_nonce(nonce)?;
             let len = session.noise.read_message(packet.payload(), &mut raw_packet)?;
-            if len > 0 {
+            if nonce + 1 >= reject_after_messages {
+                // that was the last message the session may carry, so it's done with.
+                session.birthday = Timestamp::unset();
+            }
+            if len == LATENCY_PROBE_SIZE {
+                // too short for an IP packet, so it's a timestamped keepalive or its echo.
+                probe = Some((LittleEndian::read_u64(&raw_packet[..8]), raw_packet[8] == LATENCY_ECHO));
+                raw_packet.truncate(0);
+            } else if len > 0 {
                 let len = IpPacket::new(&raw_packet[..len])
-                    .ok_or_else(||format_err!("invalid IP packet (len {})", len))?
+                    .ok_or(DropReason::MalformedPacket)?
                     .length();
                 raw_packet.truncate(len as usize);
             } else {
                 raw_packet.truncate(0);
             }
+            let _ = session.counters.rx_bytes.fetch_add(raw_packet.len() as u64, Ordering::Relaxed);
 
             session_type
         };
@@ -366,9 +712,21 @@ impl Peer {
         if !raw_packet.is_empty() {
             self.timers.data_received = Timestamp::now();
         }
+        match probe {
+            Some((sent, true))  => {
+                // our own send time come back, so both ends of the round trip are by our clock.
+                let rtt       = unix_nanos().saturating_sub(sent);
+                self.last_rtt = Some(Duration::new(rtt / 1_000_000_000, (rtt % 1_000_000_000) as u32));
+            },
+            Some((sent, false)) => self.latency_echo = Some(sent),
+            None                => {},
+        }
         self.timers.authenticated_received  = Timestamp::now();
+        self.timers.data_sent_unanswered    = Timestamp::unset();
         self.timers.authenticated_traversed = Timestamp::now();
         self.timers.keepalive_sent          = false; // reset passive keepalive token since received a valid ingress transport
+        self.timers.rekey_attempt_started   = Timestamp::unset();
+        self.reset_reconnect_backoff();
 
         let transition = if session_type == SessionType::Next {
             debug!("moving 'next' session to current after receiving first transport packet");
@@ -377,21 +735,60 @@ impl Peer {
             let dead    = std::mem::replace(&mut self.sessions.past, current);
 
             self.timers.handshake_completed = Timestamp::now();
+            self.mark_connected(*addr);
 
             SessionTransition::Transition(dead.map(|session| session.our_index))
         } else {
             SessionTransition::NoTransition
         };
 
-        self.rx_bytes     += packet.len() as u64;
+        self.rx_bytes     += raw_packet.len() as u64;
+        self.rx_packets   += 1;
         self.info.endpoint = Some(addr); // update peer endpoint after successful authentication
 
+        if let PeerConnectionState::Connected { since, endpoint } = self.connection_state.clone() {
+            if endpoint != *addr {
+                self.set_connection_state(PeerConnectionState::Connected { since, endpoint: *addr });
+            }
+        }
+
         Ok((raw_packet, transition))
     }
 
     pub fn handle_outgoing_transport(&mut self, packet: &[u8]) -> Result<(Endpoint, Vec<u8>), Error> {
+        self.seal_transport(packet, false)
+    }
+
+    /// An empty transport packet, or with `measure_latency` one carrying only its send time
+    /// for the other side to echo back.
+    pub fn handle_outgoing_keepalive(&mut self, measure_latency: bool) -> Result<(Endpoint, Vec<u8>), Error> {
+        if measure_latency {
+            self.seal_latency_probe(unix_nanos(), LATENCY_PROBE)
+        } else {
+            self.seal_transport(&[], false)
+        }
+    }
+
+    /// The echo of the peer's latest timestamped keepalive, if it's still owed one.
+    pub fn handle_outgoing_latency_echo(&mut self) -> Result<Option<(Endpoint, Vec<u8>)>, Error> {
+        match self.latency_echo.take() {
+            Some(sent) => self.seal_latency_probe(sent, LATENCY_ECHO).map(Some),
+            None       => Ok(None),
+        }
+    }
+
+    fn seal_latency_probe(&mut self, sent: u64, kind: u8) -> Result<(Endpoint, Vec<u8>), Error> {
+        let mut probe = [0u8; 9];
+        LittleEndian::write_u64(&mut probe, sent);
+        probe[8] = kind;
+        self.seal_transport(&probe, true)
+    }
+
+    fn seal_transport(&mut self, packet: &[u8], probe: bool) -> Result<(Endpoint, Vec<u8>), Error> {
         let session        = self.sessions.current.as_mut().ok_or_else(|| err_msg("no current noise session"))?;
         let endpoint       = self.info.endpoint.ok_or_else(|| err_msg("no known peer endpoint"))?;
+        let reject_after   = self.timer_config.reject_after_time;
+        let max_messages   = self.reject_after_messages;
         let padding        = if packet.len() % PADDING_MULTIPLE != 0 {
             PADDING_MULTIPLE - (packet.len() % PADDING_MULTIPLE)
         } else { 0 };
@@ -399,18 +796,29 @@ impl Peer {
         let mut out_packet = vec![0u8; padded_len + TRANSPORT_OVERHEAD];
 
         let nonce = session.noise.sending_nonce()?;
-        ensure!(nonce                      < REJECT_AFTER_MESSAGES, "exceeded REJECT-AFTER-MESSAGES");
-        ensure!(session.birthday.elapsed() < *REJECT_AFTER_TIME,    "exceeded REJECT-AFTER-TIME");
+        if nonce >= max_messages {
+            session.birthday = Timestamp::unset();
+            return Err(DropReason::RejectAfterMessages.into());
+        }
+        ensure!(!session.is_expired(reject_after), "exceeded REJECT-AFTER-TIME");
 
         out_packet[0] = 4;
         LittleEndian::write_u32(&mut out_packet[4..], session.their_index);
         LittleEndian::write_u64(&mut out_packet[8..], nonce);
         let padded_packet = &[packet, &vec![0u8; padding]].concat();
         let len = session.noise.write_message(padded_packet, &mut out_packet[16..])?;
-        self.tx_bytes += len as u64;
+        if nonce + 1 >= max_messages {
+            session.birthday = Timestamp::unset();
+        }
+        self.tx_packets += 1;
 
-        if !packet.is_empty() {
+        if !packet.is_empty() && !probe {
+            self.tx_bytes        += packet.len() as u64;
             self.timers.data_sent = Timestamp::now();
+            if !self.timers.data_sent_unanswered.is_set() {
+                self.timers.data_sent_unanswered = Timestamp::now();
+            }
+            let _ = session.counters.tx_bytes.fetch_add(packet.len() as u64, Ordering::Relaxed);
         }
         self.timers.authenticated_traversed = Timestamp::now();
 
@@ -424,7 +832,7 @@ impl Peer {
             s.push_str(&format!("preshared_key={}\n", hex::encode(psk)));
         }
         if let Some(ref endpoint) = self.info.endpoint {
-            s.push_str(&format!("endpoint={}:{}\n", endpoint.ip().to_string(),endpoint.port()));
+            s.push_str(&format!("endpoint={}\n", **endpoint));
         }
         if let Some(keepalive) = self.info.keepalive {
             s.push_str(&format!("persistent_keepalive_interval={}\n",keepalive));
@@ -433,9 +841,16 @@ impl Peer {
             s.push_str(&format!("allowed_ip={}/{}\n", ip, cidr));
         }
         s.push_str(&format!("tx_bytes={}\nrx_bytes={}\n", self.tx_bytes, self.rx_bytes));
+        s.push_str(&format!("anti_replay_drops={}\n", self.anti_replay_drops));
+        if let Some(pps) = self.info.max_inbound_pps.filter(|&pps| pps > 0) {
+            s.push_str(&format!("max_inbound_pps={}\ninbound_rate_limited_drops={}\n", pps, self.inbound_rate_limited_drops));
+        }
+        if let Some(rtt) = self.last_rtt {
+            s.push_str(&format!("last_rtt_nanoseconds={}\n", rtt.as_secs() * 1_000_000_000 + u64::from(rtt.subsec_nanos())));
+        }
 
-        if self.timers.handshake_completed.is_set() {
-            if let Ok(time) = (SystemTime::now() - self.timers.handshake_completed.elapsed()).duration_since(UNIX_EPOCH) {
+        if let Some(time) = self.last_handshake_time() {
+            if let Ok(time) = time.duration_since(UNIX_EPOCH) {
                 s.push_str(&format!("last_handshake_time_sec={}\nlast_handshake_time_nsec={}\n",
                                     time.as_secs(), time.subsec_nanos()));
             } else {
@@ -444,4 +859,652 @@ impl Peer {
         }
         s
     }
+
+    /// The lines a version 2 `get` response adds to each peer.
+    pub fn to_extended_config_string(&self) -> String {
+        match self.sessions.current {
+            Some(ref session) if session.birthday.is_set() => format!("session_age_seconds={}\n", session.birthday.elapsed().as_secs()),
+            _                                              => String::new(),
+        }
+    }
+
+    pub fn last_handshake_time(&self) -> Option<SystemTime> {
+        if self.timers.handshake_completed.is_set() {
+            Some(SystemTime::now() - self.timers.handshake_completed.elapsed())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+impl Peer {
+    /// Installs `noise`, which must have finished its handshake as the initiator, as the current
+    /// session without going through `Peer`'s own handshake handling.
+    pub fn pin_transport_session(&mut self, noise: snow::Session, our_index: u32, their_index: u32) -> Result<(), Error> {
+        let mut session     = Session::new(noise, our_index, self.replay_window_size).into_transport_mode()?;
+        session.their_index = their_index;
+        session.birthday    = Timestamp::now();
+        self.sessions.current = Some(session);
+        self.timers.handshake_completed = Timestamp::now();
+        Ok(())
+    }
+}
+
+fn unix_nanos() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    now.as_secs() * 1_000_000_000 + u64::from(now.subsec_nanos())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+    use byteorder::BigEndian;
+    use futures::Stream;
+    use test_peers::{keypair, endpoint, peer_pair, ordered_peer_pair, handshake};
+    use x25519_dalek::generate_public;
+    use types::PublicKey;
+
+    /// Runs the initiation and response halves of a handshake between two fresh peers,
+    /// returning (initiator, responder).
+    fn connected_peers() -> (Peer, Peer) {
+        connected_peers_with_psk(None)
+    }
+
+    fn connected_peers_with_psk(psk: Option<[u8; 32]>) -> (Peer, Peer) {
+        let (mut init, mut resp) = peer_pair(psk);
+        let response             = handshake(&mut init, &mut resp, 1, 2);
+        init.process_incoming_handshake_response(endpoint(2), &response).unwrap();
+        (init.peer, resp.peer)
+    }
+
+    #[test]
+    fn responder_handshake() {
+        let (mut init, mut resp) = connected_peers();
+        assert!(init.ready_for_transport());
+        assert!(resp.sessions.next.is_some());
+        assert!(!resp.ready_for_transport());
+
+        // The responder only promotes its session once the initiator proves it has the keys.
+        let (_, packet)        = init.handle_outgoing_transport(&[]).unwrap();
+        let packet : Transport = packet.try_into().unwrap();
+        let (raw, transition)  = resp.handle_incoming_transport(endpoint(1), &packet).unwrap();
+        assert!(raw.is_empty());
+        assert_eq!(transition, SessionTransition::Transition(None));
+        assert!(resp.ready_for_transport());
+        assert_eq!(resp.sessions.current.as_ref().unwrap().their_index, 1);
+    }
+
+    #[test]
+    fn replayed_transport_counted() {
+        let (mut init, mut resp) = connected_peers();
+        let (_, packet)          = init.handle_outgoing_transport(&[]).unwrap();
+        let packet : Transport   = packet.try_into().unwrap();
+
+        resp.handle_incoming_transport(endpoint(1), &packet).unwrap();
+        let err = resp.handle_incoming_transport(endpoint(1), &packet).unwrap_err();
+        assert_eq!(err.downcast_ref::<DropReason>(), Some(&DropReason::ReplayAttack));
+        assert!(resp.handle_incoming_transport(endpoint(1), &packet).is_err());
+        assert_eq!(resp.anti_replay_drops, 2);
+        assert!(resp.to_config_string().contains("anti_replay_drops=2\n"));
+    }
+
+    #[test]
+    fn persistent_keepalive_config() {
+        let mut peer = Peer::new(PeerInfo { keepalive: Some(25), ..Default::default() });
+        assert_eq!(peer.info.persistent_keepalive(), Some(std::time::Duration::from_secs(25)));
+        assert!(peer.to_config_string().contains("persistent_keepalive_interval=25\n"));
+
+        peer.info.keepalive = Some(0);
+        assert_eq!(peer.info.persistent_keepalive(), None);
+    }
+
+    #[test]
+    fn session_states() {
+        let (mut init, mut resp) = connected_peers();
+        assert_eq!(init.session_state(), SessionState::Transport);
+        assert_eq!(resp.session_state(), SessionState::Responding);
+
+        let (_, packet)        = init.handle_outgoing_transport(&[]).unwrap();
+        let packet : Transport = packet.try_into().unwrap();
+        resp.handle_incoming_transport(endpoint(1), &packet).unwrap();
+        assert_eq!(resp.session_state(), SessionState::Transport);
+
+        assert_eq!(resp.expire().len(), 1);
+        assert_eq!(resp.session_state(), SessionState::Idle);
+        assert!(resp.handle_outgoing_transport(&[]).is_err());
+    }
+
+    #[test]
+    fn connection_state_updates() {
+        let (mut init, mut resp) = connected_peers();
+        let rx = resp.subscribe();
+        for port in &[1, 3] {
+            let (_, packet)        = init.handle_outgoing_transport(&[]).unwrap();
+            let packet : Transport = packet.try_into().unwrap();
+            resp.handle_incoming_transport(endpoint(*port), &packet).unwrap();
+        }
+        let _ = resp.expire();
+        drop(resp);
+
+        let states = rx.wait().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(states.len(), 5);
+        assert_eq!(states[0], PeerConnectionState::Idle);
+        match (&states[1], &states[2]) {
+            (&PeerConnectionState::Connected { since: first, endpoint: from },
+             &PeerConnectionState::Connected { since: second, endpoint: to }) => {
+                assert_eq!(first, second);
+                assert_eq!((from, to), (*endpoint(1), *endpoint(3)));
+            },
+            other => panic!("expected two connected states, got {:?}", other),
+        }
+        assert_eq!(&states[3..], &[PeerConnectionState::Idle, PeerConnectionState::Dead]);
+    }
+
+    #[test]
+    fn endpoint_roaming() {
+        let (mut init, mut resp) = connected_peers();
+        for port in &[1, 3] {
+            let (_, packet)        = init.handle_outgoing_transport(&[]).unwrap();
+            let packet : Transport = packet.try_into().unwrap();
+            resp.handle_incoming_transport(endpoint(*port), &packet).unwrap();
+            assert_eq!(*resp.info.endpoint.unwrap(), *endpoint(*port));
+        }
+        assert!(resp.to_config_string().contains("endpoint=127.0.0.1:3\n"));
+
+        // a packet that fails authentication must not move the endpoint
+        let (_, mut packet)    = init.handle_outgoing_transport(&[]).unwrap();
+        let last               = packet.len() - 1;
+        packet[last]          ^= 1;
+        let packet : Transport = packet.try_into().unwrap();
+        assert!(resp.handle_incoming_transport(endpoint(4), &packet).is_err());
+        assert_eq!(*resp.info.endpoint.unwrap(), *endpoint(3));
+    }
+
+    #[test]
+    fn transfer_counters() {
+        let (mut init, mut resp) = connected_peers();
+        let mut total            = 0;
+        for i in 0..10 {
+            let len        = 20 + i * 7;
+            let mut packet = vec![0u8; len];
+            packet[0]      = 0x45;
+            BigEndian::write_u16(&mut packet[2..], len as u16);
+            total         += len as u64;
+
+            let (_, packet)        = init.handle_outgoing_transport(&packet).unwrap();
+            let packet : Transport = packet.try_into().unwrap();
+            let (raw, _)           = resp.handle_incoming_transport(endpoint(1), &packet).unwrap();
+            assert_eq!(raw.len(), len);
+        }
+
+        assert_eq!((init.tx_bytes, init.tx_packets), (total, 10));
+        assert_eq!((resp.rx_bytes, resp.rx_packets), (total, 10));
+        assert_eq!((init.rx_bytes, resp.tx_bytes), (0, 0));
+    }
+
+    #[test]
+    fn last_handshake_time() {
+        let (mut init, mut resp) = connected_peers();
+        assert!(!resp.to_config_string().contains("last_handshake_time_sec="));
+
+        let (_, packet)        = init.handle_outgoing_transport(&[]).unwrap();
+        let packet : Transport = packet.try_into().unwrap();
+        resp.handle_incoming_transport(endpoint(1), &packet).unwrap();
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        for peer in &[init, resp] {
+            let config = peer.to_config_string();
+            let secs   = config.lines()
+                .find(|line| line.starts_with("last_handshake_time_sec="))
+                .and_then(|line| line["last_handshake_time_sec=".len()..].parse::<u64>().ok())
+                .expect("last_handshake_time_sec present");
+            assert!(now.saturating_sub(secs) <= 2 && secs <= now);
+        }
+    }
+
+    #[test]
+    fn psk_round_trip() {
+        for psk in &[None, Some([0x42u8; 32])] {
+            let (mut init, mut resp) = connected_peers_with_psk(*psk);
+
+            let mut payload = vec![0u8; 40];
+            payload[0]      = 0x45;
+            BigEndian::write_u16(&mut payload[2..], 40);
+            payload[20..].copy_from_slice(b"psk round trip payload");
+
+            let (_, packet)        = init.handle_outgoing_transport(&payload).unwrap();
+            let packet : Transport = packet.try_into().unwrap();
+            let (raw, _)           = resp.handle_incoming_transport(endpoint(1), &packet).unwrap();
+            assert_eq!(raw, payload);
+        }
+    }
+
+    #[test]
+    fn mismatched_psk_fails() {
+        let (mut init, mut resp) = peer_pair(None);
+        init.info.psk            = Some([1u8; 32]);
+
+        let response = handshake(&mut init, &mut resp, 1, 2);
+        assert!(init.process_incoming_handshake_response(endpoint(2), &response).is_err());
+    }
+
+    #[test]
+    fn reject_after_messages() {
+        let (mut init, mut resp) = connected_peers();
+        init.reject_after_messages = 3;
+        resp.reject_after_messages = 3;
+
+        // nonces 0 and 1 are used up as usual, 2 is the last the session may carry.
+        for _ in 0..2 {
+            let (_, packet) = init.handle_outgoing_keepalive(false).unwrap();
+            let _ = resp.handle_incoming_transport(endpoint(1), &packet.try_into().unwrap()).unwrap();
+        }
+        assert!(init.ready_for_transport());
+        let (_, last) = init.handle_outgoing_keepalive(false).unwrap();
+        assert!(!init.ready_for_transport());
+        assert!(init.needs_new_handshake(true));
+
+        // sending at and past the limit fails.
+        for _ in 0..2 {
+            let err = init.handle_outgoing_keepalive(false).unwrap_err();
+            assert_eq!(err.to_string(), "exceeded REJECT-AFTER-MESSAGES");
+        }
+
+        // the last message is still taken, after which the session is done with on this side too.
+        let mut past_limit = last.clone();
+        assert!(resp.handle_incoming_transport(endpoint(1), &last.try_into().unwrap()).is_ok());
+        assert!(!resp.ready_for_transport());
+        for &nonce in &[3, 4] {
+            LittleEndian::write_u64(&mut past_limit[8..16], nonce);
+            let err = resp.handle_incoming_transport(endpoint(1), &past_limit.clone().try_into().unwrap()).unwrap_err();
+            assert_eq!(err.downcast_ref::<DropReason>(), Some(&DropReason::RejectAfterMessages));
+        }
+    }
+
+    #[test]
+    fn expired_session_needs_handshake() {
+        let (mut init, _) = connected_peers();
+        assert!(!init.needs_new_handshake(true));
+
+        init.sessions.current.as_mut().unwrap().birthday = Timestamp::unset();
+        init.timers.handshake_completed                  = Timestamp::unset();
+        assert!(!init.ready_for_transport());
+        assert!(init.needs_new_handshake(true));
+        assert!(init.handle_outgoing_transport(&[]).is_err());
+    }
+
+    #[test]
+    fn unresponsive_peer() {
+        let (mut init, mut resp)            = connected_peers();
+        init.timer_config.keepalive_timeout = Duration::from_millis(50);
+        init.timer_config.rekey_timeout     = Duration::from_millis(50);
+        assert!(!init.is_unresponsive());
+
+        // an idle tunnel isn't a dead one, even after a passive keepalive nobody answers.
+        let (_, packet)        = init.handle_outgoing_keepalive(false).unwrap();
+        let packet : Transport = packet.try_into().unwrap();
+        let _                  = resp.handle_incoming_transport(endpoint(1), &packet).unwrap();
+        init.timers.keepalive_sent      = true;
+        init.timers.handshake_completed = Timestamp::ago(Duration::from_millis(150));
+        assert!(!init.is_unresponsive());
+
+        let _ = init.handle_outgoing_transport(&[0x45, 0, 0, 20]).unwrap();
+        assert!(!init.is_unresponsive());
+        init.timers.data_sent_unanswered = Timestamp::ago(Duration::from_millis(150));
+        assert!(init.is_unresponsive());
+
+        let (_, packet)        = resp.handle_outgoing_keepalive(false).unwrap();
+        let packet : Transport = packet.try_into().unwrap();
+        init.handle_incoming_transport(endpoint(2), &packet).unwrap();
+        assert!(!init.is_unresponsive());
+    }
+
+    #[test]
+    fn short_timers() {
+        let (mut init, _) = connected_peers();
+        init.timer_config = TimerConfig {
+            rekey_after_time   : Duration::from_millis(100),
+            reject_after_time  : Duration::from_millis(400),
+            rekey_timeout      : Duration::from_millis(100),
+            keepalive_timeout  : Duration::from_millis(100),
+            rekey_attempt_time : Duration::from_millis(100),
+        };
+        assert!(!init.needs_new_handshake(true));
+
+        let age = |init: &mut Peer, millis| {
+            init.timers.handshake_completed                  = Timestamp::ago(Duration::from_millis(millis));
+            init.sessions.current.as_mut().unwrap().birthday = Timestamp::ago(Duration::from_millis(millis));
+        };
+        age(&mut init, 150);
+        assert!(init.needs_new_handshake(true));
+        assert!(!init.needs_new_handshake(false));
+        assert!(init.handle_outgoing_transport(&[]).is_ok());
+
+        age(&mut init, 450);
+        assert!(init.needs_new_handshake(false));
+        assert!(!init.ready_for_transport());
+        assert!(init.handle_outgoing_transport(&[]).is_err());
+    }
+
+    #[test]
+    fn initiation_due_after_retry_timeout() {
+        let mut peer = Peer::new(PeerInfo::default());
+        assert!(peer.initiation_due());
+
+        peer.timers.handshake_initialized = Timestamp::now();
+        peer.timers.handshake_attempts    = 1;
+        assert!(!peer.initiation_due());
+
+        peer.timers.handshake_attempts = 3;
+        assert!(!peer.initiation_due());
+
+        peer.timers.handshake_initialized = Timestamp::unset();
+        assert!(peer.initiation_due());
+    }
+
+    #[test]
+    fn reconnect_backoff() {
+        let mut peer = Peer::new(PeerInfo::default());
+        assert!(!peer.reconnect_due());
+
+        for &expected in &[1000u64, 2000, 4000] {
+            let delay  = peer.schedule_reconnect();
+            let millis = delay.as_secs() * 1000 + u64::from(delay.subsec_nanos() / 1_000_000);
+            assert!(millis >= expected * 8 / 10 && millis <= expected * 12 / 10, "{:?} not within 20% of {}ms", delay, expected);
+        }
+        assert_eq!(peer.timers.reconnect_backoff, Duration::from_secs(8));
+
+        peer.timers.next_reconnect = Some(Instant::now());
+        assert!(peer.reconnect_due());
+        for _ in 0..10 {
+            let _ = peer.schedule_reconnect();
+        }
+        assert_eq!(peer.timers.reconnect_backoff, *RECONNECT_BACKOFF_MAX);
+
+        peer.reset_reconnect_backoff();
+        assert_eq!(peer.timers.reconnect_backoff, *RECONNECT_BACKOFF_MIN);
+        assert!(!peer.reconnect_due());
+    }
+
+    #[test]
+    fn endpoint_failover() {
+        let endpoints = vec![*endpoint(1), *endpoint(2), *endpoint(3)];
+        let mut peer  = Peer::new(PeerInfo { endpoint: Some(endpoint(1)), endpoints: endpoints.clone(), ..Default::default() });
+
+        assert!(peer.fail_over_endpoint());
+        assert!(peer.fail_over_endpoint());
+        assert_eq!((peer.active_endpoint, *peer.info.endpoint.unwrap()), (2, endpoints[2]));
+        assert!(!peer.fail_over_endpoint());
+        assert_eq!((peer.active_endpoint, *peer.info.endpoint.unwrap()), (0, endpoints[0]));
+
+        assert!(peer.fail_over_endpoint());
+        peer.reset_reconnect_backoff();
+        assert!(peer.fail_over_endpoint());
+        assert!(peer.fail_over_endpoint());
+
+        let mut single = Peer::new(PeerInfo { endpoint: Some(endpoint(1)), endpoints: vec![*endpoint(1)], ..Default::default() });
+        assert!(!single.fail_over_endpoint());
+    }
+
+    #[test]
+    fn responder_rejects_replayed_initiation() {
+        let (mut init, mut resp)   = peer_pair(None);
+        let (init_priv, resp_priv) = (init.private, resp.private);
+
+        let (_, packet, _)        = init.initiate_new_session(&init_priv, noise::DEFAULT_PROTOCOL, 1).unwrap();
+        let packet   : Initiation = packet.try_into().unwrap();
+        let handshake             = Peer::process_incoming_handshake(&resp_priv, noise::DEFAULT_PROTOCOL, &packet).unwrap();
+        resp.complete_incoming_handshake(endpoint(1), 2, handshake).unwrap();
+
+        let handshake = Peer::process_incoming_handshake(&resp_priv, noise::DEFAULT_PROTOCOL, &packet).unwrap();
+        let err       = resp.complete_incoming_handshake(endpoint(1), 3, handshake).unwrap_err();
+        assert_eq!(err.downcast_ref::<DropReason>(), Some(&DropReason::ReplayAttack));
+        assert!(resp.last_handshake_tai64n.is_some());
+    }
+
+    /// Session keys that follow from fixed static and ephemeral keys. The expected ciphertext
+    /// comes from `tests/pinned_session.py`, which derives it from the Noise spec without snow.
+    #[test]
+    fn pinned_session_ciphertext() {
+        let init_priv = [0x11u8; 32];
+        let resp_priv = [0x22u8; 32];
+        let resp_pub  = generate_public(&resp_priv).to_bytes();
+        let (mut initiator, mut responder) = noise::build_pinned(&init_priv, &[0x33; 32], &resp_priv, &resp_pub, &[0x44; 32]).unwrap();
+
+        let mut buf = [0u8; 256];
+        let mut ts  = [0u8; 12];
+        let len = initiator.write_message(&[0u8; 12], &mut buf).unwrap();
+        let _   = responder.read_message(&buf[..len], &mut ts).unwrap();
+        let len = responder.write_message(&[], &mut buf).unwrap();
+        let _   = initiator.read_message(&buf[..len], &mut []).unwrap();
+
+        let mut peer = Peer::new(PeerInfo { pub_key: PublicKey(resp_pub), endpoint: Some(endpoint(2)), ..Default::default() });
+        peer.pin_transport_session(initiator, 1, 2).unwrap();
+        assert_eq!(peer.sessions.current.as_ref().unwrap().role, HandshakeRole::Initiator);
+
+        let (_, packet) = peer.handle_outgoing_transport(b"pinned session, known key bytes!").unwrap();
+        assert_eq!(&packet[..16], &[4, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&packet[16..], &[
+            0xa4, 0xa5, 0xd4, 0xd0, 0x4c, 0x2f, 0xa7, 0xad, 0x40, 0x55, 0x32, 0x1c, 0xd5, 0x3c, 0x43, 0x35,
+            0x28, 0xc7, 0x27, 0x99, 0x3f, 0x4e, 0x6b, 0x17, 0xa7, 0xf7, 0x2c, 0xc9, 0x40, 0x18, 0x44, 0x10,
+            0xb0, 0xb0, 0xb8, 0x17, 0xd1, 0x78, 0x17, 0x80, 0xe2, 0xfa, 0x39, 0x68, 0xef, 0x05, 0x2b, 0x39,
+        ][..]);
+    }
+
+    #[test]
+    fn latency_probe() {
+        let (mut init, mut resp) = connected_peers();
+        let (addr, packet)       = init.handle_outgoing_transport(&[]).unwrap();
+        resp.handle_incoming_transport(addr, &packet.try_into().unwrap()).unwrap();
+        assert!(resp.handle_outgoing_latency_echo().unwrap().is_none());
+
+        let (addr, packet)  = init.handle_outgoing_keepalive(true).unwrap();
+        assert_eq!(packet.len(), TRANSPORT_OVERHEAD + LATENCY_PROBE_SIZE);
+        let (raw_packet, _) = resp.handle_incoming_transport(addr, &packet.try_into().unwrap()).unwrap();
+        assert!(raw_packet.is_empty());
+        assert_eq!(resp.last_rtt, None);
+
+        // the round trip is only known once the echo is back where the probe started.
+        let (addr, echo)    = resp.handle_outgoing_latency_echo().unwrap().unwrap();
+        assert_eq!(echo.len(), TRANSPORT_OVERHEAD + LATENCY_PROBE_SIZE);
+        assert!(resp.handle_outgoing_latency_echo().unwrap().is_none());
+        let (raw_packet, _) = init.handle_incoming_transport(addr, &echo.try_into().unwrap()).unwrap();
+        assert!(raw_packet.is_empty());
+        assert!(init.last_rtt.map_or(false, |rtt| rtt < Duration::from_secs(1)));
+        assert!(init.to_config_string().contains("last_rtt_nanoseconds="));
+        assert!(init.handle_outgoing_latency_echo().unwrap().is_none());
+        assert_eq!((init.tx_bytes, init.rx_bytes), (0, 0));
+        assert_eq!((resp.tx_bytes, resp.rx_bytes), (0, 0));
+
+        let (_, packet) = init.handle_outgoing_keepalive(false).unwrap();
+        assert_eq!(packet.len(), TRANSPORT_OVERHEAD);
+    }
+
+    #[test]
+    fn inbound_rate_limit() {
+        let (mut init, mut resp) = connected_peers();
+        resp.info.max_inbound_pps = Some(100);
+        resp.update_inbound_limit();
+
+        let mut processed = 0;
+        for _ in 0..200 {
+            let (addr, packet) = init.handle_outgoing_transport(&[]).unwrap();
+            if resp.handle_incoming_transport(addr, &packet.try_into().unwrap()).is_ok() {
+                processed += 1;
+            }
+        }
+        assert!(processed >= 90 && processed <= 110, "{} of 200 packets got through", processed);
+        assert_eq!(resp.inbound_rate_limited_drops, 200 - processed);
+        assert!(resp.to_config_string().contains("max_inbound_pps=100\n"));
+
+        resp.info.max_inbound_pps = Some(0);
+        resp.update_inbound_limit();
+        assert!(resp.inbound_limiter.is_none());
+    }
+
+    #[test]
+    fn session_age_in_extended_config() {
+        let (init, _) = connected_peers();
+        assert_eq!(init.to_extended_config_string(), "session_age_seconds=0\n");
+        assert!(Peer::new(PeerInfo::default()).to_extended_config_string().is_empty());
+    }
+
+    #[test]
+    fn mismatched_noise_protocol() {
+        let (init_priv, _)        = keypair();
+        let (resp_priv, resp_pub) = keypair();
+        let mut init = Peer::new(PeerInfo { pub_key: PublicKey(resp_pub), endpoint: Some(endpoint(2)), ..Default::default() });
+
+        let (_, packet, _)        = init.initiate_new_session(&init_priv, "Noise_IKpsk2_25519_ChaChaPoly_SHA256", 1).unwrap();
+        let packet   : Initiation = packet.try_into().unwrap();
+        let err = Peer::process_incoming_handshake(&resp_priv, noise::DEFAULT_PROTOCOL, &packet).err().unwrap();
+        assert!(err.to_string().contains("noise protocol"));
+        assert!(Peer::process_incoming_handshake(&resp_priv, "Noise_IKpsk2_25519_ChaChaPoly_SHA256", &packet).is_ok());
+
+        assert!(init.initiate_new_session(&init_priv, "not a protocol", 2).is_err());
+    }
+
+    #[test]
+    fn egress_queued_until_handshake() {
+        let (mut init, mut resp) = peer_pair(None);
+
+        let packets = (0..10u8).map(|i| {
+            let mut packet = vec![0u8; 24];
+            packet[0]      = 0x45;
+            packet[20]     = i;
+            BigEndian::write_u16(&mut packet[2..], 24);
+            packet
+        }).collect::<Vec<_>>();
+        for packet in &packets {
+            init.queue_egress(UtunPacket::from(packet.clone()).unwrap());
+        }
+        assert!(init.handle_outgoing_transport(&packets[0]).is_err());
+
+        let response = handshake(&mut init, &mut resp, 1, 2);
+        init.process_incoming_handshake_response(endpoint(2), &response).unwrap();
+
+        let queued = init.take_queued_egress();
+        assert_eq!(queued.len(), 10);
+        assert!(init.outgoing_queue.is_empty());
+        for (queued, expected) in queued.iter().zip(&packets) {
+            let (_, packet)        = init.handle_outgoing_transport(queued.payload()).unwrap();
+            let packet : Transport = packet.try_into().unwrap();
+            let (raw, _)           = resp.handle_incoming_transport(endpoint(1), &packet).unwrap();
+            assert_eq!(&raw, expected);
+        }
+    }
+
+    #[test]
+    fn egress_queue_drops_oldest() {
+        let mut peer = Peer::new(PeerInfo::default());
+        for i in 0..MAX_QUEUED_PACKETS + 2 {
+            peer.queue_egress(UtunPacket::from(vec![0x45, i as u8, 0, 20]).unwrap());
+        }
+        let queued = peer.take_queued_egress();
+        assert_eq!(queued.len(), MAX_QUEUED_PACKETS);
+        assert_eq!(queued[0].payload()[1], 2);
+
+        peer.queue_egress(UtunPacket::from(vec![0x45, 0, 0, 20]).unwrap());
+        peer.outgoing_queue[0].1 = Instant::now() - peer.timer_config.rekey_attempt_time;
+        assert!(peer.take_queued_egress().is_empty());
+    }
+
+    #[test]
+    fn no_drops_during_rekey() {
+        let (mut init, mut resp) = peer_pair(None);
+
+        let send = |init: &mut Peer| -> Transport {
+            let mut packet = vec![0u8; 20];
+            packet[0]      = 0x45;
+            BigEndian::write_u16(&mut packet[2..], 20);
+            init.handle_outgoing_transport(&packet).unwrap().1.try_into().unwrap()
+        };
+
+        let response = handshake(&mut init, &mut resp, 1, 2);
+        init.process_incoming_handshake_response(endpoint(2), &response).unwrap();
+        resp.handle_incoming_transport(endpoint(1), &send(&mut init)).unwrap();
+
+        // rekey: both sides keep using the old session while the new one is negotiated.
+        let response = handshake(&mut init, &mut resp, 3, 4);
+        assert_eq!(init.get_mapped_indices(), vec![1, 3]);
+        assert_eq!(resp.get_mapped_indices(), vec![2, 4]);
+        let in_flight = send(&mut init);
+        resp.handle_incoming_transport(endpoint(1), &send(&mut init)).unwrap();
+
+        init.process_incoming_handshake_response(endpoint(2), &response).unwrap();
+        assert_eq!(init.get_mapped_indices(), vec![1, 3]);
+        let (_, transition) = resp.handle_incoming_transport(endpoint(1), &send(&mut init)).unwrap();
+        assert_eq!(transition, SessionTransition::Transition(None));
+        assert_eq!(resp.sessions.current.as_ref().unwrap().our_index, 4);
+
+        // a packet sent on the old session before the switch still gets through.
+        let (_, transition) = resp.handle_incoming_transport(endpoint(1), &in_flight).unwrap();
+        assert_eq!(transition, SessionTransition::NoTransition);
+        assert_eq!(resp.rx_packets, 4);
+    }
+
+    #[test]
+    fn simultaneous_initiation() {
+        let (mut high, mut low)   = ordered_peer_pair();
+        let (high_priv, high_pub) = (high.private, high.public);
+        let (low_priv, low_pub)   = (low.private, low.public);
+
+        let (_, high_init, _)        = high.initiate_new_session(&high_priv, noise::DEFAULT_PROTOCOL, 1).unwrap();
+        let (_, low_init, _)         = low.initiate_new_session(&low_priv, noise::DEFAULT_PROTOCOL, 2).unwrap();
+        let high_init : Initiation   = high_init.try_into().unwrap();
+        let low_init  : Initiation   = low_init.try_into().unwrap();
+
+        // the lower key keeps its own initiation and ignores the crossing one...
+        let handshake = Peer::process_incoming_handshake(&low_priv, noise::DEFAULT_PROTOCOL, &high_init).unwrap();
+        let err       = low.resolve_initiation_conflict(&PublicKey(low_pub), &handshake).unwrap_err();
+        assert_eq!(err.downcast_ref::<DropReason>(), Some(&DropReason::InitiationConflict));
+
+        // ...while the higher one gives up its attempt and responds.
+        let handshake              = Peer::process_incoming_handshake(&high_priv, noise::DEFAULT_PROTOCOL, &low_init).unwrap();
+        assert_eq!(high.resolve_initiation_conflict(&PublicKey(high_pub), &handshake).unwrap(), Some(1));
+        let (response, dead_index) = high.complete_incoming_handshake(endpoint(2), 3, handshake).unwrap();
+        assert_eq!(dead_index, None);
+        let response : Response    = response.try_into().unwrap();
+        low.process_incoming_handshake_response(endpoint(1), &response).unwrap();
+        assert!(low.ready_for_transport());
+
+        let (_, packet)        = low.handle_outgoing_transport(&[]).unwrap();
+        let packet : Transport = packet.try_into().unwrap();
+        high.handle_incoming_transport(endpoint(2), &packet).unwrap();
+        assert!(high.ready_for_transport());
+        assert_eq!(high.sessions.current.as_ref().unwrap().their_index, 2);
+
+        let (_, low_init, _)       = low.initiate_new_session(&low_priv, noise::DEFAULT_PROTOCOL, 4).unwrap();
+        let low_init  : Initiation = low_init.try_into().unwrap();
+        let handshake              = Peer::process_incoming_handshake(&high_priv, noise::DEFAULT_PROTOCOL, &low_init).unwrap();
+        assert_eq!(high.resolve_initiation_conflict(&PublicKey(high_pub), &handshake).unwrap(), None);
+    }
+
+    #[test]
+    fn initiation_conflict_needs_fresh_initiations() {
+        let (mut high, mut low)   = ordered_peer_pair();
+        let (high_priv, low_priv) = (high.private, low.private);
+        let low_pub               = low.public;
+
+        let (_, high_init, _)      = high.initiate_new_session(&high_priv, noise::DEFAULT_PROTOCOL, 1).unwrap();
+        let high_init : Initiation = high_init.try_into().unwrap();
+        let _                      = low.initiate_new_session(&low_priv, noise::DEFAULT_PROTOCOL, 2).unwrap();
+
+        // a replayed initiation leaves ours alone.
+        let handshake = Peer::process_incoming_handshake(&low_priv, noise::DEFAULT_PROTOCOL, &high_init).unwrap();
+        low.last_handshake_tai64n = Some(handshake.timestamp);
+        let err = low.resolve_initiation_conflict(&PublicKey(low_pub), &handshake).unwrap_err();
+        assert_eq!(err.downcast_ref::<DropReason>(), Some(&DropReason::ReplayAttack));
+        assert!(low.sessions.next.is_some());
+
+        // one of ours that's gone unanswered past rekey_timeout gives way, whatever the keys.
+        let (_, high_init, _)      = high.initiate_new_session(&high_priv, noise::DEFAULT_PROTOCOL, 3).unwrap();
+        let high_init : Initiation = high_init.try_into().unwrap();
+        let handshake              = Peer::process_incoming_handshake(&low_priv, noise::DEFAULT_PROTOCOL, &high_init).unwrap();
+        low.timers.handshake_initialized = Timestamp::unset();
+        assert_eq!(low.resolve_initiation_conflict(&PublicKey(low_pub), &handshake).unwrap(), Some(2));
+        assert!(low.complete_incoming_handshake(endpoint(1), 4, handshake).is_ok());
+    }
 }
@@ -22,6 +22,7 @@ const MAX_TOKENS         : u64 = PACKET_COST * PACKETS_BURSTABLE;
 
 lazy_static! {
     pub static ref GC_INTERVAL: Duration = Duration::new(1, 0);
+    pub static ref HANDSHAKE_WINDOW: Duration = Duration::new(1, 0);
 }
 
 struct Entry {
@@ -83,6 +84,72 @@ impl RateLimiter {
     }
 }
 
+/// Counts incoming handshake initiations across all sources, so that a flood of them can
+/// switch the interface into its under-load (cookie) mode.
+pub struct HandshakeCounter {
+    pub threshold : u32,
+    window_start  : Instant,
+    count         : u32,
+}
+
+impl HandshakeCounter {
+    pub fn new(threshold: u32) -> Self {
+        Self { threshold, window_start: Instant::now(), count: 0 }
+    }
+
+    /// Records one incoming initiation, returning true if more than `threshold` have been
+    /// seen within the current one-second window.
+    pub fn record(&mut self) -> bool {
+        self.record_at(Instant::now())
+    }
+
+    fn record_at(&mut self, now: Instant) -> bool {
+        if now.duration_since(self.window_start) >= *HANDSHAKE_WINDOW {
+            self.window_start = now;
+            self.count        = 0;
+        }
+
+        self.count = self.count.saturating_add(1);
+        self.count > self.threshold
+    }
+}
+
+/// Lets through `rate` packets a second on average, in bursts of up to a second's worth.
+pub struct TokenBucket {
+    tokens      : f64,
+    last_refill : Instant,
+    rate        : f64,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32) -> Self {
+        Self { tokens: f64::from(rate), last_refill: Instant::now(), rate: f64::from(rate) }
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// Takes a token for one packet, returning false if there aren't any left.
+    pub fn take(&mut self) -> bool {
+        self.take_at(Instant::now())
+    }
+
+    fn take_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last_refill);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens      = self.rate.min(self.tokens + elapsed * self.rate);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
 impl Future for RateLimiter {
     type Item = ();
     type Error = ();
@@ -106,6 +173,20 @@ mod tests {
         wait: Duration,
     }
 
+    #[test]
+    fn token_bucket() {
+        let start      = Instant::now();
+        let mut bucket = TokenBucket { tokens: 10.0, last_refill: start, rate: 10.0 };
+        assert_eq!((0..20).filter(|_| bucket.take_at(start)).count(), 10);
+        assert!(!bucket.take_at(start + Duration::from_millis(50)));
+        assert!(bucket.take_at(start + Duration::from_millis(100)));
+        assert!(!bucket.take_at(start + Duration::from_millis(100)));
+
+        // idle time only ever refills up to one second's worth.
+        let later = start + Duration::from_secs(60);
+        assert_eq!((0..20).filter(|_| bucket.take_at(later)).count(), 10);
+    }
+
     #[test]
     fn test_ratelimiter() {
         let mut ratelimiter = RateLimiter::_new_for_test();
@@ -179,4 +260,17 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_handshake_counter() {
+        let mut counter = HandshakeCounter::new(25);
+        let start       = counter.window_start;
+
+        let flagged: Vec<bool> = (0..50).map(|_| counter.record_at(start)).collect();
+        assert!(flagged[..25].iter().all(|flag| !flag));
+        assert!(flagged[25..].iter().all(|flag| *flag));
+        assert!(counter.record_at(start + *HANDSHAKE_WINDOW - Duration::from_millis(1)));
+
+        assert!(!counter.record_at(start + *HANDSHAKE_WINDOW));
+    }
 }
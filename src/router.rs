@@ -3,10 +3,12 @@
  * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
  */
 
-use failure::{Error, err_msg};
+use error::DropReason;
+use failure::Error;
 use interface::SharedPeer;
 use treebitmap::{IpLookupTable, IpLookupTableOps};
 use std::net::{Ipv4Addr, Ipv6Addr, IpAddr};
+use std::sync::Arc;
 use ip_packet::IpPacket;
 
 /// The `Router` struct is, as one might expect, the authority for the IP routing table.
@@ -38,16 +40,25 @@ impl Router {
         }
     }
 
-    pub fn remove_allowed_ips(&mut self, allowed_ips: &[(IpAddr, u32)]) {
+    pub fn remove_allowed_ips(&mut self, allowed_ips: &[(IpAddr, u32)], peer: &SharedPeer) {
         for &(ip_addr, mask) in allowed_ips {
-            self.remove_allowed_ip(ip_addr, mask);
+            self.remove_allowed_ip(ip_addr, mask, peer);
         }
     }
 
-    pub fn remove_allowed_ip(&mut self, addr: IpAddr, mask: u32) {
+    /// Removes the route only if it still points at `peer`, as another peer may have claimed it since.
+    pub fn remove_allowed_ip(&mut self, addr: IpAddr, mask: u32, peer: &SharedPeer) {
         match addr {
-            IpAddr::V4(v4_addr) => { let _ = self.ip4_map.remove(v4_addr, mask); },
-            IpAddr::V6(v6_addr) => { let _ = self.ip6_map.remove(v6_addr, mask); },
+            IpAddr::V4(v4_addr) => {
+                if self.ip4_map.exact_match(v4_addr, mask).map_or(false, |owner| Arc::ptr_eq(owner, peer)) {
+                    let _ = self.ip4_map.remove(v4_addr, mask);
+                }
+            },
+            IpAddr::V6(v6_addr) => {
+                if self.ip6_map.exact_match(v6_addr, mask).map_or(false, |owner| Arc::ptr_eq(owner, peer)) {
+                    let _ = self.ip6_map.remove(v6_addr, mask);
+                }
+            },
         }
     }
 
@@ -56,27 +67,68 @@ impl Router {
         self.ip6_map = IpLookupTable::new();
     }
 
-    fn get_peer_from_ip(&self, ip: IpAddr) -> Option<SharedPeer> {
+    pub fn get_peer_from_ip(&self, ip: IpAddr) -> Option<SharedPeer> {
         match ip {
             IpAddr::V4(ip) => self.ip4_map.longest_match(ip).map(|(_, _, peer)| peer.clone()),
             IpAddr::V6(ip) => self.ip6_map.longest_match(ip).map(|(_, _, peer)| peer.clone())
         }
     }
 
-    pub fn route_to_peer(&self, packet: &[u8]) -> Option<SharedPeer> {
-        match IpPacket::new(packet) {
-            Some(packet) => self.get_peer_from_ip(packet.destination()),
-            _ => None
-        }
-    }
-
     pub fn validate_source(&self, packet: &[u8], peer: &SharedPeer) -> Result<(), Error> {
         let routed_peer = match IpPacket::new(packet) {
             Some(packet) => self.get_peer_from_ip(packet.source()),
             _ => None
-        }.ok_or_else(|| err_msg("no peer found on route"))?;
+        }.ok_or(DropReason::AllowedIpMismatch)?;
 
-        ensure!(&routed_peer == peer, "peer mismatch");
+        if !Arc::ptr_eq(&routed_peer, peer) {
+            return Err(DropReason::AllowedIpMismatch.into());
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use peer::Peer;
+    use std::sync::Mutex;
+    use types::{PeerInfo, PublicKey};
+
+    fn peer(key: u8) -> SharedPeer {
+        Arc::new(Mutex::new(Peer::new(PeerInfo { pub_key: PublicKey([key; 32]), ..Default::default() })))
+    }
+
+    #[test]
+    fn remove_only_owned_routes() {
+        let mut router = Router::default();
+        let (a, b)     = (peer(1), peer(2));
+        let ip         = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+        router.add_allowed_ip(ip, 32, a.clone());
+        router.add_allowed_ip(ip, 32, b.clone());
+        router.remove_allowed_ip(ip, 32, &a);
+        assert!(router.get_peer_from_ip(ip).map_or(false, |owner| Arc::ptr_eq(&owner, &b)));
+
+        router.remove_allowed_ip(ip, 32, &b);
+        assert!(router.get_peer_from_ip(ip).is_none());
+    }
+
+    #[test]
+    fn reject_spoofed_source() {
+        let mut router = Router::default();
+        let (a, b)     = (peer(1), peer(2));
+        router.add_allowed_ip(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 32, a.clone());
+        router.add_allowed_ip(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 32, b.clone());
+
+        let mut packet = [0u8; 20];
+        packet[0] = 0x45;
+        packet[12..16].copy_from_slice(&[10, 0, 0, 2]);
+        assert!(router.validate_source(&packet, &b).is_ok());
+
+        let err = router.validate_source(&packet, &a).unwrap_err();
+        assert_eq!(err.downcast_ref::<DropReason>(), Some(&DropReason::AllowedIpMismatch));
+
+        packet[12..16].copy_from_slice(&[10, 0, 0, 3]);
+        assert!(router.validate_source(&packet, &a).is_err());
+    }
+}
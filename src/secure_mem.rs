@@ -0,0 +1,175 @@
+/* SPDX-License-Identifier: GPL-2.0
+ *
+ * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
+ */
+
+//! Keeping long-lived key material out of swap.
+
+use libc;
+use nix::sys::mman::{mlock, munlock};
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Formatter};
+use std::mem;
+use std::ops::Deref;
+use std::sync::{Mutex, Once, ONCE_INIT};
+use zeroize::Zeroize;
+
+lazy_static! {
+    static ref PAGE_SIZE: usize = match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as usize,
+        _                => 4096,
+    };
+
+    /// How many live boxes sit on each locked page. Locks don't nest, so a page is only
+    /// unlocked once the last box on it is gone.
+    static ref LOCKED_PAGES: Mutex<HashMap<usize, usize>> = Mutex::new(HashMap::new());
+}
+
+static LOCK_FAILURE_WARNING: Once = ONCE_INIT;
+
+/// A heap allocation that's locked into memory for as long as it lives and wiped when dropped.
+///
+/// Locking needs `CAP_IPC_LOCK` or a big enough `RLIMIT_MEMLOCK`, so failing to lock only
+/// logs a warning and the value is kept anyway. `is_locked()` says which happened.
+pub struct SecureBox<T: Zeroize> {
+    inner: Box<T>,
+    locked: bool,
+}
+
+impl<T: Zeroize> SecureBox<T> {
+    pub fn new(value: T) -> Self {
+        let inner  = Box::new(value);
+        let locked = lock(&*inner as *const T as usize, mem::size_of::<T>());
+        SecureBox { inner, locked }
+    }
+
+    /// Whether the value is actually locked into memory, for auditing.
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+}
+
+impl<T: Zeroize> Deref for SecureBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: Zeroize + Clone> Clone for SecureBox<T> {
+    fn clone(&self) -> Self {
+        SecureBox::new((*self.inner).clone())
+    }
+}
+
+impl<T: Zeroize + PartialEq> PartialEq for SecureBox<T> {
+    fn eq(&self, other: &Self) -> bool {
+        *self.inner == *other.inner
+    }
+}
+
+impl<T: Zeroize + Eq> Eq for SecureBox<T> {}
+
+impl<T: Zeroize + AsRef<[u8]>> AsRef<[u8]> for SecureBox<T> {
+    fn as_ref(&self) -> &[u8] {
+        (*self.inner).as_ref()
+    }
+}
+
+impl<T: Zeroize + Debug> Debug for SecureBox<T> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        (*self.inner).fmt(f)
+    }
+}
+
+impl<T: Zeroize> Drop for SecureBox<T> {
+    fn drop(&mut self) {
+        self.inner.zeroize();
+        if self.locked {
+            unlock(&*self.inner as *const T as usize, mem::size_of::<T>());
+        }
+    }
+}
+
+fn pages(addr: usize, len: usize) -> (usize, usize) {
+    let start = addr & !(*PAGE_SIZE - 1);
+    let end   = (addr + len + *PAGE_SIZE - 1) & !(*PAGE_SIZE - 1);
+    (start, end)
+}
+
+fn each_page(start: usize, end: usize) -> impl Iterator<Item = usize> {
+    (0..(end - start) / *PAGE_SIZE).map(move |i| start + i * *PAGE_SIZE)
+}
+
+fn lock(addr: usize, len: usize) -> bool {
+    if len == 0 {
+        return false;
+    }
+    let (start, end) = pages(addr, len);
+    let mut locked   = LOCKED_PAGES.lock().unwrap();
+
+    if let Err(e) = unsafe { mlock(start as *const libc::c_void, end - start) } {
+        LOCK_FAILURE_WARNING.call_once(|| {
+            warn!("unable to lock key material into memory, it may be swapped to disk ({})", e);
+        });
+        return false;
+    }
+    for page in each_page(start, end) {
+        *locked.entry(page).or_insert(0) += 1;
+    }
+    true
+}
+
+fn unlock(addr: usize, len: usize) {
+    let (start, end) = pages(addr, len);
+    let mut locked   = LOCKED_PAGES.lock().unwrap();
+
+    for page in each_page(start, end) {
+        let remaining = match locked.get_mut(&page) {
+            Some(count) => { *count -= 1; *count }
+            None        => continue,
+        };
+        if remaining == 0 {
+            locked.remove(&page);
+            if let Err(e) = unsafe { munlock(page as *const libc::c_void, *PAGE_SIZE) } {
+                debug!("failed to unlock key page {:#x} ({})", page, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use types::PrivateKey;
+
+    #[test]
+    fn behaves_like_its_contents() {
+        let key    = SecureBox::new(PrivateKey([0x11; 32]));
+        let copied = key.clone();
+        assert_eq!(key, copied);
+        assert_eq!(key.public_key(), PrivateKey([0x11; 32]).public_key());
+        assert_eq!(format!("{:?}", key), "PrivateKey([redacted])");
+        assert_eq!(key.as_ref(), &[0x11; 32][..]);
+    }
+
+    #[test]
+    fn page_range() {
+        let page = *PAGE_SIZE;
+        assert_eq!(pages(page + 8, 32), (page, 2 * page));
+        assert_eq!(pages(2 * page - 16, 32), (page, 3 * page));
+    }
+
+    #[test]
+    fn shared_pages_stay_locked() {
+        let one = SecureBox::new(PrivateKey([0x22; 32]));
+        let two = SecureBox::new(PrivateKey([0x33; 32]));
+        if !one.is_locked() || !two.is_locked() {
+            return; // no CAP_IPC_LOCK here, nothing to count
+        }
+        let page = pages(&**two as *const PrivateKey as usize, 32).0;
+        drop(one);
+        assert!(LOCKED_PAGES.lock().unwrap().contains_key(&page));
+    }
+}
@@ -0,0 +1,77 @@
+/* SPDX-License-Identifier: GPL-2.0
+ *
+ * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
+ */
+
+//! Pairs of peers wired up to each other in memory, for the tests and fuzz targets that need a
+//! handshake or a session without a socket in between.
+
+use cookie::Validator;
+use message::{Initiation, Response};
+use noise;
+use peer::Peer;
+use rand::OsRng;
+use std::convert::TryInto;
+use std::net::SocketAddr;
+use std::ops::{Deref, DerefMut};
+use types::{PeerInfo, PublicKey};
+use udp::Endpoint;
+use x25519_dalek::{generate_secret, generate_public};
+
+/// One side of a connection: a peer configured for the other side, plus its own static keys.
+pub struct Side {
+    pub peer    : Peer,
+    pub private : [u8; 32],
+    pub public  : [u8; 32],
+}
+
+impl Deref for Side {
+    type Target = Peer;
+    fn deref(&self) -> &Peer { &self.peer }
+}
+
+impl DerefMut for Side {
+    fn deref_mut(&mut self) -> &mut Peer { &mut self.peer }
+}
+
+pub fn keypair() -> ([u8; 32], [u8; 32]) {
+    let mut rng = OsRng::new().unwrap();
+    let private = generate_secret(&mut rng);
+    let public  = generate_public(&private).to_bytes();
+    (private, public)
+}
+
+pub fn endpoint(port: u16) -> Endpoint {
+    SocketAddr::from(([127, 0, 0, 1], port)).into()
+}
+
+/// Two fresh peers configured for each other. The first is at `endpoint(1)` and the second at
+/// `endpoint(2)`, and each knows where the other is.
+pub fn peer_pair(psk: Option<[u8; 32]>) -> (Side, Side) {
+    let (init_priv, init_pub) = keypair();
+    let (resp_priv, resp_pub) = keypair();
+    let init = Peer::new(PeerInfo { pub_key: PublicKey(resp_pub), psk, endpoint: Some(endpoint(2)), ..Default::default() });
+    let resp = Peer::new(PeerInfo { pub_key: PublicKey(init_pub), psk, endpoint: Some(endpoint(1)), ..Default::default() });
+    (Side { peer: init, private: init_priv, public: init_pub },
+     Side { peer: resp, private: resp_priv, public: resp_pub })
+}
+
+/// Like `peer_pair`, but ordered so the first side has the higher public key.
+#[cfg(test)]
+pub fn ordered_peer_pair() -> (Side, Side) {
+    let (a, b) = peer_pair(None);
+    if a.public > b.public { (a, b) } else { (b, a) }
+}
+
+/// Sends an initiation from `init` and has `resp` answer it, leaving the response for the
+/// caller to deliver.
+pub fn handshake(init: &mut Side, resp: &mut Side, init_index: u32, resp_index: u32) -> Response {
+    let (_, packet, _)         = init.peer.initiate_new_session(&init.private, noise::DEFAULT_PROTOCOL, init_index).unwrap();
+    let packet    : Initiation = packet.try_into().unwrap();
+    Validator::new(&resp.public).verify_mac1(&packet[..116], packet.mac1()).unwrap();
+    let handshake              = Peer::process_incoming_handshake(&resp.private, noise::DEFAULT_PROTOCOL, &packet).unwrap();
+    assert_eq!(handshake.their_pubkey(), &init.public[..]);
+
+    let (response, _)          = resp.complete_incoming_handshake(endpoint(1), resp_index, handshake).unwrap();
+    response.try_into().unwrap()
+}
@@ -16,7 +16,9 @@ pub enum TimerMessage {
     PersistentKeepAlive(WeakSharedPeer),
     PassiveKeepAlive(WeakSharedPeer),
     Rekey(WeakSharedPeer, u32),
+    Reject(WeakSharedPeer, u32),
     Wipe(WeakSharedPeer),
+    Reconnect(WeakSharedPeer),
 }
 
 pub struct TimerHandle {
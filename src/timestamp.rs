@@ -7,22 +7,38 @@ use byteorder::{ByteOrder, BigEndian};
 use std::ops::Deref;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+/// Label of the UNIX epoch: 2^62 plus the 10 seconds TAI was ahead of UTC back then.
 const TAI64N_BASE: i64 = 4611686018427387914;
 
-#[derive(PartialEq, PartialOrd)]
+/// A TAI64N timestamp, as carried in handshake initiations. Peers only accept initiations
+/// whose timestamp is later than the last one they saw, which is what keeps a recorded
+/// initiation from being replayed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd)]
 pub struct Tai64n {
     tai64n: [u8; 12]
 }
 
 impl Tai64n {
     pub fn now() -> Tai64n {
+        Self::from_system_time(SystemTime::now())
+    }
+
+    fn from_system_time(time: SystemTime) -> Tai64n {
         let mut tai64n = [0u8; 12];
-        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-        BigEndian::write_i64(&mut tai64n[0..], TAI64N_BASE + now.as_secs() as i64);
-        BigEndian::write_i32(&mut tai64n[8..], now.subsec_nanos() as i32);
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap();
+        BigEndian::write_i64(&mut tai64n[0..], TAI64N_BASE + since_epoch.as_secs() as i64);
+        BigEndian::write_i32(&mut tai64n[8..], since_epoch.subsec_nanos() as i32);
 
         Tai64n { tai64n }
     }
+
+    pub fn to_bytes(&self) -> [u8; 12] {
+        self.tai64n
+    }
+
+    pub fn from_bytes(b: &[u8; 12]) -> Self {
+        Tai64n { tai64n: *b }
+    }
 }
 
 impl Deref for Tai64n {
@@ -73,6 +89,12 @@ impl Timestamp {
         Timestamp(None)
     }
 
+    /// A timestamp `duration` in the past, for tests to stand in for waiting that long.
+    #[cfg(test)]
+    pub fn ago(duration: Duration) -> Self {
+        Timestamp(Some(Instant::now() - duration))
+    }
+
     pub fn is_set(&self) -> bool {
         self.0.is_some()
     }
@@ -84,3 +106,23 @@ impl Timestamp {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tai64n_labels() {
+        // the UNIX epoch is 1970-01-01 00:00:10 TAI, labelled 0x400000000000000a.
+        let epoch = Tai64n::from_system_time(UNIX_EPOCH);
+        assert_eq!(epoch.to_bytes(), [0x40, 0, 0, 0, 0, 0, 0, 0x0a, 0, 0, 0, 0]);
+
+        let later = Tai64n::from_system_time(UNIX_EPOCH + Duration::new(1_500_000_000, 123));
+        assert_eq!(later.to_bytes(), [0x40, 0, 0, 0, 0x59, 0x68, 0x2f, 0x0a, 0, 0, 0, 0x7b]);
+        assert_eq!(Tai64n::from_bytes(&later.to_bytes()), later);
+
+        assert!(epoch < later);
+        assert!(later < Tai64n::from_system_time(UNIX_EPOCH + Duration::new(1_500_000_000, 124)));
+        assert!(Tai64n::from_bytes(&[0u8; 12]) < epoch);
+    }
+}
@@ -3,19 +3,193 @@
  * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
  */
 
+use anti_replay::DEFAULT_WINDOW_SIZE as DEFAULT_REPLAY_WINDOW_SIZE;
 use base64;
-use std::fmt::{self, Display, Formatter};
-use std::net::IpAddr;
+use consts::{INITIAL_REKEY_TIMEOUT, KEEPALIVE_TIMEOUT, REJECT_AFTER_TIME, REKEY_AFTER_TIME, REKEY_ATTEMPT_TIME, REKEY_TIMEOUT};
+use error::{ConfigError, NameError};
+use failure::Error;
+use hex;
+use noise;
+use secure_mem::SecureBox;
+use std::borrow::Borrow;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
 use std::time::Duration;
+use subtle::ConstantTimeEq;
 use udp::Endpoint;
+use x25519_dalek as x25519;
+use zeroize::{Zeroize, zeroize};
+
+/// A Curve25519 public key. Displays as base64, while `Debug` only shows a short hex prefix.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Deref)]
+pub struct PublicKey(pub [u8; 32]);
+
+impl AsRef<[u8]> for PublicKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Lets maps keyed by `PublicKey` be queried with the raw key bytes out of a handshake.
+impl Borrow<[u8]> for PublicKey {
+    fn borrow(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Display for PublicKey {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", base64::encode(&self.0))
+    }
+}
+
+impl Debug for PublicKey {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "PublicKey({})", &hex::encode(&self.0)[..8])
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let bytes = base64::decode(s)?;
+        ensure!(bytes.len() == 32, "public key must be 32 bytes, got {}", bytes.len());
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&bytes);
+        Ok(PublicKey(key))
+    }
+}
+
+/// Compares secrets without bailing out at the first differing byte, so how long it takes
+/// says nothing about how much of a guess was right. Slices of different lengths are unequal.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).unwrap_u8() == 1
+}
+
+/// Whether `endpoint` is a hostname and port that can be looked up, like `vpn.example.com:51820`.
+/// Literal addresses are left to `SocketAddr`'s parser.
+pub fn is_valid_endpoint_host(endpoint: &str) -> bool {
+    let (host, port) = match endpoint.rfind(':') {
+        Some(colon) => (&endpoint[..colon], &endpoint[colon + 1..]),
+        None        => return false,
+    };
+    let valid_label = |label: &str| {
+        !label.is_empty() && label.len() <= 63 && !label.starts_with('-') && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    port.parse::<u16>().map(|port| port != 0).unwrap_or(false)
+        && host.len() <= 253
+        && host.split('.').all(valid_label)
+}
+
+/// A Curve25519 private key, zeroed when dropped and never printed.
+#[derive(Clone, Default, Deref)]
+pub struct PrivateKey(pub [u8; 32]);
+
+impl PrivateKey {
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(*x25519::generate_public(&self.0).as_bytes())
+    }
+
+    /// An all-zero key, which the configuration protocol uses to mean "no key".
+    pub fn is_zero(&self) -> bool {
+        constant_time_eq(&self.0, &[0u8; 32])
+    }
+}
+
+impl PartialEq for PrivateKey {
+    fn eq(&self, other: &PrivateKey) -> bool {
+        constant_time_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for PrivateKey {}
+
+impl AsRef<[u8]> for PrivateKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Debug for PrivateKey {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "PrivateKey([redacted])")
+    }
+}
+
+impl Zeroize for PrivateKey {
+    fn zeroize(&mut self) {
+        zeroize(&mut self.0);
+    }
+}
+
+impl Drop for PrivateKey {
+    fn drop(&mut self) {
+        zeroize(&mut self.0);
+    }
+}
+
+/// A name the OS will accept for a network interface: 1 to 15 (`IFNAMSIZ - 1`) letters, digits,
+/// underscores or hyphens, not starting with a digit.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deref)]
+pub struct InterfaceName(String);
+
+impl InterfaceName {
+    pub fn new(name: &str) -> Result<Self, NameError> {
+        let len = name.chars().count();
+        if len == 0 {
+            return Err(NameError::Empty);
+        }
+        if len > 15 {
+            return Err(NameError::TooLong(len));
+        }
+        if let Some(c) = name.chars().find(|&c| !(c.is_ascii_alphanumeric() || c == '_' || c == '-')) {
+            return Err(NameError::InvalidChar(c));
+        }
+        if name.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(NameError::StartsWithDigit);
+        }
+        Ok(InterfaceName(name.to_owned()))
+    }
+}
+
+impl AsRef<str> for InterfaceName {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for InterfaceName {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for InterfaceName {
+    type Err = NameError;
+
+    fn from_str(s: &str) -> Result<Self, NameError> {
+        InterfaceName::new(s)
+    }
+}
 
 #[derive(Clone, Debug, Default)]
 pub struct PeerInfo {
-    pub pub_key: [u8; 32],
+    pub pub_key: PublicKey,
     pub psk: Option<[u8; 32]>,
     pub endpoint: Option<Endpoint>,
+    /// The `host:port` the endpoint was configured as, when that wasn't a literal address.
+    /// It's resolved again periodically, keeping `endpoint` up to date.
+    pub endpoint_host: Option<String>,
+    /// Every address configured for the peer, tried in turn when handshakes go unanswered.
+    pub endpoints: Vec<SocketAddr>,
     pub allowed_ips: Vec<(IpAddr, u32)>,
     pub keepalive: Option<u16>,
+    /// Transport packets per second accepted from the peer, with excess ones dropped before
+    /// they're decrypted. `Some(0)` lifts a previously set limit.
+    pub max_inbound_pps: Option<u32>,
 }
 
 impl PeerInfo {
@@ -34,10 +208,257 @@ impl Display for PeerInfo {
     }
 }
 
-#[derive(Clone, Debug, Default)]
+/// The protocol's timers, which default to the values the WireGuard paper gives them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimerConfig {
+    pub rekey_after_time   : Duration,
+    pub reject_after_time  : Duration,
+    pub rekey_timeout      : Duration,
+    pub keepalive_timeout  : Duration,
+    pub rekey_attempt_time : Duration,
+}
+
+impl Default for TimerConfig {
+    fn default() -> Self {
+        TimerConfig {
+            rekey_after_time   : *REKEY_AFTER_TIME,
+            reject_after_time  : *REJECT_AFTER_TIME,
+            rekey_timeout      : *REKEY_TIMEOUT,
+            keepalive_timeout  : *KEEPALIVE_TIMEOUT,
+            rekey_attempt_time : *REKEY_ATTEMPT_TIME,
+        }
+    }
+}
+
+impl TimerConfig {
+    /// Whether the timers fit together: none are zero, sessions get rekeyed before they're
+    /// rejected, and a receiving side still has time to rekey after a keepalive and a retry.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |reason: &str| Err(ConfigError::InvalidTimers(reason.to_owned()));
+        if TimerSetting::ALL.iter().any(|&setting| self.get(setting) == Duration::new(0, 0)) {
+            return invalid("timers can't be zero");
+        }
+        if self.rekey_after_time >= self.reject_after_time {
+            return invalid("rekey_after_time must be shorter than reject_after_time");
+        }
+        if self.keepalive_timeout + self.rekey_timeout >= self.reject_after_time {
+            return invalid("keepalive_timeout and rekey_timeout together must be shorter than reject_after_time");
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, setting: TimerSetting) -> Duration {
+        match setting {
+            TimerSetting::RekeyAfterTime   => self.rekey_after_time,
+            TimerSetting::RejectAfterTime  => self.reject_after_time,
+            TimerSetting::RekeyTimeout     => self.rekey_timeout,
+            TimerSetting::KeepaliveTimeout => self.keepalive_timeout,
+            TimerSetting::RekeyAttemptTime => self.rekey_attempt_time,
+        }
+    }
+
+    pub fn set(&mut self, setting: TimerSetting, value: Duration) {
+        match setting {
+            TimerSetting::RekeyAfterTime   => self.rekey_after_time   = value,
+            TimerSetting::RejectAfterTime  => self.reject_after_time  = value,
+            TimerSetting::RekeyTimeout     => self.rekey_timeout      = value,
+            TimerSetting::KeepaliveTimeout => self.keepalive_timeout  = value,
+            TimerSetting::RekeyAttemptTime => self.rekey_attempt_time = value,
+        }
+    }
+
+    /// How old a session may get on the receiving side before we rekey it ourselves, leaving
+    /// the peer a keepalive and a retry's worth of time before it's rejected.
+    pub fn rekey_after_time_recv(&self) -> Duration {
+        self.reject_after_time - self.keepalive_timeout - self.rekey_timeout
+    }
+
+    pub fn stale_session_timeout(&self) -> Duration {
+        self.keepalive_timeout + self.rekey_timeout
+    }
+
+    pub fn wipe_after_time(&self) -> Duration {
+        self.reject_after_time * 3
+    }
+
+    /// How long to wait for a response to handshake initiation number `attempt` (counting
+    /// from zero) before retrying. Doubles with each attempt, up to `rekey_timeout`.
+    pub fn handshake_retry_timeout(&self, attempt: u64) -> Duration {
+        let backoff = 1u32 << attempt.min(31);
+        INITIAL_REKEY_TIMEOUT.checked_mul(backoff)
+            .map_or(self.rekey_timeout, |timeout| timeout.min(self.rekey_timeout))
+    }
+}
+
+/// One of the timers in a `TimerConfig`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimerSetting {
+    RekeyAfterTime, RejectAfterTime, RekeyTimeout, KeepaliveTimeout, RekeyAttemptTime
+}
+
+impl TimerSetting {
+    pub const ALL: [TimerSetting; 5] = [
+        TimerSetting::RekeyAfterTime, TimerSetting::RejectAfterTime, TimerSetting::RekeyTimeout,
+        TimerSetting::KeepaliveTimeout, TimerSetting::RekeyAttemptTime,
+    ];
+
+    /// Its key in configuration requests, which give it in milliseconds.
+    pub fn key(&self) -> &'static str {
+        match *self {
+            TimerSetting::RekeyAfterTime   => "rekey_after_time_ms",
+            TimerSetting::RejectAfterTime  => "reject_after_time_ms",
+            TimerSetting::RekeyTimeout     => "rekey_timeout_ms",
+            TimerSetting::KeepaliveTimeout => "keepalive_timeout_ms",
+            TimerSetting::RekeyAttemptTime => "rekey_attempt_time_ms",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<TimerSetting> {
+        TimerSetting::ALL.iter().cloned().find(|setting| setting.key() == key)
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct InterfaceInfo {
-    pub private_key: Option<[u8; 32]>,
-    pub pub_key: Option<[u8; 32]>,
+    /// Kept locked in memory where the process is allowed to.
+    pub private_key: Option<SecureBox<PrivateKey>>,
+    pub pub_key: Option<PublicKey>,
     pub listen_port: Option<u16>,
     pub fwmark: Option<u32>,
+    pub handshake_rate_limit: Option<u32>,
+    pub mtu: Option<u16>,
+    pub udp_recv_buffer: Option<usize>,
+    pub udp_send_buffer: Option<usize>,
+    /// The `(recv, send)` buffer sizes the kernel actually granted the bound sockets.
+    pub udp_buffer_sizes: Option<(usize, usize)>,
+    /// Copy each inner packet's DSCP bits onto the outer UDP packet carrying it.
+    pub preserve_dscp: bool,
+    /// The Noise protocol name handshakes are built from. Only peers using the same one can
+    /// complete a handshake with us.
+    pub noise_protocol: String,
+    /// Stamp outgoing keepalives with their send time, for the peer to echo back so we can time
+    /// the round trip. Peers that don't echo them still take them as keepalives.
+    pub measure_latency: bool,
+    /// Where to send a copy of each decrypted inbound packet, for passive monitoring.
+    pub mirror_decrypted: Option<SocketAddr>,
+    /// Where to send a copy of each outbound packet before it's encrypted.
+    pub mirror_plaintext_out: Option<SocketAddr>,
+    /// Route each peer's allowed IPs through the interface, like `wg-quick` does. Linux
+    /// only.
+    pub auto_routes: bool,
+    /// The routing table `auto_routes` go in, the main table if unset.
+    pub routing_table: Option<u32>,
+    /// How many nonces behind the newest each session still accepts, a power of 2 from 64 to
+    /// 8192. The default of 64 is the spec's; links with more in flight than that, like
+    /// satellite ones, may need a larger one.
+    pub replay_window_size: u32,
+    /// Rekey, expiry and keepalive timing. Tests shorten these to see rekeys without waiting minutes.
+    pub timers: TimerConfig,
+}
+
+impl Default for InterfaceInfo {
+    fn default() -> Self {
+        InterfaceInfo {
+            private_key          : None,
+            pub_key              : None,
+            listen_port          : None,
+            fwmark               : None,
+            handshake_rate_limit : None,
+            mtu                  : None,
+            udp_recv_buffer      : None,
+            udp_send_buffer      : None,
+            udp_buffer_sizes     : None,
+            preserve_dscp        : false,
+            noise_protocol       : noise::DEFAULT_PROTOCOL.to_owned(),
+            measure_latency      : false,
+            mirror_decrypted     : None,
+            mirror_plaintext_out : None,
+            auto_routes          : false,
+            routing_table        : None,
+            replay_window_size   : DEFAULT_REPLAY_WINDOW_SIZE,
+            timers               : TimerConfig::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn public_key_formatting() {
+        let key = PublicKey([0xab; 32]);
+        assert_eq!(key.to_string().parse::<PublicKey>().unwrap(), key);
+        assert_eq!(format!("{:?}", key), "PublicKey(abababab)");
+        assert!("AAAA".parse::<PublicKey>().is_err());
+    }
+
+    #[test]
+    fn private_key_redacted() {
+        assert_eq!(format!("{:?}", PrivateKey([0x11; 32])), "PrivateKey([redacted])");
+    }
+
+    #[test]
+    fn interface_names() {
+        assert!(InterfaceName::new("wg0").is_ok());
+        assert!(InterfaceName::new("wg_tun-0").is_ok());
+        assert!(InterfaceName::new("abcdefghijklmno").is_ok());
+        assert_eq!(InterfaceName::new("abcdefghijklmnop"), Err(NameError::TooLong(16)));
+        assert_eq!(InterfaceName::new(""), Err(NameError::Empty));
+        assert_eq!(InterfaceName::new("0wg"), Err(NameError::StartsWithDigit));
+        assert_eq!(InterfaceName::new("wg 0"), Err(NameError::InvalidChar(' ')));
+        assert_eq!(InterfaceName::new("wg/0"), Err(NameError::InvalidChar('/')));
+        assert_eq!("wg0".parse::<InterfaceName>().unwrap().to_string(), "wg0");
+    }
+
+    #[test]
+    fn endpoint_hosts() {
+        assert!(is_valid_endpoint_host("vpn.example.com:51820"));
+        assert!(is_valid_endpoint_host("localhost:1"));
+        assert!(!is_valid_endpoint_host("vpn.example.com"));
+        assert!(!is_valid_endpoint_host("vpn.example.com:0"));
+        assert!(!is_valid_endpoint_host("vpn.example.com:65536"));
+        assert!(!is_valid_endpoint_host(":51820"));
+        assert!(!is_valid_endpoint_host("vpn..example.com:51820"));
+        assert!(!is_valid_endpoint_host("-vpn.example.com:51820"));
+        assert!(!is_valid_endpoint_host("vpn example.com:51820"));
+        assert!(!is_valid_endpoint_host("not-a-key"));
+    }
+
+    #[test]
+    fn private_key_comparison() {
+        let mut other = [0x11; 32];
+        other[31] ^= 1;
+        assert_eq!(PrivateKey([0x11; 32]), PrivateKey([0x11; 32]));
+        assert_ne!(PrivateKey([0x11; 32]), PrivateKey(other));
+        assert!(PrivateKey::default().is_zero());
+        assert!(!PrivateKey(other).is_zero());
+        assert!(!constant_time_eq(&[0u8; 16], &[0u8; 32]));
+    }
+
+    #[test]
+    fn timer_config() {
+        let timers = TimerConfig::default();
+        assert!(timers.validate().is_ok());
+        assert_eq!(timers.rekey_after_time_recv(), Duration::new(165, 0));
+        assert_eq!(timers.stale_session_timeout(), Duration::new(15, 0));
+        assert_eq!(timers.wipe_after_time(), Duration::new(540, 0));
+
+        let retries: Vec<u64> = (0..6).map(|attempt| timers.handshake_retry_timeout(attempt).as_secs()).collect();
+        assert_eq!(retries, vec![1, 2, 4, 5, 5, 5]);
+        assert_eq!(timers.handshake_retry_timeout(u64::max_value()), timers.rekey_timeout);
+
+        let short = TimerConfig { rekey_timeout: Duration::from_millis(100), ..timers };
+        assert_eq!(short.handshake_retry_timeout(0), Duration::from_millis(100));
+
+        for &setting in TimerSetting::ALL.iter() {
+            assert_eq!(TimerSetting::from_key(setting.key()), Some(setting));
+            let mut zeroed = timers;
+            zeroed.set(setting, Duration::new(0, 0));
+            assert!(zeroed.validate().is_err());
+        }
+        assert_eq!(TimerSetting::from_key("rekey_after_time"), None);
+        assert!(TimerConfig { rekey_after_time: Duration::new(180, 0), ..timers }.validate().is_err());
+        assert!(TimerConfig { keepalive_timeout: Duration::new(175, 0), ..timers }.validate().is_err());
+    }
 }
@@ -0,0 +1,197 @@
+/* SPDX-License-Identifier: GPL-2.0
+ *
+ * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
+ */
+
+//! `sendmmsg(2)` and `recvmmsg(2)`, moving a whole burst of datagrams per syscall.
+//!
+//! Packet info control messages are carried the same way `UdpSocket::sendmsg` and
+//! `UdpSocket::recv_from` do, so sticky source addresses survive batching.
+
+use std::{io, mem, ptr};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::RawFd;
+
+use libc::{self, c_void, in_addr, in_pktinfo, in6_pktinfo};
+use udp::{EgressMessage, Endpoint, MAX_BATCH};
+
+// large enough for an in6_pktinfo and a traffic class control message, in cmsghdr-aligned words.
+type CmsgBuffer = [u64; 8];
+
+fn cmsg_align(len: usize) -> usize {
+    let align = mem::size_of::<usize>();
+    (len + align - 1) & !(align - 1)
+}
+
+fn cmsg_header_size() -> usize {
+    cmsg_align(mem::size_of::<libc::cmsghdr>())
+}
+
+/// Appends a control message after any already in `buf`.
+unsafe fn put_cmsg<T>(hdr: &mut libc::msghdr, buf: &mut CmsgBuffer, level: libc::c_int, kind: libc::c_int, data: &T) {
+    let offset = if hdr.msg_control.is_null() { 0 } else { hdr.msg_controllen as usize };
+    let cmsg   = (buf.as_mut_ptr() as *mut u8).offset(offset as isize) as *mut libc::cmsghdr;
+    (*cmsg).cmsg_level = level;
+    (*cmsg).cmsg_type  = kind;
+    (*cmsg).cmsg_len   = (cmsg_header_size() + mem::size_of::<T>()) as _;
+    ptr::copy_nonoverlapping(data, (cmsg as *mut u8).offset(cmsg_header_size() as isize) as *mut T, 1);
+
+    hdr.msg_control    = buf.as_mut_ptr() as *mut c_void;
+    hdr.msg_controllen = (offset + cmsg_header_size() + cmsg_align(mem::size_of::<T>())) as _;
+}
+
+fn to_sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    match *addr {
+        SocketAddr::V4(ref addr) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port   = addr.port().to_be();
+            sin.sin_addr   = in_addr { s_addr: u32::from(*addr.ip()).to_be() };
+            (storage, mem::size_of::<libc::sockaddr_in>() as libc::socklen_t)
+        },
+        SocketAddr::V6(ref addr) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family          = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port            = addr.port().to_be();
+            sin6.sin6_flowinfo        = addr.flowinfo();
+            sin6.sin6_addr.s6_addr    = addr.ip().octets();
+            sin6.sin6_scope_id        = addr.scope_id();
+            (storage, mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t)
+        },
+    }
+}
+
+fn from_sockaddr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match libc::c_int::from(storage.ss_family) {
+        libc::AF_INET => {
+            let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            let ip  = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+            Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(sin.sin_port))))
+        },
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            let ip   = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+            Some(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(sin6.sin6_port), sin6.sin6_flowinfo, sin6.sin6_scope_id)))
+        },
+        _ => None,
+    }
+}
+
+/// Rebuilds the endpoint `recv_from` would have returned, from a received header's address and
+/// packet info. Datagrams without packet info simply get an endpoint without a sticky source.
+unsafe fn to_endpoint(hdr: &libc::msghdr, storage: &libc::sockaddr_storage) -> Option<Endpoint> {
+    let addr = from_sockaddr(storage)?;
+
+    let mut offset = 0;
+    while offset + cmsg_header_size() <= hdr.msg_controllen as usize {
+        let cmsg = (hdr.msg_control as *const u8).offset(offset as isize) as *const libc::cmsghdr;
+        let data = (cmsg as *const u8).offset(cmsg_header_size() as isize);
+        match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+            (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                let info = ptr::read_unaligned(data as *const in_pktinfo);
+                return Some(Endpoint::V4(addr, Some(in_pktinfo {
+                    ipi_addr    : in_addr { s_addr: 0 },
+                    ipi_spec_dst: info.ipi_addr,
+                    ipi_ifindex : info.ipi_ifindex,
+                })));
+            },
+            (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                return Some(Endpoint::V6(addr, Some(ptr::read_unaligned(data as *const in6_pktinfo))));
+            },
+            _ => {},
+        }
+        if (*cmsg).cmsg_len == 0 {
+            break;
+        }
+        offset += cmsg_align((*cmsg).cmsg_len as usize);
+    }
+    Some(addr.into())
+}
+
+/// Sends as many of `packets` as the socket takes in one syscall, returning how many went out.
+pub fn sendmmsg(fd: RawFd, packets: &[EgressMessage]) -> io::Result<usize> {
+    let packets   = &packets[..packets.len().min(MAX_BATCH)];
+    let mut addrs = packets.iter().map(|&(ref endpoint, _, _)| to_sockaddr(endpoint)).collect::<Vec<_>>();
+    let mut iovs  = packets.iter().map(|&(_, ref packet, _)| libc::iovec {
+        iov_base: packet.as_ptr() as *mut c_void,
+        iov_len : packet.len(),
+    }).collect::<Vec<_>>();
+    let mut cmsgs = vec![CmsgBuffer::default(); packets.len()];
+    let mut hdrs  = Vec::with_capacity(packets.len());
+
+    for (i, &(ref endpoint, _, tos)) in packets.iter().enumerate() {
+        let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+        hdr.msg_name    = &mut addrs[i].0 as *mut _ as *mut c_void;
+        hdr.msg_namelen = addrs[i].1;
+        hdr.msg_iov     = &mut iovs[i];
+        hdr.msg_iovlen  = 1;
+        unsafe {
+            match *endpoint {
+                Endpoint::V4(_, Some(ref info)) => put_cmsg(&mut hdr, &mut cmsgs[i], libc::IPPROTO_IP, libc::IP_PKTINFO, info),
+                Endpoint::V6(_, Some(ref info)) => put_cmsg(&mut hdr, &mut cmsgs[i], libc::IPPROTO_IPV6, libc::IPV6_PKTINFO, info),
+                _                               => {},
+            }
+            let tos = libc::c_int::from(tos);
+            match *endpoint {
+                _ if tos == 0      => {},
+                Endpoint::V4(_, _) => put_cmsg(&mut hdr, &mut cmsgs[i], libc::IPPROTO_IP, libc::IP_TOS, &tos),
+                Endpoint::V6(_, _) => put_cmsg(&mut hdr, &mut cmsgs[i], libc::IPPROTO_IPV6, libc::IPV6_TCLASS, &tos),
+            }
+        }
+        hdrs.push(libc::mmsghdr { msg_hdr: hdr, msg_len: 0 });
+    }
+
+    match unsafe { libc::sendmmsg(fd, hdrs.as_mut_ptr(), hdrs.len() as libc::c_uint, 0) } {
+        -1   => Err(io::Error::last_os_error()),
+        sent => Ok(sent as usize),
+    }
+}
+
+/// Receives up to one datagram per buffer in `bufs`, returning each one's length and source, in order.
+pub fn recvmmsg(fd: RawFd, bufs: &mut [Vec<u8>]) -> io::Result<Vec<(usize, Endpoint)>> {
+    let count     = bufs.len().min(MAX_BATCH);
+    let mut addrs = vec![unsafe { mem::zeroed::<libc::sockaddr_storage>() }; count];
+    let mut iovs  = bufs.iter_mut().take(count).map(|buf| libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut c_void,
+        iov_len : buf.len(),
+    }).collect::<Vec<_>>();
+    let mut cmsgs = vec![CmsgBuffer::default(); count];
+    let mut hdrs  = Vec::with_capacity(count);
+
+    for i in 0..count {
+        let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+        hdr.msg_name       = &mut addrs[i] as *mut _ as *mut c_void;
+        hdr.msg_namelen    = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        hdr.msg_iov        = &mut iovs[i];
+        hdr.msg_iovlen     = 1;
+        hdr.msg_control    = cmsgs[i].as_mut_ptr() as *mut c_void;
+        hdr.msg_controllen = mem::size_of::<CmsgBuffer>() as _;
+        hdrs.push(libc::mmsghdr { msg_hdr: hdr, msg_len: 0 });
+    }
+
+    let received = match unsafe { libc::recvmmsg(fd, hdrs.as_mut_ptr(), count as libc::c_uint, 0, ptr::null_mut()) } {
+        -1       => return Err(io::Error::last_os_error()),
+        received => received as usize,
+    };
+
+    // entries line up with `bufs`, so a bad source fails the batch rather than being skipped.
+    hdrs.iter().zip(addrs.iter()).take(received)
+        .map(|(hdr, addr)| unsafe { to_endpoint(&hdr.msg_hdr, addr) }
+             .map(|endpoint| (hdr.msg_len as usize, endpoint))
+             .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "invalid source address")))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sockaddr_round_trip() {
+        for addr in &["10.0.0.1:51820", "[fd00::1]:51820"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            assert_eq!(from_sockaddr(&to_sockaddr(&addr).0), Some(addr));
+        }
+    }
+}
@@ -3,16 +3,15 @@
  * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
  */
 
+use std::collections::VecDeque;
 use std::io;
-use std::net::{SocketAddr, Ipv4Addr, SocketAddrV4, IpAddr};
 use std::os::unix::io::{AsRawFd, RawFd};
 
 use failure::Error;
 use futures::{Async, Future, Poll, Stream, Sink, StartSend, AsyncSink, future, stream, unsync::mpsc};
-use nix::sys::socket::{sockopt, setsockopt};
-use udp::{Endpoint, UdpSocket};
+use nix::sys::socket::{sockopt, getsockopt, setsockopt};
+use udp::{Endpoint, UdpSocket, MAX_BATCH};
 use tokio_core::reactor::Handle;
-use std::net::Ipv6Addr;
 
 /// A unified `Stream` and `Sink` interface to an underlying `UdpSocket`, using
 /// the `UdpCodec` trait to encode and decode frames.
@@ -23,10 +22,9 @@ use std::net::Ipv6Addr;
 pub struct UdpFramed {
     socket: UdpSocket,
     codec: VecUdpCodec,
-    rd: Vec<u8>,
-    wr: Vec<u8>,
-    out_addr: Endpoint,
-    flushed: bool,
+    rd: Vec<Vec<u8>>,
+    received: VecDeque<PeerServerMessage>,
+    wr: Vec<EgressMessage>,
 }
 
 impl Stream for UdpFramed {
@@ -34,55 +32,55 @@ impl Stream for UdpFramed {
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Option<PeerServerMessage>, io::Error> {
-        
-        let (n, addr) = try_nb!(self.socket.recv_from(&mut self.rd));
-        trace!("received {} bytes, decoding", n);
-        let frame = self.codec.decode(&addr, &self.rd[..n])?;
-        trace!("frame decoded from buffer");
-        Ok(Async::Ready(Some(frame)))
+        loop {
+            if let Some(frame) = self.received.pop_front() {
+                return Ok(Async::Ready(Some(frame)))
+            }
+
+            let received = try_nb!(self.socket.recv_batch(&mut self.rd));
+            trace!("received a batch of {} datagrams, decoding", received.len());
+            for (i, (n, addr)) in received.into_iter().enumerate() {
+                // one bad datagram shouldn't cost us the rest of the batch.
+                match self.codec.decode(&addr, &self.rd[i][..n]) {
+                    Ok(frame) => self.received.push_back(frame),
+                    Err(e)    => warn!("dropping undecodable datagram from {:?}: {}", addr, e),
+                }
+            }
+        }
     }
 }
 
 impl Sink for UdpFramed {
-    type SinkItem = PeerServerMessage;
+    type SinkItem = EgressMessage;
     type SinkError = io::Error;
 
-    fn start_send(&mut self, item: PeerServerMessage) -> StartSend<PeerServerMessage, io::Error> {
+    /// Frames are queued up to a batch's worth, and go out together once the sink is flushed.
+    fn start_send(&mut self, item: EgressMessage) -> StartSend<EgressMessage, io::Error> {
         trace!("sending frame");
 
-        if !self.flushed {
-            match self.poll_complete()? {
-                Async::Ready(()) => {},
-                Async::NotReady => return Ok(AsyncSink::NotReady(item)),
+        if self.wr.len() >= MAX_BATCH {
+            if let Async::NotReady = self.poll_complete()? {
+                return Ok(AsyncSink::NotReady(item))
             }
         }
 
-        self.out_addr = self.codec.encode(item, &mut self.wr);
-        self.flushed = false;
-        trace!("frame encoded; length={}", self.wr.len());
+        let (addr, packet, tos) = item;
+        let mut buf  = vec![];
+        let out_addr = self.codec.encode((addr, packet), &mut buf);
+        trace!("frame encoded; length={}", buf.len());
+        self.wr.push((out_addr, buf, tos));
 
         Ok(AsyncSink::Ready)
     }
 
     fn poll_complete(&mut self) -> Poll<(), io::Error> {
-        if self.flushed {
-            return Ok(Async::Ready(()))
-        }
-
-        trace!("flushing frame; length={}", self.wr.len());
-        let n = try_nb!(self.socket.send_to(&self.wr, &self.out_addr));
-        trace!("written {}", n);
-
-        let wrote_all = n == self.wr.len();
-        self.wr.clear();
-        self.flushed = true;
-
-        if wrote_all {
-            Ok(Async::Ready(()))
-        } else {
-            Err(io::Error::new(io::ErrorKind::Other,
-                               "failed to write entire datagram to socket"))
+        while !self.wr.is_empty() {
+            trace!("flushing {} frames", self.wr.len());
+            let n = try_nb!(self.socket.send_batch(&self.wr));
+            trace!("written {} frames", n);
+            self.wr.drain(..n);
         }
+        Ok(Async::Ready(()))
     }
 
     fn close(&mut self) -> Poll<(), io::Error> {
@@ -95,10 +93,9 @@ pub fn new(socket: UdpSocket) -> UdpFramed {
     UdpFramed {
         socket,
         codec: VecUdpCodec {},
-        out_addr: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0)).into(),
-        rd: vec![0; 64 * 1024],
-        wr: Vec::with_capacity(8 * 1024),
-        flushed: true,
+        rd: vec![vec![0; 64 * 1024]; MAX_BATCH],
+        received: VecDeque::with_capacity(MAX_BATCH),
+        wr: Vec::with_capacity(MAX_BATCH),
     }
 }
 
@@ -132,17 +129,12 @@ impl UdpFramed {
     }
 }
 
-fn v6_mapped_to_v4(addr: Ipv6Addr) -> Option<Ipv4Addr> {
-    match addr.segments() {
-        [0, 0, 0, 0, 0, f, g, h] if f == 0xffff => {
-            Some(Ipv4Addr::new((g >> 8) as u8, g as u8,
-                               (h >> 8) as u8, h as u8))
-        },
-        _ => None
-    }
-}
 
 pub type PeerServerMessage = (Endpoint, Vec<u8>);
+
+/// An outgoing datagram and the TOS byte to send it with, where 0 leaves the socket's default.
+pub type EgressMessage = (Endpoint, Vec<u8>, u8);
+
 pub struct VecUdpCodec;
 impl VecUdpCodec {
     fn decode(&mut self, src: &Endpoint, buf: &[u8]) -> io::Result<PeerServerMessage> {
@@ -158,7 +150,7 @@ impl VecUdpCodec {
 
 pub struct UdpChannel {
     pub ingress : stream::SplitStream<UdpFramed>,
-    pub egress  : mpsc::UnboundedSender<PeerServerMessage>,
+    pub egress  : mpsc::UnboundedSender<EgressMessage>,
     pub fd4     : RawFd,
     pub fd6     : RawFd,
         handle  : Handle,
@@ -173,9 +165,9 @@ impl From<UdpFramed> for UdpChannel {
         let (egress, egress_rx) = mpsc::unbounded();
         let udp_writethrough    = udp_sink
             .sink_map_err(|_| ())
-            .send_all(egress_rx.and_then(|(addr, packet)| {
+            .send_all(egress_rx.and_then(|(addr, packet, tos)| {
                           trace!("sending UDP packet to {:?}", &addr);
-                          future::ok((addr, packet))
+                          future::ok((addr, packet, tos))
                       })
                       .map_err(|_| { info!("udp sink error"); () }))
             .then(|_| Ok(()));
@@ -188,7 +180,25 @@ impl From<UdpFramed> for UdpChannel {
 
 impl UdpChannel {
     pub fn send(&self, message: PeerServerMessage) {
-        self.egress.clone().unbounded_send(message);
+        self.send_with_tos(message, 0);
+    }
+
+    pub fn send_with_tos(&self, (addr, packet): PeerServerMessage, tos: u8) {
+        self.egress.clone().unbounded_send((addr, packet, tos));
+    }
+
+    /// Asks for the given buffer sizes on both sockets, returning the `(recv, send)` sizes the
+    /// kernel actually granted, since it silently caps requests (and Linux doubles them).
+    pub fn set_buffer_sizes(&self, recv: Option<usize>, send: Option<usize>) -> Result<(usize, usize), Error> {
+        for fd in &[self.fd4, self.fd6] {
+            if let Some(size) = recv {
+                setsockopt(*fd, sockopt::RcvBuf, &size)?;
+            }
+            if let Some(size) = send {
+                setsockopt(*fd, sockopt::SndBuf, &size)?;
+            }
+        }
+        Ok((getsockopt(self.fd4, sockopt::RcvBuf)?, getsockopt(self.fd4, sockopt::SndBuf)?))
     }
 
     #[cfg(target_os = "linux")]
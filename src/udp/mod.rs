@@ -30,8 +30,13 @@ use socket2::{Socket, Domain, Type, Protocol};
 
 use tokio_core::reactor::{Handle, PollEvented};
 
+#[cfg(target_os = "linux")]
+mod batch;
 mod frame;
-pub use self::frame::{UdpChannel, UdpFramed, VecUdpCodec, PeerServerMessage};
+pub use self::frame::{UdpChannel, UdpFramed, VecUdpCodec, PeerServerMessage, EgressMessage};
+
+/// The most datagrams moved by a single `send_batch` or `recv_batch` call.
+pub const MAX_BATCH: usize = 16;
 use std::ops::Deref;
 
 /// An I/O object representing a UDP socket.
@@ -87,11 +92,46 @@ impl Deref for Endpoint {
     }
 }
 
+/// Sets the TOS (v4) or traffic class (v6) byte that packets sent on `fd` are marked with.
+fn set_tos(fd: RawFd, ipv4: bool, tos: u8) -> io::Result<()> {
+    let (level, name) = if ipv4 { (libc::IPPROTO_IP, libc::IP_TOS) } else { (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) };
+    let value         = libc::c_int::from(tos);
+    let ret = unsafe {
+        libc::setsockopt(fd, level, name, &value as *const _ as *const libc::c_void, mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok(())
+}
+
+fn v6_mapped_to_v4(addr: Ipv6Addr) -> Option<Ipv4Addr> {
+    match addr.segments() {
+        [0, 0, 0, 0, 0, f, g, h] if f == 0xffff => {
+            Some(Ipv4Addr::new((g >> 8) as u8, g as u8,
+                               (h >> 8) as u8, h as u8))
+        },
+        _ => None
+    }
+}
+
+/// Turns an IPv4-mapped IPv6 address (`::ffff:a.b.c.d`) into the plain IPv4 one. Our v6 socket is
+/// v6-only, so mapped endpoints would otherwise be unreachable and never match a received source.
+pub fn normalize_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6_mapped_to_v4(*v6.ip()) {
+            Some(v4) => SocketAddr::V4(SocketAddrV4::new(v4, v6.port())),
+            None     => addr,
+        },
+        _ => addr,
+    }
+}
+
 impl From<SocketAddr> for Endpoint {
     fn from(addr: SocketAddr) -> Self {
-        match addr {
-            SocketAddr::V4(_) => Endpoint::V4(addr, None),
-            SocketAddr::V6(_) => Endpoint::V6(addr, None),
+        match normalize_addr(addr) {
+            addr @ SocketAddr::V4(_) => Endpoint::V4(addr, None),
+            addr @ SocketAddr::V6(_) => Endpoint::V6(addr, None),
         }
     }
 }
@@ -118,6 +158,9 @@ impl UdpSocket {
         setsockopt(socket6.as_raw_fd(), sockopt::Ipv6RecvPacketInfo, &true);
 
         socket4.bind(&SocketAddr::from((Ipv4Addr::unspecified(), port)).into())?;
+
+        // If asked for any port, make sure both sockets share whichever one IPv4 was given.
+        let port = socket4.local_addr()?.as_inet().map(|addr| addr.port()).unwrap_or(port);
         socket6.bind(&SocketAddr::from((Ipv6Addr::unspecified(), port)).into())?;
 
         let socket4 = mio::net::UdpSocket::from_socket(socket4.into_udp_socket())?;
@@ -171,15 +214,30 @@ impl UdpSocket {
     }
 
     pub fn send_to(&self, buf: &[u8], target: &Endpoint) -> io::Result<usize> {
-        self.sendmsg(buf, target, false)
+        self.sendmsg(buf, target, 0, false)
     }
 
-    pub fn sendmsg(&self, buf: &[u8], target: &Endpoint, is_retry: bool) -> io::Result<usize> {
+    pub fn sendmsg(&self, buf: &[u8], target: &Endpoint, tos: u8, is_retry: bool) -> io::Result<usize> {
         let io = self.get_io(target);
         if let Async::NotReady = io.poll_write() {
             return Err(io::ErrorKind::WouldBlock.into())
         }
 
+        // nix has no TOS control message, so this path sets it on the socket for the one packet.
+        if tos != 0 {
+            set_tos(io.get_ref().as_raw_fd(), target.is_ipv4(), tos)?;
+        }
+        let res = self.sendmsg_inner(io, buf, target, tos, is_retry);
+        if tos != 0 {
+            // the packet's outcome is what the caller needs; a socket left marked is only logged.
+            if let Err(e) = set_tos(io.get_ref().as_raw_fd(), target.is_ipv4(), 0) {
+                warn!("couldn't reset TOS after sending to {:?}: {}", target, e);
+            }
+        }
+        res
+    }
+
+    fn sendmsg_inner(&self, io: &PollEvented<mio::net::UdpSocket>, buf: &[u8], target: &Endpoint, tos: u8, is_retry: bool) -> io::Result<usize> {
         let cmsgs = match *target {
             Endpoint::V4(addr, Some(ref pktinfo)) => vec![ControlMessage::Ipv4PacketInfo(pktinfo)],
             Endpoint::V6(addr, Some(ref pktinfo)) => vec![ControlMessage::Ipv6PacketInfo(pktinfo)],
@@ -202,7 +260,7 @@ impl UdpSocket {
                 if !is_retry {
                     // TODO: bubble up that the existing Endpoint pktinfo is now invalid.
                     debug!("EINVAL received after sendmsg, resending without pktinfo");
-                    self.sendmsg(buf, &target.to_cleared_pktinfo(), true)
+                    self.sendmsg_inner(io, buf, &target.to_cleared_pktinfo(), tos, true)
                 } else {
                     Err(io::Error::last_os_error())
                 }
@@ -216,6 +274,69 @@ impl UdpSocket {
         }
     }
 
+    /// Sends a prefix of `packets` with as few syscalls as the platform allows, returning how
+    /// many were sent. Only packets of the same address family as the first go out together.
+    #[cfg(target_os = "linux")]
+    pub fn send_batch(&self, packets: &[EgressMessage]) -> io::Result<usize> {
+        let first = match packets.first() {
+            Some(&(ref endpoint, _, _)) => endpoint,
+            None                        => return Ok(0),
+        };
+        let run = packets.iter().take_while(|&&(ref endpoint, _, _)| endpoint.is_ipv4() == first.is_ipv4()).count();
+
+        let io = self.get_io(first);
+        if let Async::NotReady = io.poll_write() {
+            return Err(io::ErrorKind::WouldBlock.into())
+        }
+
+        match batch::sendmmsg(io.get_ref().as_raw_fd(), &packets[..run]) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                io.need_write();
+                Err(io::ErrorKind::WouldBlock.into())
+            },
+            // sendmsg knows how to retry without a pktinfo source that's gone stale.
+            Err(ref e) if e.raw_os_error() == Some(libc::EINVAL) => self.sendmsg(&packets[0].1, first, packets[0].2, false).map(|_| 1),
+            result => result,
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn send_batch(&self, packets: &[EgressMessage]) -> io::Result<usize> {
+        let mut sent = 0;
+        for &(ref endpoint, ref packet, tos) in packets.iter().take(MAX_BATCH) {
+            match self.sendmsg(packet, endpoint, tos, false) {
+                Ok(_)                                                          => sent += 1,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock && sent > 0 => break,
+                Err(e)                                                         => return Err(e),
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Receives up to one datagram into each of `bufs`, returning each one's length and source.
+    #[cfg(target_os = "linux")]
+    pub fn recv_batch(&self, bufs: &mut [Vec<u8>]) -> io::Result<Vec<(usize, Endpoint)>> {
+        let io = match (self.io4.poll_read(), self.io6.poll_read()) {
+            (Async::Ready(_), _) => &self.io4,
+            (_, Async::Ready(_)) => &self.io6,
+            _                    => return Err(io::ErrorKind::WouldBlock.into()),
+        };
+
+        match batch::recvmmsg(io.get_ref().as_raw_fd(), bufs) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                io.need_read();
+                Err(io::ErrorKind::WouldBlock.into())
+            },
+            result => result,
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn recv_batch(&self, bufs: &mut [Vec<u8>]) -> io::Result<Vec<(usize, Endpoint)>> {
+        let received = self.recv_from(&mut bufs[0])?;
+        Ok(vec![received])
+    }
+
     /// Receives data from the socket. On success, returns the number of bytes
     /// read and the address from whence the data came.
     pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, Endpoint)> {
@@ -282,3 +403,68 @@ impl UdpSocket {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use std::time::Duration;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn mapped_endpoints() {
+        let mapped: SocketAddr = "[::ffff:1.2.3.4]:51820".parse().unwrap();
+        let plain:  SocketAddr = "1.2.3.4:51820".parse().unwrap();
+        let stored = Endpoint::from(mapped);
+        match stored {
+            Endpoint::V4(addr, None) => assert_eq!(addr, plain),
+            _                        => panic!("mapped address kept as {:?}", stored),
+        }
+
+        let v6: SocketAddr = "[fd00::1]:51820".parse().unwrap();
+        assert_eq!(normalize_addr(v6), v6);
+    }
+
+    #[test]
+    fn dual_stack_endpoints() {
+        let mut core       = Core::new().unwrap();
+        let socket         = UdpSocket::bind(0, core.handle()).unwrap();
+        let (addr4, addr6) = socket.local_addrs().unwrap();
+        assert_eq!(addr4.port(), addr6.port());
+
+        let client4 = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client6 = net::UdpSocket::bind("[::1]:0").unwrap();
+        client4.send_to(b"v4", (Ipv4Addr::localhost(), addr4.port())).unwrap();
+        client6.send_to(b"v6", (Ipv6Addr::localhost(), addr6.port())).unwrap();
+
+        let mut received = vec![];
+        let mut buf      = [0u8; 16];
+        core.run(future::poll_fn(|| -> Poll<(), io::Error> {
+            while received.len() < 2 {
+                let (len, endpoint) = try_nb!(socket.recv_from(&mut buf));
+                received.push((buf[..len].to_vec(), endpoint));
+            }
+            Ok(Async::Ready(()))
+        })).unwrap();
+
+        // replies go out of the socket matching each endpoint's family.
+        core.run(future::poll_fn(|| -> Poll<(), io::Error> {
+            for &(ref payload, ref endpoint) in &received {
+                let expected = match *endpoint {
+                    Endpoint::V4(..) => &b"v4"[..],
+                    Endpoint::V6(..) => &b"v6"[..],
+                };
+                assert_eq!(&payload[..], expected);
+                try_nb!(socket.send_to(payload, endpoint));
+            }
+            Ok(Async::Ready(()))
+        })).unwrap();
+
+        for client in &[client4, client6] {
+            client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+            let (len, from) = client.recv_from(&mut buf).unwrap();
+            assert_eq!(from.port(), addr4.port());
+            assert_eq!(&buf[..len], if from.is_ipv4() { b"v4" } else { b"v6" });
+        }
+    }
+}
@@ -0,0 +1,39 @@
+/* SPDX-License-Identifier: GPL-2.0
+ *
+ * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
+ */
+
+use std::ptr;
+use std::sync::atomic::{self, Ordering};
+
+/// Overwrites key material with zeroes in a way the optimizer won't elide, since the
+/// memory is usually about to be freed.
+pub fn zeroize(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut() {
+        unsafe { ptr::write_volatile(byte, 0); }
+    }
+    atomic::compiler_fence(Ordering::SeqCst);
+}
+
+/// Key material that can wipe itself.
+pub trait Zeroize {
+    fn zeroize(&mut self);
+}
+
+impl Zeroize for [u8; 32] {
+    fn zeroize(&mut self) {
+        zeroize(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zeroes_bytes() {
+        let mut key = [0xa5u8; 32];
+        zeroize(&mut key);
+        assert_eq!(key, [0u8; 32]);
+    }
+}
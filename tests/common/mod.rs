@@ -0,0 +1,72 @@
+/* SPDX-License-Identifier: GPL-2.0
+ *
+ * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
+ */
+
+//! Helpers for talking to a running `Interface` over its configuration socket, shared by the
+//! integration tests. Not every test file uses all of them.
+
+#![allow(dead_code)]
+
+use hex;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub fn run_path() -> PathBuf {
+    if PathBuf::from("/run").exists() { PathBuf::from("/run") } else { PathBuf::from("/var/run") }
+}
+
+pub fn wait_for_socket(name: &str) -> PathBuf {
+    let socket  = run_path().join("wireguard").join(format!("{}.sock", name));
+    let started = Instant::now();
+    while !socket.exists() {
+        assert!(started.elapsed() < Duration::from_secs(5), "{} never created its socket", socket.display());
+        thread::sleep(Duration::from_millis(10));
+    }
+    socket
+}
+
+/// Sends one request and returns the key-value pairs of the response, which has to end in errno=0.
+pub fn request(socket: &PathBuf, body: &str) -> Vec<(String, String)> {
+    let mut stream = UnixStream::connect(socket).unwrap();
+    stream.write_all(body.as_bytes()).unwrap();
+    stream.write_all(b"\n").unwrap();
+
+    let mut pairs = vec![];
+    for line in BufReader::new(stream).lines() {
+        let line = line.unwrap();
+        if line.is_empty() {
+            break;
+        }
+        let mut entry = line.splitn(2, '=');
+        let key       = entry.next().unwrap().to_owned();
+        let value     = entry.next().unwrap_or_else(|| panic!("malformed line {:?}", line)).to_owned();
+        pairs.push((key, value));
+    }
+    assert_eq!(pairs.last(), Some(&("errno".to_owned(), "0".to_owned())), "request failed: {:?}", pairs);
+    pairs.retain(|&(ref key, _)| key != "errno");
+    pairs
+}
+
+pub fn get(socket: &PathBuf) -> Vec<(String, String)> {
+    request(socket, "get=1\n")
+}
+
+pub fn key(byte: u8) -> String {
+    hex::encode(&[byte; 32])
+}
+
+pub fn values<'a>(pairs: &'a [(String, String)], key: &str) -> Vec<&'a str> {
+    pairs.iter().filter(|&&(ref k, _)| k == key).map(|&(_, ref v)| v.as_str()).collect()
+}
+
+/// The lines following `public_key=<peer>`, up to the next peer.
+pub fn peer_section<'a>(pairs: &'a [(String, String)], peer: &str) -> &'a [(String, String)] {
+    let start = pairs.iter().position(|&(ref k, ref v)| k == "public_key" && v == peer)
+        .unwrap_or_else(|| panic!("peer {} missing from {:?}", peer, pairs));
+    let len   = pairs[start + 1..].iter().position(|&(ref k, _)| k == "public_key").unwrap_or(pairs.len() - start - 1);
+    &pairs[start..start + 1 + len]
+}
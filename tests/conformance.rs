@@ -0,0 +1,219 @@
+/* SPDX-License-Identifier: GPL-2.0
+ *
+ * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
+ */
+
+//! Speaks the cross-platform userspace API to a running `Interface` over its unix socket, the
+//! same way wg(8) does. Needs permission to create tun devices, so it only builds with
+//! `--features conformance-tests`.
+
+#![cfg(feature = "conformance-tests")]
+
+extern crate futures;
+extern crate hex;
+extern crate libc;
+extern crate wireguard;
+
+mod common;
+
+use common::{get, key, peer_section, request, run_path, values, wait_for_socket};
+use futures::Stream;
+use std::env;
+use std::fs;
+use std::net::UdpSocket;
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use wireguard::error::InterfaceError;
+use wireguard::interface::Interface;
+
+/// Starts an interface called `name` on its own thread, returning once its socket is up.
+fn start(name: &'static str) -> PathBuf {
+    thread::spawn(move || Interface::new(name.parse().unwrap()).start().unwrap());
+    wait_for_socket(name)
+}
+
+#[test]
+fn get_without_peers() {
+    let socket = start("wgconf0");
+    let pairs  = get(&socket);
+    assert!(values(&pairs, "public_key").is_empty());
+    assert!(values(&pairs, "private_key").is_empty());
+}
+
+#[test]
+fn get_with_one_peer() {
+    let socket = start("wgconf1");
+    request(&socket, &format!("set=1\nprivate_key={}\nlisten_port=51831\npublic_key={}\npreshared_key={}\n\
+                               endpoint=127.0.0.1:51832\npersistent_keepalive_interval=25\nallowed_ip=10.0.0.2/32\n\
+                               allowed_ip=fd00::2/128\n", key(1), key(2), key(3)));
+
+    let pairs = get(&socket);
+    assert_eq!(&pairs[..2], &[("private_key".to_owned(), key(1)), ("listen_port".to_owned(), "51831".to_owned())]);
+
+    let peer = peer_section(&pairs, &key(2));
+    assert_eq!(values(peer, "preshared_key"), vec![key(3).as_str()]);
+    assert_eq!(values(peer, "endpoint"), vec!["127.0.0.1:51832"]);
+    assert_eq!(values(peer, "persistent_keepalive_interval"), vec!["25"]);
+    assert_eq!(values(peer, "allowed_ip"), vec!["10.0.0.2/32", "fd00::2/128"]);
+    assert_eq!(values(peer, "tx_bytes"), vec!["0"]);
+    assert_eq!(values(peer, "rx_bytes"), vec!["0"]);
+}
+
+#[test]
+fn set_private_key() {
+    let socket = start("wgconf2");
+    request(&socket, &format!("set=1\nprivate_key={}\n", key(1)));
+    assert_eq!(values(&get(&socket), "private_key"), vec![key(1).as_str()]);
+
+    request(&socket, &format!("set=1\nprivate_key={}\n", key(0)));
+    assert!(values(&get(&socket), "private_key").is_empty());
+}
+
+#[test]
+fn replace_peers() {
+    let socket = start("wgconf3");
+    request(&socket, &format!("set=1\npublic_key={}\nallowed_ip=10.0.0.2/32\n", key(2)));
+    request(&socket, &format!("set=1\nreplace_peers=true\npublic_key={}\nallowed_ip=10.0.0.3/32\n\
+                               public_key={}\nallowed_ip=10.0.0.4/32\n", key(3), key(4)));
+
+    let pairs     = get(&socket);
+    let mut peers = values(&pairs, "public_key");
+    peers.sort();
+    assert_eq!(peers, vec![key(3).as_str(), key(4).as_str()]);
+    assert_eq!(values(peer_section(&pairs, &key(4)), "allowed_ip"), vec!["10.0.0.4/32"]);
+}
+
+#[test]
+fn remove_peer() {
+    let socket = start("wgconf4");
+    request(&socket, &format!("set=1\npublic_key={}\npublic_key={}\n", key(2), key(3)));
+    request(&socket, &format!("set=1\npublic_key={}\nremove=true\n", key(2)));
+    assert_eq!(values(&get(&socket), "public_key"), vec![key(3).as_str()]);
+}
+
+#[test]
+fn replace_allowed_ips() {
+    let socket = start("wgconf5");
+    request(&socket, &format!("set=1\npublic_key={}\nallowed_ip=10.0.0.2/32\n", key(2)));
+    request(&socket, &format!("set=1\npublic_key={}\nallowed_ip=10.0.0.3/32\n", key(2)));
+    assert_eq!(values(&get(&socket), "allowed_ip"), vec!["10.0.0.2/32", "10.0.0.3/32"]);
+
+    request(&socket, &format!("set=1\npublic_key={}\nreplace_allowed_ips=true\nallowed_ip=10.0.0.4/32\n", key(2)));
+    assert_eq!(values(&get(&socket), "allowed_ip"), vec!["10.0.0.4/32"]);
+}
+
+#[test]
+fn restart_cleans_up_socket() {
+    for _ in 0..2 {
+        let (tx, rx)  = mpsc::channel();
+        let runner    = thread::spawn(move || {
+            let mut interface = Interface::new("wgconf6".parse().unwrap());
+            tx.send(interface.shutdown_handle()).unwrap();
+            interface.start()
+        });
+        let shutdown  = rx.recv().unwrap();
+        let socket    = wait_for_socket("wgconf6");
+        assert!(get(&socket).is_empty());
+
+        shutdown.shutdown();
+        runner.join().unwrap().unwrap();
+        assert!(!socket.exists());
+    }
+}
+
+#[test]
+fn signals_readiness() {
+    let (tx, rx) = mpsc::channel();
+    let runner   = thread::spawn(move || {
+        let mut interface = Interface::new("wgconf7".parse().unwrap());
+        tx.send((interface.ready_watch(), interface.shutdown_handle())).unwrap();
+        interface.start()
+    });
+    let (ready, shutdown) = rx.recv().unwrap();
+    let mut ready         = ready.wait();
+    assert_eq!(ready.next(), Some(Ok(false)));
+
+    let (tx, rx) = mpsc::channel();
+    let _ = thread::spawn(move || for state in ready { let _ = tx.send(state.unwrap()); });
+    assert_eq!(rx.recv_timeout(Duration::from_secs(1)), Ok(true));
+    assert!(wait_for_socket("wgconf7").exists());
+
+    shutdown.shutdown();
+    runner.join().unwrap().unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(1)), Ok(false));
+}
+
+#[test]
+fn reloads_on_sighup() {
+    let path   = env::temp_dir().join("wgconf8.conf");
+    let config = |fields: &str| format!("[Interface]\nPrivateKey = {}\nListenPort = 51833\n\n[Peer]\nPublicKey = {}\n{}",
+                                        "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=", "AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgI=", fields);
+    fs::write(&path, config("Endpoint = 127.0.0.1:51834\nPersistentKeepalive = 25\nAllowedIPs = 10.0.0.2/32\n")).unwrap();
+
+    let watched = path.clone();
+    thread::spawn(move || {
+        let mut interface = Interface::new("wgconf8".parse().unwrap());
+        interface.watch_config_file(&watched);
+        interface.start().unwrap();
+    });
+    let socket = wait_for_socket("wgconf8");
+    let peer   = get(&socket);
+    let peer   = peer_section(&peer, &key(2));
+    assert_eq!(values(peer, "endpoint"), vec!["127.0.0.1:51834"]);
+    assert_eq!(values(peer, "persistent_keepalive_interval"), vec!["25"]);
+
+    // the endpoint and keepalive go, the allowed IPs change, and the key stays
+    fs::write(&path, config("AllowedIPs = 10.0.0.3/32\n")).unwrap();
+    unsafe { libc::kill(libc::getpid(), libc::SIGHUP) };
+    let started = Instant::now();
+    loop {
+        let pairs = get(&socket);
+        if values(peer_section(&pairs, &key(2)), "allowed_ip") == vec!["10.0.0.3/32"] {
+            let peer = peer_section(&pairs, &key(2));
+            assert!(values(peer, "endpoint").is_empty());
+            assert!(values(peer, "persistent_keepalive_interval").iter().all(|&value| value == "0"));
+            assert_eq!(values(&pairs, "private_key"), vec![key(1).as_str()]);
+            break;
+        }
+        assert!(started.elapsed() < Duration::from_secs(5), "reload never applied: {:?}", pairs);
+        thread::sleep(Duration::from_millis(50));
+    }
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn counts_malformed_datagrams() {
+    let socket = start("wgconf9");
+    request(&socket, &format!("set=1\nprivate_key={}\nlisten_port=51835\n", key(1)));
+
+    let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+    for datagram in &[&[9u8, 0, 0, 0][..], &[1u8, 0, 0, 0, 1, 2, 3][..], &[4u8; 8][..]] {
+        let _ = sender.send_to(datagram, "127.0.0.1:51835").unwrap();
+    }
+    let started = Instant::now();
+    while values(&get(&socket), "drop_reason_malformed_packet") != vec!["3"] {
+        assert!(started.elapsed() < Duration::from_secs(5), "drops never counted: {:?}", get(&socket));
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn config_socket_in_use() {
+    let dir    = run_path().join("wireguard");
+    let _      = fs::create_dir(&dir);
+    let socket = dir.join("wgconf10.sock");
+    let _      = fs::remove_file(&socket);
+    let taken  = UnixListener::bind(&socket).unwrap();
+
+    match Interface::new("wgconf10".parse().unwrap()).start() {
+        Err(InterfaceError::Socket(_)) => {},
+        result                         => panic!("expected Socket, got {:?}", result),
+    }
+    assert!(socket.exists());
+
+    drop(taken);
+    let _ = fs::remove_file(&socket);
+}
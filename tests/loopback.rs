@@ -0,0 +1,152 @@
+/* SPDX-License-Identifier: GPL-2.0
+ *
+ * Copyright (C) 2017-2018 WireGuard LLC. All Rights Reserved.
+ */
+
+//! Two interfaces in one process, peered with each other over loopback UDP and driven through
+//! their configuration sockets. Like the conformance tests these need permission to create tun
+//! devices, so they only build with `--features conformance-tests`.
+
+#![cfg(feature = "conformance-tests")]
+
+extern crate hex;
+extern crate wireguard;
+
+mod common;
+
+use common::{get, key, peer_section, request, values, wait_for_socket};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+use wireguard::config_file::InterfaceConfig;
+use wireguard::interface::{Interface, InterfaceManager};
+use wireguard::types::PrivateKey;
+
+struct Pair {
+    one     : PathBuf,
+    two     : PathBuf,
+    one_pub : String,
+    two_pub : String,
+    port    : u16,
+}
+
+fn start(name: &'static str) -> PathBuf {
+    thread::spawn(move || Interface::new(name.parse().unwrap()).start().unwrap());
+    wait_for_socket(name)
+}
+
+/// The public key belonging to `key(byte)`, hex encoded.
+fn public_key(byte: u8) -> String {
+    hex::encode(&PrivateKey([byte; 32]).public_key().0)
+}
+
+fn peer_config(pub_key: &str, port: u16, allowed_ip: &str) -> String {
+    format!("public_key={}\nendpoint=127.0.0.1:{}\npersistent_keepalive_interval=1\nallowed_ip={}\n", pub_key, port, allowed_ip)
+}
+
+/// Starts interfaces `names` listening on 127.0.0.1:`port` and `port + 1`, each with the
+/// other as its only peer and a one second persistent keepalive to get things going.
+fn pair(names: (&'static str, &'static str), port: u16) -> Pair {
+    peered((start(names.0), start(names.1)), port)
+}
+
+/// Peers the interfaces behind `sockets` with each other, as `pair` does.
+fn peered(sockets: (PathBuf, PathBuf), port: u16) -> Pair {
+    let pair = Pair { one: sockets.0, two: sockets.1, one_pub: public_key(1), two_pub: public_key(2), port };
+    request(&pair.one, &format!("set=1\nprivate_key={}\nlisten_port={}\n{}", key(1), port, peer_config(&pair.two_pub, port + 1, "10.0.0.2/32")));
+    request(&pair.two, &format!("set=1\nprivate_key={}\nlisten_port={}\n{}", key(2), port + 1, peer_config(&pair.one_pub, port, "10.0.0.1/32")));
+    pair
+}
+
+/// Polls `socket` until `condition` holds for `peer`'s section of the configuration.
+fn wait_for<F>(socket: &PathBuf, peer: &str, what: &str, condition: F)
+    where F: Fn(&[(String, String)]) -> bool
+{
+    let started = Instant::now();
+    loop {
+        let pairs = get(socket);
+        if condition(peer_section(&pairs, peer)) {
+            return;
+        }
+        assert!(started.elapsed() < Duration::from_secs(10), "gave up waiting for {}: {:?}", what, pairs);
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn counter(section: &[(String, String)], key: &str) -> u64 {
+    values(section, key).first().and_then(|value| value.parse().ok()).unwrap_or(0)
+}
+
+fn has_handshake(section: &[(String, String)]) -> bool {
+    counter(section, "last_handshake_time_sec") > 0
+}
+
+/// When the last handshake completed, to tell one handshake from the next.
+fn handshake_time(section: &[(String, String)]) -> (u64, u64) {
+    (counter(section, "last_handshake_time_sec"), counter(section, "last_handshake_time_nsec"))
+}
+
+#[test]
+fn handshake_completes() {
+    let pair = pair(("wgloop0", "wgloop1"), 51840);
+    wait_for(&pair.one, &pair.two_pub, "handshake on the first interface", has_handshake);
+    wait_for(&pair.two, &pair.one_pub, "handshake on the second interface", has_handshake);
+}
+
+#[test]
+fn bidirectional_transport() {
+    let pair = pair(("wgloop2", "wgloop3"), 51842);
+    for &(socket, peer) in &[(&pair.one, &pair.two_pub), (&pair.two, &pair.one_pub)] {
+        wait_for(socket, peer, "traffic both ways", |section| counter(section, "tx_bytes") > 0 && counter(section, "rx_bytes") > 0);
+    }
+}
+
+#[test]
+fn keepalives_keep_arriving() {
+    let pair  = pair(("wgloop4", "wgloop5"), 51844);
+    wait_for(&pair.two, &pair.one_pub, "first keepalive", |section| counter(section, "rx_bytes") > 0);
+
+    let received = counter(peer_section(&get(&pair.two), &pair.one_pub), "rx_bytes");
+    wait_for(&pair.two, &pair.one_pub, "later keepalives", |section| counter(section, "rx_bytes") > received);
+}
+
+#[test]
+fn peer_removed_and_readded() {
+    let pair = pair(("wgloop6", "wgloop7"), 51846);
+    wait_for(&pair.two, &pair.one_pub, "first handshake", has_handshake);
+
+    request(&pair.two, &format!("set=1\npublic_key={}\nremove=true\n", pair.one_pub));
+    assert!(values(&get(&pair.two), "public_key").is_empty());
+
+    request(&pair.two, &format!("set=1\n{}", peer_config(&pair.one_pub, pair.port, "10.0.0.1/32")));
+    wait_for(&pair.two, &pair.one_pub, "handshake after re-adding", has_handshake);
+    wait_for(&pair.one, &pair.two_pub, "traffic after re-adding", |section| counter(section, "rx_bytes") > 0);
+}
+
+#[test]
+fn rekeys_with_short_timers() {
+    // received keepalives start a rekey once sessions are reject - keepalive - rekey_timeout = 2s old
+    let pair   = pair(("wgloop8", "wgloop9"), 51848);
+    let timers = "set=1\nreject_after_time_ms=3000\nrekey_after_time_ms=1000\nkeepalive_timeout_ms=500\nrekey_timeout_ms=500\n";
+    request(&pair.one, timers);
+    request(&pair.two, timers);
+    wait_for(&pair.one, &pair.two_pub, "first handshake", has_handshake);
+
+    let first = handshake_time(peer_section(&get(&pair.one), &pair.two_pub));
+    wait_for(&pair.one, &pair.two_pub, "a rekey", |section| handshake_time(section) != first);
+}
+
+#[test]
+fn shared_reactor() {
+    let names = ("wgloop10", "wgloop11");
+    thread::spawn(move || {
+        let mut manager = InterfaceManager::new().unwrap();
+        let _ = manager.spawn_interface(names.0, InterfaceConfig::default()).unwrap();
+        let _ = manager.spawn_interface(names.1, InterfaceConfig::default()).unwrap();
+        manager.run().unwrap();
+    });
+    let pair = peered((wait_for_socket(names.0), wait_for_socket(names.1)), 51850);
+    for &(socket, peer) in &[(&pair.one, &pair.two_pub), (&pair.two, &pair.one_pub)] {
+        wait_for(socket, peer, "traffic both ways", |section| counter(section, "tx_bytes") > 0 && counter(section, "rx_bytes") > 0);
+    }
+}
@@ -0,0 +1,89 @@
+#!/usr/bin/env python3
+
+# Derives the transport ciphertext `pinned_session_ciphertext` in src/peer.rs expects, straight
+# from the Noise spec's IKpsk2 pattern and WireGuard's prologue, without going through snow.
+# Needs the `cryptography` package.
+
+import hashlib
+import hmac
+from cryptography.hazmat.primitives import serialization
+from cryptography.hazmat.primitives.asymmetric.x25519 import X25519PrivateKey, X25519PublicKey
+from cryptography.hazmat.primitives.ciphers.aead import ChaCha20Poly1305
+
+PROTOCOL = b"Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s"
+PROLOGUE = b"WireGuard v1 zx2c4 Jason@zx2c4.com"
+
+INIT_STATIC     = bytes([0x11] * 32)
+INIT_EPHEMERAL  = bytes([0x33] * 32)
+RESP_STATIC     = bytes([0x22] * 32)
+RESP_EPHEMERAL  = bytes([0x44] * 32)
+PSK             = bytes(32)
+TIMESTAMP       = bytes(12)
+PAYLOAD         = b"pinned session, known key bytes!"
+
+def blake2s(data):
+    return hashlib.blake2s(data).digest()
+
+def hkdf(chaining_key, input_key_material, outputs):
+    prk, out, prev = hmac.new(chaining_key, input_key_material, hashlib.blake2s).digest(), [], b''
+    for i in range(1, outputs + 1):
+        prev = hmac.new(prk, prev + bytes([i]), hashlib.blake2s).digest()
+        out.append(prev)
+    return out
+
+def public(private):
+    key = X25519PrivateKey.from_private_bytes(private).public_key()
+    return key.public_bytes(serialization.Encoding.Raw, serialization.PublicFormat.Raw)
+
+def dh(private, public):
+    return X25519PrivateKey.from_private_bytes(private).exchange(X25519PublicKey.from_public_bytes(public))
+
+def encrypt(key, counter, plaintext, ad):
+    return ChaCha20Poly1305(key).encrypt(bytes(4) + counter.to_bytes(8, 'little'), plaintext, ad)
+
+class SymmetricState:
+    def __init__(self):
+        self.h  = blake2s(PROTOCOL)
+        self.ck = self.h
+        self.k  = None
+        self.n  = 0
+
+    def mix_hash(self, data):
+        self.h = blake2s(self.h + data)
+
+    def mix_key(self, input_key_material):
+        self.ck, self.k = hkdf(self.ck, input_key_material, 2)
+        self.n = 0
+
+    def mix_key_and_hash(self, input_key_material):
+        self.ck, temp_h, self.k = hkdf(self.ck, input_key_material, 3)
+        self.mix_hash(temp_h)
+        self.n = 0
+
+    def encrypt_and_hash(self, plaintext):
+        ciphertext = encrypt(self.k, self.n, plaintext, self.h)
+        self.n += 1
+        self.mix_hash(ciphertext)
+
+state = SymmetricState()
+state.mix_hash(PROLOGUE)
+state.mix_hash(public(RESP_STATIC))
+
+# -> e, es, s, ss
+state.mix_hash(public(INIT_EPHEMERAL))
+state.mix_key(public(INIT_EPHEMERAL))
+state.mix_key(dh(INIT_EPHEMERAL, public(RESP_STATIC)))
+state.encrypt_and_hash(public(INIT_STATIC))
+state.mix_key(dh(INIT_STATIC, public(RESP_STATIC)))
+state.encrypt_and_hash(TIMESTAMP)
+
+# <- e, ee, se, psk
+state.mix_hash(public(RESP_EPHEMERAL))
+state.mix_key(public(RESP_EPHEMERAL))
+state.mix_key(dh(INIT_EPHEMERAL, public(RESP_EPHEMERAL)))
+state.mix_key(dh(INIT_STATIC, public(RESP_EPHEMERAL)))
+state.mix_key_and_hash(PSK)
+state.encrypt_and_hash(b'')
+
+initiator_sending, _ = hkdf(state.ck, b'', 2)
+print(encrypt(initiator_sending, 0, PAYLOAD, b'').hex())